@@ -7,6 +7,7 @@ use listenfd::ListenFd;
 use r2d2_redis::{r2d2, redis, RedisConnectionManager};
 use serde::{Deserialize, Serialize};
 use chrono::{Local, Duration};
+use shutdown::{Readiness, ShutdownOptions};
 use std::ops::DerefMut;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -25,6 +26,8 @@ impl PartialEq for Claims {
 
 static SECRET: &str = "secret";
 static DURATION_DELTA: i64 = 30;
+static READINESS_DELAY_MS: u64 = 5000;
+static SHUTDOWN_TIMEOUT_SECS: u64 = 30;
 
 fn generate_token(claims: &mut Claims) -> String {
     claims.exp = (Local::now() + Duration::minutes(DURATION_DELTA))
@@ -91,6 +94,14 @@ async fn auth(
     HttpResponse::Ok().json(token)
 }
 
+async fn readiness(ready: web::Data<Readiness>) -> HttpResponse {
+    if ready.is_ready() {
+        HttpResponse::Ok().finish()
+    } else {
+        HttpResponse::ServiceUnavailable().finish()
+    }
+}
+
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "debug");
@@ -102,15 +113,23 @@ async fn main() -> std::io::Result<()> {
         .build(manager)
         .expect("Failed to create pool");
 
+    let ready = web::Data::new(Readiness::default());
+    let app_ready = ready.clone();
+
     let mut listen_fd = ListenFd::from_env();
     let mut server = HttpServer::new(move || {
         App::new()
             .data(pool.clone())
+            .app_data(app_ready.clone())
             .wrap(Logger::new(
                 "ip: %a, date: %t, response code: %s, response size: %b (bytes), duration: %D (ms)",
             ))
             .service(web::resource("/auth").route(web::post().to(auth)))
-    });
+            .service(web::resource("/probe/liveness").route(web::get().to(HttpResponse::Ok)))
+            .service(web::resource("/probe/readiness").route(web::get().to(readiness)))
+    })
+    .disable_signals()
+    .shutdown_timeout(SHUTDOWN_TIMEOUT_SECS);
 
     server = if let Some(l) = listen_fd.take_tcp_listener(0)? {
         server.listen(l)?
@@ -118,5 +137,19 @@ async fn main() -> std::io::Result<()> {
         server.workers(4).bind(format!("0.0.0.0:{}", 3000))?
     };
 
-    server.run().await
+    let server = server.run();
+    let stopping = server.clone();
+
+    // Server waits for in-flight requests at most SHUTDOWN_TIMEOUT_SECS
+    std::thread::spawn(move || {
+        let options = ShutdownOptions {
+            readiness_delay_ms: READINESS_DELAY_MS,
+            timeout_secs: SHUTDOWN_TIMEOUT_SECS,
+        };
+        shutdown::on_signal(&options, &ready, || {
+            actix_rt::System::new("shutdown").block_on(stopping.stop(true));
+        }, || {});
+    });
+
+    server.await
 }
@@ -0,0 +1,34 @@
+use r2d2_redis::{r2d2, redis, RedisConnectionManager};
+use rdkafka::message::{BorrowedMessage, Message};
+use std::collections::HashMap;
+use std::ops::DerefMut;
+
+const PROCESSED_KEY_PREFIX: &str = "processed:billing";
+
+// Producers of this project put 'message_id' header into every message,
+// gateway doesn't do it, so message position in topic is used instead,
+// it is stable between redeliveries of the same message
+pub fn message_id(msg: &BorrowedMessage, metadata: &HashMap<&str, &str>) -> String {
+    match metadata.get("message_id") {
+        Some(id) => id.to_string(),
+        None => format!("{}:{}:{}", msg.topic(), msg.partition(), msg.offset()),
+    }
+}
+
+pub fn is_processed(
+    message_id: &str,
+    conn: &mut r2d2::PooledConnection<RedisConnectionManager>,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let exists: i32 = redis::cmd("EXISTS")
+        .arg(format!("{}:{}", PROCESSED_KEY_PREFIX, message_id))
+        .query(conn.deref_mut())?;
+    Ok(exists == 1)
+}
+
+pub fn mark_processed(pipe: &mut redis::Pipeline, message_id: &str, ttl_secs: usize) {
+    pipe.cmd("SET")
+        .arg(format!("{}:{}", PROCESSED_KEY_PREFIX, message_id))
+        .arg(1)
+        .arg("EX")
+        .arg(ttl_secs);
+}
@@ -1,16 +1,32 @@
-use crate::KafkaTopics;
+use crate::idempotency::{is_processed, message_id};
+use crate::outbox::{Destination, Outbox};
+use crate::{KafkaProcessingOptions, KafkaTopics};
 use futures::stream::Stream;
+use r2d2_redis::{r2d2, redis, RedisConnectionManager};
 use rdkafka::client::ClientContext;
 use rdkafka::consumer::stream_consumer::StreamConsumer;
 use rdkafka::consumer::{CommitMode, Consumer, ConsumerContext, Rebalance};
 use rdkafka::error::KafkaResult;
-use rdkafka::message::{BorrowedHeaders, Headers, Message, OwnedHeaders};
-use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::message::{BorrowedHeaders, BorrowedMessage, Headers, Message};
+use serde::Deserialize;
 use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::ops::DerefMut;
 use std::sync::Arc;
+use std::time::Duration;
+
+const REFUNDS_KEY_PREFIX: &str = "refunds:billing";
 
 pub struct BillingContext;
 
+// Amount in minor units orders service priced the order with,
+// older orders service sends no payload
+#[derive(Deserialize)]
+struct Payment {
+    amount: u64,
+    currency: String,
+}
+
 impl ClientContext for BillingContext {}
 
 impl ConsumerContext for BillingContext {
@@ -60,10 +76,208 @@ fn get_kafka_message_metadata<'a>(
     Ok(metadata)
 }
 
+// Errors of infrastructure (redis is down) are worth retrying, all other
+// errors mean that message itself is wrong and will never be processed
+fn is_transient(e: &(dyn std::error::Error + 'static)) -> bool {
+    if let Some(e) = e.downcast_ref::<redis::RedisError>() {
+        e.is_io_error()
+    } else {
+        e.is::<r2d2::Error>()
+    }
+}
+
+fn with_retries<T>(
+    options: &KafkaProcessingOptions,
+    mut f: impl FnMut() -> Result<T, Box<dyn std::error::Error>>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let mut backoff = options.retry_backoff_ms;
+
+    loop {
+        match f() {
+            Err(ref e) if is_transient(e.as_ref()) => {
+                warn!(
+                    "line:{}: Transient error: {}, retrying in {} ms",
+                    line!(),
+                    e,
+                    backoff
+                );
+                std::thread::sleep(Duration::from_millis(backoff));
+                backoff = std::cmp::min(backoff * 2, options.max_retry_backoff_ms);
+            }
+            result => return result,
+        }
+    }
+}
+
+// Orders service puts the same 'refund_id' into every request of one
+// refund, older orders service doesn't, so it is made of what is refunded
+fn refund_id(metadata: &HashMap<&str, &str>) -> String {
+    match metadata.get("refund_id") {
+        Some(refund_id) => refund_id.to_string(),
+        None => format!(
+            "{}:user_id:{}:order_id:{}:return_id:{}",
+            metadata.get("operation").cloned().unwrap_or(""),
+            metadata["user_id"],
+            metadata["order_id"],
+            metadata.get("return_id").cloned().unwrap_or("")
+        ),
+    }
+}
+
+// Mark of the refund is kept forever and is set in the same transaction as
+// the answer, returns false if the refund was already made before
+fn commit_refund(
+    refund_id: &str,
+    outbox: &Outbox,
+    conn: &mut r2d2::PooledConnection<RedisConnectionManager>,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    pipe.cmd("SET")
+        .arg(format!("{}:{}", REFUNDS_KEY_PREFIX, refund_id))
+        .arg(1)
+        .arg("NX");
+    outbox.write(&mut pipe)?;
+
+    let results: Vec<redis::Value> = pipe.query(conn.deref_mut())?;
+    Ok(results.first() == Some(&redis::Value::Okay))
+}
+
+fn handle_message(
+    options: &KafkaProcessingOptions,
+    pool: &r2d2::Pool<RedisConnectionManager>,
+    msg: &BorrowedMessage,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let payload = match msg.payload_view::<str>() {
+        None => "",
+        Some(Ok(payload)) => payload,
+        Some(Err(e)) => {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "{}:Error: can't deserialize message payload: {:?}",
+                    line!(),
+                    e
+                ),
+            )))
+        }
+    };
+
+    debug!(
+        "payload: '{}', topic: {}, partition: {}, offset: {}, timestamp: {:?}",
+        payload,
+        msg.topic(),
+        msg.partition(),
+        msg.offset(),
+        msg.timestamp()
+    );
+
+    let headers = msg.headers();
+    let metadata = get_kafka_message_metadata(&headers)?;
+    let message_id = message_id(msg, &metadata);
+
+    if with_retries(options, || is_processed(&message_id, &mut pool.get()?))? {
+        info!(
+            "{}:Message '{}' was already processed, skipping",
+            line!(),
+            message_id
+        );
+        return Ok(());
+    }
+
+    // Payment and refund of an order are answered as different transactions
+    let transaction = match metadata.get("operation") {
+        Some(&"make_billing") => "billing",
+        Some(&"refund") => "refund",
+        Some(&"refund_return") => "refund_return",
+        op => {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!("{}:Error: unknown operation: {:?}", line!(), op),
+            )))
+        }
+    };
+
+    if transaction == "billing" && !payload.is_empty() {
+        let payment: Payment = serde_json::from_str(payload)?;
+        info!(
+            "{}:Charging {} {} for order '{}' of user '{}'",
+            line!(),
+            payment.amount,
+            payment.currency,
+            metadata["order_id"],
+            metadata["user_id"]
+        );
+    }
+
+    let mut outbox = Outbox::new(&message_id, options.processed_ttl_secs);
+    let mut headers = vec![
+        ("user_id", metadata["user_id"]),
+        ("order_id", metadata["order_id"]),
+        ("transaction", transaction),
+        ("operation", "commit"),
+        ("actor", "billing"),
+        (
+            "correlation_id",
+            metadata
+                .get("correlation_id")
+                .cloned()
+                .unwrap_or(&message_id),
+        ),
+    ];
+
+    if let Some(return_id) = metadata.get("return_id") {
+        headers.push(("return_id", return_id));
+    }
+
+    // Orders service waits for the answer to move order saga or return forward
+    outbox.push(Destination::Transactions, &headers, "".to_string());
+
+    if transaction == "billing" {
+        return with_retries(options, || outbox.commit(&mut pool.get()?));
+    }
+
+    let refund_id = refund_id(&metadata);
+
+    if !with_retries(options, || {
+        commit_refund(&refund_id, &outbox, &mut pool.get()?)
+    })? {
+        info!(
+            "{}:Refund '{}' was already made, answering only",
+            line!(),
+            refund_id
+        );
+        return Ok(());
+    }
+
+    // Returned goods are refunded partially, by the amount orders service computed
+    match serde_json::from_str::<Payment>(payload) {
+        Ok(payment) => info!(
+            "{}:Refunding {} {} as '{}' of order '{}' of user '{}'",
+            line!(),
+            payment.amount,
+            payment.currency,
+            refund_id,
+            metadata["order_id"],
+            metadata["user_id"]
+        ),
+        Err(_) => info!(
+            "{}:Refunding order '{}' of user '{}' as '{}'",
+            line!(),
+            metadata["order_id"],
+            metadata["user_id"],
+            refund_id
+        ),
+    }
+
+    Ok(())
+}
+
 pub fn consume_and_process(
     topics: KafkaTopics,
-    producer: FutureProducer,
+    options: KafkaProcessingOptions,
     consumer: Arc<StreamConsumer<BillingContext>>,
+    pool: r2d2::Pool<RedisConnectionManager>,
 ) {
     consumer
         .subscribe(&[&topics.billing_service_topic])
@@ -74,48 +288,13 @@ pub fn consume_and_process(
             Err(e) => error!("{}:Error: can't read from kafka stream: {:?}", line!(), e),
             Ok(Err(e)) => error!("{}:Error: kafka error: {}", line!(), e),
             Ok(Ok(msg)) => {
-                match msg.payload_view::<str>() {
-                    None => {
-                        error!("{}:Error: empty payload came from kafka", line!());
-                    }
-                    Some(Ok(payload)) => {
-                        debug!(
-                            "payload: '{}', topic: {}, partition: {}, offset: {}, timestamp: {:?}",
-                            payload,
-                            msg.topic(),
-                            msg.partition(),
-                            msg.offset(),
-                            msg.timestamp()
-                        );
-
-                        match get_kafka_message_metadata(&msg.headers()) {
-                            Ok(metadata) => {
-                                let payload = "".to_string();
-                                let record: FutureRecord<String, String> =
-                                    FutureRecord::to(&topics.orders_service_topic)
-                                        .headers(
-                                            OwnedHeaders::new()
-                                                .add("user_id", metadata["user_id"])
-                                                .add("order_id", metadata["order_id"])
-                                                .add("operation", "make_billing"),
-                                        )
-                                        .payload(&payload);
-
-                                let _ = producer.send(record, 0);
-                            }
-                            Err(e) => error!("{}:Error: {}", line!(), e),
-                        }
-                    }
-                    Some(Err(e)) => {
-                        error!(
-                            "{}:Error: can't deserialize message payload: {:?}",
-                            line!(),
-                            e
-                        );
-                    }
+                if let Err(e) = handle_message(&options, &pool, &msg) {
+                    error!("{}:Error: {}, message is skipped", line!(), e);
                 }
 
-                consumer.commit_message(&msg, CommitMode::Async).unwrap();
+                if let Err(e) = consumer.commit_message(&msg, CommitMode::Sync) {
+                    error!("{}:Error: can't commit offset: {}", line!(), e);
+                }
             }
         };
     }
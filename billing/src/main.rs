@@ -1,16 +1,21 @@
 #[macro_use]
 extern crate log;
 
-use actix_web::{middleware::Logger, App, HttpServer};
+use actix_web::{middleware::Logger, web, App, HttpServer};
+use futures::Future;
+use r2d2_redis::{r2d2, RedisConnectionManager};
 use rdkafka::config::{ClientConfig, RDKafkaLogLevel};
 use rdkafka::consumer::stream_consumer::StreamConsumer;
 use rdkafka::producer::FutureProducer;
 use serde::Deserialize;
-use signal_hook::{iterator::Signals, SIGINT, SIGQUIT, SIGTERM};
+use shutdown::{Readiness, ShutdownOptions};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 mod appconfig;
+mod idempotency;
 mod kafka_processor;
+mod outbox;
 
 #[derive(Deserialize)]
 struct ServerOptions {
@@ -18,6 +23,7 @@ struct ServerOptions {
     workers: usize,
     kafka_workers: usize,
     log_level: String,
+    redis_connection_string: String,
 }
 
 #[derive(Clone, Deserialize)]
@@ -38,7 +44,21 @@ pub struct KafkaProducerOptions {
 #[derive(Clone, Deserialize)]
 pub struct KafkaTopics {
     billing_service_topic: String,
-    orders_service_topic: String,
+    transactions_topic: String,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct KafkaProcessingOptions {
+    retry_backoff_ms: u64,
+    max_retry_backoff_ms: u64,
+    processed_ttl_secs: usize,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct OutboxOptions {
+    poll_interval_ms: u64,
+    batch_size: usize,
+    lock_ttl_ms: u64,
 }
 
 #[derive(Deserialize)]
@@ -47,6 +67,9 @@ struct Config {
     kafka_producer: KafkaProducerOptions,
     kafka_consumer: KafkaConsumerOptions,
     kafka_topics: KafkaTopics,
+    kafka_processing: KafkaProcessingOptions,
+    outbox: OutboxOptions,
+    shutdown: ShutdownOptions,
 }
 
 fn read_config(config_file_path: &str) -> Result<String, std::io::Error> {
@@ -83,7 +106,11 @@ fn main() {
             std::env::set_var("RUST_LOG", &config.server.log_level);
             env_logger::init();
 
-            let mut handlers = vec![];
+            let manager =
+                RedisConnectionManager::new(&config.server.redis_connection_string[..]).unwrap();
+            let pool = r2d2::Pool::builder().build(manager).unwrap();
+
+            let mut consumer_handlers = vec![];
             let mut consumers: Vec<Arc<StreamConsumer<kafka_processor::BillingContext>>> = vec![];
 
             for _ in 0..config.server.kafka_workers {
@@ -112,49 +139,65 @@ fn main() {
                 );
                 consumers.push(Arc::clone(&consumer));
 
-                let producer: FutureProducer = ClientConfig::new()
-                    .set(
-                        "bootstrap.servers",
-                        &config.kafka_producer.bootstrap_servers,
-                    )
-                    .set(
-                        "message.timeout.ms",
-                        &config.kafka_producer.message_timeout_ms,
-                    )
-                    .create()
-                    .expect("Producer creation error");
                 let kafka_topics = config.kafka_topics.clone();
+                let kafka_processing = config.kafka_processing.clone();
+                let pool = pool.clone();
 
-                handlers.push(std::thread::spawn(move || {
+                consumer_handlers.push(std::thread::spawn(move || {
                     kafka_processor::consume_and_process(
                         kafka_topics,
-                        producer,
+                        kafka_processing,
                         Arc::clone(&consumer),
+                        pool,
                     )
                 }));
             }
 
-            let signals = Signals::new(&[SIGINT, SIGTERM, SIGQUIT]).unwrap();
-            let signal_handler = std::thread::spawn(move || {
-                for _ in signals.forever() {
-                    for consumer in consumers.iter() {
-                        consumer.stop();
-                    }
+            let producer: FutureProducer = ClientConfig::new()
+                .set(
+                    "bootstrap.servers",
+                    &config.kafka_producer.bootstrap_servers,
+                )
+                .set(
+                    "message.timeout.ms",
+                    &config.kafka_producer.message_timeout_ms,
+                )
+                .create()
+                .expect("Producer creation error");
+            let running = Arc::new(AtomicBool::new(true));
+            let mut handlers = vec![];
 
-                    break;
-                }
-            });
+            {
+                let kafka_topics = config.kafka_topics.clone();
+                let outbox = config.outbox.clone();
+                let pool = pool.clone();
+                let running = Arc::clone(&running);
 
+                handlers.push(std::thread::spawn(move || {
+                    outbox::relay(kafka_topics, outbox, producer, pool, running)
+                }));
+            }
+
+            let readiness = web::Data::new(Readiness::default());
             let sys = actix_rt::System::new("billing");
 
             let mut listen_fd = listenfd::ListenFd::from_env();
-            let mut server = HttpServer::new(move || {
-                App::new()
-                    .configure(appconfig::config_app)
-                    .wrap(Logger::new(
-                    "ip: %a, date: %t, response code: %s, response size: %b (bytes), duration: %D (ms)",
-                ))
-            });
+            let mut server = {
+                let readiness = readiness.clone();
+                HttpServer::new(move || {
+                    App::new()
+                        .configure(appconfig::config_app)
+                        .register_data(readiness.clone())
+                        .wrap(Logger::new(
+                        "ip: %a, date: %t, response code: %s, response size: %b (bytes), duration: %D (ms)",
+                    ))
+                })
+            };
+
+            // Signals are handled below, so that all parts of service stop in order
+            server = server
+                .disable_signals()
+                .shutdown_timeout(config.shutdown.timeout_secs);
 
             server = if let Some(l) = listen_fd.take_tcp_listener(0).unwrap() {
                 server.listen(l).unwrap()
@@ -165,14 +208,38 @@ fn main() {
                     .unwrap()
             };
 
-            server.start();
-            let _ = sys.run();
+            let server = server.start();
+            let system = actix_rt::System::current();
+            let shutdown_options = config.shutdown;
 
-            signal_handler.join().unwrap();
+            let signal_handler = std::thread::spawn(move || {
+                shutdown::on_signal(
+                    &shutdown_options,
+                    &readiness,
+                    || {
+                        let _ = server.stop(true).wait();
+                    },
+                    || {
+                        for consumer in consumers.iter() {
+                            consumer.stop();
+                        }
+
+                        for handler in consumer_handlers {
+                            handler.join().unwrap();
+                        }
+
+                        running.store(false, Ordering::SeqCst);
+
+                        for handler in handlers {
+                            handler.join().unwrap();
+                        }
+                    },
+                );
+                system.stop();
+            });
 
-            for handler in handlers {
-                handler.join().unwrap();
-            }
+            let _ = sys.run();
+            signal_handler.join().unwrap();
         }
     }
 }
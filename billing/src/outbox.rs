@@ -0,0 +1,283 @@
+use crate::idempotency::mark_processed;
+use crate::{KafkaTopics, OutboxOptions};
+use futures::Future;
+use r2d2_redis::{r2d2, redis, RedisConnectionManager};
+use rdkafka::message::OwnedHeaders;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+use std::ops::DerefMut;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const OUTBOX_KEY: &str = "outbox:billing";
+const OUTBOX_LOCK_KEY: &str = "outbox:billing:lock";
+
+// Lock is prolonged by its owner, so only one relay publishes messages
+// and their order is kept the same as order of state changes
+const ACQUIRE_LOCK: &str = r#"
+    if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+        return 1
+    end
+    if redis.call('GET', KEYS[1]) == ARGV[1] then
+        redis.call('PEXPIRE', KEYS[1], ARGV[2])
+        return 1
+    end
+    return 0"#;
+
+// Entries have no ids in list, published ones are removed by content,
+// and only while the lock is still held by the relay which read them
+const ACK_OUTBOX: &str = r#"
+    if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+        return 0
+    end
+    for i = 2, #ARGV do
+        redis.call('LREM', KEYS[2], 1, ARGV[i])
+    end
+    return 1"#;
+
+const RELEASE_LOCK: &str = r#"
+    if redis.call('GET', KEYS[1]) == ARGV[1] then
+        return redis.call('DEL', KEYS[1])
+    end
+    return 0"#;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Destination {
+    Transactions,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OutboxMessage {
+    destination: Destination,
+    headers: Vec<(String, String)>,
+    payload: String,
+}
+
+// Messages produced while processing one incoming message, they are stored
+// in the same redis transaction as state change together with processed mark
+pub struct Outbox {
+    message_id: String,
+    processed_ttl_secs: usize,
+    messages: Vec<OutboxMessage>,
+}
+
+impl Outbox {
+    pub fn new(message_id: &str, processed_ttl_secs: usize) -> Self {
+        Outbox {
+            message_id: message_id.to_string(),
+            processed_ttl_secs,
+            messages: vec![],
+        }
+    }
+
+    pub fn push(&mut self, destination: Destination, headers: &[(&str, &str)], payload: String) {
+        let mut headers: Vec<(String, String)> = headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        headers.push((
+            "message_id".to_string(),
+            format!(
+                "{}:{:?}:{}",
+                self.message_id,
+                destination,
+                self.messages.len()
+            ),
+        ));
+
+        self.messages.push(OutboxMessage {
+            destination,
+            headers,
+            payload,
+        });
+    }
+
+    pub fn write(&self, pipe: &mut redis::Pipeline) -> Result<(), serde_json::Error> {
+        for message in &self.messages {
+            pipe.cmd("RPUSH")
+                .arg(OUTBOX_KEY)
+                .arg(serde_json::to_string(message)?);
+        }
+
+        mark_processed(pipe, &self.message_id, self.processed_ttl_secs);
+        Ok(())
+    }
+
+    // Used when processing doesn't change any state
+    pub fn commit(
+        &self,
+        conn: &mut r2d2::PooledConnection<RedisConnectionManager>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        self.write(&mut pipe)?;
+        pipe.query::<()>(conn.deref_mut())?;
+        Ok(())
+    }
+}
+
+fn send_and_wait(
+    producer: &FutureProducer,
+    topic: &str,
+    message: &OutboxMessage,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut headers = OwnedHeaders::new();
+
+    for (key, value) in &message.headers {
+        headers = headers.add(key, value);
+    }
+
+    let record: FutureRecord<String, String> = FutureRecord::to(topic)
+        .headers(headers)
+        .payload(&message.payload);
+
+    match producer.send(record, 0).wait() {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err((e, _))) => Err(Box::new(e)),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+fn lock_outbox(
+    conn: &mut r2d2::PooledConnection<RedisConnectionManager>,
+    owner: &str,
+    ttl_ms: u64,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let locked: i32 = redis::cmd("EVAL")
+        .arg(&[ACQUIRE_LOCK, "1", OUTBOX_LOCK_KEY, owner])
+        .arg(ttl_ms)
+        .query(conn.deref_mut())?;
+    Ok(locked == 1)
+}
+
+fn relay_batch(
+    topics: &KafkaTopics,
+    options: &OutboxOptions,
+    producer: &FutureProducer,
+    owner: &str,
+    conn: &mut r2d2::PooledConnection<RedisConnectionManager>,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    if !lock_outbox(conn, owner, options.lock_ttl_ms)? {
+        return Ok(0);
+    }
+
+    let entries: Vec<String> = redis::cmd("LRANGE")
+        .arg(OUTBOX_KEY)
+        .arg(0)
+        .arg(options.batch_size as isize - 1)
+        .query(conn.deref_mut())?;
+
+    let mut locked_at = Instant::now();
+
+    let mut sent = 0;
+    let mut result = Ok(());
+
+    for entry in &entries {
+        // Lock is prolonged before it expires, so no other relay starts
+        // publishing the same entries while this batch is sent
+        if locked_at.elapsed() >= Duration::from_millis(options.lock_ttl_ms / 2) {
+            if !lock_outbox(conn, owner, options.lock_ttl_ms)? {
+                break;
+            }
+
+            locked_at = Instant::now();
+        }
+
+        match serde_json::from_str::<OutboxMessage>(entry) {
+            Ok(message) => {
+                let topic = match message.destination {
+                    Destination::Transactions => &topics.transactions_topic,
+                };
+
+                if let Err(e) = send_and_wait(producer, topic, &message) {
+                    result = Err(e);
+                    break;
+                }
+            }
+            Err(e) => error!(
+                "line:{}: Dropping invalid outbox entry '{}': {}",
+                line!(),
+                entry,
+                e
+            ),
+        }
+
+        sent += 1;
+    }
+
+    if sent > 0 {
+        let acked: i32 = redis::cmd("EVAL")
+            .arg(&[ACK_OUTBOX, "2", OUTBOX_LOCK_KEY, OUTBOX_KEY, owner])
+            .arg(&entries[..sent])
+            .query(conn.deref_mut())?;
+
+        if acked == 0 {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Outbox lock was lost, {} published messages are left to new owner",
+                    line!(),
+                    sent
+                ),
+            )));
+        }
+    }
+
+    result.map(|_| sent)
+}
+
+// Publishes stored messages to kafka and removes them from outbox after kafka
+// acknowledged them, so every message is published at least once
+pub fn relay(
+    topics: KafkaTopics,
+    options: OutboxOptions,
+    producer: FutureProducer,
+    pool: r2d2::Pool<RedisConnectionManager>,
+    running: Arc<AtomicBool>,
+) {
+    let owner = format!(
+        "{}:{}",
+        std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_string()),
+        std::process::id()
+    );
+
+    // On shutdown messages written by the last processed ones are relayed too
+    loop {
+        let result = pool
+            .get()
+            .map_err(|e| e.into())
+            .and_then(|mut conn| relay_batch(&topics, &options, &producer, &owner, &mut conn));
+        let stopping = !running.load(Ordering::SeqCst);
+
+        match result {
+            Ok(0) if stopping => break,
+            Ok(0) => std::thread::sleep(Duration::from_millis(options.poll_interval_ms)),
+            Ok(sent) => debug!("line:{}: Relayed {} messages from outbox", line!(), sent),
+            Err(e) => {
+                error!("line:{}: Can't relay messages from outbox: {}", line!(), e);
+
+                if stopping {
+                    break;
+                }
+
+                std::thread::sleep(Duration::from_millis(options.poll_interval_ms));
+            }
+        }
+    }
+
+    producer.flush(None);
+
+    if let Ok(mut conn) = pool.get() {
+        let _ = redis::cmd("EVAL")
+            .arg(&[RELEASE_LOCK, "1", OUTBOX_LOCK_KEY, &owner])
+            .query::<i32>(conn.deref_mut());
+    }
+
+    info!(
+        "thread id {:?}: stopping outbox relay thread",
+        std::thread::current().id(),
+    );
+}
@@ -1,4 +1,5 @@
-use crate::{KafkaTopics, ServicesParams};
+use crate::cart;
+use crate::{AdminOptions, CartOptions, KafkaTopics, ServicesParams};
 use actix_web::http::StatusCode;
 use actix_web::{web, HttpRequest, HttpResponse};
 use crypto::digest::Digest;
@@ -8,6 +9,7 @@ use r2d2_redis::{r2d2, redis, RedisConnectionManager};
 use rdkafka::message::OwnedHeaders;
 use rdkafka::producer::{FutureProducer, FutureRecord};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::ops::DerefMut;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -17,12 +19,41 @@ struct Good {
     count: u64,
     #[serde(default)]
     naming: String,
+    #[serde(default)]
+    price: Option<u64>,
+    #[serde(default)]
+    total: u64,
+    #[serde(default)]
+    discount: u64,
+    // Count warehouse is waiting for, 'state' tells if the line is reserved
+    #[serde(default)]
+    backordered: u64,
+    #[serde(default)]
+    state: String,
 }
 
+// Prices are in minor units of the currency
 #[derive(Debug, Serialize, Deserialize)]
 struct Order {
     status: String,
     goods: Vec<Good>,
+    #[serde(default)]
+    currency: String,
+    #[serde(default)]
+    subtotal: u64,
+    #[serde(default)]
+    promotion_code: Option<String>,
+    #[serde(default)]
+    discount: u64,
+    #[serde(default)]
+    tax: u64,
+    #[serde(default)]
+    total: u64,
+    // Shape of delivery details is checked by orders service
+    #[serde(default)]
+    delivery: Option<serde_json::Value>,
+    #[serde(default)]
+    fulfilment: String,
 }
 
 fn liveness_probe(host: &str, path: &str, f: &dyn Fn(&str, &str) -> HttpResponse) -> HttpResponse {
@@ -97,6 +128,73 @@ fn check_auth_token(
     }
 }
 
+fn check_admin_token(req: &HttpRequest, admin: &AdminOptions) -> bool {
+    match req.headers().get("Admin-Authorization") {
+        Some(x) => match x.to_str() {
+            Ok(x) => x == admin.token,
+            Err(e) => {
+                error!("{}", e);
+                false
+            }
+        },
+        None => {
+            error!("No 'Admin-Authorization' header in request");
+            false
+        }
+    }
+}
+
+// Entity tag of an order is its version in quotes, weak tags are never matched
+fn parse_etag(tag: &str) -> Option<u64> {
+    let tag = tag.trim();
+
+    if tag.len() > 1 && tag.starts_with('"') && tag.ends_with('"') {
+        tag[1..tag.len() - 1].parse().ok()
+    } else {
+        None
+    }
+}
+
+fn header_str<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+// Current entity tag of the order, None if there is no such order
+fn order_etag(host: &str, path: &str) -> Result<Option<String>, reqwest::Error> {
+    let res = reqwest::get(&format!("http://{}{}", host, path))?;
+
+    if res.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+
+    Ok(res
+        .error_for_status()?
+        .headers()
+        .get("ETag")
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string()))
+}
+
+// Id which ties together all changes caused by one request, client can pass its own
+fn correlation_id(req: &HttpRequest, key: &str) -> String {
+    if let Some(id) = req.headers().get("Correlation-Id") {
+        if let Ok(id) = id.to_str() {
+            return id.to_string();
+        }
+    }
+
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let mut hasher = Sha256::new();
+    hasher.input(key.as_bytes());
+    hasher.input(nanos.to_string().as_bytes());
+    hasher.result_str()
+}
+
 pub fn get_orders(
     req: HttpRequest,
     user_id: web::Path<String>,
@@ -107,9 +205,15 @@ pub fn get_orders(
         return HttpResponse::Unauthorized().finish();
     }
 
+    // Query is passed as is, so pagination, filters and sorting are the same
+    let path = match req.uri().path_and_query() {
+        Some(path) => path.as_str(),
+        None => req.path(),
+    };
+
     liveness_probe(
         &services_params.orders_service_addr,
-        &req.path(),
+        path,
         &|host, path| match reqwest::get(&format!("http://{}{}", host, path)) {
             Ok(mut res) => match res.text() {
                 Ok(text) => HttpResponse::build(res.status())
@@ -161,7 +265,9 @@ pub fn create_order(
                 .headers(
                     OwnedHeaders::new()
                         .add("operation", "create")
-                        .add("user_id", user_id.as_ref()),
+                        .add("user_id", user_id.as_ref())
+                        .add("actor", user_id.as_ref())
+                        .add("correlation_id", &correlation_id(&req, &key)),
                 ),
             0,
         )
@@ -202,13 +308,34 @@ pub fn get_order(
         &services_params.orders_service_addr,
         &req.path(),
         &|host, path| {
-            let mut res = match reqwest::get(&format!("http://{}{}", host, path)) {
+            let mut request = reqwest::Client::new().get(&format!("http://{}{}", host, path));
+
+            if let Some(tags) = header_str(&req, "If-None-Match") {
+                request = request.header("If-None-Match", tags);
+            }
+
+            let mut res = match request.send() {
                 Ok(res) => res,
                 Err(e) => {
                     error!("Error: {}", e);
                     return HttpResponse::InternalServerError().finish();
                 }
             };
+            let etag = res
+                .headers()
+                .get("ETag")
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string());
+
+            if res.status() == reqwest::StatusCode::NOT_MODIFIED {
+                let mut builder = HttpResponse::NotModified();
+
+                if let Some(etag) = etag {
+                    builder.header("ETag", etag);
+                }
+
+                return builder.finish();
+            }
 
             if res.status().is_success() {
                 let mut order: Order = match res.json() {
@@ -247,7 +374,13 @@ pub fn get_order(
                     );
                 }
 
-                HttpResponse::Ok().json(order)
+                let mut builder = HttpResponse::Ok();
+
+                if let Some(etag) = etag {
+                    builder.header("ETag", etag);
+                }
+
+                builder.json(order)
             } else {
                 HttpResponse::build(res.status()).finish()
             }
@@ -255,15 +388,79 @@ pub fn get_order(
     )
 }
 
-pub fn update_order(
+pub fn get_order_history(
+    req: HttpRequest,
+    params: web::Path<(String, String)>,
+    services_params: web::Data<ServicesParams>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    if !check_auth_token(&req, &params.0, &mut pool.get().unwrap()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    liveness_probe(
+        &services_params.orders_service_addr,
+        req.path(),
+        &|host, path| match reqwest::get(&format!("http://{}{}", host, path)) {
+            Ok(mut res) => match res.text() {
+                Ok(text) => HttpResponse::build(res.status())
+                    .content_type("application/json")
+                    .body(text),
+                Err(e) => {
+                    error!("Error: {}", e);
+                    HttpResponse::InternalServerError().finish()
+                }
+            },
+            Err(e) => {
+                error!("Error: {}", e);
+                HttpResponse::InternalServerError().finish()
+            }
+        },
+    )
+}
+
+// Shipment is tracked by warehouse from the moment the order is paid
+pub fn get_shipment(
+    req: HttpRequest,
+    params: web::Path<(String, String)>,
+    services_params: web::Data<ServicesParams>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    if !check_auth_token(&req, &params.0, &mut pool.get().unwrap()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    liveness_probe(
+        &services_params.warehouse_service_addr,
+        req.path(),
+        &|host, path| match reqwest::get(&format!("http://{}{}", host, path)) {
+            Ok(mut res) => match res.text() {
+                Ok(text) => HttpResponse::build(res.status())
+                    .content_type("application/json")
+                    .body(text),
+                Err(e) => {
+                    error!("Error: {}", e);
+                    HttpResponse::InternalServerError().finish()
+                }
+            },
+            Err(e) => {
+                error!("Error: {}", e);
+                HttpResponse::InternalServerError().finish()
+            }
+        },
+    )
+}
+
+// Warehouse staff moves shipment to the next state, e.g. ships it with tracking number
+pub fn advance_shipment(
     req: HttpRequest,
     bytes: web::Bytes,
     params: web::Path<(String, String)>,
     producer: web::Data<FutureProducer>,
     kafka_topics: web::Data<KafkaTopics>,
-    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+    admin: web::Data<AdminOptions>,
 ) -> HttpResponse {
-    if !check_auth_token(&req, &params.0, &mut pool.get().unwrap()) {
+    if !check_admin_token(&req, &admin) {
         return HttpResponse::Unauthorized().finish();
     }
 
@@ -283,14 +480,16 @@ pub fn update_order(
 
     let result = producer
         .send(
-            FutureRecord::to(&kafka_topics.orders_service_topic)
+            FutureRecord::to(&kafka_topics.warehouse_service_topic)
                 .key(&key[..])
                 .payload(payload)
                 .headers(
                     OwnedHeaders::new()
-                        .add("operation", "update")
+                        .add("operation", "advance_shipment")
                         .add("user_id", &params.0)
-                        .add("order_id", &params.1),
+                        .add("order_id", &params.1)
+                        .add("actor", "admin")
+                        .add("correlation_id", &correlation_id(&req, &key)),
                 ),
             0,
         )
@@ -317,7 +516,167 @@ pub fn update_order(
     }
 }
 
-pub fn delete_order(
+// Stock is replenished by warehouse, orders waiting for the good get it first
+pub fn restock_good(
+    req: HttpRequest,
+    bytes: web::Bytes,
+    good_id: web::Path<u64>,
+    producer: web::Data<FutureProducer>,
+    kafka_topics: web::Data<KafkaTopics>,
+    admin: web::Data<AdminOptions>,
+) -> HttpResponse {
+    if !check_admin_token(&req, &admin) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.input(good_id.to_string().as_bytes());
+    hasher.input(bytes.as_ref());
+    let key = hasher.result_str();
+
+    let payload = match std::str::from_utf8(bytes.as_ref()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}:Couldn't deserialize payload: {}", line!(), e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    let result = producer
+        .send(
+            FutureRecord::to(&kafka_topics.warehouse_service_topic)
+                .key(&key[..])
+                .payload(payload)
+                .headers(
+                    OwnedHeaders::new()
+                        .add("operation", "restock")
+                        .add("good_id", &good_id.to_string())
+                        .add("actor", "admin")
+                        .add("correlation_id", &correlation_id(&req, &key)),
+                ),
+            0,
+        )
+        .wait();
+
+    match result {
+        Ok(Ok(delivery)) => {
+            info!(
+                "Message sent to kafka: partition: {}, offset: {}",
+                delivery.0, delivery.1
+            );
+            HttpResponse::Ok().finish()
+        }
+        Ok(Err((e, msg))) => {
+            error!(
+                "{}:Error occured while sending message to kafka: error: {}, message: {:?}",
+                line!(),
+                e,
+                msg
+            );
+            HttpResponse::BadRequest().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// With 'If-Match' header order is updated only if it wasn't changed since
+// client has seen it, orders service checks version once more on update and
+// writes the order only over it, so of concurrent updates one is applied
+pub fn update_order(
+    req: HttpRequest,
+    bytes: web::Bytes,
+    params: web::Path<(String, String)>,
+    producer: web::Data<FutureProducer>,
+    kafka_topics: web::Data<KafkaTopics>,
+    services_params: web::Data<ServicesParams>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    if !check_auth_token(&req, &params.0, &mut pool.get().unwrap()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let version = match header_str(&req, "If-Match") {
+        None => None,
+        Some(tags) if tags.trim() == "*" => None,
+        Some(tags) => match order_etag(
+            &services_params.orders_service_addr,
+            &format!("/user/{}/order/{}", params.0, params.1),
+        ) {
+            Ok(Some(etag)) => match parse_etag(&etag) {
+                Some(version) if tags.split(',').any(|tag| parse_etag(tag) == Some(version)) => {
+                    Some(version.to_string())
+                }
+                _ => {
+                    return HttpResponse::PreconditionFailed()
+                        .header("ETag", etag)
+                        .finish()
+                }
+            },
+            Ok(None) => return HttpResponse::PreconditionFailed().finish(),
+            Err(e) => {
+                error!("{}:Couldn't get version of order: {}", line!(), e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        },
+    };
+
+    let mut hasher = Sha256::new();
+    hasher.input(params.0.as_bytes());
+    hasher.input(params.1.as_bytes());
+    hasher.input(bytes.as_ref());
+    let key = hasher.result_str();
+
+    let payload = match std::str::from_utf8(bytes.as_ref()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}:Couldn't deserialize payload: {}", line!(), e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    let mut headers = OwnedHeaders::new()
+        .add("operation", "update")
+        .add("user_id", &params.0)
+        .add("order_id", &params.1)
+        .add("actor", &params.0)
+        .add("correlation_id", &correlation_id(&req, &key));
+
+    if let Some(version) = &version {
+        headers = headers.add("if_match", version);
+    }
+
+    let result = producer
+        .send(
+            FutureRecord::to(&kafka_topics.orders_service_topic)
+                .key(&key[..])
+                .payload(payload)
+                .headers(headers),
+            0,
+        )
+        .wait();
+
+    match result {
+        Ok(Ok(delivery)) => {
+            info!(
+                "Message sent to kafka: partition: {}, offset: {}",
+                delivery.0, delivery.1
+            );
+            HttpResponse::Ok().finish()
+        }
+        Ok(Err((e, msg))) => {
+            error!(
+                "{}:Error occured while sending message to kafka: error: {}, message: {:?}",
+                line!(),
+                e,
+                msg
+            );
+            HttpResponse::BadRequest().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub fn cancel_order(
     req: HttpRequest,
     bytes: web::Bytes,
     params: web::Path<(String, String)>,
@@ -350,9 +709,11 @@ pub fn delete_order(
                 .payload(payload)
                 .headers(
                     OwnedHeaders::new()
-                        .add("operation", "delete")
+                        .add("operation", "cancel")
                         .add("user_id", &params.0)
-                        .add("order_id", &params.1),
+                        .add("order_id", &params.1)
+                        .add("actor", &params.0)
+                        .add("correlation_id", &correlation_id(&req, &key)),
                 ),
             0,
         )
@@ -379,15 +740,16 @@ pub fn delete_order(
     }
 }
 
-pub fn make_billing(
+// Order is removed for good, users cancel their orders instead
+pub fn delete_order(
     req: HttpRequest,
     bytes: web::Bytes,
     params: web::Path<(String, String)>,
     producer: web::Data<FutureProducer>,
     kafka_topics: web::Data<KafkaTopics>,
-    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+    admin: web::Data<AdminOptions>,
 ) -> HttpResponse {
-    if !check_auth_token(&req, &params.0, &mut pool.get().unwrap()) {
+    if !check_admin_token(&req, &admin) {
         return HttpResponse::Unauthorized().finish();
     }
 
@@ -407,13 +769,1451 @@ pub fn make_billing(
 
     let result = producer
         .send(
-            FutureRecord::to(&kafka_topics.billing_service_topic)
+            FutureRecord::to(&kafka_topics.orders_service_topic)
+                .key(&key[..])
+                .payload(payload)
+                .headers(
+                    OwnedHeaders::new()
+                        .add("operation", "delete")
+                        .add("user_id", &params.0)
+                        .add("order_id", &params.1)
+                        .add("actor", "admin")
+                        .add("correlation_id", &correlation_id(&req, &key)),
+                ),
+            0,
+        )
+        .wait();
+
+    match result {
+        Ok(Ok(delivery)) => {
+            info!(
+                "Message sent to kafka: partition: {}, offset: {}",
+                delivery.0, delivery.1
+            );
+            HttpResponse::Ok().finish()
+        }
+        Ok(Err((e, msg))) => {
+            error!(
+                "{}:Error occured while sending message to kafka: error: {}, message: {:?}",
+                line!(),
+                e,
+                msg
+            );
+            HttpResponse::BadRequest().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub fn apply_promotion(
+    req: HttpRequest,
+    bytes: web::Bytes,
+    params: web::Path<(String, String)>,
+    producer: web::Data<FutureProducer>,
+    kafka_topics: web::Data<KafkaTopics>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    if !check_auth_token(&req, &params.0, &mut pool.get().unwrap()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.input(params.0.as_bytes());
+    hasher.input(params.1.as_bytes());
+    hasher.input(bytes.as_ref());
+    let key = hasher.result_str();
+
+    let payload = match std::str::from_utf8(bytes.as_ref()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}:Couldn't deserialize payload: {}", line!(), e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    let result = producer
+        .send(
+            FutureRecord::to(&kafka_topics.orders_service_topic)
+                .key(&key[..])
+                .payload(payload)
+                .headers(
+                    OwnedHeaders::new()
+                        .add("operation", "apply_promotion")
+                        .add("user_id", &params.0)
+                        .add("order_id", &params.1)
+                        .add("actor", &params.0)
+                        .add("correlation_id", &correlation_id(&req, &key)),
+                ),
+            0,
+        )
+        .wait();
+
+    match result {
+        Ok(Ok(delivery)) => {
+            info!(
+                "Message sent to kafka: partition: {}, offset: {}",
+                delivery.0, delivery.1
+            );
+            HttpResponse::Ok().finish()
+        }
+        Ok(Err((e, msg))) => {
+            error!(
+                "{}:Error occured while sending message to kafka: error: {}, message: {:?}",
+                line!(),
+                e,
+                msg
+            );
+            HttpResponse::BadRequest().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// Customer asks to return some goods of a delivered order, the return is
+// reviewed by an operator before goods are restocked and refunded
+pub fn request_return(
+    req: HttpRequest,
+    bytes: web::Bytes,
+    params: web::Path<(String, String)>,
+    producer: web::Data<FutureProducer>,
+    kafka_topics: web::Data<KafkaTopics>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    if !check_auth_token(&req, &params.0, &mut pool.get().unwrap()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.input(params.0.as_bytes());
+    hasher.input(params.1.as_bytes());
+    hasher.input(bytes.as_ref());
+    let key = hasher.result_str();
+
+    let payload = match std::str::from_utf8(bytes.as_ref()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}:Couldn't deserialize payload: {}", line!(), e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    let result = producer
+        .send(
+            FutureRecord::to(&kafka_topics.orders_service_topic)
+                .key(&key[..])
+                .payload(payload)
+                .headers(
+                    OwnedHeaders::new()
+                        .add("operation", "request_return")
+                        .add("user_id", &params.0)
+                        .add("order_id", &params.1)
+                        .add("actor", &params.0)
+                        .add("correlation_id", &correlation_id(&req, &key)),
+                ),
+            0,
+        )
+        .wait();
+
+    match result {
+        Ok(Ok(delivery)) => {
+            info!(
+                "Message sent to kafka: partition: {}, offset: {}",
+                delivery.0, delivery.1
+            );
+            HttpResponse::Ok().finish()
+        }
+        Ok(Err((e, msg))) => {
+            error!(
+                "{}:Error occured while sending message to kafka: error: {}, message: {:?}",
+                line!(),
+                e,
+                msg
+            );
+            HttpResponse::BadRequest().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub fn get_order_returns(
+    req: HttpRequest,
+    params: web::Path<(String, String)>,
+    services_params: web::Data<ServicesParams>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    if !check_auth_token(&req, &params.0, &mut pool.get().unwrap()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    liveness_probe(
+        &services_params.orders_service_addr,
+        req.path(),
+        &|host, path| match reqwest::get(&format!("http://{}{}", host, path)) {
+            Ok(mut res) => match res.text() {
+                Ok(text) => HttpResponse::build(res.status())
+                    .content_type("application/json")
+                    .body(text),
+                Err(e) => {
+                    error!("Error: {}", e);
+                    HttpResponse::InternalServerError().finish()
+                }
+            },
+            Err(e) => {
+                error!("Error: {}", e);
+                HttpResponse::InternalServerError().finish()
+            }
+        },
+    )
+}
+
+// Operator approves or rejects a return
+pub fn review_return(
+    req: HttpRequest,
+    bytes: web::Bytes,
+    params: web::Path<(String, String, u64)>,
+    producer: web::Data<FutureProducer>,
+    kafka_topics: web::Data<KafkaTopics>,
+    admin: web::Data<AdminOptions>,
+) -> HttpResponse {
+    if !check_admin_token(&req, &admin) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.input(params.0.as_bytes());
+    hasher.input(params.1.as_bytes());
+    hasher.input(params.2.to_string().as_bytes());
+    hasher.input(bytes.as_ref());
+    let key = hasher.result_str();
+
+    let payload = match std::str::from_utf8(bytes.as_ref()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}:Couldn't deserialize payload: {}", line!(), e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    let result = producer
+        .send(
+            FutureRecord::to(&kafka_topics.orders_service_topic)
+                .key(&key[..])
+                .payload(payload)
+                .headers(
+                    OwnedHeaders::new()
+                        .add("operation", "review_return")
+                        .add("user_id", &params.0)
+                        .add("order_id", &params.1)
+                        .add("return_id", &params.2.to_string())
+                        .add("actor", "admin")
+                        .add("correlation_id", &correlation_id(&req, &key)),
+                ),
+            0,
+        )
+        .wait();
+
+    match result {
+        Ok(Ok(delivery)) => {
+            info!(
+                "Message sent to kafka: partition: {}, offset: {}",
+                delivery.0, delivery.1
+            );
+            HttpResponse::Ok().finish()
+        }
+        Ok(Err((e, msg))) => {
+            error!(
+                "{}:Error occured while sending message to kafka: error: {}, message: {:?}",
+                line!(),
+                e,
+                msg
+            );
+            HttpResponse::BadRequest().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// New order gets goods of the order, warehouse reserves them from current stock
+pub fn reorder(
+    req: HttpRequest,
+    bytes: web::Bytes,
+    params: web::Path<(String, String)>,
+    producer: web::Data<FutureProducer>,
+    kafka_topics: web::Data<KafkaTopics>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    if !check_auth_token(&req, &params.0, &mut pool.get().unwrap()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.input(params.0.as_bytes());
+    hasher.input(params.1.as_bytes());
+    hasher.input(bytes.as_ref());
+    let key = hasher.result_str();
+
+    let payload = match std::str::from_utf8(bytes.as_ref()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}:Couldn't deserialize payload: {}", line!(), e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    let result = producer
+        .send(
+            FutureRecord::to(&kafka_topics.orders_service_topic)
+                .key(&key[..])
+                .payload(payload)
+                .headers(
+                    OwnedHeaders::new()
+                        .add("operation", "reorder")
+                        .add("user_id", &params.0)
+                        .add("order_id", &params.1)
+                        .add("actor", &params.0)
+                        .add("correlation_id", &correlation_id(&req, &key)),
+                ),
+            0,
+        )
+        .wait();
+
+    match result {
+        Ok(Ok(delivery)) => {
+            info!(
+                "Message sent to kafka: partition: {}, offset: {}",
+                delivery.0, delivery.1
+            );
+            HttpResponse::Created().finish()
+        }
+        Ok(Err((e, msg))) => {
+            error!(
+                "{}:Error occured while sending message to kafka: error: {}, message: {:?}",
+                line!(),
+                e,
+                msg
+            );
+            HttpResponse::BadRequest().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub fn get_templates(
+    req: HttpRequest,
+    user_id: web::Path<String>,
+    services_params: web::Data<ServicesParams>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    if !check_auth_token(&req, &user_id, &mut pool.get().unwrap()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    liveness_probe(
+        &services_params.orders_service_addr,
+        req.path(),
+        &|host, path| match reqwest::get(&format!("http://{}{}", host, path)) {
+            Ok(mut res) => match res.text() {
+                Ok(text) => HttpResponse::build(res.status())
+                    .content_type("application/json")
+                    .body(text),
+                Err(e) => {
+                    error!("Error: {}", e);
+                    HttpResponse::InternalServerError().finish()
+                }
+            },
+            Err(e) => {
+                error!("Error: {}", e);
+                HttpResponse::InternalServerError().finish()
+            }
+        },
+    )
+}
+
+// Template with the same name is replaced
+pub fn put_template(
+    req: HttpRequest,
+    bytes: web::Bytes,
+    user_id: web::Path<String>,
+    producer: web::Data<FutureProducer>,
+    kafka_topics: web::Data<KafkaTopics>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    if !check_auth_token(&req, &user_id, &mut pool.get().unwrap()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.input(user_id.as_bytes());
+    hasher.input(bytes.as_ref());
+    let key = hasher.result_str();
+
+    let payload = match std::str::from_utf8(bytes.as_ref()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}:Couldn't deserialize payload: {}", line!(), e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    let result = producer
+        .send(
+            FutureRecord::to(&kafka_topics.orders_service_topic)
+                .key(&key)
+                .payload(payload)
+                .headers(
+                    OwnedHeaders::new()
+                        .add("operation", "put_template")
+                        .add("user_id", user_id.as_ref())
+                        .add("actor", user_id.as_ref())
+                        .add("correlation_id", &correlation_id(&req, &key)),
+                ),
+            0,
+        )
+        .wait();
+
+    match result {
+        Ok(Ok(delivery)) => {
+            info!(
+                "Message sent to kafka: partition: {}, offset: {}",
+                delivery.0, delivery.1
+            );
+            HttpResponse::Ok().finish()
+        }
+        Ok(Err((e, msg))) => {
+            error!(
+                "{}:Error occured while sending message to kafka: error: {}, message: {:?}",
+                line!(),
+                e,
+                msg
+            );
+            HttpResponse::BadRequest().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub fn delete_template(
+    req: HttpRequest,
+    bytes: web::Bytes,
+    params: web::Path<(String, String)>,
+    producer: web::Data<FutureProducer>,
+    kafka_topics: web::Data<KafkaTopics>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    if !check_auth_token(&req, &params.0, &mut pool.get().unwrap()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.input(params.0.as_bytes());
+    hasher.input(params.1.as_bytes());
+    hasher.input(bytes.as_ref());
+    let key = hasher.result_str();
+
+    let payload = match std::str::from_utf8(bytes.as_ref()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}:Couldn't deserialize payload: {}", line!(), e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    let result = producer
+        .send(
+            FutureRecord::to(&kafka_topics.orders_service_topic)
+                .key(&key[..])
+                .payload(payload)
+                .headers(
+                    OwnedHeaders::new()
+                        .add("operation", "delete_template")
+                        .add("user_id", &params.0)
+                        .add("template", &params.1)
+                        .add("actor", &params.0)
+                        .add("correlation_id", &correlation_id(&req, &key)),
+                ),
+            0,
+        )
+        .wait();
+
+    match result {
+        Ok(Ok(delivery)) => {
+            info!(
+                "Message sent to kafka: partition: {}, offset: {}",
+                delivery.0, delivery.1
+            );
+            HttpResponse::Ok().finish()
+        }
+        Ok(Err((e, msg))) => {
+            error!(
+                "{}:Error occured while sending message to kafka: error: {}, message: {:?}",
+                line!(),
+                e,
+                msg
+            );
+            HttpResponse::BadRequest().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub fn instantiate_template(
+    req: HttpRequest,
+    bytes: web::Bytes,
+    params: web::Path<(String, String)>,
+    producer: web::Data<FutureProducer>,
+    kafka_topics: web::Data<KafkaTopics>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    if !check_auth_token(&req, &params.0, &mut pool.get().unwrap()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.input(params.0.as_bytes());
+    hasher.input(params.1.as_bytes());
+    hasher.input(bytes.as_ref());
+    let key = hasher.result_str();
+
+    let payload = match std::str::from_utf8(bytes.as_ref()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}:Couldn't deserialize payload: {}", line!(), e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    let result = producer
+        .send(
+            FutureRecord::to(&kafka_topics.orders_service_topic)
+                .key(&key[..])
+                .payload(payload)
+                .headers(
+                    OwnedHeaders::new()
+                        .add("operation", "instantiate_template")
+                        .add("user_id", &params.0)
+                        .add("template", &params.1)
+                        .add("actor", &params.0)
+                        .add("correlation_id", &correlation_id(&req, &key)),
+                ),
+            0,
+        )
+        .wait();
+
+    match result {
+        Ok(Ok(delivery)) => {
+            info!(
+                "Message sent to kafka: partition: {}, offset: {}",
+                delivery.0, delivery.1
+            );
+            HttpResponse::Created().finish()
+        }
+        Ok(Err((e, msg))) => {
+            error!(
+                "{}:Error occured while sending message to kafka: error: {}, message: {:?}",
+                line!(),
+                e,
+                msg
+            );
+            HttpResponse::BadRequest().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+// Orders are made from schedules by orders service, it also tells how runs went
+pub fn get_schedules(
+    req: HttpRequest,
+    user_id: web::Path<String>,
+    services_params: web::Data<ServicesParams>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    if !check_auth_token(&req, &user_id, &mut pool.get().unwrap()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    liveness_probe(
+        &services_params.orders_service_addr,
+        req.path(),
+        &|host, path| match reqwest::get(&format!("http://{}{}", host, path)) {
+            Ok(mut res) => match res.text() {
+                Ok(text) => HttpResponse::build(res.status())
+                    .content_type("application/json")
+                    .body(text),
+                Err(e) => {
+                    error!("Error: {}", e);
+                    HttpResponse::InternalServerError().finish()
+                }
+            },
+            Err(e) => {
+                error!("Error: {}", e);
+                HttpResponse::InternalServerError().finish()
+            }
+        },
+    )
+}
+
+// Template with the same name is replaced
+
+// Schedule with the same name is replaced
+pub fn put_schedule(
+    req: HttpRequest,
+    bytes: web::Bytes,
+    user_id: web::Path<String>,
+    producer: web::Data<FutureProducer>,
+    kafka_topics: web::Data<KafkaTopics>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    if !check_auth_token(&req, &user_id, &mut pool.get().unwrap()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.input(user_id.as_bytes());
+    hasher.input(bytes.as_ref());
+    let key = hasher.result_str();
+
+    let payload = match std::str::from_utf8(bytes.as_ref()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}:Couldn't deserialize payload: {}", line!(), e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    let result = producer
+        .send(
+            FutureRecord::to(&kafka_topics.orders_service_topic)
+                .key(&key)
+                .payload(payload)
+                .headers(
+                    OwnedHeaders::new()
+                        .add("operation", "put_schedule")
+                        .add("user_id", user_id.as_ref())
+                        .add("actor", user_id.as_ref())
+                        .add("correlation_id", &correlation_id(&req, &key)),
+                ),
+            0,
+        )
+        .wait();
+
+    match result {
+        Ok(Ok(delivery)) => {
+            info!(
+                "Message sent to kafka: partition: {}, offset: {}",
+                delivery.0, delivery.1
+            );
+            HttpResponse::Ok().finish()
+        }
+        Ok(Err((e, msg))) => {
+            error!(
+                "{}:Error occured while sending message to kafka: error: {}, message: {:?}",
+                line!(),
+                e,
+                msg
+            );
+            HttpResponse::BadRequest().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub fn delete_schedule(
+    req: HttpRequest,
+    bytes: web::Bytes,
+    params: web::Path<(String, String)>,
+    producer: web::Data<FutureProducer>,
+    kafka_topics: web::Data<KafkaTopics>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    if !check_auth_token(&req, &params.0, &mut pool.get().unwrap()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.input(params.0.as_bytes());
+    hasher.input(params.1.as_bytes());
+    hasher.input(bytes.as_ref());
+    let key = hasher.result_str();
+
+    let payload = match std::str::from_utf8(bytes.as_ref()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}:Couldn't deserialize payload: {}", line!(), e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    let result = producer
+        .send(
+            FutureRecord::to(&kafka_topics.orders_service_topic)
+                .key(&key[..])
+                .payload(payload)
+                .headers(
+                    OwnedHeaders::new()
+                        .add("operation", "delete_schedule")
+                        .add("user_id", &params.0)
+                        .add("schedule", &params.1)
+                        .add("actor", &params.0)
+                        .add("correlation_id", &correlation_id(&req, &key)),
+                ),
+            0,
+        )
+        .wait();
+
+    match result {
+        Ok(Ok(delivery)) => {
+            info!(
+                "Message sent to kafka: partition: {}, offset: {}",
+                delivery.0, delivery.1
+            );
+            HttpResponse::Ok().finish()
+        }
+        Ok(Err((e, msg))) => {
+            error!(
+                "{}:Error occured while sending message to kafka: error: {}, message: {:?}",
+                line!(),
+                e,
+                msg
+            );
+            HttpResponse::BadRequest().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[derive(Deserialize)]
+struct CartLine {
+    id: u64,
+    count: u64,
+}
+
+#[derive(Deserialize)]
+struct CartCount {
+    count: u64,
+}
+
+// Good as warehouse has it now, count is what is in stock
+#[derive(Deserialize)]
+struct StockedGood {
+    count: u64,
+    #[serde(default)]
+    naming: String,
+    #[serde(default)]
+    price: Option<u64>,
+    #[serde(default)]
+    currency: String,
+}
+
+// Names and prices of goods aren't kept in the cart, they are taken from
+// warehouse every time the cart is viewed
+#[derive(Serialize)]
+struct CartGood {
+    id: u64,
+    count: u64,
+    naming: String,
+    price: Option<u64>,
+    currency: String,
+    total: u64,
+    in_stock: u64,
+}
+
+#[derive(Serialize)]
+struct Cart {
+    goods: Vec<CartGood>,
+    expires_in: Option<u64>,
+}
+
+// Only goods known to warehouse are put into carts
+fn good_exists(host: &str, good_id: u64) -> Result<bool, reqwest::Error> {
+    let res = reqwest::get(&format!("http://{}/goods/{}", host, good_id))?;
+
+    if res.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(false);
+    }
+
+    res.error_for_status()?;
+    Ok(true)
+}
+
+pub fn get_cart(
+    req: HttpRequest,
+    user_id: web::Path<String>,
+    services_params: web::Data<ServicesParams>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    let mut conn = pool.get().unwrap();
+
+    if !check_auth_token(&req, &user_id, &mut conn) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let (lines, expires_in) = match (
+        cart::lines(&mut conn, &user_id),
+        cart::expires_in(&mut conn, &user_id),
+    ) {
+        (Ok(lines), Ok(expires_in)) => (lines, expires_in),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("{}:Couldn't get cart: {}", line!(), e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    liveness_probe(
+        &services_params.warehouse_service_addr,
+        "/goods",
+        &|host, path| {
+            let mut goods = Vec::new();
+
+            for (good_id, count) in &lines {
+                let mut good = CartGood {
+                    id: *good_id,
+                    count: *count,
+                    naming: String::new(),
+                    price: None,
+                    currency: String::new(),
+                    total: 0,
+                    in_stock: 0,
+                };
+
+                // Goods removed from warehouse stay in the cart without price
+                match reqwest::get(&format!("http://{}{}/{}", host, path, good_id)) {
+                    Ok(mut res) => {
+                        if res.status().is_success() {
+                            let stocked: StockedGood = match res.json() {
+                                Ok(stocked) => stocked,
+                                Err(e) => {
+                                    error!("Error: {}", e);
+                                    return HttpResponse::InternalServerError().finish();
+                                }
+                            };
+
+                            good.naming = stocked.naming;
+                            good.price = stocked.price;
+                            good.currency = stocked.currency;
+                            good.total = stocked.price.unwrap_or(0) * count;
+                            good.in_stock = stocked.count;
+                        }
+                    }
+                    Err(e) => {
+                        error!("Error: {}", e);
+                        return HttpResponse::InternalServerError().finish();
+                    }
+                }
+
+                goods.push(good);
+            }
+
+            HttpResponse::Ok().json(Cart { goods, expires_in })
+        },
+    )
+}
+
+// Count is added to the line of the good if there is one already
+pub fn add_to_cart(
+    req: HttpRequest,
+    bytes: web::Bytes,
+    user_id: web::Path<String>,
+    services_params: web::Data<ServicesParams>,
+    cart_options: web::Data<CartOptions>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    let mut conn = pool.get().unwrap();
+
+    if !check_auth_token(&req, &user_id, &mut conn) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let line: CartLine = match serde_json::from_slice(bytes.as_ref()) {
+        Ok(line) => line,
+        Err(e) => {
+            error!("{}:Couldn't deserialize payload: {}", line!(), e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    if line.count == 0 {
+        return HttpResponse::BadRequest().finish();
+    }
+
+    match good_exists(&services_params.warehouse_service_addr, line.id) {
+        Ok(true) => {}
+        Ok(false) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!("Error: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    match cart::add(
+        &mut conn,
+        &user_id,
+        line.id,
+        line.count,
+        cart_options.ttl_secs,
+    ) {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            error!("{}:Couldn't add good {} to cart: {}", line!(), line.id, e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Zero count removes the line
+pub fn update_cart_good(
+    req: HttpRequest,
+    bytes: web::Bytes,
+    params: web::Path<(String, u64)>,
+    services_params: web::Data<ServicesParams>,
+    cart_options: web::Data<CartOptions>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    let mut conn = pool.get().unwrap();
+
+    if !check_auth_token(&req, &params.0, &mut conn) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let count: CartCount = match serde_json::from_slice(bytes.as_ref()) {
+        Ok(count) => count,
+        Err(e) => {
+            error!("{}:Couldn't deserialize payload: {}", line!(), e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    if count.count > 0 {
+        match good_exists(&services_params.warehouse_service_addr, params.1) {
+            Ok(true) => {}
+            Ok(false) => return HttpResponse::NotFound().finish(),
+            Err(e) => {
+                error!("Error: {}", e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+
+    match cart::set(
+        &mut conn,
+        &params.0,
+        params.1,
+        count.count,
+        cart_options.ttl_secs,
+    ) {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            error!(
+                "{}:Couldn't update good {} in cart: {}",
+                line!(),
+                params.1,
+                e
+            );
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub fn remove_from_cart(
+    req: HttpRequest,
+    params: web::Path<(String, u64)>,
+    cart_options: web::Data<CartOptions>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    let mut conn = pool.get().unwrap();
+
+    if !check_auth_token(&req, &params.0, &mut conn) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    match cart::remove(&mut conn, &params.0, params.1, cart_options.ttl_secs) {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            error!(
+                "{}:Couldn't remove good {} from cart: {}",
+                line!(),
+                params.1,
+                e
+            );
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub fn clear_cart(
+    req: HttpRequest,
+    user_id: web::Path<String>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    let mut conn = pool.get().unwrap();
+
+    if !check_auth_token(&req, &user_id, &mut conn) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    match cart::clear(&mut conn, &user_id) {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            error!("{}:Couldn't clear cart: {}", line!(), e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+fn restore_cart(
+    conn: &mut r2d2::PooledConnection<RedisConnectionManager>,
+    user_id: &str,
+    lines: &BTreeMap<u64, u64>,
+    ttl_secs: u64,
+) {
+    if let Err(e) = cart::restore(conn, user_id, lines, ttl_secs) {
+        error!("{}:Couldn't restore cart: {}", line!(), e);
+    }
+}
+
+// Cart becomes an order the same way as one posted to /order, body can carry
+// the rest of the order, e.g. delivery details and fulfilment policy.
+// Cart is taken when the order is sent and given back if it couldn't be sent
+pub fn checkout_cart(
+    req: HttpRequest,
+    bytes: web::Bytes,
+    user_id: web::Path<String>,
+    producer: web::Data<FutureProducer>,
+    kafka_topics: web::Data<KafkaTopics>,
+    cart_options: web::Data<CartOptions>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    let mut conn = pool.get().unwrap();
+
+    if !check_auth_token(&req, &user_id, &mut conn) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut order: serde_json::Map<String, serde_json::Value> = if bytes.is_empty() {
+        serde_json::Map::new()
+    } else {
+        match serde_json::from_slice(bytes.as_ref()) {
+            Ok(order) => order,
+            Err(e) => {
+                error!("{}:Couldn't deserialize payload: {}", line!(), e);
+                return HttpResponse::BadRequest().finish();
+            }
+        }
+    };
+
+    let lines = match cart::take(&mut conn, &user_id) {
+        Ok(lines) => lines,
+        Err(e) => {
+            error!("{}:Couldn't get cart: {}", line!(), e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    if lines.is_empty() {
+        error!("{}:Cart of user '{}' is empty", line!(), user_id);
+        return HttpResponse::BadRequest().finish();
+    }
+
+    let goods: Vec<serde_json::Value> = lines
+        .iter()
+        .map(|(id, count)| serde_json::json!({ "id": id, "count": count }))
+        .collect();
+    order.insert("goods".to_string(), serde_json::Value::from(goods));
+    let payload = serde_json::Value::from(order).to_string();
+
+    let mut hasher = Sha256::new();
+    hasher.input(user_id.as_bytes());
+    hasher.input(payload.as_bytes());
+    let key = hasher.result_str();
+
+    let result = producer
+        .send(
+            FutureRecord::to(&kafka_topics.orders_service_topic)
+                .key(&key)
+                .payload(&payload)
+                .headers(
+                    OwnedHeaders::new()
+                        .add("operation", "create")
+                        .add("user_id", user_id.as_ref())
+                        .add("actor", user_id.as_ref())
+                        .add("correlation_id", &correlation_id(&req, &key)),
+                ),
+            0,
+        )
+        .wait();
+
+    match result {
+        Ok(Ok(delivery)) => {
+            info!(
+                "Message sent to kafka: partition: {}, offset: {}",
+                delivery.0, delivery.1
+            );
+            HttpResponse::Created().finish()
+        }
+        Ok(Err((e, msg))) => {
+            error!(
+                "{}:Error occured while sending message to kafka: error: {}, message: {:?}",
+                line!(),
+                e,
+                msg
+            );
+            restore_cart(&mut conn, &user_id, &lines, cart_options.ttl_secs);
+            HttpResponse::BadRequest().finish()
+        }
+        Err(_) => {
+            restore_cart(&mut conn, &user_id, &lines, cart_options.ttl_secs);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub fn remove_promotion(
+    req: HttpRequest,
+    bytes: web::Bytes,
+    params: web::Path<(String, String)>,
+    producer: web::Data<FutureProducer>,
+    kafka_topics: web::Data<KafkaTopics>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    if !check_auth_token(&req, &params.0, &mut pool.get().unwrap()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.input(params.0.as_bytes());
+    hasher.input(params.1.as_bytes());
+    hasher.input(bytes.as_ref());
+    let key = hasher.result_str();
+
+    let payload = match std::str::from_utf8(bytes.as_ref()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}:Couldn't deserialize payload: {}", line!(), e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    let result = producer
+        .send(
+            FutureRecord::to(&kafka_topics.orders_service_topic)
+                .key(&key[..])
+                .payload(payload)
+                .headers(
+                    OwnedHeaders::new()
+                        .add("operation", "remove_promotion")
+                        .add("user_id", &params.0)
+                        .add("order_id", &params.1)
+                        .add("actor", &params.0)
+                        .add("correlation_id", &correlation_id(&req, &key)),
+                ),
+            0,
+        )
+        .wait();
+
+    match result {
+        Ok(Ok(delivery)) => {
+            info!(
+                "Message sent to kafka: partition: {}, offset: {}",
+                delivery.0, delivery.1
+            );
+            HttpResponse::Ok().finish()
+        }
+        Ok(Err((e, msg))) => {
+            error!(
+                "{}:Error occured while sending message to kafka: error: {}, message: {:?}",
+                line!(),
+                e,
+                msg
+            );
+            HttpResponse::BadRequest().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub fn get_promotions(
+    req: HttpRequest,
+    services_params: web::Data<ServicesParams>,
+    admin: web::Data<AdminOptions>,
+) -> HttpResponse {
+    if !check_admin_token(&req, &admin) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    liveness_probe(
+        &services_params.orders_service_addr,
+        req.path(),
+        &|host, path| match reqwest::get(&format!("http://{}{}", host, path)) {
+            Ok(mut res) => match res.text() {
+                Ok(text) => HttpResponse::build(res.status())
+                    .content_type("application/json")
+                    .body(text),
+                Err(e) => {
+                    error!("Error: {}", e);
+                    HttpResponse::InternalServerError().finish()
+                }
+            },
+            Err(e) => {
+                error!("Error: {}", e);
+                HttpResponse::InternalServerError().finish()
+            }
+        },
+    )
+}
+
+// Body of a response is passed on in chunks as it is read
+struct BodyChunks(reqwest::Response);
+
+impl Iterator for BodyChunks {
+    type Item = Result<web::Bytes, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut chunk = vec![0; 64 * 1024];
+
+        match std::io::Read::read(&mut self.0, &mut chunk) {
+            Ok(0) => None,
+            Ok(read) => {
+                chunk.truncate(read);
+                Some(Ok(web::Bytes::from(chunk)))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+// Export may take long, so it isn't limited by a timeout and is streamed
+// to the client instead of being read whole
+pub fn export_orders(
+    req: HttpRequest,
+    services_params: web::Data<ServicesParams>,
+    admin: web::Data<AdminOptions>,
+) -> HttpResponse {
+    if !check_admin_token(&req, &admin) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    // Query is passed as is, so format and filters are the same
+    let path = match req.uri().path_and_query() {
+        Some(path) => path.as_str(),
+        None => req.path(),
+    };
+
+    liveness_probe(&services_params.orders_service_addr, path, &|host, path| {
+        let client = match reqwest::Client::builder().timeout(None).build() {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Error: {}", e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        };
+
+        match client.get(&format!("http://{}{}", host, path)).send() {
+            Ok(res) => {
+                let content_type = res
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|content_type| content_type.to_str().ok())
+                    .unwrap_or("text/plain")
+                    .to_string();
+
+                HttpResponse::build(res.status())
+                    .content_type(content_type)
+                    .streaming(stream::iter_result(BodyChunks(res)))
+            }
+            Err(e) => {
+                error!("Error: {}", e);
+                HttpResponse::InternalServerError().finish()
+            }
+        }
+    })
+}
+
+pub fn search_orders(
+    req: HttpRequest,
+    services_params: web::Data<ServicesParams>,
+    admin: web::Data<AdminOptions>,
+) -> HttpResponse {
+    if !check_admin_token(&req, &admin) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    // Query is passed as is, so filters and cursor are the same
+    let path = match req.uri().path_and_query() {
+        Some(path) => path.as_str(),
+        None => req.path(),
+    };
+
+    liveness_probe(
+        &services_params.orders_service_addr,
+        path,
+        &|host, path| match reqwest::get(&format!("http://{}{}", host, path)) {
+            Ok(mut res) => match res.text() {
+                Ok(text) => HttpResponse::build(res.status())
+                    .content_type("application/json")
+                    .body(text),
+                Err(e) => {
+                    error!("Error: {}", e);
+                    HttpResponse::InternalServerError().finish()
+                }
+            },
+            Err(e) => {
+                error!("Error: {}", e);
+                HttpResponse::InternalServerError().finish()
+            }
+        },
+    )
+}
+
+// Existing promotion with the same code is replaced
+pub fn put_promotion(
+    req: HttpRequest,
+    bytes: web::Bytes,
+    producer: web::Data<FutureProducer>,
+    kafka_topics: web::Data<KafkaTopics>,
+    admin: web::Data<AdminOptions>,
+) -> HttpResponse {
+    if !check_admin_token(&req, &admin) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.input(bytes.as_ref());
+    let key = hasher.result_str();
+
+    let payload = match std::str::from_utf8(bytes.as_ref()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}:Couldn't deserialize payload: {}", line!(), e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    let result = producer
+        .send(
+            FutureRecord::to(&kafka_topics.orders_service_topic)
+                .key(&key[..])
+                .payload(payload)
+                .headers(
+                    OwnedHeaders::new()
+                        .add("operation", "put_promotion")
+                        .add("actor", "admin")
+                        .add("correlation_id", &correlation_id(&req, &key)),
+                ),
+            0,
+        )
+        .wait();
+
+    match result {
+        Ok(Ok(delivery)) => {
+            info!(
+                "Message sent to kafka: partition: {}, offset: {}",
+                delivery.0, delivery.1
+            );
+            HttpResponse::Created().finish()
+        }
+        Ok(Err((e, msg))) => {
+            error!(
+                "{}:Error occured while sending message to kafka: error: {}, message: {:?}",
+                line!(),
+                e,
+                msg
+            );
+            HttpResponse::BadRequest().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub fn delete_promotion(
+    req: HttpRequest,
+    code: web::Path<String>,
+    producer: web::Data<FutureProducer>,
+    kafka_topics: web::Data<KafkaTopics>,
+    admin: web::Data<AdminOptions>,
+) -> HttpResponse {
+    if !check_admin_token(&req, &admin) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.input(code.as_bytes());
+    let key = hasher.result_str();
+    let payload = "";
+
+    let result = producer
+        .send(
+            FutureRecord::to(&kafka_topics.orders_service_topic)
+                .key(&key[..])
+                .payload(payload)
+                .headers(
+                    OwnedHeaders::new()
+                        .add("operation", "delete_promotion")
+                        .add("code", code.as_str())
+                        .add("actor", "admin")
+                        .add("correlation_id", &correlation_id(&req, &key)),
+                ),
+            0,
+        )
+        .wait();
+
+    match result {
+        Ok(Ok(delivery)) => {
+            info!(
+                "Message sent to kafka: partition: {}, offset: {}",
+                delivery.0, delivery.1
+            );
+            HttpResponse::Ok().finish()
+        }
+        Ok(Err((e, msg))) => {
+            error!(
+                "{}:Error occured while sending message to kafka: error: {}, message: {:?}",
+                line!(),
+                e,
+                msg
+            );
+            HttpResponse::BadRequest().finish()
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub fn make_billing(
+    req: HttpRequest,
+    bytes: web::Bytes,
+    params: web::Path<(String, String)>,
+    producer: web::Data<FutureProducer>,
+    kafka_topics: web::Data<KafkaTopics>,
+    pool: web::Data<r2d2::Pool<RedisConnectionManager>>,
+) -> HttpResponse {
+    if !check_auth_token(&req, &params.0, &mut pool.get().unwrap()) {
+        return HttpResponse::Unauthorized().finish();
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.input(params.0.as_bytes());
+    hasher.input(params.1.as_bytes());
+    hasher.input(bytes.as_ref());
+    let key = hasher.result_str();
+
+    let payload = match std::str::from_utf8(bytes.as_ref()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{}:Couldn't deserialize payload: {}", line!(), e);
+            return HttpResponse::BadRequest().finish();
+        }
+    };
+
+    let result = producer
+        .send(
+            FutureRecord::to(&kafka_topics.orders_service_topic)
                 .key(&key[..])
                 .payload(payload)
                 .headers(
                     OwnedHeaders::new()
                         .add("user_id", &params.0)
-                        .add("order_id", &params.1),
+                        .add("order_id", &params.1)
+                        .add("operation", "make_billing")
+                        .add("actor", &params.0)
+                        .add("correlation_id", &correlation_id(&req, &key)),
                 ),
             0,
         )
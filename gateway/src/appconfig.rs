@@ -1,6 +1,7 @@
 use actix_web::{web, HttpResponse};
 
 use crate::api::*;
+use shutdown::readiness;
 
 pub fn config_app(cfg: &mut web::ServiceConfig) {
     cfg.service(
@@ -8,9 +9,7 @@ pub fn config_app(cfg: &mut web::ServiceConfig) {
             .service(
                 web::scope("/probe")
                     .service(web::resource("/liveness").route(web::get().to(|| HttpResponse::Ok())))
-                    .service(
-                        web::resource("/readiness").route(web::get().to(|| HttpResponse::Ok())),
-                    )
+                    .service(web::resource("/readiness").route(web::get().to(readiness)))
                     .service(web::resource("/startup").route(web::get().to(|| HttpResponse::Ok()))),
             )
             .service(
@@ -18,15 +17,102 @@ pub fn config_app(cfg: &mut web::ServiceConfig) {
                     .service(web::resource("").route(web::get().to_async(get_goods)))
                     .service(web::resource("/{good_id}").route(web::get().to_async(get_good))),
             )
+            .service(
+                web::scope("/admin")
+                    .service(
+                        web::resource("/user/{user_id}/order/{order_id}")
+                            .route(web::delete().to(delete_order)),
+                    )
+                    .service(
+                        web::resource("/user/{user_id}/order/{order_id}/shipment")
+                            .route(web::post().to(advance_shipment)),
+                    )
+                    .service(
+                        web::resource("/user/{user_id}/order/{order_id}/returns/{return_id}")
+                            .route(web::post().to(review_return)),
+                    )
+                    .service(
+                        web::resource("/goods/{good_id}/stock").route(web::post().to(restock_good)),
+                    )
+                    .service(
+                        web::resource("/promotions")
+                            .route(web::get().to(get_promotions))
+                            .route(web::post().to(put_promotion)),
+                    )
+                    .service(
+                        web::resource("/promotions/{code}")
+                            .route(web::delete().to(delete_promotion)),
+                    )
+                    .service(web::resource("/orders/export").route(web::get().to(export_orders)))
+                    .service(web::resource("/orders/search").route(web::get().to(search_orders))),
+            )
             .service(
                 web::scope("/user/{user_id}")
                     .service(web::resource("/orders").route(web::get().to_async(get_orders)))
                     .service(web::resource("/order").route(web::post().to(create_order)))
+                    .service(
+                        web::resource("/cart")
+                            .route(web::get().to(get_cart))
+                            .route(web::delete().to(clear_cart)),
+                    )
+                    .service(web::resource("/cart/goods").route(web::post().to(add_to_cart)))
+                    .service(
+                        web::resource("/cart/goods/{good_id}")
+                            .route(web::put().to(update_cart_good))
+                            .route(web::delete().to(remove_from_cart)),
+                    )
+                    .service(web::resource("/cart/checkout").route(web::post().to(checkout_cart)))
+                    .service(
+                        web::resource("/templates")
+                            .route(web::get().to(get_templates))
+                            .route(web::post().to(put_template)),
+                    )
+                    .service(
+                        web::resource("/templates/{template}")
+                            .route(web::delete().to(delete_template)),
+                    )
+                    .service(
+                        web::resource("/templates/{template}/order")
+                            .route(web::post().to(instantiate_template)),
+                    )
+                    .service(
+                        web::resource("/schedules")
+                            .route(web::get().to(get_schedules))
+                            .route(web::post().to(put_schedule)),
+                    )
+                    .service(
+                        web::resource("/schedules/{schedule}")
+                            .route(web::delete().to(delete_schedule)),
+                    )
                     .service(
                         web::resource("/order/{order_id}")
                             .route(web::get().to(get_order))
-                            .route(web::put().to(update_order))
-                            .route(web::delete().to(delete_order)),
+                            .route(web::put().to(update_order)),
+                    )
+                    .service(
+                        web::resource("/order/{order_id}/history")
+                            .route(web::get().to(get_order_history)),
+                    )
+                    .service(
+                        web::resource("/order/{order_id}/shipment")
+                            .route(web::get().to(get_shipment)),
+                    )
+                    .service(
+                        web::resource("/order/{order_id}/reorder").route(web::post().to(reorder)),
+                    )
+                    .service(
+                        web::resource("/order/{order_id}/returns")
+                            .route(web::get().to(get_order_returns))
+                            .route(web::post().to(request_return)),
+                    )
+                    .service(
+                        web::resource("/order/{order_id}/promotion")
+                            .route(web::post().to(apply_promotion))
+                            .route(web::delete().to(remove_promotion)),
+                    )
+                    .service(
+                        web::resource("/order/{order_id}/cancel")
+                            .route(web::post().to(cancel_order)),
                     )
                     .service(
                         web::resource("/order/{order_id}/billing")
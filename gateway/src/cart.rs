@@ -0,0 +1,136 @@
+use r2d2_redis::redis;
+use std::collections::BTreeMap;
+
+// Cart of a user is a hash of good id to count, it expires if the user
+// doesn't touch it for a while
+fn key(user_id: &str) -> String {
+    format!("cart:user_id:{}", user_id)
+}
+
+// Every change of the cart pushes its expiry back
+pub fn add(
+    conn: &mut redis::Connection,
+    user_id: &str,
+    good_id: u64,
+    count: u64,
+    ttl_secs: u64,
+) -> redis::RedisResult<()> {
+    redis::pipe()
+        .atomic()
+        .cmd("HINCRBY")
+        .arg(key(user_id))
+        .arg(good_id)
+        .arg(count)
+        .ignore()
+        .cmd("EXPIRE")
+        .arg(key(user_id))
+        .arg(ttl_secs)
+        .ignore()
+        .query(conn)
+}
+
+// Line with zero count is removed
+pub fn set(
+    conn: &mut redis::Connection,
+    user_id: &str,
+    good_id: u64,
+    count: u64,
+    ttl_secs: u64,
+) -> redis::RedisResult<()> {
+    if count == 0 {
+        return remove(conn, user_id, good_id, ttl_secs);
+    }
+
+    redis::pipe()
+        .atomic()
+        .cmd("HSET")
+        .arg(key(user_id))
+        .arg(good_id)
+        .arg(count)
+        .ignore()
+        .cmd("EXPIRE")
+        .arg(key(user_id))
+        .arg(ttl_secs)
+        .ignore()
+        .query(conn)
+}
+
+pub fn remove(
+    conn: &mut redis::Connection,
+    user_id: &str,
+    good_id: u64,
+    ttl_secs: u64,
+) -> redis::RedisResult<()> {
+    redis::pipe()
+        .atomic()
+        .cmd("HDEL")
+        .arg(key(user_id))
+        .arg(good_id)
+        .ignore()
+        .cmd("EXPIRE")
+        .arg(key(user_id))
+        .arg(ttl_secs)
+        .ignore()
+        .query(conn)
+}
+
+pub fn clear(conn: &mut redis::Connection, user_id: &str) -> redis::RedisResult<()> {
+    redis::cmd("DEL").arg(key(user_id)).query(conn)
+}
+
+// Cart is read and removed at once, so lines added meanwhile either
+// get into the order or stay in a new cart
+pub fn take(conn: &mut redis::Connection, user_id: &str) -> redis::RedisResult<BTreeMap<u64, u64>> {
+    let (lines,): (BTreeMap<u64, u64>,) = redis::pipe()
+        .atomic()
+        .cmd("HGETALL")
+        .arg(key(user_id))
+        .cmd("DEL")
+        .arg(key(user_id))
+        .ignore()
+        .query(conn)?;
+    Ok(lines)
+}
+
+// Lines of the order which wasn't sent are added back to the cart
+pub fn restore(
+    conn: &mut redis::Connection,
+    user_id: &str,
+    lines: &BTreeMap<u64, u64>,
+    ttl_secs: u64,
+) -> redis::RedisResult<()> {
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+
+    for (good_id, count) in lines {
+        pipe.cmd("HINCRBY")
+            .arg(key(user_id))
+            .arg(*good_id)
+            .arg(*count)
+            .ignore();
+    }
+
+    pipe.cmd("EXPIRE")
+        .arg(key(user_id))
+        .arg(ttl_secs)
+        .ignore()
+        .query(conn)
+}
+
+pub fn lines(
+    conn: &mut redis::Connection,
+    user_id: &str,
+) -> redis::RedisResult<BTreeMap<u64, u64>> {
+    redis::cmd("HGETALL").arg(key(user_id)).query(conn)
+}
+
+// Seconds left until the cart expires, None if there is no cart
+pub fn expires_in(conn: &mut redis::Connection, user_id: &str) -> redis::RedisResult<Option<u64>> {
+    let ttl: i64 = redis::cmd("TTL").arg(key(user_id)).query(conn)?;
+
+    if ttl < 0 {
+        Ok(None)
+    } else {
+        Ok(Some(ttl as u64))
+    }
+}
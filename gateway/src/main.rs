@@ -1,14 +1,17 @@
 #[macro_use]
 extern crate log;
 
-use actix_web::{middleware::Logger, App, HttpServer};
+use actix_web::{middleware::Logger, web, App, HttpServer};
+use futures::Future;
 use r2d2_redis::{r2d2, RedisConnectionManager};
 use rdkafka::config::ClientConfig;
 use rdkafka::producer::FutureProducer;
 use serde::Deserialize;
+use shutdown::{Readiness, ShutdownOptions};
 
 mod api;
 mod appconfig;
+mod cart;
 
 #[derive(Deserialize)]
 struct ServerOptions {
@@ -27,7 +30,7 @@ struct KafkaProducerOptions {
 #[derive(Clone, Deserialize)]
 pub struct KafkaTopics {
     orders_service_topic: String,
-    billing_service_topic: String,
+    warehouse_service_topic: String,
 }
 
 #[derive(Clone, Deserialize)]
@@ -36,12 +39,27 @@ pub struct ServicesParams {
     warehouse_service_addr: String,
 }
 
+// Token of operators allowed to use admin api
+#[derive(Clone, Deserialize)]
+pub struct AdminOptions {
+    token: String,
+}
+
+// Carts which weren't changed for that long are dropped
+#[derive(Clone, Deserialize)]
+pub struct CartOptions {
+    ttl_secs: u64,
+}
+
 #[derive(Deserialize)]
 struct Config {
     server: ServerOptions,
     kafka_producer: KafkaProducerOptions,
     kafka_topics: KafkaTopics,
     services: ServicesParams,
+    admin: AdminOptions,
+    cart: CartOptions,
+    shutdown: ShutdownOptions,
 }
 
 fn read_config(config_file_path: &str) -> Result<String, std::io::Error> {
@@ -93,23 +111,39 @@ fn main() {
                 .expect("Producer creation error");
             let kafka_topics = config.kafka_topics.clone();
             let services_params = config.services.clone();
+            let admin_options = config.admin.clone();
+            let cart_options = config.cart.clone();
 
             let manager =
                 RedisConnectionManager::new(&config.server.redis_connection_string[..]).unwrap();
             let pool = r2d2::Pool::builder().build(manager).unwrap();
 
+            let readiness = web::Data::new(Readiness::default());
+
             let mut listen_fd = listenfd::ListenFd::from_env();
-            let mut server = HttpServer::new(move || {
-                App::new()
-                    .configure(appconfig::config_app)
-                    .data(producer.clone())
-                    .data(kafka_topics.clone())
-                    .data(services_params.clone())
-                    .data(pool.clone())
-                    .wrap(Logger::new(
-                        "ip: %a, date: %t, response code: %s, response size: %b (bytes), duration: %D (ms)",
-                    ))
-            });
+            let mut server = {
+                let producer = producer.clone();
+                let readiness = readiness.clone();
+                HttpServer::new(move || {
+                    App::new()
+                        .configure(appconfig::config_app)
+                        .register_data(readiness.clone())
+                        .data(producer.clone())
+                        .data(kafka_topics.clone())
+                        .data(services_params.clone())
+                        .data(admin_options.clone())
+                        .data(cart_options.clone())
+                        .data(pool.clone())
+                        .wrap(Logger::new(
+                            "ip: %a, date: %t, response code: %s, response size: %b (bytes), duration: %D (ms)",
+                        ))
+                })
+            };
+
+            // Signals are handled below, so that producer is flushed after last request
+            server = server
+                .disable_signals()
+                .shutdown_timeout(config.shutdown.timeout_secs);
 
             server = if let Some(l) = listen_fd.take_tcp_listener(0).unwrap() {
                 server.listen(l).unwrap()
@@ -120,8 +154,24 @@ fn main() {
                     .unwrap()
             };
 
-            server.start();
+            let server = server.start();
+            let system = actix_rt::System::current();
+            let shutdown_options = config.shutdown;
+
+            let signal_handler = std::thread::spawn(move || {
+                shutdown::on_signal(
+                    &shutdown_options,
+                    &readiness,
+                    || {
+                        let _ = server.stop(true).wait();
+                    },
+                    || producer.flush(None),
+                );
+                system.stop();
+            });
+
             let _ = sys.run();
+            signal_handler.join().unwrap();
         }
     }
 }
@@ -0,0 +1,8 @@
+-- Returns are kept in json, they are removed together with the order
+CREATE TABLE order_returns (
+    user_id TEXT NOT NULL,
+    order_id BIGINT NOT NULL,
+    return_id BIGINT NOT NULL,
+    definition TEXT NOT NULL,
+    PRIMARY KEY (user_id, order_id, return_id)
+);
@@ -0,0 +1,7 @@
+-- Templates are kept in json, names are unique for a user
+CREATE TABLE order_templates (
+    user_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    definition TEXT NOT NULL,
+    PRIMARY KEY (user_id, name)
+);
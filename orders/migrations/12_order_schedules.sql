@@ -0,0 +1,11 @@
+-- Schedules are kept in json, time of the next run is a column of its own,
+-- so due schedules are found by index
+CREATE TABLE order_schedules (
+    user_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    next_run_at BIGINT NOT NULL,
+    definition TEXT NOT NULL,
+    PRIMARY KEY (user_id, name)
+);
+
+CREATE INDEX order_schedules_next_run_at_idx ON order_schedules (next_run_at);
@@ -0,0 +1,2 @@
+-- Orders of all users are exported in order of their ids
+CREATE INDEX orders_order_id_idx ON orders (order_id);
@@ -0,0 +1,5 @@
+-- Support staff look orders up without their owner, by status,
+-- by good they contain and by creation date
+CREATE INDEX orders_status_idx ON orders (status, order_id);
+CREATE INDEX orders_created_at_idx ON orders (created_at, order_id);
+CREATE INDEX order_lines_good_id_idx ON order_lines (good_id);
@@ -0,0 +1,11 @@
+-- Last return id given within the order, returns made before
+-- the counter existed continue their numbering
+CREATE TABLE order_return_ids (
+    user_id TEXT NOT NULL,
+    order_id BIGINT NOT NULL,
+    return_id BIGINT NOT NULL,
+    PRIMARY KEY (user_id, order_id)
+);
+
+INSERT INTO order_return_ids (user_id, order_id, return_id)
+SELECT user_id, order_id, MAX(return_id) FROM order_returns GROUP BY user_id, order_id;
@@ -0,0 +1,66 @@
+CREATE TABLE orders (
+    user_id TEXT NOT NULL,
+    order_id BIGINT NOT NULL,
+    status TEXT NOT NULL,
+    PRIMARY KEY (user_id, order_id)
+);
+
+CREATE TABLE order_lines (
+    user_id TEXT NOT NULL,
+    order_id BIGINT NOT NULL,
+    good_id BIGINT NOT NULL,
+    count BIGINT NOT NULL,
+    PRIMARY KEY (user_id, order_id, good_id),
+    FOREIGN KEY (user_id, order_id) REFERENCES orders (user_id, order_id) ON DELETE CASCADE
+);
+
+CREATE SEQUENCE order_id_seq;
+
+-- Copy of an order waiting for warehouse answer, goods are kept as json
+CREATE TABLE pending_orders (
+    user_id TEXT NOT NULL,
+    order_id BIGINT NOT NULL,
+    status TEXT NOT NULL,
+    goods TEXT NOT NULL,
+    PRIMARY KEY (user_id, order_id)
+);
+
+CREATE TABLE order_transactions (
+    user_id TEXT NOT NULL,
+    order_id BIGINT NOT NULL,
+    operation TEXT NOT NULL,
+    message TEXT NOT NULL,
+    created_at BIGINT NOT NULL,
+    deadline BIGINT NOT NULL,
+    attempts BIGINT NOT NULL,
+    PRIMARY KEY (user_id, order_id)
+);
+
+CREATE INDEX order_transactions_deadline_idx ON order_transactions (deadline);
+
+CREATE TABLE sagas (
+    user_id TEXT NOT NULL,
+    order_id BIGINT NOT NULL,
+    state TEXT NOT NULL,
+    deadline BIGINT,
+    updated_at BIGINT NOT NULL,
+    PRIMARY KEY (user_id, order_id)
+);
+
+CREATE INDEX sagas_deadline_idx ON sagas (deadline) WHERE deadline IS NOT NULL;
+
+CREATE TABLE processed_messages (
+    message_id TEXT PRIMARY KEY,
+    expires_at BIGINT NOT NULL
+);
+
+CREATE TABLE outbox (
+    id BIGSERIAL PRIMARY KEY,
+    entry TEXT NOT NULL
+);
+
+CREATE TABLE outbox_locks (
+    name TEXT PRIMARY KEY,
+    owner TEXT NOT NULL,
+    expires_at BIGINT NOT NULL
+);
@@ -0,0 +1,5 @@
+-- Orders created before this migration are treated as created at epoch
+ALTER TABLE orders ADD COLUMN created_at BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE pending_orders ADD COLUMN created_at BIGINT NOT NULL DEFAULT 0;
+
+CREATE INDEX orders_user_created_at_idx ON orders (user_id, created_at, order_id);
@@ -0,0 +1,13 @@
+-- Orders paid before statuses were introduced were stored as 'payed'
+UPDATE orders SET status = 'paid' WHERE status = 'payed';
+UPDATE pending_orders SET status = 'paid' WHERE status = 'payed';
+
+CREATE TABLE order_status_history (
+    id BIGSERIAL PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    order_id BIGINT NOT NULL,
+    status TEXT NOT NULL,
+    at BIGINT NOT NULL
+);
+
+CREATE INDEX order_status_history_order_idx ON order_status_history (user_id, order_id, id);
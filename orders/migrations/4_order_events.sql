@@ -0,0 +1,23 @@
+ALTER TABLE orders ADD COLUMN updated_at BIGINT NOT NULL DEFAULT 0;
+UPDATE orders SET updated_at = created_at;
+
+-- Append-only log of what happened to orders, status history becomes part of it
+CREATE TABLE order_events (
+    id BIGSERIAL PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    order_id BIGINT NOT NULL,
+    kind TEXT NOT NULL,
+    at BIGINT NOT NULL,
+    actor TEXT NOT NULL,
+    correlation_id TEXT NOT NULL,
+    details TEXT NOT NULL
+);
+
+CREATE INDEX order_events_order_idx ON order_events (user_id, order_id, id);
+
+INSERT INTO order_events (user_id, order_id, kind, at, actor, correlation_id, details)
+SELECT user_id, order_id, CASE status WHEN 'new' THEN 'created' ELSE status END,
+       at, 'system', '', ''
+FROM order_status_history ORDER BY id;
+
+DROP TABLE order_status_history;
@@ -0,0 +1,8 @@
+-- Lines of orders created before prices were introduced have no price
+ALTER TABLE order_lines ADD COLUMN unit_price BIGINT;
+ALTER TABLE orders ADD COLUMN currency TEXT NOT NULL DEFAULT '';
+ALTER TABLE orders ADD COLUMN tax_rate_bp BIGINT NOT NULL DEFAULT 0;
+
+ALTER TABLE pending_orders ADD COLUMN prices TEXT NOT NULL DEFAULT '{}';
+ALTER TABLE pending_orders ADD COLUMN currency TEXT NOT NULL DEFAULT '';
+ALTER TABLE pending_orders ADD COLUMN tax_rate_bp BIGINT NOT NULL DEFAULT 0;
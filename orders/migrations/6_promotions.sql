@@ -0,0 +1,15 @@
+-- Promotions are kept in json, orders keep a copy of the one applied to them
+CREATE TABLE promotions (
+    code TEXT PRIMARY KEY,
+    definition TEXT NOT NULL
+);
+
+CREATE TABLE promotion_uses (
+    code TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    order_id BIGINT NOT NULL,
+    PRIMARY KEY (code, user_id, order_id)
+);
+
+ALTER TABLE orders ADD COLUMN promotion TEXT;
+ALTER TABLE pending_orders ADD COLUMN promotion TEXT;
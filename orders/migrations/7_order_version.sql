@@ -0,0 +1,3 @@
+-- Version is incremented every time an order is written
+ALTER TABLE orders ADD COLUMN version BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE pending_orders ADD COLUMN version BIGINT NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- Shipping address and delivery details are kept in json
+ALTER TABLE orders ADD COLUMN delivery TEXT;
+ALTER TABLE pending_orders ADD COLUMN delivery TEXT;
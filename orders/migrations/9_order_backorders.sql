@@ -0,0 +1,6 @@
+-- Orders created before backorders were introduced are all or nothing
+ALTER TABLE orders ADD COLUMN fulfilment TEXT NOT NULL DEFAULT 'all_or_nothing';
+ALTER TABLE order_lines ADD COLUMN backordered BIGINT NOT NULL DEFAULT 0;
+
+ALTER TABLE pending_orders ADD COLUMN fulfilment TEXT NOT NULL DEFAULT 'all_or_nothing';
+ALTER TABLE pending_orders ADD COLUMN backorders TEXT NOT NULL DEFAULT '{}';
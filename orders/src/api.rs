@@ -1,225 +1,627 @@
+use crate::backorders;
+use crate::events::OrderEvent;
+use crate::export::{self, Export, ExportFormat};
+use crate::pricing;
+use crate::schedules;
+use crate::status::OrderStatus;
+use crate::storage::{Cursor, Order, OrderQuery, OrderRepository, OrderSearch, SortField};
+use crate::transactions;
 use actix_web::{web, HttpRequest, HttpResponse};
-use r2d2_redis::{r2d2, redis, RedisConnectionManager};
 use serde_json::map::Map;
 use serde_json::value::Value;
-use std::ops::DerefMut;
+use std::sync::Arc;
 
-// FIXME: OMG!!! plz change redis for posrgresql and diesel...
-fn parse_redis_answer(bulk: Vec<redis::Value>) -> Option<Map<String, Value>> {
+pub fn order_to_json(order: Order) -> Map<String, Value> {
     let mut json: Map<String, Value> = Map::new();
-    let mut goods: Vec<Value> = vec![];
-    let mut i = 0;
+    let totals = pricing::totals(&order);
+    let goods: Vec<Value> = order
+        .goods
+        .iter()
+        .map(|(good_id, count)| {
+            let mut good: Map<String, Value> = Map::new();
+            good.insert(
+                "good_id".to_string(),
+                Value::Number(serde_json::Number::from(*good_id)),
+            );
+            good.insert(
+                "count".to_string(),
+                Value::Number(serde_json::Number::from(*count)),
+            );
+            good.insert(
+                "price".to_string(),
+                match order.prices.get(good_id) {
+                    Some(price) => Value::Number(serde_json::Number::from(*price)),
+                    None => Value::Null,
+                },
+            );
+            good.insert(
+                "total".to_string(),
+                Value::Number(serde_json::Number::from(totals.lines[good_id])),
+            );
+            good.insert(
+                "discount".to_string(),
+                Value::Number(serde_json::Number::from(
+                    totals.discounts.get(good_id).cloned().unwrap_or(0),
+                )),
+            );
+            let backordered = order.backorders.get(good_id).cloned().unwrap_or(0);
+            good.insert(
+                "backordered".to_string(),
+                Value::Number(serde_json::Number::from(backordered)),
+            );
+            good.insert(
+                "state".to_string(),
+                Value::String(backorders::line_state(*count, backordered).to_string()),
+            );
+            Value::Object(good)
+        })
+        .collect();
 
-    while i < bulk.len() {
-        if let redis::Value::Data(data) = &bulk[i] {
-            let key = match String::from_utf8(data.to_vec()) {
-                Ok(s) => s,
-                Err(e) => {
-                    error!("{}:Couldn't deserialize redis answer: {}", line!(), e);
-                    return None;
-                }
-            };
+    json.insert(
+        "status".to_string(),
+        Value::String(order.status.as_str().to_string()),
+    );
+    json.insert("goods".to_string(), Value::Array(goods));
+    json.insert("currency".to_string(), Value::String(order.currency));
+    json.insert(
+        "subtotal".to_string(),
+        Value::Number(serde_json::Number::from(totals.subtotal)),
+    );
+    json.insert(
+        "promotion_code".to_string(),
+        match order.promotion {
+            Some(promotion) => Value::String(promotion.code),
+            None => Value::Null,
+        },
+    );
+    json.insert(
+        "discount".to_string(),
+        Value::Number(serde_json::Number::from(totals.discount)),
+    );
+    json.insert(
+        "tax".to_string(),
+        Value::Number(serde_json::Number::from(totals.tax)),
+    );
+    json.insert(
+        "total".to_string(),
+        Value::Number(serde_json::Number::from(totals.total)),
+    );
+    json.insert(
+        "created_at".to_string(),
+        Value::Number(serde_json::Number::from(order.created_at)),
+    );
+    json.insert(
+        "updated_at".to_string(),
+        Value::Number(serde_json::Number::from(order.updated_at)),
+    );
+    json.insert(
+        "version".to_string(),
+        Value::Number(serde_json::Number::from(order.version)),
+    );
+    json.insert(
+        "delivery".to_string(),
+        serde_json::to_value(&order.delivery).unwrap_or(Value::Null),
+    );
+    json.insert(
+        "fulfilment".to_string(),
+        Value::String(order.fulfilment.as_str().to_string()),
+    );
+    json
+}
 
-            if let redis::Value::Data(data) = &bulk[i + 1] {
-                let value = match String::from_utf8(data.to_vec()) {
-                    Ok(s) => s,
-                    Err(e) => {
-                        error!("{}:Couldn't deserialize redis answer: {}", line!(), e);
-                        return None;
-                    }
-                };
-
-                if key.contains("good_id:") {
-                    let mut good: Map<String, Value> = Map::new();
-                    let splits: Vec<&str> = key.split(':').collect();
-
-                    let order_id = match splits[1].to_string().parse::<u64>() {
-                        Ok(id) => id,
-                        Err(e) => {
-                            error!("{}:Couldn't convert to number: {}", line!(), e);
-                            return None;
-                        }
-                    };
-
-                    let value = match value.parse::<u64>() {
-                        Ok(v) => v,
-                        Err(e) => {
-                            error!("{}:Couldn't convert to number: {}", line!(), e);
-                            return None;
-                        }
-                    };
-
-                    good.insert(
-                        splits[0].to_string(),
-                        Value::Number(serde_json::Number::from(order_id)),
-                    );
-                    good.insert(
-                        "count".to_string(),
-                        Value::Number(serde_json::Number::from(value)),
-                    );
-                    goods.push(Value::Object(good));
-                } else {
-                    json.insert(key, Value::String(value));
-                }
-            }
+// Version of the order is its strong entity tag
+fn etag(order: &Order) -> String {
+    format!("\"{}\"", order.version)
+}
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+// Cursor looks like '<sort key>:<order id>' of the last order of previous page
+fn parse_cursor(cursor: &str) -> Option<Cursor> {
+    let mut splits = cursor.splitn(2, ':');
+    let key = splits.next()?.parse().ok()?;
+    let order_id = splits.next()?.parse().ok()?;
+    Some(Cursor { key, order_id })
+}
+
+// Status history is a part of order events
+pub fn status_history_to_json(events: &[OrderEvent]) -> Value {
+    Value::Array(
+        events
+            .iter()
+            .filter_map(|event| {
+                let status = event.status()?;
+                let mut json: Map<String, Value> = Map::new();
+                json.insert(
+                    "status".to_string(),
+                    Value::String(status.as_str().to_string()),
+                );
+                json.insert(
+                    "at".to_string(),
+                    Value::Number(serde_json::Number::from(event.at)),
+                );
+                Some(Value::Object(json))
+            })
+            .collect(),
+    )
+}
+
+fn event_to_json(event: OrderEvent) -> Value {
+    let mut json: Map<String, Value> = Map::new();
+    json.insert(
+        "event".to_string(),
+        Value::String(event.kind.as_str().to_string()),
+    );
+    json.insert(
+        "at".to_string(),
+        Value::Number(serde_json::Number::from(event.at)),
+    );
+    json.insert("actor".to_string(), Value::String(event.actor));
+    json.insert(
+        "correlation_id".to_string(),
+        Value::String(event.correlation_id),
+    );
+    json.insert(
+        "details".to_string(),
+        serde_json::from_str(&event.details).unwrap_or(Value::Null),
+    );
+    Value::Object(json)
+}
+
+fn parse_query(query: &qstring::QString) -> Result<OrderQuery, String> {
+    let number = |name: &str| -> Result<Option<u64>, String> {
+        match query.get(name) {
+            Some(value) => match value.parse() {
+                Ok(value) => Ok(Some(value)),
+                Err(_) => Err(format!("Invalid '{}': {}", name, value)),
+            },
+            None => Ok(None),
         }
+    };
 
-        i += 2;
-    }
+    let limit = match number("limit")? {
+        Some(limit) if limit > 0 && limit as usize <= MAX_LIMIT => limit as usize,
+        Some(limit) => {
+            return Err(format!(
+                "Invalid 'limit': {}, it must be from 1 to {}",
+                limit, MAX_LIMIT
+            ))
+        }
+        None => DEFAULT_LIMIT,
+    };
 
-    json.insert("goods".to_string(), Value::Array(goods));
-    Some(json)
+    let sort = match query.get("sort") {
+        None | Some("order_id") => SortField::OrderId,
+        Some("created_at") => SortField::CreatedAt,
+        Some(sort) => return Err(format!("Invalid 'sort': {}", sort)),
+    };
+
+    let descending = match query.get("order") {
+        None | Some("asc") => false,
+        Some("desc") => true,
+        Some(order) => return Err(format!("Invalid 'order': {}", order)),
+    };
+
+    let after = match query.get("cursor") {
+        Some(cursor) => match parse_cursor(cursor) {
+            Some(cursor) => Some(cursor),
+            None => return Err(format!("Invalid 'cursor': {}", cursor)),
+        },
+        None => None,
+    };
+
+    let status = match query.get("status") {
+        Some(status) => match OrderStatus::parse(status) {
+            Some(status) => Some(status),
+            None => return Err(format!("Invalid 'status': {}", status)),
+        },
+        None => None,
+    };
+
+    Ok(OrderQuery {
+        status,
+        created_from: number("created_from")?,
+        created_to: number("created_to")?,
+        sort,
+        descending,
+        after,
+        limit,
+    })
 }
 
+// Supports 'limit', 'cursor', 'status', 'created_from' and 'created_to'
+// in milliseconds, 'sort' by 'order_id' or 'created_at' and 'order'
 pub fn get_orders(
     req: HttpRequest,
     user_id: web::Path<String>,
-    db: web::Data<r2d2::Pool<RedisConnectionManager>>,
+    storage: web::Data<Arc<dyn OrderRepository>>,
 ) -> HttpResponse {
-    let query = qstring::QString::from(req.query_string());
-    let limit = match query.get("limit") {
-        Some(limit) if limit.parse::<i64>().unwrap_or(100) > 0 => limit,
-        _ => "100",
+    let query = match parse_query(&qstring::QString::from(req.query_string())) {
+        Ok(query) => query,
+        Err(e) => {
+            error!("{}:Invalid query of orders: {}", line!(), e);
+            return HttpResponse::BadRequest().body(e);
+        }
     };
 
-    let mut conn = match db.get() {
-        Ok(conn) => conn,
+    let page = match storage.orders(&user_id, &query) {
+        Ok(page) => page,
         Err(e) => {
-            error!("{}:Couldn't get connection to database: {}", line!(), e);
+            error!(
+                "{}:Couldn't get orders of user '{}': {}",
+                line!(),
+                user_id,
+                e
+            );
             return HttpResponse::InternalServerError().finish();
         }
     };
 
-    let key_matcher = &format!("user_id:{}:order_id:*", user_id);
+    let orders: Vec<Value> = page
+        .orders
+        .into_iter()
+        .map(|(order_id, order)| {
+            let mut json = order_to_json(order);
+            json.insert(
+                "order_id".to_string(),
+                Value::Number(serde_json::Number::from(order_id)),
+            );
+            Value::Object(json)
+        })
+        .collect();
+
+    let mut result: Map<String, Value> = Map::new();
+    result.insert("orders".to_string(), Value::Array(orders));
+    result.insert(
+        "total".to_string(),
+        Value::Number(serde_json::Number::from(page.total)),
+    );
+    result.insert(
+        "next_cursor".to_string(),
+        match page.next {
+            Some(cursor) => Value::String(format!("{}:{}", cursor.key, cursor.order_id)),
+            None => Value::Null,
+        },
+    );
+
+    HttpResponse::Ok().json(result)
+}
+
+pub fn get_order(
+    req: HttpRequest,
+    params: web::Path<(String, String)>,
+    storage: web::Data<Arc<dyn OrderRepository>>,
+) -> HttpResponse {
+    match storage.order(&params.0, &params.1) {
+        Ok(Some(order)) => match storage.events(&params.0, &params.1) {
+            Ok(events) => {
+                let etag = etag(&order);
+                let not_modified = req
+                    .headers()
+                    .get("If-None-Match")
+                    .and_then(|value| value.to_str().ok())
+                    .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag));
 
-    let result = match redis::cmd("SCAN")
-        .cursor_arg(0)
-        .arg(&["MATCH", key_matcher, "COUNT", &limit])
-        .query::<Vec<redis::Value>>(conn.deref_mut())
-    {
-        Ok(r) => r,
+                if not_modified {
+                    return HttpResponse::NotModified().header("ETag", etag).finish();
+                }
+
+                let mut json = order_to_json(order);
+                json.insert(
+                    "status_history".to_string(),
+                    status_history_to_json(&events),
+                );
+                HttpResponse::Ok().header("ETag", etag).json(json)
+            }
+            Err(e) => {
+                error!("{}:Couldn't get status history of order: {}", line!(), e);
+                HttpResponse::InternalServerError().finish()
+            }
+        },
+        Ok(None) => {
+            error!(
+                "{}:Order with id: {} of user '{}' wasn't found",
+                line!(),
+                params.1,
+                params.0
+            );
+            HttpResponse::NotFound().finish()
+        }
         Err(e) => {
+            error!("{}:Couldn't get order: {}", line!(), e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub fn get_order_history(
+    params: web::Path<(String, String)>,
+    storage: web::Data<Arc<dyn OrderRepository>>,
+) -> HttpResponse {
+    match storage.order(&params.0, &params.1) {
+        Ok(Some(_)) => match storage.events(&params.0, &params.1) {
+            Ok(events) => HttpResponse::Ok().json(Value::Array(
+                events.into_iter().map(event_to_json).collect(),
+            )),
+            Err(e) => {
+                error!("{}:Couldn't get history of order: {}", line!(), e);
+                HttpResponse::InternalServerError().finish()
+            }
+        },
+        Ok(None) => {
             error!(
-                "{}:Error happened on cmd to redis 'SCAN 0 MATCH {} COUNT ': {}",
-                key_matcher, limit, e
+                "{}:Order with id: {} of user '{}' wasn't found",
+                line!(),
+                params.1,
+                params.0
             );
-            return HttpResponse::InternalServerError().finish();
+            HttpResponse::NotFound().finish()
         }
-    };
+        Err(e) => {
+            error!("{}:Couldn't get order: {}", line!(), e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
 
-    let mut pipe = redis::pipe();
-    let mut keys = Vec::new();
-
-    for x in result {
-        match x {
-            redis::Value::Bulk(data) => {
-                for x in data {
-                    if let redis::Value::Data(s) = x {
-                        let key = match String::from_utf8(s) {
-                            Ok(s) => s,
-                            Err(e) => {
-                                error!("{}:Couldn't deserialize redis answer: {}", line!(), e);
-                                return HttpResponse::InternalServerError().finish();
-                            }
-                        };
-
-                        pipe.cmd("HGETALL").arg(&key);
-
-                        let values: Vec<&str> = key.split(":").collect();
-
-                        if let Some(key) = values.last() {
-                            match key.parse::<u32>() {
-                                Ok(key) => {
-                                    keys.push(key);
-                                }
-                                Err(e) => {
-                                    error!("{}:Couldn't convert string to number: {}", line!(), e);
-                                    return HttpResponse::InternalServerError().finish();
-                                }
-                            }
-                        }
-                    }
-                }
+pub fn get_order_returns(
+    params: web::Path<(String, String)>,
+    storage: web::Data<Arc<dyn OrderRepository>>,
+) -> HttpResponse {
+    match storage.order(&params.0, &params.1) {
+        Ok(Some(_)) => match storage.returns(&params.0, &params.1) {
+            Ok(returns) => HttpResponse::Ok().json(returns),
+            Err(e) => {
+                error!("{}:Couldn't get returns of order: {}", line!(), e);
+                HttpResponse::InternalServerError().finish()
             }
-            _ => (),
+        },
+        Ok(None) => {
+            error!(
+                "{}:Order with id: {} of user '{}' wasn't found",
+                line!(),
+                params.1,
+                params.0
+            );
+            HttpResponse::NotFound().finish()
+        }
+        Err(e) => {
+            error!("{}:Couldn't get order: {}", line!(), e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub fn get_templates(
+    user_id: web::Path<String>,
+    storage: web::Data<Arc<dyn OrderRepository>>,
+) -> HttpResponse {
+    match storage.templates(&user_id) {
+        Ok(templates) => HttpResponse::Ok().json(templates),
+        Err(e) => {
+            error!("{}:Couldn't list templates of user: {}", line!(), e);
+            HttpResponse::InternalServerError().finish()
         }
     }
+}
 
-    let items: redis::Value = match pipe.query(conn.deref_mut()) {
-        Ok(x) => x,
+// Runs of schedules are shown with outcomes of their orders
+pub fn get_schedules(
+    user_id: web::Path<String>,
+    storage: web::Data<Arc<dyn OrderRepository>>,
+) -> HttpResponse {
+    let schedules = match storage.schedules(&user_id) {
+        Ok(schedules) => schedules,
         Err(e) => {
-            error!("{}:Couldn't execute redis pipeline request: {}", line!(), e);
+            error!("{}:Couldn't list schedules of user: {}", line!(), e);
             return HttpResponse::InternalServerError().finish();
         }
     };
+    let mut result: Vec<Value> = vec![];
 
-    if let redis::Value::Bulk(data) = items {
-        let mut result: Vec<Map<String, Value>> = Vec::new();
-
-        for x in data {
-            if let redis::Value::Bulk(bulk) = x {
-                if let Some(mut json) = parse_redis_answer(bulk) {
-                    if json["status"] != "deleted" {
-                        json.insert(
-                            "order_id".to_string(),
-                            Value::Number(serde_json::Number::from(keys.remove(0))),
-                        );
-                        result.push(json);
-                    }
-                } else {
+    for schedule in schedules {
+        let mut runs: Vec<Value> = vec![];
+
+        for run in &schedule.runs {
+            let outcome = match schedules::outcome(&user_id, run, storage.get_ref().as_ref()) {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    error!("{}:Couldn't get outcome of scheduled order: {}", line!(), e);
                     return HttpResponse::InternalServerError().finish();
                 }
+            };
+
+            let mut json = match serde_json::to_value(run) {
+                Ok(Value::Object(json)) => json,
+                _ => Map::new(),
+            };
+            json.insert("outcome".to_string(), Value::String(outcome.to_string()));
+            runs.push(Value::Object(json));
+        }
+
+        let mut json = match serde_json::to_value(&schedule) {
+            Ok(Value::Object(json)) => json,
+            _ => Map::new(),
+        };
+        json.insert("runs".to_string(), Value::Array(runs));
+        result.push(Value::Object(json));
+    }
+
+    HttpResponse::Ok().json(result)
+}
+
+// Promotions with number of orders paid with them
+pub fn get_promotions(storage: web::Data<Arc<dyn OrderRepository>>) -> HttpResponse {
+    let promotions = match storage.promotions() {
+        Ok(promotions) => promotions,
+        Err(e) => {
+            error!("{}:Couldn't list promotions: {}", line!(), e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let mut result: Vec<Value> = vec![];
+
+    for promotion in promotions {
+        let uses = match storage.promotion_uses(&promotion.code) {
+            Ok(uses) => uses.len(),
+            Err(e) => {
+                error!("{}:Couldn't get uses of promotion: {}", line!(), e);
+                return HttpResponse::InternalServerError().finish();
             }
+        };
+
+        let mut json = match serde_json::to_value(&promotion) {
+            Ok(Value::Object(json)) => json,
+            _ => Map::new(),
+        };
+        json.insert(
+            "uses".to_string(),
+            Value::Number(serde_json::Number::from(uses)),
+        );
+        result.push(Value::Object(json));
+    }
+
+    HttpResponse::Ok().json(result)
+}
+
+pub fn get_transactions(storage: web::Data<Arc<dyn OrderRepository>>) -> HttpResponse {
+    match transactions::list(storage.get_ref().as_ref()) {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(e) => {
+            error!("{}:Couldn't list transactions: {}", line!(), e);
+            HttpResponse::InternalServerError().finish()
         }
+    }
+}
 
-        if result.is_empty() {
-            HttpResponse::NotFound().json(result)
-        } else {
-            HttpResponse::Ok().json(result)
+// Cursor of search is id of the last order of previous page, the rest of
+// parameters are checked the same way as filters of orders of a user,
+// found orders always go by id, so they can't be sorted
+fn parse_search(query: &qstring::QString) -> Result<(OrderSearch, u64), String> {
+    for name in &["sort", "order"] {
+        if query.get(name).is_some() {
+            return Err(format!(
+                "'{}' isn't supported by search, orders go by id",
+                name
+            ));
         }
-    } else {
-        HttpResponse::NotFound().finish()
     }
+
+    let number = |name: &str| -> Result<Option<u64>, String> {
+        match query.get(name) {
+            Some(value) => match value.parse() {
+                Ok(value) => Ok(Some(value)),
+                Err(_) => Err(format!("Invalid '{}': {}", name, value)),
+            },
+            None => Ok(None),
+        }
+    };
+
+    let filters = qstring::QString::new(
+        query
+            .to_pairs()
+            .into_iter()
+            .filter(|(name, _)| *name != "cursor")
+            .collect(),
+    );
+
+    Ok((
+        OrderSearch {
+            order_id: number("order_id")?,
+            user_id: query.get("user_id").map(|user_id| user_id.to_string()),
+            good_id: number("good_id")?,
+            query: parse_query(&filters)?,
+        },
+        number("cursor")?.unwrap_or(0),
+    ))
 }
 
-pub fn get_order(
-    params: web::Path<(String, String)>,
-    db: web::Data<r2d2::Pool<RedisConnectionManager>>,
+// Support staff find orders without knowing their owner by 'order_id',
+// 'user_id', 'good_id', 'status', 'created_from' and 'created_to'
+pub fn search_orders(
+    req: HttpRequest,
+    storage: web::Data<Arc<dyn OrderRepository>>,
 ) -> HttpResponse {
-    let mut conn = match db.get() {
-        Ok(conn) => conn,
+    let (search, cursor) = match parse_search(&qstring::QString::from(req.query_string())) {
+        Ok(search) => search,
+        Err(e) => {
+            error!("{}:Invalid search of orders: {}", line!(), e);
+            return HttpResponse::BadRequest().body(e);
+        }
+    };
+
+    let scan = match storage.search_orders(&search, cursor) {
+        Ok(scan) => scan,
         Err(e) => {
-            error!("{}:Couldn't get connection to database: {}", line!(), e);
+            error!("{}:Couldn't search orders: {}", line!(), e);
             return HttpResponse::InternalServerError().finish();
         }
     };
 
-    let redis_key = &format!("user_id:{}:order_id:{}", params.0, params.1);
-
-    match redis::cmd("HGETALL").arg(redis_key).query(conn.deref_mut()) {
-        Ok(redis::Value::Bulk(bulk)) => {
-            if bulk.is_empty() {
-                error!("{}:Order with id: {} wasn't found", line!(), redis_key);
-                HttpResponse::NotFound().finish()
-            } else {
-                if let Some(json) = parse_redis_answer(bulk) {
-                    if json["status"] != "deleted" {
-                        HttpResponse::Ok().json(json)
-                    } else {
-                        HttpResponse::NotFound().finish()
-                    }
-                } else {
-                    HttpResponse::InternalServerError().finish()
-                }
+    let orders: Vec<Value> = scan
+        .orders
+        .into_iter()
+        .map(|(user_id, order_id, order)| {
+            let mut json = order_to_json(order);
+            json.insert("user_id".to_string(), Value::String(user_id));
+            json.insert(
+                "order_id".to_string(),
+                Value::Number(serde_json::Number::from(order_id)),
+            );
+            Value::Object(json)
+        })
+        .collect();
+
+    let mut result: Map<String, Value> = Map::new();
+    result.insert("orders".to_string(), Value::Array(orders));
+    result.insert(
+        "next_cursor".to_string(),
+        match scan.next {
+            Some(order_id) => Value::String(order_id.to_string()),
+            None => Value::Null,
+        },
+    );
+
+    HttpResponse::Ok().json(result)
+}
+
+// Orders of all users as 'csv' or 'jsonl', filters are the same as of orders
+// of a user and 'limit' is the number of orders read at once, response is
+// streamed, so an error in the middle of it can only cut it short
+pub fn export_orders(
+    req: HttpRequest,
+    storage: web::Data<Arc<dyn OrderRepository>>,
+) -> HttpResponse {
+    let query_string = qstring::QString::from(req.query_string());
+    let format = match query_string.get("format") {
+        None => ExportFormat::Csv,
+        Some(format) => match ExportFormat::parse(format) {
+            Some(format) => format,
+            None => {
+                error!("{}:Invalid export format: {}", line!(), format);
+                return HttpResponse::BadRequest().body(format!("Invalid 'format': {}", format));
             }
+        },
+    };
+    let query = match parse_query(&query_string) {
+        Ok(query) => query,
+        Err(e) => {
+            error!("{}:Invalid query of export: {}", line!(), e);
+            return HttpResponse::BadRequest().body(e);
         }
-        Ok(result) => {
-            error!("{}:Redis returned invalid answer: {:?}", line!(), result);
-            HttpResponse::InternalServerError().finish()
-        }
+    };
+
+    let export = Export::new(storage.get_ref().clone(), export::search(query), format);
+    let chunks = export.map(|chunk| match chunk {
+        Ok(chunk) => Ok(web::Bytes::from(chunk)),
         Err(e) => {
-            error!("{}:Redis error: {}", line!(), e);
-            HttpResponse::InternalServerError().finish()
+            error!("{}:Couldn't export orders: {}", line!(), e);
+            Err(actix_web::error::ErrorInternalServerError(e.to_string()))
         }
-    }
+    });
+
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .streaming(futures::stream::iter_result(chunks))
 }
@@ -1,6 +1,7 @@
 use actix_web::{web, HttpResponse};
 
 use crate::api::*;
+use shutdown::readiness;
 
 pub fn config_app(cfg: &mut web::ServiceConfig) {
     cfg.service(
@@ -8,15 +9,30 @@ pub fn config_app(cfg: &mut web::ServiceConfig) {
             .service(
                 web::scope("/probe")
                     .service(web::resource("/liveness").route(web::get().to(|| HttpResponse::Ok())))
-                    .service(
-                        web::resource("/readiness").route(web::get().to(|| HttpResponse::Ok())),
-                    )
+                    .service(web::resource("/readiness").route(web::get().to(readiness)))
                     .service(web::resource("/startup").route(web::get().to(|| HttpResponse::Ok()))),
             )
+            .service(
+                web::scope("/admin")
+                    .service(web::resource("/transactions").route(web::get().to(get_transactions)))
+                    .service(web::resource("/promotions").route(web::get().to(get_promotions)))
+                    .service(web::resource("/orders/export").route(web::get().to(export_orders)))
+                    .service(web::resource("/orders/search").route(web::get().to(search_orders))),
+            )
             .service(
                 web::scope("/user/{user_id}")
                     .service(web::resource("/orders").route(web::get().to(get_orders)))
-                    .service(web::resource("/order/{order_id}").route(web::get().to(get_order))),
+                    .service(web::resource("/templates").route(web::get().to(get_templates)))
+                    .service(web::resource("/schedules").route(web::get().to(get_schedules)))
+                    .service(web::resource("/order/{order_id}").route(web::get().to(get_order)))
+                    .service(
+                        web::resource("/order/{order_id}/history")
+                            .route(web::get().to(get_order_history)),
+                    )
+                    .service(
+                        web::resource("/order/{order_id}/returns")
+                            .route(web::get().to(get_order_returns)),
+                    ),
             ),
     );
 }
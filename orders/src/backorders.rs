@@ -0,0 +1,94 @@
+use crate::events::{self, EventKind};
+use crate::outbox::Outbox;
+use crate::storage::{Change, Changes, OrderRepository};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+
+// With partial fulfilment warehouse reserves goods which are in stock and
+// the rest is backordered, otherwise order isn't created or updated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FulfilmentPolicy {
+    #[default]
+    AllOrNothing,
+    Partial,
+}
+
+impl FulfilmentPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FulfilmentPolicy::AllOrNothing => "all_or_nothing",
+            FulfilmentPolicy::Partial => "partial",
+        }
+    }
+
+    pub fn parse(policy: &str) -> Option<FulfilmentPolicy> {
+        match policy {
+            "all_or_nothing" => Some(FulfilmentPolicy::AllOrNothing),
+            "partial" => Some(FulfilmentPolicy::Partial),
+            _ => None,
+        }
+    }
+}
+
+// Reservation state of one line of an order
+pub fn line_state(count: u64, backordered: u64) -> &'static str {
+    if backordered == 0 {
+        "reserved"
+    } else if backordered < count {
+        "partially_reserved"
+    } else {
+        "backordered"
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BackorderFilled {
+    backorders: BTreeMap<u64, u64>,
+}
+
+impl BackorderFilled {
+    // Warehouse reserved goods after stock was replenished, backorders are
+    // what the order still waits for
+    pub fn apply(
+        self,
+        user_id: &str,
+        order_id: &str,
+        outbox: &Outbox,
+        storage: &dyn OrderRepository,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut order = match storage.order(user_id, order_id)? {
+            Some(order) => order,
+            None => {
+                return Err(Box::new(Error::new(
+                    ErrorKind::Other,
+                    format!(
+                        "line:{}: Order '{}' of user '{}' does not exist",
+                        line!(),
+                        order_id,
+                        user_id
+                    ),
+                )))
+            }
+        };
+
+        let mut changes = Changes::default();
+        events::record(
+            &mut changes,
+            user_id,
+            order_id,
+            EventKind::BackorderFilled,
+            outbox.origin(),
+            serde_json::to_string(&self.backorders)?,
+        );
+        order.backorders = self.backorders;
+        changes.push(Change::PutOrder {
+            user_id: user_id.to_string(),
+            order_id: order_id.to_string(),
+            order,
+        });
+        outbox.write(&mut changes)?;
+        storage.apply(changes)
+    }
+}
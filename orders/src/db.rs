@@ -1,43 +1,111 @@
-use r2d2_redis::{r2d2, redis, RedisConnectionManager};
+use crate::backorders::FulfilmentPolicy;
+use crate::delivery::Delivery;
+use crate::events::{self, EventKind, OrderEvent};
+use crate::outbox::{Destination, Outbox};
+use crate::pricing::Quote;
+use crate::promotions;
+use crate::saga::{self, Transition};
+use crate::status::{self, OrderStatus};
+use crate::storage::{now_ms, Change, Changes, Order, OrderRepository};
+use crate::transactions;
+use crate::TransactionOptions;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::io::{Error, ErrorKind};
-use std::ops::DerefMut;
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 struct CreateGood {
     id: u64,
     count: u64,
 }
 
-#[derive(Deserialize)]
+// Delivery details aren't sent to warehouse with goods
+#[derive(Serialize, Deserialize)]
 pub struct CreateOrder {
     goods: Vec<CreateGood>,
+    #[serde(default, skip_serializing)]
+    delivery: Option<Delivery>,
+    #[serde(default)]
+    fulfilment: FulfilmentPolicy,
 }
 
 impl CreateOrder {
+    // Orders are also created from lines of other orders and from templates
+    pub fn from_lines(
+        goods: &BTreeMap<u64, u64>,
+        delivery: Option<Delivery>,
+        fulfilment: FulfilmentPolicy,
+    ) -> Self {
+        CreateOrder {
+            goods: goods
+                .iter()
+                .map(|(id, count)| CreateGood {
+                    id: *id,
+                    count: *count,
+                })
+                .collect(),
+            delivery,
+            fulfilment,
+        }
+    }
+
     pub fn create(
         &self,
         user_id: &str,
-        conn: &mut r2d2::PooledConnection<RedisConnectionManager>,
+        transition: &Transition,
+        outbox: &mut Outbox,
+        storage: &dyn OrderRepository,
     ) -> Result<i64, Box<dyn std::error::Error>> {
-        let order_id = redis::cmd("INCR").arg("order_id").query(conn.deref_mut())?;
-        let redis_key = &format!("tx:user_id:{}:order_id:{}", user_id, order_id);
+        let mut changes = Changes::default();
+        let order_id = self.begin(&mut changes, user_id, transition, outbox, storage)?;
+        outbox.write(&mut changes)?;
+        storage.apply(changes)?;
 
-        let mut pipe = redis::pipe();
-        pipe.cmd("MULTI")
-            .cmd("HSET")
-            .arg(&[redis_key, "status", "new"]);
+        Ok(order_id)
+    }
 
-        for good in &self.goods {
-            pipe.cmd("HSET").arg(&[
-                redis_key,
-                &format!("good_id:{}", good.id),
-                &good.count.to_string(),
-            ]);
+    // Changes are applied by the caller, so it can store more with them
+    pub fn begin(
+        &self,
+        changes: &mut Changes,
+        user_id: &str,
+        transition: &Transition,
+        outbox: &mut Outbox,
+        storage: &dyn OrderRepository,
+    ) -> Result<i64, Box<dyn std::error::Error>> {
+        if let Some(delivery) = &self.delivery {
+            delivery.check()?;
         }
 
-        let _ = pipe.cmd("EXEC").query(conn.deref_mut())?;
+        let order_id = storage.next_order_id()?;
+        let order = Order {
+            status: OrderStatus::New,
+            goods: self
+                .goods
+                .iter()
+                .map(|good| (good.id, good.count))
+                .collect(),
+            created_at: now_ms(),
+            delivery: self.delivery.clone(),
+            fulfilment: self.fulfilment,
+            ..Order::default()
+        };
+
+        changes.push(Change::BeginPending {
+            user_id: user_id.to_string(),
+            order_id: order_id.to_string(),
+            order,
+        });
+        outbox.push(
+            Destination::Warehouse,
+            &[
+                ("user_id", user_id),
+                ("operation", "create"),
+                ("order_id", &order_id.to_string()),
+            ],
+            serde_json::to_string(self)?,
+        );
+        transition.write(changes, user_id, &order_id.to_string());
 
         Ok(order_id)
     }
@@ -48,259 +116,556 @@ struct UpdateGood {
     id: u64,
     #[serde(default)]
     count: i64,
+    #[serde(default)]
     operation: String,
 }
 
+// Patch changes only the listed lines, replace makes the listed lines
+// the whole order and drops the rest
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdateMode {
+    #[default]
+    Patch,
+    Replace,
+}
+
+// What is done with an update which leaves the order without goods
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyOrderPolicy {
+    #[default]
+    Reject,
+    Cancel,
+}
+
+// Either goods or delivery details can be changed, or both
 #[derive(Serialize, Deserialize)]
 pub struct UpdateOrder {
+    #[serde(default)]
     goods: Vec<UpdateGood>,
+    #[serde(default, skip_serializing)]
+    mode: UpdateMode,
+    #[serde(default, skip_serializing)]
+    delivery: Option<Delivery>,
+}
+
+// Copy of the order is made, changes are applied to it and
+// it replaces the order after warehouse commits them
+fn begin_pending(
+    user_id: &str,
+    order_id: &str,
+    storage: &dyn OrderRepository,
+) -> Result<Order, Box<dyn std::error::Error>> {
+    if storage.pending(user_id, order_id)?.is_some() {
+        return Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!(
+                "line:{}: Transaction of order '{}' of user '{}' already exists",
+                line!(),
+                order_id,
+                user_id
+            ),
+        )));
+    }
+
+    match storage.order(user_id, order_id)? {
+        Some(order) => Ok(order),
+        None => Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!(
+                "line:{}: Order '{}' of user '{}' does not exist",
+                line!(),
+                order_id,
+                user_id
+            ),
+        ))),
+    }
 }
 
-const EXEC_TX: &str = r#"
-    if redis.call('EXISTS', KEYS[2]) == 1 then
-        return { err = 'Transaction '..KEYS[2]..' already exists'}
-    end
-    local hash = redis.call('HGETALL', KEYS[1]);
-    if #hash == 0 then
-        return { err = 'The key '..KEYS[1]..' does not exist' }
-    end
-    return redis.call('HMSET', KEYS[2], unpack(hash))"#;
-
-// TODO: consider wrapping all operations with MULTI/EXEC to make operation atomic
-// right now if redis is down, then it will make some temporary object persist
-// TODO: order can be empty after update, consider fixing it
 impl UpdateOrder {
+    // Lines of the order after the update, repeated updates of one good
+    // are merged into one line with their counts summed up
+    fn lines(
+        &self,
+        current: &BTreeMap<u64, u64>,
+    ) -> Result<BTreeMap<u64, u64>, Box<dyn std::error::Error>> {
+        let mut updated = BTreeMap::new();
+        let mut deleted = BTreeSet::new();
+
+        for good in &self.goods {
+            match (self.mode, &good.operation[..]) {
+                (_, "update") | (UpdateMode::Replace, "") => {
+                    *updated.entry(good.id).or_insert(0) += good.count as u64;
+                }
+                (UpdateMode::Patch, "delete") => {
+                    deleted.insert(good.id);
+                }
+                _ => {
+                    return Err(Box::new(Error::new(
+                        ErrorKind::Other,
+                        format!(
+                            "line:{}: Unknown operation '{}' of good {}",
+                            line!(),
+                            good.operation,
+                            good.id
+                        ),
+                    )))
+                }
+            }
+        }
+
+        if let Some(good_id) = updated.keys().find(|good_id| deleted.contains(good_id)) {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Good {} can't be both updated and deleted",
+                    line!(),
+                    good_id
+                ),
+            )));
+        }
+
+        let mut lines = match self.mode {
+            UpdateMode::Patch => current.clone(),
+            UpdateMode::Replace => BTreeMap::new(),
+        };
+        for good_id in deleted {
+            lines.remove(&good_id);
+        }
+        lines.extend(updated);
+
+        Ok(lines)
+    }
+
+    // Would the update leave the order without goods, it is false
+    // when the update is going to fail anyway
+    pub fn empties(
+        &self,
+        user_id: &str,
+        order_id: &str,
+        version: Option<u64>,
+        storage: &dyn OrderRepository,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let order = match storage.order(user_id, order_id)? {
+            Some(order) => order,
+            None => return Ok(false),
+        };
+
+        if version.is_some_and(|version| version != order.version) {
+            return Ok(false);
+        }
+
+        Ok(self
+            .lines(&order.goods)
+            .map(|lines| lines.is_empty())
+            .unwrap_or(false))
+    }
+
+    // Version is passed if client wants to change only the order it has seen
     pub fn update(
-        &mut self,
+        &self,
         user_id: &str,
         order_id: &str,
-        conn: &mut r2d2::PooledConnection<RedisConnectionManager>,
+        version: Option<u64>,
+        options: &TransactionOptions,
+        outbox: &mut Outbox,
+        storage: &dyn OrderRepository,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let order_key = &format!("user_id:{}:order_id:{}", user_id, order_id);
-        let tx_key = &format!("tx:{}", order_key);
-
-        let status: String = redis::cmd("HGET")
-            .arg(&[order_key, "status"])
-            .query(conn.deref_mut())?;
+        let order = begin_pending(user_id, order_id, storage)?;
 
-        if status == "payed" {
+        if !order.status.is_modifiable() {
             return Err(Box::new(Error::new(
                 ErrorKind::Other,
                 format!(
-                    "line:{}: Order '{}' can't be updated, cause it was already payed",
+                    "line:{}: Order '{}' can't be updated in status '{}'",
                     line!(),
-                    order_id
+                    order_id,
+                    order.status.as_str()
                 ),
             )));
         }
 
-        let status: String = redis::cmd("EVAL")
-            .arg(&[EXEC_TX, "2", order_key, tx_key])
-            .query(conn.deref_mut())?;
-
-        if status == "OK" {
-            let mut pipe = redis::pipe();
-
-            for good in &mut self.goods {
-                let good_id = &format!("good_id:{}", good.id);
-                let redis_count = redis::cmd("HGET")
-                    .arg(&[order_key, good_id])
-                    .query(conn.deref_mut())?;
-
-                let redis_count = match redis_count {
-                    redis::Value::Nil => 0,
-                    redis::Value::Data(data) => std::str::from_utf8(&data)?.parse::<i64>()?,
-                    value => {
-                        return Err(Box::new(Error::new(
-                            ErrorKind::Other,
-                            format!("Invalid value: {:?}", value),
-                        )))
-                    }
-                };
-
-                match &good.operation[..] {
-                    "update" => {
-                        pipe.cmd("HSET")
-                            .arg(&[tx_key, good_id, &good.count.to_string()]);
-                    }
-                    "delete" => {
-                        pipe.cmd("HDEL").arg(&[tx_key, good_id]);
-                    }
-                    _ => {
-                        // consider making one place to execute this code
-                        // right now it looks like crutch
-                        let _ = redis::cmd("DEL").arg(tx_key).query(conn.deref_mut())?;
-                        return Err(Box::new(Error::new(
-                            ErrorKind::Other,
-                            format!("line:{}: Unknown operation: {}", line!(), good.operation),
-                        )));
-                    }
-                }
-
-                good.count = redis_count - good.count;
+        if let Some(version) = version {
+            if version != order.version {
+                return Err(Box::new(Error::new(
+                    ErrorKind::Other,
+                    format!(
+                        "line:{}: Order '{}' was changed, its version is {}, not {}",
+                        line!(),
+                        order_id,
+                        order.version,
+                        version
+                    ),
+                )));
             }
+        }
+
+        let mut pending = order.clone();
+
+        if let Some(delivery) = &self.delivery {
+            delivery.check()?;
+            pending.delivery = Some(delivery.clone());
+        }
+
+        let lines = self.lines(&order.goods)?;
 
-            let _ = pipe.query(conn.deref_mut())?;
-        } else {
-            let _ = redis::cmd("DEL").arg(tx_key).query(conn.deref_mut())?;
+        if lines.is_empty() {
             return Err(Box::new(Error::new(
                 ErrorKind::Other,
                 format!(
-                    "line:{}: Redis returned invalid status: {}",
+                    "line:{}: Order '{}' can't be left without goods, it should be cancelled",
                     line!(),
-                    status
+                    order_id
                 ),
             )));
         }
 
-        Ok(())
+        // Warehouse gets one line per changed good with the count
+        // it should take back, deleted goods are returned whole
+        let changed = UpdateOrder {
+            goods: changed_lines(&order.goods, &lines),
+            mode: UpdateMode::Patch,
+            delivery: None,
+        };
+        pending.goods = lines;
+
+        let mut changes = Changes::default();
+        changes.push(Change::BeginPending {
+            user_id: user_id.to_string(),
+            order_id: order_id.to_string(),
+            order: pending,
+        });
+        let message = outbox.push(
+            Destination::Warehouse,
+            &[
+                ("user_id", user_id),
+                ("operation", "update"),
+                ("order_id", order_id),
+                ("currency", &order.currency),
+                ("fulfilment", order.fulfilment.as_str()),
+            ],
+            serde_json::to_string(&changed)?,
+        );
+        transactions::begin(&mut changes, user_id, order_id, "update", message, options)?;
+        outbox.write(&mut changes)?;
+        storage.apply(changes)
     }
 }
 
-// TODO: if order is payed and was deleted, then billing must be rollout
-// right now there is no logic with billing, so no rollout
+fn changed_lines(old: &BTreeMap<u64, u64>, new: &BTreeMap<u64, u64>) -> Vec<UpdateGood> {
+    let good_ids: BTreeSet<u64> = old.keys().chain(new.keys()).cloned().collect();
+
+    good_ids
+        .into_iter()
+        .filter_map(|id| {
+            let before = old.get(&id).cloned().unwrap_or(0) as i64;
+
+            match new.get(&id) {
+                None => Some(UpdateGood {
+                    id,
+                    count: before,
+                    operation: "delete".to_string(),
+                }),
+                Some(&after) if after as i64 != before => Some(UpdateGood {
+                    id,
+                    count: before - after as i64,
+                    operation: "update".to_string(),
+                }),
+                Some(_) => None,
+            }
+        })
+        .collect()
+}
+
+// Hard deletion is an admin action, goods are returned to warehouse,
+// but payment is not refunded, orders are cancelled for that
 pub fn delete_order(
     user_id: &str,
     order_id: &str,
-    conn: &mut r2d2::PooledConnection<RedisConnectionManager>,
-) -> Result<HashMap<String, u64>, Box<dyn std::error::Error>> {
-    let order_key = &format!("user_id:{}:order_id:{}", user_id, order_id);
-    let tx_key = &format!("tx:{}", order_key);
-
-    let status: String = redis::cmd("EVAL")
-        .arg(&[EXEC_TX, "2", order_key, tx_key])
-        .query(conn.deref_mut())?;
-
-    if status == "OK" {
-        let order: Vec<redis::Value> = redis::cmd("HGETALL")
-            .arg(&[tx_key])
-            .query(conn.deref_mut())?;
-
-        let mut map = HashMap::new();
-        let mut i = 0;
-
-        while i < order.len() {
-            if let redis::Value::Data(data) = &order[i] {
-                let key = std::str::from_utf8(&data)?;
-
-                if key.contains("good_id") {
-                    if let redis::Value::Data(data) = &order[i + 1] {
-                        let value = std::str::from_utf8(&data)?;
-
-                        map.insert(key.to_string(), value.parse::<u64>()?);
-                        i += 2;
-                    }
-                } else {
-                    i += 1;
-                }
-            }
+    options: &TransactionOptions,
+    outbox: &mut Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let order = begin_pending(user_id, order_id, storage)?;
+    let goods: HashMap<String, u64> = order
+        .goods
+        .iter()
+        .map(|(good_id, count)| (format!("good_id:{}", good_id), *count))
+        .collect();
+
+    let mut changes = Changes::default();
+    changes.push(Change::BeginPending {
+        user_id: user_id.to_string(),
+        order_id: order_id.to_string(),
+        order,
+    });
+    let message = outbox.push(
+        Destination::Warehouse,
+        &[
+            ("user_id", user_id),
+            ("operation", "delete"),
+            ("order_id", order_id),
+        ],
+        serde_json::to_string(&goods)?,
+    );
+    transactions::begin(&mut changes, user_id, order_id, "delete", message, options)?;
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
+
+// Warehouse is asked to ship goods of the paid order
+pub fn make_billing(
+    user_id: &str,
+    order_id: &str,
+    transition: &Transition,
+    outbox: &mut Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut order = match storage.order(user_id, order_id)? {
+        Some(order) => order,
+        None => {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Billing can't be made, there is no order with id: {}",
+                    line!(),
+                    order_id
+                ),
+            )))
         }
+    };
 
-        Ok(map)
-    } else {
-        Err(Box::new(Error::new(
+    if storage.pending(user_id, order_id)?.is_some() {
+        return Err(Box::new(Error::new(
             ErrorKind::Other,
             format!(
-                "line:{}: Redis returned invalid status: {}",
+                "line:{}: Billing can't be made, there is unfinished transaction of order: {}",
                 line!(),
-                status
+                order_id
             ),
-        )))
+        )));
     }
+
+    let mut changes = Changes::default();
+    status::change(
+        &mut changes,
+        user_id,
+        order_id,
+        &mut order,
+        OrderStatus::Paid,
+        outbox.origin(),
+    )?;
+    outbox.push(
+        Destination::Warehouse,
+        &[
+            ("user_id", user_id),
+            ("operation", "ship"),
+            ("order_id", order_id),
+        ],
+        serde_json::json!({ "delivery": order.delivery }).to_string(),
+    );
+    changes.push(Change::PutOrder {
+        user_id: user_id.to_string(),
+        order_id: order_id.to_string(),
+        order,
+    });
+    transition.write(&mut changes, user_id, order_id);
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
 }
 
-pub fn make_billing(
+pub fn rollout_tx(
     user_id: &str,
     order_id: &str,
-    conn: &mut r2d2::PooledConnection<RedisConnectionManager>,
+    transition: Option<&Transition>,
+    outbox: &Outbox,
+    storage: &dyn OrderRepository,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let order_key = &format!("user_id:{}:order_id:{}", user_id, order_id);
-    let tx_key = &format!("tx:{}", order_key);
+    let mut changes = Changes::default();
 
-    let order_exists: i32 = redis::cmd("EXISTS")
-        .arg(order_key)
-        .query(conn.deref_mut())?;
-
-    if order_exists == 1 {
-        let tx_exists: i32 = redis::cmd("EXISTS").arg(tx_key).query(conn.deref_mut())?;
+    if let Some(transition) = transition {
+        transition.write(&mut changes, user_id, order_id);
+    }
 
-        if tx_exists == 1 {
-            Err(Box::new(Error::new(
-                ErrorKind::Other,
-                format!(
-                    "line:{}: Billing can't be made, there is unfinished transaction: {}",
-                    line!(),
-                    tx_key
-                ),
-            )))
-        } else {
-            let status: String = redis::cmd("HGET")
-                .arg(&[order_key, "status"])
-                .query(conn.deref_mut())?;
-
-            if status != "payed" {
-                let _ = redis::cmd("HSET")
-                    .arg(&[order_key, "status", "payed"])
-                    .query(conn.deref_mut())?;
-            } else {
-                return Err(Box::new(Error::new(
-                    ErrorKind::Other,
-                    format!(
-                        "line:{}: Order with id: {} is already payed",
-                        line!(),
-                        order_id
-                    ),
-                )));
-            }
+    finish_tx(changes, user_id, order_id, outbox, storage)
+}
 
-            Ok(())
-        }
-    } else {
-        Err(Box::new(Error::new(
+// Answer can come after transaction was aborted by timeout
+fn pending(
+    user_id: &str,
+    order_id: &str,
+    storage: &dyn OrderRepository,
+) -> Result<Order, Box<dyn std::error::Error>> {
+    match storage.pending(user_id, order_id)? {
+        Some(pending) => Ok(pending),
+        None => Err(Box::new(Error::new(
             ErrorKind::Other,
             format!(
-                "line:{}: Billing can't be made, there is no order with id: {}",
+                "line:{}: Transaction of order '{}' doesn't exist, it was aborted or already finished",
                 line!(),
                 order_id
             ),
-        )))
+        ))),
     }
 }
 
-pub fn rollout_tx(
+fn finish_tx(
+    mut changes: Changes,
+    user_id: &str,
+    order_id: &str,
+    outbox: &Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    changes.push(Change::ClearPending {
+        user_id: user_id.to_string(),
+        order_id: order_id.to_string(),
+    });
+    transactions::finish(&mut changes, user_id, order_id);
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
+
+pub fn commit_delete_tx(
     user_id: &str,
     order_id: &str,
-    conn: &mut r2d2::PooledConnection<RedisConnectionManager>,
+    outbox: &Outbox,
+    storage: &dyn OrderRepository,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let tx_key = &format!("tx:user_id:{}:order_id:{}", user_id, order_id);
-    let _ = redis::cmd("DEL").arg(tx_key).query(conn.deref_mut())?;
-    Ok(())
+    let pending = pending(user_id, order_id, storage)?;
+
+    let mut changes = Changes::default();
+    promotions::release(&mut changes, user_id, order_id, &pending);
+    changes.push(Change::DeleteOrder {
+        user_id: user_id.to_string(),
+        order_id: order_id.to_string(),
+    });
+    saga::remove(&mut changes, user_id, order_id);
+    finish_tx(changes, user_id, order_id, outbox, storage)
 }
 
 pub fn commit_tx(
     user_id: &str,
     order_id: &str,
-    conn: &mut r2d2::PooledConnection<RedisConnectionManager>,
-    delete_order: bool,
+    status: Option<OrderStatus>,
+    quote: Option<&Quote>,
+    transition: Option<&Transition>,
+    outbox: &Outbox,
+    storage: &dyn OrderRepository,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let order_key = &format!("user_id:{}:order_id:{}", user_id, order_id);
-    let tx_key = &format!("tx:{}", order_key);
-
-    if delete_order {
-        let _ = redis::cmd("DEL").arg(order_key).query(conn.deref_mut())?;
-    } else {
-        let mut pipe = redis::pipe();
-        let _ = pipe
-            .cmd("MULTI")
-            .cmd("DEL")
-            .arg(order_key)
-            .cmd("EVAL")
-            .arg(&[EXEC_TX, "2", tx_key, order_key])
-            .cmd("EXEC")
-            .query(conn.deref_mut())?;
+    let mut pending = pending(user_id, order_id, storage)?;
+    let mut changes = Changes::default();
+
+    let origin = outbox.origin();
+
+    if let Some(quote) = quote {
+        quote.apply(&mut pending);
+    }
+
+    // Pending copy replaces the order as it is now, so it is written
+    // over the version read here
+    let order = storage.order(user_id, order_id)?;
+    pending.version = order.as_ref().map(|order| order.version).unwrap_or(0);
+
+    match &order {
+        // New order is stored only after its goods are reserved,
+        // so its creation is recorded when transaction is committed
+        None => changes.push(Change::RecordEvent {
+            user_id: user_id.to_string(),
+            order_id: order_id.to_string(),
+            event: OrderEvent::new(
+                EventKind::Created,
+                pending.created_at,
+                origin,
+                serde_json::to_string(&pending.goods)?,
+            ),
+        }),
+        Some(order) if order.goods != pending.goods => events::record(
+            &mut changes,
+            user_id,
+            order_id,
+            EventKind::LinesChanged,
+            origin,
+            serde_json::to_string(&pending.goods)?,
+        ),
+        Some(_) => {}
+    }
+
+    if order.is_some_and(|order| order.delivery != pending.delivery) {
+        events::record(
+            &mut changes,
+            user_id,
+            order_id,
+            EventKind::DeliveryChanged,
+            origin,
+            serde_json::to_string(&pending.delivery)?,
+        );
+    }
+
+    if let Some(next) = status {
+        status::change(&mut changes, user_id, order_id, &mut pending, next, origin)?;
     }
 
-    let _ = redis::cmd("DEL").arg(tx_key).query(conn.deref_mut())?;
+    changes.push(Change::PutOrder {
+        user_id: user_id.to_string(),
+        order_id: order_id.to_string(),
+        order: pending,
+    });
 
-    Ok(())
+    if let Some(transition) = transition {
+        transition.write(&mut changes, user_id, order_id);
+    }
+
+    finish_tx(changes, user_id, order_id, outbox, storage)
+}
+
+// Statuses after payment are reported by other services, e.g. delivery
+pub fn set_status(
+    user_id: &str,
+    order_id: &str,
+    next: &str,
+    outbox: &Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let next = match OrderStatus::parse(next) {
+        Some(next) if next.is_external() => next,
+        _ => {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!("line:{}: Status '{}' can't be set directly", line!(), next),
+            )))
+        }
+    };
+
+    let mut order = match storage.order(user_id, order_id)? {
+        Some(order) => order,
+        None => {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Order '{}' of user '{}' does not exist",
+                    line!(),
+                    order_id,
+                    user_id
+                ),
+            )))
+        }
+    };
+
+    let mut changes = Changes::default();
+    status::change(
+        &mut changes,
+        user_id,
+        order_id,
+        &mut order,
+        next,
+        outbox.origin(),
+    )?;
+    changes.push(Change::PutOrder {
+        user_id: user_id.to_string(),
+        order_id: order_id.to_string(),
+        order,
+    });
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
 }
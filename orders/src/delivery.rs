@@ -0,0 +1,71 @@
+use crate::storage::now_ms;
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Address {
+    pub recipient: String,
+    pub line1: String,
+    #[serde(default)]
+    pub line2: String,
+    pub city: String,
+    #[serde(default)]
+    pub region: String,
+    pub postal_code: String,
+    // ISO 3166-1 alpha-2 code
+    pub country: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryMethod {
+    Standard,
+    Express,
+    Pickup,
+}
+
+// Milliseconds since epoch
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeliveryWindow {
+    pub from: u64,
+    pub to: u64,
+}
+
+// Shape of the details is checked by json schema, address can be
+// omitted only if the order is picked up
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Delivery {
+    pub method: DeliveryMethod,
+    #[serde(default)]
+    pub address: Option<Address>,
+    pub phone: String,
+    #[serde(default)]
+    pub window: Option<DeliveryWindow>,
+}
+
+impl Delivery {
+    pub fn check(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.method != DeliveryMethod::Pickup && self.address.is_none() {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!("line:{}: Shipping address wasn't passed", line!()),
+            )));
+        }
+
+        if let Some(window) = self.window {
+            if window.from >= window.to || window.to <= now_ms() {
+                return Err(Box::new(Error::new(
+                    ErrorKind::Other,
+                    format!(
+                        "line:{}: Delivery window {}..{} is empty or in the past",
+                        line!(),
+                        window.from,
+                        window.to
+                    ),
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use crate::returns::ReturnStatus;
+use crate::status::OrderStatus;
+use crate::storage::{now_ms, Change, Changes};
+use std::collections::HashMap;
+
+// Who caused changes made while processing one message, gateway puts
+// user into 'actor' header and id of the request into 'correlation_id',
+// other services pass correlation id back in their answers
+#[derive(Clone)]
+pub struct Origin {
+    pub actor: String,
+    pub correlation_id: String,
+}
+
+impl Origin {
+    pub fn new(actor: &str, correlation_id: &str) -> Self {
+        Origin {
+            actor: actor.to_string(),
+            correlation_id: correlation_id.to_string(),
+        }
+    }
+
+    pub fn from_metadata(metadata: &HashMap<&str, &str>, message_id: &str) -> Self {
+        Origin::new(
+            metadata.get("actor").cloned().unwrap_or("system"),
+            metadata
+                .get("correlation_id")
+                .cloned()
+                .unwrap_or(message_id),
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    Created,
+    LinesChanged,
+    DeliveryChanged,
+    BackorderFilled,
+    PromotionApplied,
+    PromotionRemoved,
+    Status(OrderStatus),
+    Return(ReturnStatus),
+}
+
+impl EventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Created => "created",
+            EventKind::LinesChanged => "lines_changed",
+            EventKind::DeliveryChanged => "delivery_changed",
+            EventKind::BackorderFilled => "backorder_filled",
+            EventKind::PromotionApplied => "promotion_applied",
+            EventKind::PromotionRemoved => "promotion_removed",
+            EventKind::Status(status) => status.as_str(),
+            EventKind::Return(status) => status.event(),
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<EventKind> {
+        match kind {
+            "created" => Some(EventKind::Created),
+            "lines_changed" => Some(EventKind::LinesChanged),
+            "delivery_changed" => Some(EventKind::DeliveryChanged),
+            "backorder_filled" => Some(EventKind::BackorderFilled),
+            "promotion_applied" => Some(EventKind::PromotionApplied),
+            "promotion_removed" => Some(EventKind::PromotionRemoved),
+            _ => match kind.strip_prefix("return_") {
+                Some(status) => ReturnStatus::parse(status).map(EventKind::Return),
+                None => OrderStatus::parse(kind).map(EventKind::Status),
+            },
+        }
+    }
+}
+
+// Events of an order are only appended, details are kept as json
+#[derive(Clone)]
+pub struct OrderEvent {
+    pub kind: EventKind,
+    pub at: u64,
+    pub actor: String,
+    pub correlation_id: String,
+    pub details: String,
+}
+
+impl OrderEvent {
+    pub fn new(kind: EventKind, at: u64, origin: &Origin, details: String) -> Self {
+        OrderEvent {
+            kind,
+            at,
+            actor: origin.actor.clone(),
+            correlation_id: origin.correlation_id.clone(),
+            details,
+        }
+    }
+
+    // Creation of an order is its first status
+    pub fn status(&self) -> Option<OrderStatus> {
+        match self.kind {
+            EventKind::Created => Some(OrderStatus::New),
+            EventKind::Status(status) => Some(status),
+            _ => None,
+        }
+    }
+}
+
+pub fn record(
+    changes: &mut Changes,
+    user_id: &str,
+    order_id: &str,
+    kind: EventKind,
+    origin: &Origin,
+    details: String,
+) {
+    changes.push(Change::RecordEvent {
+        user_id: user_id.to_string(),
+        order_id: order_id.to_string(),
+        event: OrderEvent::new(kind, now_ms(), origin, details),
+    });
+}
@@ -0,0 +1,222 @@
+use crate::api::{order_to_json, status_history_to_json};
+use crate::events::OrderEvent;
+use crate::pricing;
+use crate::status::OrderStatus;
+use crate::storage::{Order, OrderQuery, OrderRepository, OrderSearch, SortField};
+use serde_json::value::Value;
+use std::io::Write;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Jsonl,
+}
+
+impl ExportFormat {
+    pub fn parse(format: &str) -> Option<ExportFormat> {
+        match format {
+            "csv" => Some(ExportFormat::Csv),
+            "jsonl" => Some(ExportFormat::Jsonl),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Jsonl => "application/x-ndjson",
+        }
+    }
+}
+
+// Every line of an order is a row, columns of the order are repeated in each
+// of them, order without goods has one row with empty line columns
+const CSV_HEADER: &str = "user_id,order_id,status,created_at,updated_at,currency,subtotal,\
+discount,tax,total,good_id,count,price,line_total,backordered,status_history\n";
+
+// Filters of the export, limit is the number of orders read at once
+pub fn query(
+    status: Option<OrderStatus>,
+    created_from: Option<u64>,
+    created_to: Option<u64>,
+    batch_size: usize,
+) -> OrderSearch {
+    search(OrderQuery {
+        status,
+        created_from,
+        created_to,
+        sort: SortField::OrderId,
+        descending: false,
+        after: None,
+        limit: batch_size,
+    })
+}
+
+// Orders of all users which fit filters of the query
+pub fn search(query: OrderQuery) -> OrderSearch {
+    OrderSearch {
+        order_id: None,
+        user_id: None,
+        good_id: None,
+        query,
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Statuses with time of change, like 'new:1571000000000|reserved:1571000000100'
+fn csv_status_history(events: &[OrderEvent]) -> String {
+    events
+        .iter()
+        .filter_map(|event| Some(format!("{}:{}", event.status()?.as_str(), event.at)))
+        .collect::<Vec<String>>()
+        .join("|")
+}
+
+fn csv_rows(
+    chunk: &mut String,
+    user_id: &str,
+    order_id: u64,
+    order: &Order,
+    events: &[OrderEvent],
+) {
+    let totals = pricing::totals(order);
+    let columns = format!(
+        "{},{},{},{},{},{},{},{},{},{}",
+        csv_field(user_id),
+        order_id,
+        order.status.as_str(),
+        order.created_at,
+        order.updated_at,
+        csv_field(&order.currency),
+        totals.subtotal,
+        totals.discount,
+        totals.tax,
+        totals.total
+    );
+    let history = csv_status_history(events);
+
+    if order.goods.is_empty() {
+        chunk.push_str(&format!("{},,,,,,{}\n", columns, history));
+    }
+
+    for (good_id, count) in &order.goods {
+        chunk.push_str(&format!(
+            "{},{},{},{},{},{},{}\n",
+            columns,
+            good_id,
+            count,
+            order
+                .prices
+                .get(good_id)
+                .map(|price| price.to_string())
+                .unwrap_or_default(),
+            totals.lines[good_id],
+            order.backorders.get(good_id).cloned().unwrap_or(0),
+            history
+        ));
+    }
+}
+
+fn jsonl_row(
+    chunk: &mut String,
+    user_id: &str,
+    order_id: u64,
+    order: Order,
+    events: &[OrderEvent],
+) {
+    let mut json = order_to_json(order);
+    json.insert("user_id".to_string(), Value::String(user_id.to_string()));
+    json.insert(
+        "order_id".to_string(),
+        Value::Number(serde_json::Number::from(order_id)),
+    );
+    json.insert("status_history".to_string(), status_history_to_json(events));
+    chunk.push_str(&Value::Object(json).to_string());
+    chunk.push('\n');
+}
+
+// Orders of all users are read batch by batch and every batch becomes
+// one chunk of the output, so the whole export is never kept in memory
+pub struct Export {
+    storage: Arc<dyn OrderRepository>,
+    search: OrderSearch,
+    format: ExportFormat,
+    cursor: Option<u64>,
+    header: bool,
+}
+
+impl Export {
+    pub fn new(
+        storage: Arc<dyn OrderRepository>,
+        search: OrderSearch,
+        format: ExportFormat,
+    ) -> Self {
+        Export {
+            storage,
+            search,
+            format,
+            cursor: Some(0),
+            header: format == ExportFormat::Csv,
+        }
+    }
+
+    fn batch(&mut self, cursor: u64) -> Result<String, Box<dyn std::error::Error>> {
+        let scan = self.storage.search_orders(&self.search, cursor)?;
+        let mut chunk = String::new();
+
+        if self.header {
+            chunk.push_str(CSV_HEADER);
+            self.header = false;
+        }
+
+        for (user_id, order_id, order) in scan.orders {
+            let events = self.storage.events(&user_id, &order_id.to_string())?;
+
+            match self.format {
+                ExportFormat::Csv => csv_rows(&mut chunk, &user_id, order_id, &order, &events),
+                ExportFormat::Jsonl => jsonl_row(&mut chunk, &user_id, order_id, order, &events),
+            }
+        }
+
+        self.cursor = scan.next;
+        Ok(chunk)
+    }
+}
+
+impl Iterator for Export {
+    type Item = Result<String, Box<dyn std::error::Error>>;
+
+    // Batches without matching orders are skipped, export stops on first error
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let cursor = self.cursor?;
+
+            match self.batch(cursor) {
+                Ok(chunk) if chunk.is_empty() => continue,
+                Ok(chunk) => return Some(Ok(chunk)),
+                Err(e) => {
+                    self.cursor = None;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+// Export from command line, it goes to the output as it is read
+pub fn write(export: Export, out: &mut dyn Write) -> Result<(), Box<dyn std::error::Error>> {
+    for chunk in export {
+        out.write_all(chunk?.as_bytes())?;
+    }
+
+    out.flush()?;
+    Ok(())
+}
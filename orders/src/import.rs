@@ -0,0 +1,558 @@
+use crate::db::CreateOrder;
+use crate::export::ExportFormat;
+use crate::outbox::{Destination, Outbox};
+use crate::storage::{Changes, OrderRepository};
+use crate::validation_schema::VALIDATION_SCHEMA_CREATE;
+use serde_json::json;
+use serde_json::value::Value;
+use std::io::{BufRead, Write};
+use std::time::Duration;
+use valico::json_schema::{schema, Scope};
+
+pub struct ImportOptions {
+    // Name of the import, rows of imports with the same name are made once
+    pub name: String,
+    pub format: ExportFormat,
+    // Orders sent per second, so warehouse isn't flooded with reservations
+    pub rate_per_sec: u64,
+    // Rows before it are skipped, used to go on after an interrupted import
+    pub from_row: usize,
+    pub processed_ttl_secs: usize,
+}
+
+// Order read from the file, row is the line its first row is on
+struct ImportRow {
+    row: usize,
+    user_id: String,
+    order: Result<Value, String>,
+}
+
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.trim_end_matches('\r').chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+
+    fields.push(field);
+    fields
+}
+
+// Line is an order to create with 'user_id' of its owner
+fn jsonl_row(row: usize, line: &str) -> ImportRow {
+    let mut order: Value = match serde_json::from_str(line) {
+        Ok(order) => order,
+        Err(e) => {
+            return ImportRow {
+                row,
+                user_id: String::new(),
+                order: Err(format!("Invalid JSON: {}", e)),
+            }
+        }
+    };
+
+    let user_id = match order
+        .as_object_mut()
+        .and_then(|order| order.remove("user_id"))
+    {
+        Some(Value::String(user_id)) => user_id,
+        Some(Value::Number(user_id)) => user_id.to_string(),
+        _ => String::new(),
+    };
+
+    ImportRow {
+        row,
+        user_id,
+        order: Ok(order),
+    }
+}
+
+// Columns are found by header, 'user_id', 'order_id', 'good_id' and 'count'
+// are required, 'fulfilment' is optional, so export of orders can be imported,
+// rows of one order go one after another and have the same 'order_id'
+struct CsvOrders {
+    columns: Vec<String>,
+    current: Option<(String, ImportRow)>,
+}
+
+impl CsvOrders {
+    fn column<'a>(&self, fields: &'a [String], name: &str) -> Result<&'a str, String> {
+        match self.columns.iter().position(|column| column == name) {
+            Some(index) => match fields.get(index) {
+                Some(field) => Ok(field),
+                None => Err(format!("Column '{}' is missing", name)),
+            },
+            None => Err(format!("There is no column '{}' in header", name)),
+        }
+    }
+
+    fn line(&self, fields: &[String]) -> Result<Value, String> {
+        let good_id: u64 = self
+            .column(fields, "good_id")?
+            .parse()
+            .map_err(|_| "Invalid 'good_id'".to_string())?;
+        let count: u64 = self
+            .column(fields, "count")?
+            .parse()
+            .map_err(|_| "Invalid 'count'".to_string())?;
+        Ok(json!({ "id": good_id, "count": count }))
+    }
+
+    fn start(&self, row: usize, user_id: &str, fields: &[String]) -> ImportRow {
+        let mut order = json!({ "goods": [] });
+
+        if let Ok(fulfilment) = self.column(fields, "fulfilment") {
+            if !fulfilment.is_empty() {
+                order["fulfilment"] = Value::String(fulfilment.to_string());
+            }
+        }
+
+        ImportRow {
+            row,
+            user_id: user_id.to_string(),
+            order: Ok(order),
+        }
+    }
+
+    // Returns the order which is complete before this row
+    fn push(&mut self, row: usize, line: &str) -> Option<ImportRow> {
+        let fields = csv_fields(line);
+        let (user_id, order_id) = match (
+            self.column(&fields, "user_id"),
+            self.column(&fields, "order_id"),
+        ) {
+            (Ok(user_id), Ok(order_id)) => (user_id, order_id),
+            (Err(e), _) | (_, Err(e)) => {
+                return Some(ImportRow {
+                    row,
+                    user_id: String::new(),
+                    order: Err(e),
+                })
+            }
+        };
+        let key = format!("{}:{}", user_id, order_id);
+
+        let done = match &self.current {
+            Some((current, _)) if *current == key => None,
+            _ => {
+                let order = self.start(row, user_id, &fields);
+                self.current.replace((key, order)).map(|(_, order)| order)
+            }
+        };
+
+        let line = self.line(&fields);
+
+        if let Some((_, current)) = &mut self.current {
+            match (&mut current.order, line) {
+                (Ok(order), Ok(line)) => {
+                    if let Some(goods) = order["goods"].as_array_mut() {
+                        goods.push(line);
+                    }
+                }
+                (order, Err(e)) => *order = Err(format!("row {}: {}", row, e)),
+                (Err(_), Ok(_)) => {}
+            }
+        }
+
+        done
+    }
+}
+
+// Errors of the schema, like '/goods/0/count Minimum condition is not met'
+fn validate(order: &Value, validator: &schema::ScopedSchema) -> Result<(), String> {
+    let state = validator.validate(order);
+
+    if state.is_valid() {
+        Ok(())
+    } else {
+        Err(state
+            .errors
+            .iter()
+            .map(|e| {
+                format!("{} {}", e.get_path(), e.get_title())
+                    .trim()
+                    .to_string()
+            })
+            .collect::<Vec<String>>()
+            .join(", "))
+    }
+}
+
+// Valid order is sent to orders topic the same way as orders of schedules,
+// so it is created and reserved by the running service, id of the message
+// is made of import name and row, so the row is made once even if the
+// import is run again
+fn send(
+    options: &ImportOptions,
+    import: &ImportRow,
+    order: &Value,
+    storage: &dyn OrderRepository,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let message_id = format!("import:{}:row:{}", options.name, import.row);
+
+    if storage.is_processed(&message_id)? {
+        return Ok(false);
+    }
+
+    let mut outbox = Outbox::new(&message_id, options.processed_ttl_secs);
+    outbox.push(
+        Destination::Orders,
+        &[
+            ("user_id", &import.user_id),
+            ("operation", "create"),
+            ("actor", "import"),
+        ],
+        order.to_string(),
+    );
+
+    let mut changes = Changes::default();
+    outbox.write(&mut changes)?;
+    storage.apply(changes)?;
+    Ok(true)
+}
+
+#[derive(Default)]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub skipped: usize,
+    pub rejected: usize,
+    pub last_row: usize,
+}
+
+// Every rejected order is reported as a line of JSON with its row and
+// the reason, infrastructure errors stop the import, so it can be
+// continued from the last reported row
+fn import_row(
+    options: &ImportOptions,
+    import: ImportRow,
+    validator: &schema::ScopedSchema,
+    report: &mut dyn Write,
+    summary: &mut ImportSummary,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    summary.last_row = import.row;
+
+    let checked = import.order.clone().and_then(|order| {
+        if import.user_id.is_empty() {
+            return Err("'user_id' is missing".to_string());
+        }
+
+        validate(&order, validator)?;
+        serde_json::value::from_value::<CreateOrder>(order.clone()).map_err(|e| e.to_string())?;
+        Ok(order)
+    });
+
+    match checked {
+        Ok(order) => {
+            if send(options, &import, &order, storage)? {
+                summary.imported += 1;
+                std::thread::sleep(Duration::from_millis(1000 / options.rate_per_sec.max(1)));
+            } else {
+                summary.skipped += 1;
+            }
+        }
+        Err(e) => {
+            summary.rejected += 1;
+            writeln!(
+                report,
+                "{}",
+                json!({ "row": import.row, "user_id": import.user_id, "error": e })
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn import(
+    options: &ImportOptions,
+    input: &mut dyn BufRead,
+    report: &mut dyn Write,
+    storage: &dyn OrderRepository,
+) -> Result<ImportSummary, Box<dyn std::error::Error>> {
+    let mut scope = Scope::new();
+    let validator = scope
+        .compile_and_return(VALIDATION_SCHEMA_CREATE.clone(), true)
+        .unwrap();
+    let mut summary = ImportSummary::default();
+    let mut csv: Option<CsvOrders> = None;
+    let mut record: Option<(usize, String)> = None;
+
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        // Quoted field of CSV can go on over several lines, they make one
+        // record which is on the line it starts on
+        let (row, line) = match record.take() {
+            Some((row, record)) => (row, format!("{}\n{}", record.trim_end_matches('\r'), line)),
+            None => (index + 1, line),
+        };
+
+        if options.format == ExportFormat::Csv && line.matches('"').count() % 2 == 1 {
+            record = Some((row, line));
+            continue;
+        }
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let import = match (options.format, &mut csv) {
+            (ExportFormat::Jsonl, _) => Some(jsonl_row(row, &line)),
+            (ExportFormat::Csv, None) => {
+                csv = Some(CsvOrders {
+                    columns: csv_fields(&line),
+                    current: None,
+                });
+                None
+            }
+            (ExportFormat::Csv, Some(csv)) => csv.push(row, &line),
+        };
+
+        if let Some(import) = import {
+            if import.row >= options.from_row {
+                import_row(options, import, &validator, report, &mut summary, storage)?;
+            }
+        }
+    }
+
+    if let Some((_, import)) = csv.and_then(|mut csv| csv.current.take()) {
+        if import.row >= options.from_row {
+            import_row(options, import, &validator, report, &mut summary, storage)?;
+        }
+    }
+
+    if let Some((row, _)) = record {
+        if row >= options.from_row {
+            let import = ImportRow {
+                row,
+                user_id: String::new(),
+                order: Err("Quoted field isn't closed till the end of file".to_string()),
+            };
+            import_row(options, import, &validator, report, &mut summary, storage)?;
+        }
+    }
+
+    report.flush()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    const HEADER: &str = "user_id,order_id,good_id,count,fulfilment";
+
+    fn options(format: ExportFormat, from_row: usize) -> ImportOptions {
+        ImportOptions {
+            name: "test".to_string(),
+            format,
+            rate_per_sec: 1000,
+            from_row,
+            processed_ttl_secs: 60,
+        }
+    }
+
+    fn run(
+        options: &ImportOptions,
+        input: &str,
+        storage: &dyn OrderRepository,
+    ) -> (ImportSummary, Vec<Value>) {
+        let mut report = vec![];
+        let summary = import(options, &mut input.as_bytes(), &mut report, storage).unwrap();
+        let report = String::from_utf8(report)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        (summary, report)
+    }
+
+    // User and order of every message sent to orders topic
+    fn sent(storage: &dyn OrderRepository) -> Vec<(String, Value)> {
+        storage
+            .outbox(100)
+            .unwrap()
+            .into_iter()
+            .map(|entry| {
+                let message: Value = serde_json::from_str(&entry.entry).unwrap();
+                let user_id = message["headers"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .find(|header| header[0] == "user_id")
+                    .unwrap()[1]
+                    .as_str()
+                    .unwrap()
+                    .to_string();
+                let order = serde_json::from_str(message["payload"].as_str().unwrap()).unwrap();
+                (user_id, order)
+            })
+            .collect()
+    }
+
+    fn csv_orders() -> CsvOrders {
+        CsvOrders {
+            columns: csv_fields(HEADER),
+            current: None,
+        }
+    }
+
+    #[test]
+    fn csv_fields_are_split_by_commas_out_of_quotes() {
+        assert_eq!(csv_fields("a,b,,c"), vec!["a", "b", "", "c"]);
+        assert_eq!(csv_fields("\"a,b\",c\r"), vec!["a,b", "c"]);
+        assert_eq!(csv_fields("\"say \"\"hi\"\"\",x"), vec!["say \"hi\"", "x"]);
+        assert_eq!(csv_fields("\"two\nlines\",x"), vec!["two\nlines", "x"]);
+        assert_eq!(csv_fields(""), vec![""]);
+    }
+
+    #[test]
+    fn csv_rows_of_one_order_are_collected() {
+        let mut csv = csv_orders();
+
+        assert!(csv.push(2, "1,10,100,2,partial").is_none());
+        assert!(csv.push(3, "1,10,101,1,partial").is_none());
+
+        let done = csv.push(4, "1,11,100,1,").unwrap();
+        assert_eq!(done.row, 2);
+        assert_eq!(done.user_id, "1");
+        assert_eq!(
+            done.order.unwrap(),
+            json!({
+                "goods": [{ "id": 100, "count": 2 }, { "id": 101, "count": 1 }],
+                "fulfilment": "partial"
+            })
+        );
+
+        let (_, last) = csv.current.take().unwrap();
+        assert_eq!(last.row, 4);
+        assert_eq!(
+            last.order.unwrap(),
+            json!({ "goods": [{ "id": 100, "count": 1 }] })
+        );
+    }
+
+    #[test]
+    fn csv_row_errors_reject_the_order() {
+        let mut csv = csv_orders();
+
+        assert!(csv.push(2, "1,10,100,2,").is_none());
+        assert!(csv.push(3, "1,10,101,x,").is_none());
+        let done = csv.push(4, "1,11,100,1,").unwrap();
+        assert_eq!(done.order.unwrap_err(), "row 3: Invalid 'count'");
+
+        let missing = csv.push(5, "1").unwrap();
+        assert_eq!(missing.order.unwrap_err(), "Column 'order_id' is missing");
+    }
+
+    #[test]
+    fn csv_orders_are_sent() {
+        let storage = MemoryStorage::new();
+        let input = format!(
+            "{}\n1,10,100,2,\n1,10,101,1,\n\n2,20,100,1,partial\n",
+            HEADER
+        );
+        let (summary, report) = run(&options(ExportFormat::Csv, 0), &input, &storage);
+
+        assert_eq!(
+            (summary.imported, summary.skipped, summary.rejected),
+            (2, 0, 0)
+        );
+        assert_eq!(summary.last_row, 5);
+        assert!(report.is_empty());
+        assert_eq!(
+            sent(&storage),
+            vec![
+                (
+                    "1".to_string(),
+                    json!({ "goods": [{ "id": 100, "count": 2 }, { "id": 101, "count": 1 }] })
+                ),
+                (
+                    "2".to_string(),
+                    json!({ "goods": [{ "id": 100, "count": 1 }], "fulfilment": "partial" })
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn rows_are_sent_once_when_import_is_repeated() {
+        let storage = MemoryStorage::new();
+        let input = format!("{}\n1,10,100,2,\n2,20,100,1,\n", HEADER);
+        run(&options(ExportFormat::Csv, 0), &input, &storage);
+        let (summary, _) = run(&options(ExportFormat::Csv, 0), &input, &storage);
+
+        assert_eq!((summary.imported, summary.skipped), (0, 2));
+        assert_eq!(sent(&storage).len(), 2);
+    }
+
+    #[test]
+    fn import_goes_on_from_row() {
+        let storage = MemoryStorage::new();
+        let input = format!("{}\n1,10,100,2,\n1,10,101,1,\n2,20,100,1,\n", HEADER);
+        let (summary, _) = run(&options(ExportFormat::Csv, 4), &input, &storage);
+
+        assert_eq!(summary.imported, 1);
+        assert_eq!(sent(&storage)[0].0, "2");
+    }
+
+    #[test]
+    fn quoted_field_can_take_several_lines() {
+        let storage = MemoryStorage::new();
+        let input = format!(
+            "{},note\r\n1,10,100,2,,\"first\r\nsecond\"\r\n2,20,100,1,,x\r\n",
+            HEADER
+        );
+        let (summary, _) = run(&options(ExportFormat::Csv, 0), &input, &storage);
+
+        assert_eq!(summary.imported, 2);
+        assert_eq!(summary.last_row, 4);
+    }
+
+    #[test]
+    fn unclosed_quote_is_rejected() {
+        let storage = MemoryStorage::new();
+        let input = format!("{},note\n1,10,100,2,,x\n2,20,100,1,,\"open\nend\n", HEADER);
+        let (summary, report) = run(&options(ExportFormat::Csv, 0), &input, &storage);
+
+        assert_eq!((summary.imported, summary.rejected), (1, 1));
+        assert_eq!(report[0]["row"], 3);
+        assert_eq!(
+            report[0]["error"],
+            "Quoted field isn't closed till the end of file"
+        );
+    }
+
+    #[test]
+    fn invalid_jsonl_rows_are_reported() {
+        let storage = MemoryStorage::new();
+        let input = concat!(
+            "{\"user_id\": 1, \"goods\": [{\"id\": 100, \"count\": 1}]}\n",
+            "{\"goods\": [{\"id\": 100, \"count\": 1}]}\n",
+            "not json\n",
+            "{\"user_id\": \"2\", \"goods\": [{\"id\": 100, \"count\": 0}]}\n",
+        );
+        let (summary, report) = run(&options(ExportFormat::Jsonl, 0), input, &storage);
+
+        assert_eq!((summary.imported, summary.rejected), (1, 3));
+        assert_eq!(sent(&storage)[0].0, "1");
+        assert_eq!(
+            report[0],
+            json!({ "row": 2, "user_id": "", "error": "'user_id' is missing" })
+        );
+        assert_eq!(report[1]["row"], 3);
+        assert_eq!(report[2]["user_id"], "2");
+    }
+}
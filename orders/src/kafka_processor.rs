@@ -1,17 +1,33 @@
-use crate::db::{commit_tx, delete_order, make_billing, rollout_tx, CreateOrder, UpdateOrder};
-use crate::validation_schema::{VALIDATION_SCHEMA_CREATE, VALIDATION_SCHEMA_UPDATE};
-use crate::KafkaTopics;
+use crate::backorders::BackorderFilled;
+use crate::db::{delete_order, set_status, CreateOrder, EmptyOrderPolicy, UpdateOrder};
+use crate::events::Origin;
+use crate::idempotency::message_id;
+use crate::outbox::Outbox;
+use crate::pricing::Quote;
+use crate::promotions::{self, Promotion};
+use crate::returns::{self, RequestReturn};
+use crate::saga::{self, Context, SagaState, Transition};
+use crate::schedules::{self, PutSchedule};
+use crate::storage::{Conflict, OrderRepository};
+use crate::templates::{self, PutTemplate};
+use crate::validation_schema::{
+    VALIDATION_SCHEMA_APPLY_PROMOTION, VALIDATION_SCHEMA_CREATE, VALIDATION_SCHEMA_PROMOTION,
+    VALIDATION_SCHEMA_REQUEST_RETURN, VALIDATION_SCHEMA_REVIEW_RETURN, VALIDATION_SCHEMA_SCHEDULE,
+    VALIDATION_SCHEMA_TEMPLATE, VALIDATION_SCHEMA_UPDATE,
+};
+use crate::{KafkaProcessingOptions, KafkaTopics, PricingOptions, SagaOptions, TransactionOptions};
 use futures::stream::Stream;
-use r2d2_redis::{r2d2, RedisConnectionManager};
+use postgres::error::SqlState;
+use r2d2_redis::{r2d2, redis};
 use rdkafka::client::ClientContext;
 use rdkafka::consumer::stream_consumer::StreamConsumer;
 use rdkafka::consumer::{CommitMode, Consumer, ConsumerContext, Rebalance};
 use rdkafka::error::KafkaResult;
-use rdkafka::message::{BorrowedHeaders, Headers, Message, OwnedHeaders};
-use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::message::{BorrowedHeaders, BorrowedMessage, Headers, Message};
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind};
 use std::sync::Arc;
+use std::time::Duration;
 use valico::json_schema::{schema, Scope};
 
 pub struct OrdersContext;
@@ -65,50 +81,180 @@ fn get_kafka_message_metadata<'a>(
     Ok(metadata)
 }
 
+// Errors of infrastructure (database is down) and conflicts with concurrent
+// writers are worth retrying, all other errors mean that message itself is
+// wrong and will never be processed
+fn is_transient(e: &(dyn std::error::Error + 'static)) -> bool {
+    if e.is::<Conflict>() {
+        true
+    } else if let Some(e) = e.downcast_ref::<redis::RedisError>() {
+        e.is_io_error()
+    } else if let Some(e) = e.downcast_ref::<postgres::Error>() {
+        e.is_closed()
+            || e.code() == Some(&SqlState::T_R_SERIALIZATION_FAILURE)
+            || e.code() == Some(&SqlState::T_R_DEADLOCK_DETECTED)
+            || std::error::Error::source(e).is_some_and(|e| e.is::<std::io::Error>())
+    } else {
+        e.is::<r2d2::Error>()
+    }
+}
+
+fn with_retries<T>(
+    options: &KafkaProcessingOptions,
+    mut f: impl FnMut() -> Result<T, Box<dyn std::error::Error>>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let mut backoff = options.retry_backoff_ms;
+
+    loop {
+        match f() {
+            Err(ref e) if is_transient(e.as_ref()) => {
+                warn!(
+                    "line:{}: Transient error: {}, retrying in {} ms",
+                    line!(),
+                    e,
+                    backoff
+                );
+                std::thread::sleep(Duration::from_millis(backoff));
+                backoff = std::cmp::min(backoff * 2, options.max_retry_backoff_ms);
+            }
+            result => return result,
+        }
+    }
+}
+
 fn process_operation(
     validators: &HashMap<&str, schema::ScopedSchema>,
+    tx_options: &TransactionOptions,
+    pricing: &PricingOptions,
     op: &str,
-    metadata: &mut HashMap<&str, &str>,
+    metadata: &HashMap<&str, &str>,
     payload: &str,
-    pool: &r2d2::Pool<RedisConnectionManager>,
-) -> Result<(Option<(Option<i64>, serde_json::Value)>, &'static str), Box<dyn std::error::Error>> {
+    ctx: Context,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Context {
+        outbox,
+        storage,
+        options: saga_options,
+    } = ctx;
+
     match validators.get(op) {
         // TODO: this can be called via hashmap and command pattern
-        None => match &op[..] {
+        None => match op {
             "delete" => {
-                let result = delete_order(
+                saga::check_modifiable(metadata["user_id"], metadata["order_id"], storage)?;
+                delete_order(
                     metadata["user_id"],
                     metadata["order_id"],
-                    &mut pool.get().unwrap(),
-                )?;
-                let value = serde_json::to_value(result)?;
-                Ok((Some((None, value)), "delete"))
+                    tx_options,
+                    outbox,
+                    storage,
+                )
             }
-            "make_billing" => {
-                let _ = make_billing(
+            "set_status" => match metadata.get("status") {
+                Some(status) => set_status(
                     metadata["user_id"],
                     metadata["order_id"],
-                    &mut pool.get().unwrap(),
-                )?;
-                Ok((None, "delete"))
-            }
-            "commit" => {
-                let _ = commit_tx(
-                    metadata["user_id"],
-                    metadata["order_id"],
-                    &mut pool.get().unwrap(),
-                    metadata["transaction"] == "delete",
-                )?;
-                Ok((None, "commit"))
-            }
-            "rollout" => {
-                let _ = rollout_tx(
+                    status,
+                    outbox,
+                    storage,
+                ),
+                None => Err(Box::new(Error::new(
+                    ErrorKind::Other,
+                    format!("line:{}: Status wasn't passed in message", line!()),
+                ))),
+            },
+            "reorder" => templates::reorder(
+                metadata["user_id"],
+                metadata["order_id"],
+                &Transition::new(
+                    SagaState::Reserving,
+                    Some(saga_options.reservation_timeout_ms),
+                ),
+                outbox,
+                storage,
+            ),
+            "delete_template" => match metadata.get("template") {
+                Some(name) => templates::delete(metadata["user_id"], name, outbox, storage),
+                None => Err(Box::new(Error::new(
+                    ErrorKind::Other,
+                    format!("line:{}: Template name wasn't passed in message", line!()),
+                ))),
+            },
+            "instantiate_template" => match metadata.get("template") {
+                Some(name) => templates::instantiate(
                     metadata["user_id"],
-                    metadata["order_id"],
-                    &mut pool.get().unwrap(),
-                )?;
-                Ok((None, "rollout"))
+                    name,
+                    &Transition::new(
+                        SagaState::Reserving,
+                        Some(saga_options.reservation_timeout_ms),
+                    ),
+                    outbox,
+                    storage,
+                ),
+                None => Err(Box::new(Error::new(
+                    ErrorKind::Other,
+                    format!("line:{}: Template name wasn't passed in message", line!()),
+                ))),
+            },
+            "delete_schedule" => match metadata.get("schedule") {
+                Some(name) => schedules::delete(metadata["user_id"], name, outbox, storage),
+                None => Err(Box::new(Error::new(
+                    ErrorKind::Other,
+                    format!("line:{}: Schedule name wasn't passed in message", line!()),
+                ))),
+            },
+            "delete_promotion" => match metadata.get("code") {
+                Some(code) => promotions::delete(code, outbox, storage),
+                None => Err(Box::new(Error::new(
+                    ErrorKind::Other,
+                    format!("line:{}: Promotion code wasn't passed in message", line!()),
+                ))),
+            },
+            "remove_promotion" => {
+                promotions::remove(metadata["user_id"], metadata["order_id"], outbox, storage)
             }
+            "backorder_filled" => serde_json::from_str::<BackorderFilled>(payload)?.apply(
+                metadata["user_id"],
+                metadata["order_id"],
+                outbox,
+                storage,
+            ),
+            "cancel" => saga::cancel(
+                metadata["user_id"],
+                metadata["order_id"],
+                saga_options,
+                outbox,
+                storage,
+            ),
+            "make_billing" => saga::request_payment(
+                metadata["user_id"],
+                metadata["order_id"],
+                saga_options,
+                outbox,
+                storage,
+            ),
+            // Returns are answered with id of the return, not of the order saga
+            "commit" | "rollout" if metadata.contains_key("return_id") => returns::on_reply(
+                metadata["transaction"],
+                op,
+                metadata["return_id"].parse()?,
+                metadata["user_id"],
+                metadata["order_id"],
+                outbox,
+                storage,
+            ),
+            "commit" | "rollout" => saga::on_reply(
+                metadata["transaction"],
+                op,
+                Quote::parse(payload, pricing)?,
+                metadata["user_id"],
+                metadata["order_id"],
+                Context {
+                    outbox,
+                    storage,
+                    options: saga_options,
+                },
+            ),
             _ => Err(Box::new(Error::new(
                 ErrorKind::Other,
                 format!("line:{}: Unknown operation: {}", line!(), op),
@@ -117,25 +263,122 @@ fn process_operation(
         Some(validator) => match serde_json::from_str(payload) {
             Ok(value) => {
                 if validator.validate(&value).is_valid() {
-                    match &op[..] {
+                    match op {
                         "create" => {
-                            let order: CreateOrder =
-                                serde_json::value::from_value(value.clone()).unwrap();
-                            let order_id =
-                                order.create(metadata["user_id"], &mut pool.get().unwrap())?;
-                            Ok((Some((Some(order_id), value)), "create"))
+                            let order: CreateOrder = serde_json::value::from_value(value)?;
+                            let transition = Transition::new(
+                                SagaState::Reserving,
+                                Some(saga_options.reservation_timeout_ms),
+                            );
+                            // Orders of schedules are remembered as their runs
+                            match (metadata.get("schedule"), metadata.get("run_at")) {
+                                (Some(name), Some(run_at)) => schedules::start_run(
+                                    &order,
+                                    metadata["user_id"],
+                                    name,
+                                    run_at.parse()?,
+                                    &transition,
+                                    outbox,
+                                    storage,
+                                ),
+                                _ => {
+                                    order.create(
+                                        metadata["user_id"],
+                                        &transition,
+                                        outbox,
+                                        storage,
+                                    )?;
+                                    Ok(())
+                                }
+                            }
                         }
                         "update" => {
-                            let mut order: UpdateOrder =
-                                serde_json::value::from_value(value.clone()).unwrap();
-                            let _ = order.update(
+                            let order: UpdateOrder = serde_json::value::from_value(value)?;
+                            saga::check_modifiable(
                                 metadata["user_id"],
                                 metadata["order_id"],
-                                &mut pool.get().unwrap(),
+                                storage,
                             )?;
-                            let value = serde_json::to_value(order)?;
-                            Ok((Some((None, value)), "update"))
+                            let version = metadata
+                                .get("if_match")
+                                .map(|version| version.parse::<u64>())
+                                .transpose()?;
+
+                            // Update which removes every good cancels the order if it's allowed
+                            if tx_options.empty_order == EmptyOrderPolicy::Cancel
+                                && order.empties(
+                                    metadata["user_id"],
+                                    metadata["order_id"],
+                                    version,
+                                    storage,
+                                )?
+                            {
+                                return saga::cancel(
+                                    metadata["user_id"],
+                                    metadata["order_id"],
+                                    saga_options,
+                                    outbox,
+                                    storage,
+                                );
+                            }
+
+                            order.update(
+                                metadata["user_id"],
+                                metadata["order_id"],
+                                version,
+                                tx_options,
+                                outbox,
+                                storage,
+                            )
+                        }
+                        "put_promotion" => {
+                            let promotion: Promotion = serde_json::value::from_value(value)?;
+                            promotions::put(&promotion, outbox, storage)
                         }
+                        "apply_promotion" => match value["code"].as_str() {
+                            Some(code) => promotions::apply(
+                                metadata["user_id"],
+                                metadata["order_id"],
+                                code,
+                                outbox,
+                                storage,
+                            ),
+                            None => Err(Box::new(Error::new(
+                                ErrorKind::Other,
+                                format!("line:{}: Promotion code wasn't passed", line!()),
+                            ))),
+                        },
+                        "put_template" => {
+                            let template: PutTemplate = serde_json::value::from_value(value)?;
+                            template.put(metadata["user_id"], outbox, storage)
+                        }
+                        "put_schedule" => {
+                            let schedule: PutSchedule = serde_json::value::from_value(value)?;
+                            schedule.put(metadata["user_id"], outbox, storage)
+                        }
+                        "request_return" => {
+                            let order_return: RequestReturn = serde_json::value::from_value(value)?;
+                            order_return.request(
+                                metadata["user_id"],
+                                metadata["order_id"],
+                                outbox,
+                                storage,
+                            )
+                        }
+                        "review_return" => match metadata.get("return_id") {
+                            Some(return_id) => returns::review(
+                                metadata["user_id"],
+                                metadata["order_id"],
+                                return_id.parse()?,
+                                value["decision"] == "approve",
+                                outbox,
+                                storage,
+                            ),
+                            None => Err(Box::new(Error::new(
+                                ErrorKind::Other,
+                                format!("line:{}: Return id wasn't passed in message", line!()),
+                            ))),
+                        },
                         _ => Err(Box::new(Error::new(
                             ErrorKind::Other,
                             format!("line:{}: Unknown operation: {}", line!(), op),
@@ -153,11 +396,91 @@ fn process_operation(
     }
 }
 
+// State change, messages for other services and processed mark are stored
+// in one storage transaction, offset is committed only after that
+fn handle_message(
+    validators: &HashMap<&str, schema::ScopedSchema>,
+    options: &KafkaProcessingOptions,
+    saga_options: &SagaOptions,
+    tx_options: &TransactionOptions,
+    pricing: &PricingOptions,
+    storage: &dyn OrderRepository,
+    msg: &BorrowedMessage,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let payload = match msg.payload_view::<str>() {
+        None => "",
+        Some(Ok(payload)) => payload,
+        Some(Err(e)) => {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Can't deserialize message payload: {:?}",
+                    line!(),
+                    e
+                ),
+            )))
+        }
+    };
+
+    debug!(
+        "payload: '{}', topic: {}, partition: {}, offset: {}, timestamp: {:?}",
+        payload,
+        msg.topic(),
+        msg.partition(),
+        msg.offset(),
+        msg.timestamp()
+    );
+
+    let headers = msg.headers();
+    let metadata = get_kafka_message_metadata(&headers)?;
+    let message_id = message_id(msg, &metadata);
+
+    if with_retries(options, || storage.is_processed(&message_id))? {
+        info!(
+            "line:{}: Message '{}' was already processed, skipping",
+            line!(),
+            message_id
+        );
+        return Ok(());
+    }
+
+    let op = match metadata.get("operation") {
+        Some(op) => op,
+        None => {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!("line:{}: Operation type wasn't passed in message", line!()),
+            )))
+        }
+    };
+
+    with_retries(options, || {
+        let mut outbox = Outbox::new(&message_id, options.processed_ttl_secs);
+        outbox.set_origin(Origin::from_metadata(&metadata, &message_id));
+        process_operation(
+            validators,
+            tx_options,
+            pricing,
+            op,
+            &metadata,
+            payload,
+            Context {
+                outbox: &mut outbox,
+                storage,
+                options: saga_options,
+            },
+        )
+    })
+}
+
 pub fn consume_and_process(
     topics: KafkaTopics,
-    producer: FutureProducer,
+    options: KafkaProcessingOptions,
+    saga_options: SagaOptions,
+    tx_options: TransactionOptions,
+    pricing: PricingOptions,
     consumer: Arc<StreamConsumer<OrdersContext>>,
-    pool: r2d2::Pool<RedisConnectionManager>,
+    storage: Arc<dyn OrderRepository>,
 ) {
     consumer
         .subscribe(&[&topics.orders_service_topic, &topics.transactions_topic])
@@ -176,88 +499,62 @@ pub fn consume_and_process(
         .unwrap();
     validators.insert("update", update_validator);
 
+    let mut promotion_scope = Scope::new();
+    let promotion_validator = promotion_scope
+        .compile_and_return(VALIDATION_SCHEMA_PROMOTION.clone(), true)
+        .unwrap();
+    validators.insert("put_promotion", promotion_validator);
+
+    let mut apply_promotion_scope = Scope::new();
+    let apply_promotion_validator = apply_promotion_scope
+        .compile_and_return(VALIDATION_SCHEMA_APPLY_PROMOTION.clone(), true)
+        .unwrap();
+    validators.insert("apply_promotion", apply_promotion_validator);
+
+    let mut request_return_scope = Scope::new();
+    let request_return_validator = request_return_scope
+        .compile_and_return(VALIDATION_SCHEMA_REQUEST_RETURN.clone(), true)
+        .unwrap();
+    validators.insert("request_return", request_return_validator);
+
+    let mut review_return_scope = Scope::new();
+    let review_return_validator = review_return_scope
+        .compile_and_return(VALIDATION_SCHEMA_REVIEW_RETURN.clone(), true)
+        .unwrap();
+    validators.insert("review_return", review_return_validator);
+
+    let mut template_scope = Scope::new();
+    let template_validator = template_scope
+        .compile_and_return(VALIDATION_SCHEMA_TEMPLATE.clone(), true)
+        .unwrap();
+    validators.insert("put_template", template_validator);
+
+    let mut schedule_scope = Scope::new();
+    let schedule_validator = schedule_scope
+        .compile_and_return(VALIDATION_SCHEMA_SCHEDULE.clone(), true)
+        .unwrap();
+    validators.insert("put_schedule", schedule_validator);
+
     for message in consumer.start().wait() {
         match message {
             Err(e) => error!("line:{}: Can't read from kafka stream: {:?}", line!(), e),
             Ok(Err(e)) => error!("line:{}: Error: kafka error: {}", line!(), e),
             Ok(Ok(msg)) => {
-                match msg.payload_view::<str>() {
-                    None => {
-                        error!("line:{}: Empty payload came from kafka", line!());
-                    }
-                    Some(Ok(payload)) => {
-                        debug!(
-                            "payload: '{}', topic: {}, partition: {}, offset: {}, timestamp: {:?}",
-                            payload,
-                            msg.topic(),
-                            msg.partition(),
-                            msg.offset(),
-                            msg.timestamp()
-                        );
-
-                        match get_kafka_message_metadata(&msg.headers()) {
-                            Ok(ref mut metadata) => match metadata.get("operation") {
-                                Some(op) => {
-                                    match process_operation(
-                                        &validators,
-                                        op,
-                                        metadata,
-                                        payload,
-                                        &pool,
-                                    ) {
-                                        Ok((result, op)) => {
-                                            if op != "commit"
-                                                && op != "rollout"
-                                                && op != "make_billing"
-                                            {
-                                                let mut headers = OwnedHeaders::new()
-                                                    .add("user_id", metadata["user_id"])
-                                                    .add("operation", op);
-
-                                                let mut record: FutureRecord<String, String> =
-                                                    FutureRecord::to(
-                                                        &topics.warehouse_service_topic,
-                                                    );
-
-                                                let mut payload = "".to_string();
-                                                let order_id;
-
-                                                if let Some(result) = result {
-                                                    if let Some(id) = result.0 {
-                                                        order_id = id.to_string();
-                                                        metadata.insert("order_id", &order_id);
-                                                    }
-
-                                                    payload = result.1.to_string();
-                                                }
-
-                                                headers =
-                                                    headers.add("order_id", metadata["order_id"]);
-                                                record = record.headers(headers).payload(&payload);
-                                                let _ = producer.send(record, 0);
-                                            }
-                                        }
-                                        Err(e) => error!("line:{}: Error: {}", line!(), e),
-                                    }
-                                }
-                                None => error!(
-                                    "line:{}: Operation type wasn't passed in message",
-                                    line!()
-                                ),
-                            },
-                            Err(e) => {
-                                error!("line:{}: Can't parse kafka message headers: {}", line!(), e)
-                            }
-                        }
-                    }
-                    Some(Err(e)) => error!(
-                        "line:{}: Can't deserialize message payload: {:?}",
-                        line!(),
-                        e
-                    ),
+                if let Err(e) = handle_message(
+                    &validators,
+                    &options,
+                    &saga_options,
+                    &tx_options,
+                    &pricing,
+                    storage.as_ref(),
+                    &msg,
+                ) {
+                    error!("line:{}: Error: {}, message is skipped", line!(), e);
                 }
 
-                consumer.commit_message(&msg, CommitMode::Async).unwrap();
+                if let Err(e) = consumer.commit_message(&msg, CommitMode::Sync) {
+                    error!("line:{}: Can't commit offset: {}", line!(), e);
+                }
             }
         }
     }
@@ -3,19 +3,37 @@ extern crate lazy_static;
 #[macro_use]
 extern crate log;
 
-use actix_web::{middleware::Logger, App, HttpServer};
-use r2d2_redis::{r2d2, RedisConnectionManager};
+use actix_web::{middleware::Logger, web, App, HttpServer};
+use futures::Future;
 use rdkafka::config::{ClientConfig, RDKafkaLogLevel};
 use rdkafka::consumer::stream_consumer::StreamConsumer;
 use rdkafka::producer::FutureProducer;
 use serde::Deserialize;
-use signal_hook::{iterator::Signals, SIGINT, SIGQUIT, SIGTERM};
+use shutdown::{Readiness, ShutdownOptions};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use storage::{MemoryStorage, OrderRepository, PostgresStorage, RedisStorage};
 
 mod api;
 mod appconfig;
+mod backorders;
 mod db;
+mod delivery;
+mod events;
+mod export;
+mod idempotency;
+mod import;
 mod kafka_processor;
+mod outbox;
+mod pricing;
+mod promotions;
+mod returns;
+mod saga;
+mod schedules;
+mod status;
+mod storage;
+mod templates;
+mod transactions;
 mod validation_schema;
 
 #[derive(Deserialize)]
@@ -46,7 +64,60 @@ pub struct KafkaProducerOptions {
 pub struct KafkaTopics {
     orders_service_topic: String,
     warehouse_service_topic: String,
+    billing_service_topic: String,
     transactions_topic: String,
+    notifications_topic: String,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct KafkaProcessingOptions {
+    retry_backoff_ms: u64,
+    max_retry_backoff_ms: u64,
+    processed_ttl_secs: usize,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct OutboxOptions {
+    poll_interval_ms: u64,
+    batch_size: usize,
+    lock_ttl_ms: u64,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct SagaOptions {
+    reservation_timeout_ms: u64,
+    payment_timeout_ms: u64,
+    refund_timeout_ms: u64,
+    compensation_timeout_ms: u64,
+    sweep_interval_ms: u64,
+    sweep_batch_size: usize,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct TransactionOptions {
+    timeout_ms: u64,
+    max_retries: u64,
+    sweep_interval_ms: u64,
+    sweep_batch_size: usize,
+    #[serde(default)]
+    empty_order: db::EmptyOrderPolicy,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ScheduleOptions {
+    sweep_interval_ms: u64,
+    sweep_batch_size: usize,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct PricingOptions {
+    tax_rate_bp: u64,
+}
+
+#[derive(Deserialize)]
+struct StorageOptions {
+    backend: String,
+    postgres_connection_string: String,
 }
 
 #[derive(Deserialize)]
@@ -55,6 +126,14 @@ struct Config {
     kafka_producer: KafkaProducerOptions,
     kafka_consumer: KafkaConsumerOptions,
     kafka_topics: KafkaTopics,
+    kafka_processing: KafkaProcessingOptions,
+    outbox: OutboxOptions,
+    saga: SagaOptions,
+    transactions: TransactionOptions,
+    schedules: ScheduleOptions,
+    pricing: PricingOptions,
+    shutdown: ShutdownOptions,
+    storage: StorageOptions,
 }
 
 fn read_config(config_file_path: &str) -> Result<String, std::io::Error> {
@@ -74,6 +153,84 @@ fn read_and_parse_config(config_file_path: &str) -> Option<Config> {
     None
 }
 
+fn run_export(
+    matches: &clap::ArgMatches,
+    storage: Arc<dyn OrderRepository>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let number = |name: &str| {
+        matches
+            .value_of(name)
+            .map(|value| value.parse())
+            .transpose()
+    };
+    let status = match matches.value_of("status") {
+        Some(status) => match status::OrderStatus::parse(status) {
+            Some(status) => Some(status),
+            None => {
+                return Err(Box::new(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("line:{}: Unknown order status: {}", line!(), status),
+                )))
+            }
+        },
+        None => None,
+    };
+    let query = export::query(
+        status,
+        number("created_from")?,
+        number("created_to")?,
+        matches.value_of("batch_size").unwrap_or("500").parse()?,
+    );
+    let format = export::ExportFormat::parse(matches.value_of("format").unwrap_or("csv"))
+        .unwrap_or(export::ExportFormat::Csv);
+
+    export::write(
+        export::Export::new(storage, query, format),
+        &mut std::io::stdout().lock(),
+    )
+}
+
+fn run_import(
+    matches: &clap::ArgMatches,
+    processing: &KafkaProcessingOptions,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::path::Path::new(matches.value_of("file").unwrap_or(""));
+    let format = match matches.value_of("format") {
+        Some(format) => format,
+        None => match path.extension().and_then(|extension| extension.to_str()) {
+            Some("csv") => "csv",
+            _ => "jsonl",
+        },
+    };
+    let options = import::ImportOptions {
+        name: match matches.value_of("name") {
+            Some(name) => name.to_string(),
+            None => path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        },
+        format: export::ExportFormat::parse(format).unwrap_or(export::ExportFormat::Jsonl),
+        rate_per_sec: matches.value_of("rate").unwrap_or("50").parse()?,
+        from_row: matches.value_of("from_row").unwrap_or("1").parse()?,
+        processed_ttl_secs: processing.processed_ttl_secs,
+    };
+
+    let mut input = std::io::BufReader::new(std::fs::File::open(path)?);
+    let summary = import::import(&options, &mut input, &mut std::io::stdout().lock(), storage)?;
+    info!(
+        "line:{}: Import '{}': {} orders sent, {} already sent, {} rejected, last row {}",
+        line!(),
+        options.name,
+        summary.imported,
+        summary.skipped,
+        summary.rejected,
+        summary.last_row
+    );
+    Ok(())
+}
+
 fn main() {
     let matches = clap::App::new("rsoi orders")
         .arg(
@@ -84,6 +241,72 @@ fn main() {
                 .help("Sets a custom config file")
                 .takes_value(true),
         )
+        .subcommand(
+            clap::SubCommand::with_name("export")
+                .about("Writes orders of all users to standard output")
+                .arg(
+                    clap::Arg::with_name("format")
+                        .long("format")
+                        .possible_values(&["csv", "jsonl"])
+                        .default_value("csv"),
+                )
+                .arg(
+                    clap::Arg::with_name("status")
+                        .long("status")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("created_from")
+                        .long("created-from")
+                        .value_name("MS")
+                        .help("Orders created at or after, in milliseconds since epoch")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("created_to")
+                        .long("created-to")
+                        .value_name("MS")
+                        .help("Orders created at or before, in milliseconds since epoch")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("batch_size")
+                        .long("batch-size")
+                        .default_value("500"),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("import")
+                .about("Creates orders from a file, rejected rows are written to standard output")
+                .arg(clap::Arg::with_name("file").required(true))
+                .arg(
+                    clap::Arg::with_name("format")
+                        .long("format")
+                        .possible_values(&["csv", "jsonl"])
+                        .help("Taken from extension of the file by default")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("name")
+                        .long("name")
+                        .help("Rows of imports with the same name are made once, file name by default")
+                        .takes_value(true),
+                )
+                .arg(
+                    clap::Arg::with_name("rate")
+                        .long("rate")
+                        .value_name("ORDERS")
+                        .help("Orders sent per second")
+                        .default_value("50"),
+                )
+                .arg(
+                    clap::Arg::with_name("from_row")
+                        .long("from-row")
+                        .value_name("ROW")
+                        .help("Rows before it are skipped")
+                        .default_value("1"),
+                ),
+        )
         .get_matches();
 
     if let Some(config) = matches.value_of("config") {
@@ -91,11 +314,38 @@ fn main() {
             std::env::set_var("RUST_LOG", &config.server.log_level);
             env_logger::init();
 
-            let manager =
-                RedisConnectionManager::new(&config.server.redis_connection_string[..]).unwrap();
-            let pool = r2d2::Pool::builder().build(manager).unwrap();
+            let storage: Arc<dyn OrderRepository> = match &config.storage.backend[..] {
+                "redis" => Arc::new(
+                    RedisStorage::new(&config.server.redis_connection_string)
+                        .expect("Redis storage creation failed"),
+                ),
+                "postgres" => Arc::new(
+                    PostgresStorage::new(&config.storage.postgres_connection_string)
+                        .expect("Postgres storage creation failed"),
+                ),
+                "memory" => Arc::new(MemoryStorage::new()),
+                backend => panic!("Unknown storage backend: {}", backend),
+            };
 
-            let mut handlers = vec![];
+            // Export only reads storage, nothing else of the service is started
+            if let Some(matches) = matches.subcommand_matches("export") {
+                if let Err(e) = run_export(matches, storage) {
+                    error!("{}:Export failed: {}", line!(), e);
+                    std::process::exit(1);
+                }
+                return;
+            }
+
+            // Orders are only put into outbox, running service creates them
+            if let Some(matches) = matches.subcommand_matches("import") {
+                if let Err(e) = run_import(matches, &config.kafka_processing, storage.as_ref()) {
+                    error!("{}:Import failed: {}", line!(), e);
+                    std::process::exit(1);
+                }
+                return;
+            }
+
+            let mut consumer_handlers = vec![];
             let mut consumers: Vec<Arc<StreamConsumer<kafka_processor::OrdersContext>>> = vec![];
 
             for _ in 0..config.server.kafka_workers {
@@ -124,52 +374,105 @@ fn main() {
                 );
                 consumers.push(Arc::clone(&consumer));
 
-                let producer: FutureProducer = ClientConfig::new()
-                    .set(
-                        "bootstrap.servers",
-                        &config.kafka_producer.bootstrap_servers,
-                    )
-                    .set(
-                        "message.timeout.ms",
-                        &config.kafka_producer.message_timeout_ms,
-                    )
-                    .create()
-                    .expect("Producer creation error");
                 let kafka_topics = config.kafka_topics.clone();
-                let pool = pool.clone();
+                let kafka_processing = config.kafka_processing.clone();
+                let saga = config.saga.clone();
+                let transactions = config.transactions.clone();
+                let pricing = config.pricing.clone();
+                let storage = Arc::clone(&storage);
 
-                handlers.push(std::thread::spawn(move || {
+                consumer_handlers.push(std::thread::spawn(move || {
                     kafka_processor::consume_and_process(
                         kafka_topics,
-                        producer,
+                        kafka_processing,
+                        saga,
+                        transactions,
+                        pricing,
                         Arc::clone(&consumer),
-                        pool,
+                        storage,
                     )
                 }));
             }
 
-            let signals = Signals::new(&[SIGINT, SIGTERM, SIGQUIT]).unwrap();
-            let signal_handler = std::thread::spawn(move || {
-                for _ in signals.forever() {
-                    for consumer in consumers.iter() {
-                        consumer.stop();
-                    }
+            let producer: FutureProducer = ClientConfig::new()
+                .set(
+                    "bootstrap.servers",
+                    &config.kafka_producer.bootstrap_servers,
+                )
+                .set(
+                    "message.timeout.ms",
+                    &config.kafka_producer.message_timeout_ms,
+                )
+                .create()
+                .expect("Producer creation error");
+            let running = Arc::new(AtomicBool::new(true));
+            let mut handlers = vec![];
 
-                    break;
-                }
-            });
+            {
+                let kafka_topics = config.kafka_topics.clone();
+                let outbox = config.outbox.clone();
+                let storage = Arc::clone(&storage);
+                let running = Arc::clone(&running);
+
+                handlers.push(std::thread::spawn(move || {
+                    outbox::relay(kafka_topics, outbox, producer, storage, running)
+                }));
+            }
+
+            {
+                let saga = config.saga.clone();
+                let kafka_processing = config.kafka_processing.clone();
+                let storage = Arc::clone(&storage);
+                let running = Arc::clone(&running);
+
+                handlers.push(std::thread::spawn(move || {
+                    saga::sweep(saga, kafka_processing, storage, running)
+                }));
+            }
+
+            {
+                let transactions = config.transactions.clone();
+                let kafka_processing = config.kafka_processing.clone();
+                let storage = Arc::clone(&storage);
+                let running = Arc::clone(&running);
+
+                handlers.push(std::thread::spawn(move || {
+                    transactions::sweep(transactions, kafka_processing, storage, running)
+                }));
+            }
 
+            {
+                let schedules = config.schedules.clone();
+                let kafka_processing = config.kafka_processing.clone();
+                let storage = Arc::clone(&storage);
+                let running = Arc::clone(&running);
+
+                handlers.push(std::thread::spawn(move || {
+                    schedules::sweep(schedules, kafka_processing, storage, running)
+                }));
+            }
+
+            let readiness = web::Data::new(Readiness::default());
             let sys = actix_rt::System::new("orders");
 
             let mut listen_fd = listenfd::ListenFd::from_env();
-            let mut server = HttpServer::new(move || {
-                App::new()
-                    .configure(appconfig::config_app)
-                    .data(pool.clone())
-                    .wrap(Logger::new(
-                    "ip: %a, date: %t, response code: %s, response size: %b (bytes), duration: %D (ms)",
-                ))
-            });
+            let mut server = {
+                let readiness = readiness.clone();
+                HttpServer::new(move || {
+                    App::new()
+                        .configure(appconfig::config_app)
+                        .register_data(readiness.clone())
+                        .data(storage.clone())
+                        .wrap(Logger::new(
+                        "ip: %a, date: %t, response code: %s, response size: %b (bytes), duration: %D (ms)",
+                    ))
+                })
+            };
+
+            // Signals are handled below, so that all parts of service stop in order
+            server = server
+                .disable_signals()
+                .shutdown_timeout(config.shutdown.timeout_secs);
 
             server = if let Some(l) = listen_fd.take_tcp_listener(0).unwrap() {
                 server.listen(l).unwrap()
@@ -180,14 +483,38 @@ fn main() {
                     .unwrap()
             };
 
-            server.start();
-            let _ = sys.run();
+            let server = server.start();
+            let system = actix_rt::System::current();
+            let shutdown_options = config.shutdown;
 
-            signal_handler.join().unwrap();
+            let signal_handler = std::thread::spawn(move || {
+                shutdown::on_signal(
+                    &shutdown_options,
+                    &readiness,
+                    || {
+                        let _ = server.stop(true).wait();
+                    },
+                    || {
+                        for consumer in consumers.iter() {
+                            consumer.stop();
+                        }
 
-            for handler in handlers {
-                handler.join().unwrap();
-            }
+                        for handler in consumer_handlers {
+                            handler.join().unwrap();
+                        }
+
+                        running.store(false, Ordering::SeqCst);
+
+                        for handler in handlers {
+                            handler.join().unwrap();
+                        }
+                    },
+                );
+                system.stop();
+            });
+
+            let _ = sys.run();
+            signal_handler.join().unwrap();
         }
     }
 }
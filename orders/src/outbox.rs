@@ -0,0 +1,261 @@
+use crate::events::Origin;
+use crate::storage::{Change, Changes, OrderRepository};
+use crate::{KafkaTopics, OutboxOptions};
+use futures::Future;
+use rdkafka::message::OwnedHeaders;
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Destination {
+    Warehouse,
+    Billing,
+    // Scheduler sends orders to the service itself
+    Orders,
+    // Users are told about what happened to their orders
+    Notifications,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OutboxMessage {
+    destination: Destination,
+    headers: Vec<(String, String)>,
+    payload: String,
+}
+
+impl OutboxMessage {
+    pub fn message_id(&self) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == "message_id")
+            .map(|(_, value)| &value[..])
+    }
+}
+
+// Messages produced while processing one incoming message, they are stored
+// in the same transaction as state change together with processed mark
+pub struct Outbox {
+    message_id: String,
+    processed_ttl_secs: usize,
+    origin: Origin,
+    messages: Vec<OutboxMessage>,
+}
+
+impl Outbox {
+    pub fn new(message_id: &str, processed_ttl_secs: usize) -> Self {
+        Outbox {
+            message_id: message_id.to_string(),
+            processed_ttl_secs,
+            origin: Origin::new("system", message_id),
+            messages: vec![],
+        }
+    }
+
+    // Correlation id is passed to other services, so their answers
+    // are recorded in order events with it
+    pub fn set_origin(&mut self, origin: Origin) {
+        self.origin = origin;
+    }
+
+    pub fn origin(&self) -> &Origin {
+        &self.origin
+    }
+
+    pub fn push(
+        &mut self,
+        destination: Destination,
+        headers: &[(&str, &str)],
+        payload: String,
+    ) -> &OutboxMessage {
+        let mut headers: Vec<(String, String)> = headers
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        headers.push((
+            "correlation_id".to_string(),
+            self.origin.correlation_id.clone(),
+        ));
+        headers.push((
+            "message_id".to_string(),
+            format!(
+                "{}:{:?}:{}",
+                self.message_id,
+                destination,
+                self.messages.len()
+            ),
+        ));
+
+        self.messages.push(OutboxMessage {
+            destination,
+            headers,
+            payload,
+        });
+        &self.messages[self.messages.len() - 1]
+    }
+
+    // Message is sent once more as is, so receiver deduplicates it by its id
+    pub fn push_message(&mut self, message: OutboxMessage) {
+        self.messages.push(message);
+    }
+
+    pub fn write(&self, changes: &mut Changes) -> Result<(), serde_json::Error> {
+        for message in &self.messages {
+            changes.push(Change::PushOutbox(serde_json::to_string(message)?));
+        }
+
+        changes.push(Change::MarkProcessed {
+            message_id: self.message_id.clone(),
+            ttl_secs: self.processed_ttl_secs,
+        });
+        Ok(())
+    }
+
+    // Used when processing doesn't change any state
+    pub fn commit(&self, storage: &dyn OrderRepository) -> Result<(), Box<dyn std::error::Error>> {
+        let mut changes = Changes::default();
+        self.write(&mut changes)?;
+        storage.apply(changes)
+    }
+}
+
+fn send_and_wait(
+    producer: &FutureProducer,
+    topic: &str,
+    message: &OutboxMessage,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut headers = OwnedHeaders::new();
+
+    for (key, value) in &message.headers {
+        headers = headers.add(key, value);
+    }
+
+    let record: FutureRecord<String, String> = FutureRecord::to(topic)
+        .headers(headers)
+        .payload(&message.payload);
+
+    match producer.send(record, 0).wait() {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err((e, _))) => Err(Box::new(e)),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+fn relay_batch(
+    topics: &KafkaTopics,
+    options: &OutboxOptions,
+    producer: &FutureProducer,
+    owner: &str,
+    storage: &dyn OrderRepository,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    if !storage.lock_outbox(owner, options.lock_ttl_ms)? {
+        return Ok(0);
+    }
+
+    let entries = storage.outbox(options.batch_size)?;
+    let mut locked_at = Instant::now();
+
+    let mut sent = 0;
+    let mut result = Ok(());
+
+    for entry in &entries {
+        // Lock is prolonged before it expires, so no other relay starts
+        // publishing the same entries while this batch is sent
+        if locked_at.elapsed() >= Duration::from_millis(options.lock_ttl_ms / 2) {
+            if !storage.lock_outbox(owner, options.lock_ttl_ms)? {
+                break;
+            }
+
+            locked_at = Instant::now();
+        }
+
+        match serde_json::from_str::<OutboxMessage>(&entry.entry) {
+            Ok(message) => {
+                let topic = match message.destination {
+                    Destination::Warehouse => &topics.warehouse_service_topic,
+                    Destination::Billing => &topics.billing_service_topic,
+                    Destination::Orders => &topics.orders_service_topic,
+                    Destination::Notifications => &topics.notifications_topic,
+                };
+
+                if let Err(e) = send_and_wait(producer, topic, &message) {
+                    result = Err(e);
+                    break;
+                }
+            }
+            Err(e) => error!(
+                "line:{}: Dropping invalid outbox entry '{}': {}",
+                line!(),
+                entry.entry,
+                e
+            ),
+        }
+
+        sent += 1;
+    }
+
+    if sent > 0 && !storage.ack_outbox(owner, &entries[..sent])? {
+        return Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!(
+                "line:{}: Outbox lock was lost, {} published messages are left to new owner",
+                line!(),
+                sent
+            ),
+        )));
+    }
+
+    result.map(|_| sent)
+}
+
+// Publishes stored messages to kafka and removes them from outbox after kafka
+// acknowledged them, so every message is published at least once
+pub fn relay(
+    topics: KafkaTopics,
+    options: OutboxOptions,
+    producer: FutureProducer,
+    storage: Arc<dyn OrderRepository>,
+    running: Arc<AtomicBool>,
+) {
+    let owner = format!(
+        "{}:{}",
+        std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_string()),
+        std::process::id()
+    );
+
+    // On shutdown messages written by the last processed ones are relayed too
+    loop {
+        let result = relay_batch(&topics, &options, &producer, &owner, storage.as_ref());
+        let stopping = !running.load(Ordering::SeqCst);
+
+        match result {
+            Ok(0) if stopping => break,
+            Ok(0) => std::thread::sleep(Duration::from_millis(options.poll_interval_ms)),
+            Ok(sent) => debug!("line:{}: Relayed {} messages from outbox", line!(), sent),
+            Err(e) => {
+                error!("line:{}: Can't relay messages from outbox: {}", line!(), e);
+
+                if stopping {
+                    break;
+                }
+
+                std::thread::sleep(Duration::from_millis(options.poll_interval_ms));
+            }
+        }
+    }
+
+    producer.flush(None);
+
+    if let Err(e) = storage.unlock_outbox(&owner) {
+        error!("line:{}: Can't release outbox lock: {}", line!(), e);
+    }
+
+    info!(
+        "thread id {:?}: stopping outbox relay thread",
+        std::thread::current().id(),
+    );
+}
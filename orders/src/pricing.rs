@@ -0,0 +1,105 @@
+use crate::storage::Order;
+use crate::PricingOptions;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+// Prices of goods warehouse reserved for the order and goods it couldn't
+// reserve, it is sent in the answer to 'create' and 'update' transactions
+#[derive(Deserialize)]
+pub struct Quote {
+    currency: String,
+    prices: BTreeMap<u64, u64>,
+    #[serde(default)]
+    backorders: BTreeMap<u64, u64>,
+    #[serde(skip)]
+    tax_rate_bp: u64,
+}
+
+impl Quote {
+    // Answers of older warehouse and of other operations have no prices
+    pub fn parse(
+        payload: &str,
+        options: &PricingOptions,
+    ) -> Result<Option<Quote>, Box<dyn std::error::Error>> {
+        if payload.is_empty() {
+            return Ok(None);
+        }
+
+        let mut quote: Quote = serde_json::from_str(payload)?;
+        quote.tax_rate_bp = options.tax_rate_bp;
+        Ok(Some(quote))
+    }
+
+    // Counts of goods warehouse couldn't reserve
+    pub fn backorders(&self) -> &BTreeMap<u64, u64> {
+        &self.backorders
+    }
+
+    // Goods which weren't priced again keep their previous price
+    pub fn apply(&self, order: &mut Order) {
+        order.prices = order
+            .goods
+            .keys()
+            .filter_map(|good_id| {
+                self.prices
+                    .get(good_id)
+                    .or_else(|| order.prices.get(good_id))
+                    .map(|price| (*good_id, *price))
+            })
+            .collect();
+
+        if !self.currency.is_empty() {
+            order.currency = self.currency.clone();
+        }
+
+        order.tax_rate_bp = self.tax_rate_bp;
+        order.backorders = self
+            .backorders
+            .iter()
+            .filter(|(good_id, _)| order.goods.contains_key(good_id))
+            .map(|(good_id, count)| (*good_id, *count))
+            .collect();
+    }
+}
+
+pub struct Totals {
+    pub lines: BTreeMap<u64, u64>,
+    pub discounts: BTreeMap<u64, u64>,
+    pub subtotal: u64,
+    pub discount: u64,
+    pub tax: u64,
+    pub total: u64,
+}
+
+// Everything is computed from the snapshot, so totals of an order never change
+// after it was priced, tax is taken from discounted subtotal and is rounded
+// half up to minor units
+pub fn totals(order: &Order) -> Totals {
+    let lines: Vec<(u64, u64, u64)> = order
+        .goods
+        .iter()
+        .map(|(good_id, count)| {
+            let price = order.prices.get(good_id).cloned().unwrap_or(0);
+            (*good_id, *count, price * count)
+        })
+        .collect();
+    let discounts: BTreeMap<u64, u64> = match &order.promotion {
+        Some(promotion) => promotion.discounts(&lines).into_iter().collect(),
+        None => BTreeMap::new(),
+    };
+    let subtotal = lines.iter().map(|(_, _, total)| total).sum();
+    let discount = discounts.values().sum();
+    let tax = ((subtotal - discount) * order.tax_rate_bp + 5_000) / 10_000;
+
+    Totals {
+        lines: lines
+            .into_iter()
+            .map(|(good_id, _, total)| (good_id, total))
+            .collect(),
+        discounts,
+        subtotal,
+        discount,
+        tax,
+        total: subtotal - discount + tax,
+    }
+}
@@ -0,0 +1,375 @@
+use crate::events::{self, EventKind};
+use crate::outbox::Outbox;
+use crate::saga;
+use crate::storage::{now_ms, Change, Changes, Order, OrderRepository};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscountKind {
+    // Value is in basis points (1/100 of percent)
+    Percent,
+    // Value is in minor units of the currency, per unit for per-good codes
+    Fixed,
+}
+
+// Code is applied to the whole order, or only to one good if it is set,
+// validity window is in milliseconds since epoch
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Promotion {
+    pub code: String,
+    pub kind: DiscountKind,
+    pub value: u64,
+    #[serde(default)]
+    pub currency: String,
+    #[serde(default)]
+    pub good_id: Option<u64>,
+    #[serde(default)]
+    pub valid_from: Option<u64>,
+    #[serde(default)]
+    pub valid_to: Option<u64>,
+    #[serde(default)]
+    pub max_uses: Option<u64>,
+    #[serde(default)]
+    pub max_uses_per_user: Option<u64>,
+}
+
+// Usage of a code is counted by orders it was paid with
+pub struct PromotionUse {
+    pub user_id: String,
+    pub order_id: String,
+}
+
+impl Promotion {
+    fn check_definition(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.kind == DiscountKind::Percent && self.value > 10_000 {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Promotion '{}' can't discount more than 100%",
+                    line!(),
+                    self.code
+                ),
+            )));
+        }
+
+        if self.kind == DiscountKind::Fixed && self.currency.is_empty() {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Fixed discount of promotion '{}' has no currency",
+                    line!(),
+                    self.code
+                ),
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Use of the order itself is not counted, so check can be repeated
+    fn check(
+        &self,
+        user_id: &str,
+        order_id: &str,
+        order: &Order,
+        storage: &dyn OrderRepository,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let now = now_ms();
+
+        if self.valid_from.is_some_and(|from| now < from)
+            || self.valid_to.is_some_and(|to| now > to)
+        {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Promotion '{}' isn't valid now",
+                    line!(),
+                    self.code
+                ),
+            )));
+        }
+
+        if self.kind == DiscountKind::Fixed && self.currency != order.currency {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Promotion '{}' is in '{}', order '{}' is in '{}'",
+                    line!(),
+                    self.code,
+                    self.currency,
+                    order_id,
+                    order.currency
+                ),
+            )));
+        }
+
+        if let Some(good_id) = self.good_id {
+            if !order.goods.contains_key(&good_id) {
+                return Err(Box::new(Error::new(
+                    ErrorKind::Other,
+                    format!(
+                        "line:{}: Promotion '{}' is for good {}, it isn't in order '{}'",
+                        line!(),
+                        self.code,
+                        good_id,
+                        order_id
+                    ),
+                )));
+            }
+        }
+
+        let uses: Vec<PromotionUse> = storage
+            .promotion_uses(&self.code)?
+            .into_iter()
+            .filter(|used| !(used.user_id == user_id && used.order_id == order_id))
+            .collect();
+        let user_uses = uses.iter().filter(|used| used.user_id == user_id).count() as u64;
+
+        if self.max_uses.is_some_and(|max| uses.len() as u64 >= max)
+            || self.max_uses_per_user.is_some_and(|max| user_uses >= max)
+        {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!("line:{}: Promotion '{}' was used up", line!(), self.code),
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Discount of every good in the order, it never exceeds the price
+    pub fn discounts(&self, lines: &[(u64, u64, u64)]) -> Vec<(u64, u64)> {
+        let percent = |amount: u64| (amount * self.value + 5_000) / 10_000;
+
+        match self.good_id {
+            Some(good_id) => lines
+                .iter()
+                .map(|(id, count, total)| {
+                    let discount = match (*id == good_id, self.kind) {
+                        (false, _) => 0,
+                        (true, DiscountKind::Percent) => percent(*total),
+                        (true, DiscountKind::Fixed) => self.value * count,
+                    };
+                    (*id, std::cmp::min(discount, *total))
+                })
+                .collect(),
+            // Discount of the whole order is spread over goods by their totals,
+            // the rest of rounding goes to the last one
+            None => {
+                let subtotal: u64 = lines.iter().map(|(_, _, total)| total).sum();
+                let discount = std::cmp::min(
+                    match self.kind {
+                        DiscountKind::Percent => percent(subtotal),
+                        DiscountKind::Fixed => self.value,
+                    },
+                    subtotal,
+                );
+                let mut left = discount;
+
+                lines
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (id, _, total))| {
+                        let share = if i + 1 == lines.len() {
+                            left
+                        } else {
+                            (discount * total).checked_div(subtotal).unwrap_or(0)
+                        };
+                        left -= share;
+                        (*id, share)
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+pub fn put(
+    promotion: &Promotion,
+    outbox: &Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    promotion.check_definition()?;
+
+    let mut changes = Changes::default();
+    changes.push(Change::PutPromotion(promotion.clone()));
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
+
+// Orders which already have the code keep their discount
+pub fn delete(
+    code: &str,
+    outbox: &Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut changes = Changes::default();
+    changes.push(Change::DeletePromotion {
+        code: code.to_string(),
+    });
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
+
+fn modifiable_order(
+    user_id: &str,
+    order_id: &str,
+    storage: &dyn OrderRepository,
+) -> Result<Order, Box<dyn std::error::Error>> {
+    saga::check_modifiable(user_id, order_id, storage)?;
+
+    if storage.pending(user_id, order_id)?.is_some() {
+        return Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!(
+                "line:{}: Order '{}' can't be changed while it is being changed",
+                line!(),
+                order_id
+            ),
+        )));
+    }
+
+    match storage.order(user_id, order_id)? {
+        Some(order) if order.status.is_modifiable() => Ok(order),
+        Some(order) => Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!(
+                "line:{}: Promotion can't be changed in order '{}' in status '{}'",
+                line!(),
+                order_id,
+                order.status.as_str()
+            ),
+        ))),
+        None => Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!(
+                "line:{}: Order '{}' of user '{}' does not exist",
+                line!(),
+                order_id,
+                user_id
+            ),
+        ))),
+    }
+}
+
+// Promotion is copied into the order, so later changes of the code don't
+// change discount of orders it was already applied to
+pub fn apply(
+    user_id: &str,
+    order_id: &str,
+    code: &str,
+    outbox: &Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut order = modifiable_order(user_id, order_id, storage)?;
+    let promotion = match storage.promotion(code)? {
+        Some(promotion) => promotion,
+        None => {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!("line:{}: There is no promotion '{}'", line!(), code),
+            )))
+        }
+    };
+    promotion.check(user_id, order_id, &order, storage)?;
+
+    let mut changes = Changes::default();
+    events::record(
+        &mut changes,
+        user_id,
+        order_id,
+        EventKind::PromotionApplied,
+        outbox.origin(),
+        serde_json::to_string(&promotion)?,
+    );
+    order.promotion = Some(promotion);
+    changes.push(Change::PutOrder {
+        user_id: user_id.to_string(),
+        order_id: order_id.to_string(),
+        order,
+    });
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
+
+pub fn remove(
+    user_id: &str,
+    order_id: &str,
+    outbox: &Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut order = modifiable_order(user_id, order_id, storage)?;
+    let mut changes = Changes::default();
+
+    if let Some(promotion) = order.promotion.take() {
+        events::record(
+            &mut changes,
+            user_id,
+            order_id,
+            EventKind::PromotionRemoved,
+            outbox.origin(),
+            serde_json::json!({ "code": promotion.code }).to_string(),
+        );
+        changes.push(Change::PutOrder {
+            user_id: user_id.to_string(),
+            order_id: order_id.to_string(),
+            order,
+        });
+    }
+
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
+
+// Code is checked once more when payment is requested and its use is
+// counted, storage doesn't count it if the code was used up meanwhile,
+// it is given back if the order is cancelled or deleted
+pub fn redeem(
+    changes: &mut Changes,
+    user_id: &str,
+    order_id: &str,
+    order: &Order,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let applied = match &order.promotion {
+        Some(applied) => applied,
+        None => return Ok(()),
+    };
+
+    let promotion = match storage.promotion(&applied.code)? {
+        Some(promotion) => promotion,
+        None => {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Promotion '{}' of order '{}' doesn't exist anymore",
+                    line!(),
+                    applied.code,
+                    order_id
+                ),
+            )))
+        }
+    };
+
+    promotion.check(user_id, order_id, order, storage)?;
+    changes.push(Change::AddPromotionUse {
+        code: applied.code.clone(),
+        user_id: user_id.to_string(),
+        order_id: order_id.to_string(),
+        max_uses: promotion.max_uses,
+        max_uses_per_user: promotion.max_uses_per_user,
+    });
+    Ok(())
+}
+
+pub fn release(changes: &mut Changes, user_id: &str, order_id: &str, order: &Order) {
+    if let Some(applied) = &order.promotion {
+        changes.push(Change::RemovePromotionUse {
+            code: applied.code.clone(),
+            user_id: user_id.to_string(),
+            order_id: order_id.to_string(),
+        });
+    }
+}
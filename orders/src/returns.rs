@@ -0,0 +1,477 @@
+use crate::events::{self, EventKind};
+use crate::outbox::{Destination, Outbox};
+use crate::pricing;
+use crate::status::{self, OrderStatus};
+use crate::storage::{now_ms, Change, Changes, Order, OrderRepository};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+
+// Return goes through the following statuses:
+// requested -> approved -> restocked -> refunded
+// requested -> rejected
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReturnStatus {
+    Requested,
+    Approved,
+    Rejected,
+    Restocked,
+    Refunded,
+}
+
+impl ReturnStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ReturnStatus::Requested => "requested",
+            ReturnStatus::Approved => "approved",
+            ReturnStatus::Rejected => "rejected",
+            ReturnStatus::Restocked => "restocked",
+            ReturnStatus::Refunded => "refunded",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<ReturnStatus> {
+        match status {
+            "requested" => Some(ReturnStatus::Requested),
+            "approved" => Some(ReturnStatus::Approved),
+            "rejected" => Some(ReturnStatus::Rejected),
+            "restocked" => Some(ReturnStatus::Restocked),
+            "refunded" => Some(ReturnStatus::Refunded),
+            _ => None,
+        }
+    }
+
+    // Kind of the order event recorded when return reaches the status
+    pub fn event(self) -> &'static str {
+        match self {
+            ReturnStatus::Requested => "return_requested",
+            ReturnStatus::Approved => "return_approved",
+            ReturnStatus::Rejected => "return_rejected",
+            ReturnStatus::Restocked => "return_restocked",
+            ReturnStatus::Refunded => "return_refunded",
+        }
+    }
+
+    fn can_become(self, next: ReturnStatus) -> bool {
+        use ReturnStatus::*;
+
+        matches!(
+            (self, next),
+            (Requested, Approved)
+                | (Requested, Rejected)
+                | (Approved, Restocked)
+                | (Restocked, Refunded)
+        )
+    }
+}
+
+// Returns are numbered within the order, refund is in minor units
+// of the order currency and is computed when return is requested
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OrderReturn {
+    pub return_id: u64,
+    pub status: ReturnStatus,
+    pub goods: BTreeMap<u64, u64>,
+    #[serde(default)]
+    pub reason: String,
+    pub refund: u64,
+    pub currency: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(Deserialize)]
+struct ReturnGood {
+    id: u64,
+    count: u64,
+}
+
+#[derive(Deserialize)]
+pub struct RequestReturn {
+    goods: Vec<ReturnGood>,
+    #[serde(default)]
+    reason: String,
+}
+
+// Returned goods are refunded with their share of line discount,
+// tax is taken from the refund the same way it was taken from the order.
+// Refunds never exceed what was paid, the return of the last goods gets
+// whatever is left, so rounding of earlier returns is evened out
+fn refund(order: &Order, goods: &BTreeMap<u64, u64>, returns: &[OrderReturn]) -> u64 {
+    let totals = pricing::totals(order);
+    let refunded: u64 = returns
+        .iter()
+        .filter(|order_return| order_return.status != ReturnStatus::Rejected)
+        .map(|order_return| order_return.refund)
+        .sum();
+    let left = totals.total.saturating_sub(refunded);
+    let returned = returned(returns);
+    let last = order.goods.iter().all(|(good_id, count)| {
+        returned.get(good_id).cloned().unwrap_or(0) + goods.get(good_id).cloned().unwrap_or(0)
+            == *count
+    });
+
+    if last {
+        return left;
+    }
+
+    let net: u64 = goods
+        .iter()
+        .map(|(good_id, count)| {
+            let line = totals.lines[good_id] - totals.discounts.get(good_id).cloned().unwrap_or(0);
+            line * count / order.goods[good_id]
+        })
+        .sum();
+    left.min(net + (net * order.tax_rate_bp + 5_000) / 10_000)
+}
+
+// Counts of goods which are returned or are being returned
+fn returned(returns: &[OrderReturn]) -> BTreeMap<u64, u64> {
+    let mut goods = BTreeMap::new();
+
+    for order_return in returns {
+        if order_return.status != ReturnStatus::Rejected {
+            for (good_id, count) in &order_return.goods {
+                *goods.entry(*good_id).or_default() += count;
+            }
+        }
+    }
+
+    goods
+}
+
+fn order(
+    user_id: &str,
+    order_id: &str,
+    storage: &dyn OrderRepository,
+) -> Result<Order, Box<dyn std::error::Error>> {
+    match storage.order(user_id, order_id)? {
+        Some(order) => Ok(order),
+        None => Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!(
+                "line:{}: Order '{}' of user '{}' does not exist",
+                line!(),
+                order_id,
+                user_id
+            ),
+        ))),
+    }
+}
+
+fn find(
+    user_id: &str,
+    order_id: &str,
+    return_id: u64,
+    storage: &dyn OrderRepository,
+) -> Result<OrderReturn, Box<dyn std::error::Error>> {
+    match storage
+        .returns(user_id, order_id)?
+        .into_iter()
+        .find(|order_return| order_return.return_id == return_id)
+    {
+        Some(order_return) => Ok(order_return),
+        None => Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!(
+                "line:{}: Return {} of order '{}' of user '{}' does not exist",
+                line!(),
+                return_id,
+                order_id,
+                user_id
+            ),
+        ))),
+    }
+}
+
+// The only way status of a return is changed
+fn change(
+    changes: &mut Changes,
+    user_id: &str,
+    order_id: &str,
+    mut order_return: OrderReturn,
+    next: ReturnStatus,
+    outbox: &Outbox,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !order_return.status.can_become(next) {
+        return Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!(
+                "line:{}: Return {} of order '{}' can't go from status '{}' to '{}'",
+                line!(),
+                order_return.return_id,
+                order_id,
+                order_return.status.as_str(),
+                next.as_str()
+            ),
+        )));
+    }
+
+    order_return.status = next;
+    order_return.updated_at = now_ms();
+    events::record(
+        changes,
+        user_id,
+        order_id,
+        EventKind::Return(next),
+        outbox.origin(),
+        order_return.return_id.to_string(),
+    );
+    changes.push(Change::PutReturn {
+        user_id: user_id.to_string(),
+        order_id: order_id.to_string(),
+        order_return,
+    });
+    Ok(())
+}
+
+impl RequestReturn {
+    // Only goods which left the warehouse can be returned,
+    // every unit of the order is returned at most once
+    pub fn request(
+        self,
+        user_id: &str,
+        order_id: &str,
+        outbox: &Outbox,
+        storage: &dyn OrderRepository,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let order = order(user_id, order_id, storage)?;
+
+        if !matches!(order.status, OrderStatus::Shipped | OrderStatus::Delivered) {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Goods of order '{}' can't be returned in status '{}'",
+                    line!(),
+                    order_id,
+                    order.status.as_str()
+                ),
+            )));
+        }
+
+        let returns = storage.returns(user_id, order_id)?;
+        let returned = returned(&returns);
+        let mut goods: BTreeMap<u64, u64> = BTreeMap::new();
+
+        for good in &self.goods {
+            *goods.entry(good.id).or_default() += good.count;
+        }
+
+        for (good_id, count) in &goods {
+            let ordered = order.goods.get(good_id).cloned().unwrap_or(0);
+            let returned = returned.get(good_id).cloned().unwrap_or(0);
+
+            if count + returned > ordered {
+                return Err(Box::new(Error::new(
+                    ErrorKind::Other,
+                    format!(
+                        "line:{}: Only {} of good {} of order '{}' can be returned",
+                        line!(),
+                        ordered.saturating_sub(returned),
+                        good_id,
+                        order_id
+                    ),
+                )));
+            }
+        }
+
+        let now = now_ms();
+        let order_return = OrderReturn {
+            return_id: storage.next_return_id(user_id, order_id)?,
+            status: ReturnStatus::Requested,
+            refund: refund(&order, &goods, &returns),
+            goods,
+            reason: self.reason,
+            currency: order.currency,
+            created_at: now,
+            updated_at: now,
+        };
+
+        let mut changes = Changes::default();
+        events::record(
+            &mut changes,
+            user_id,
+            order_id,
+            EventKind::Return(ReturnStatus::Requested),
+            outbox.origin(),
+            order_return.return_id.to_string(),
+        );
+        changes.push(Change::PutReturn {
+            user_id: user_id.to_string(),
+            order_id: order_id.to_string(),
+            order_return,
+        });
+        outbox.write(&mut changes)?;
+        storage.apply(changes)
+    }
+}
+
+// Approved goods are restocked by warehouse, refund is requested after that
+pub fn review(
+    user_id: &str,
+    order_id: &str,
+    return_id: u64,
+    approve: bool,
+    outbox: &mut Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let order_return = find(user_id, order_id, return_id, storage)?;
+    let mut changes = Changes::default();
+
+    if approve {
+        outbox.push(
+            Destination::Warehouse,
+            &[
+                ("user_id", user_id),
+                ("order_id", order_id),
+                ("operation", "return"),
+                ("return_id", &return_id.to_string()),
+            ],
+            serde_json::json!({ "goods": order_return.goods }).to_string(),
+        );
+        change(
+            &mut changes,
+            user_id,
+            order_id,
+            order_return,
+            ReturnStatus::Approved,
+            outbox,
+        )?;
+    } else {
+        change(
+            &mut changes,
+            user_id,
+            order_id,
+            order_return,
+            ReturnStatus::Rejected,
+            outbox,
+        )?;
+    }
+
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
+
+// Order is refunded once all its goods were returned and refunded
+fn complete_refund(
+    changes: &mut Changes,
+    user_id: &str,
+    order_id: &str,
+    returns: &[OrderReturn],
+    outbox: &Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut refunded: BTreeMap<u64, u64> = BTreeMap::new();
+
+    for order_return in returns {
+        if order_return.status == ReturnStatus::Refunded {
+            for (good_id, count) in &order_return.goods {
+                *refunded.entry(*good_id).or_default() += count;
+            }
+        }
+    }
+
+    let mut order = order(user_id, order_id, storage)?;
+
+    if refunded == order.goods {
+        status::change(
+            changes,
+            user_id,
+            order_id,
+            &mut order,
+            OrderStatus::Refunded,
+            outbox.origin(),
+        )?;
+        changes.push(Change::PutOrder {
+            user_id: user_id.to_string(),
+            order_id: order_id.to_string(),
+            order,
+        });
+    }
+
+    Ok(())
+}
+
+// Answers of warehouse and billing move the return forward, failed steps
+// leave it in its status, so they can be looked into by an operator
+pub fn on_reply(
+    transaction: &str,
+    status: &str,
+    return_id: u64,
+    user_id: &str,
+    order_id: &str,
+    outbox: &mut Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let order_return = find(user_id, order_id, return_id, storage)?;
+    let mut changes = Changes::default();
+
+    match (transaction, status) {
+        ("return", "commit") => {
+            outbox.push(
+                Destination::Billing,
+                &[
+                    ("user_id", user_id),
+                    ("order_id", order_id),
+                    ("operation", "refund_return"),
+                    ("return_id", &return_id.to_string()),
+                    (
+                        "refund_id",
+                        &format!(
+                            "refund:user_id:{}:order_id:{}:return_id:{}",
+                            user_id, order_id, return_id
+                        ),
+                    ),
+                ],
+                serde_json::json!({
+                    "amount": order_return.refund,
+                    "currency": order_return.currency,
+                })
+                .to_string(),
+            );
+            change(
+                &mut changes,
+                user_id,
+                order_id,
+                order_return,
+                ReturnStatus::Restocked,
+                outbox,
+            )?;
+        }
+        ("refund_return", "commit") => {
+            let mut returns = storage.returns(user_id, order_id)?;
+
+            for other in &mut returns {
+                if other.return_id == return_id {
+                    other.status = ReturnStatus::Refunded;
+                }
+            }
+
+            change(
+                &mut changes,
+                user_id,
+                order_id,
+                order_return,
+                ReturnStatus::Refunded,
+                outbox,
+            )?;
+            complete_refund(&mut changes, user_id, order_id, &returns, outbox, storage)?;
+        }
+        (transaction, _) => {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Transaction '{}' of return {} of order '{}' failed",
+                    line!(),
+                    transaction,
+                    return_id,
+                    order_id
+                ),
+            )))
+        }
+    }
+
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
@@ -0,0 +1,865 @@
+use crate::db::{commit_delete_tx, commit_tx, make_billing, rollout_tx};
+use crate::outbox::{Destination, Outbox};
+use crate::pricing::{self, Quote};
+use crate::promotions;
+use crate::schedules;
+use crate::status::{self, OrderStatus};
+use crate::storage::{now_ms, Change, Changes, OrderRepository, SagaRecord};
+use crate::{KafkaProcessingOptions, SagaOptions};
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// What answers of other services are processed with
+pub struct Context<'a> {
+    pub outbox: &'a mut Outbox,
+    pub storage: &'a dyn OrderRepository,
+    pub options: &'a SagaOptions,
+}
+
+// Order goes through the following states:
+// reserving -> reserved -> payment_pending -> paid
+// reserving -> cancelled, if warehouse rejected the order
+// reserving | payment_pending -> compensating -> compensated, if payment failed
+// or service didn't answer in time, stock is returned to warehouse
+// reserved -> compensating, paid -> refunding -> compensating, if order is cancelled
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SagaState {
+    Reserving,
+    Reserved,
+    PaymentPending,
+    Paid,
+    Refunding,
+    Compensating,
+    Compensated,
+    Cancelled,
+}
+
+impl SagaState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SagaState::Reserving => "reserving",
+            SagaState::Reserved => "reserved",
+            SagaState::PaymentPending => "payment_pending",
+            SagaState::Paid => "paid",
+            SagaState::Refunding => "refunding",
+            SagaState::Compensating => "compensating",
+            SagaState::Compensated => "compensated",
+            SagaState::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn parse(state: &str) -> Option<SagaState> {
+        match state {
+            "reserving" => Some(SagaState::Reserving),
+            "reserved" => Some(SagaState::Reserved),
+            "payment_pending" => Some(SagaState::PaymentPending),
+            "paid" => Some(SagaState::Paid),
+            "refunding" => Some(SagaState::Refunding),
+            "compensating" => Some(SagaState::Compensating),
+            "compensated" => Some(SagaState::Compensated),
+            "cancelled" => Some(SagaState::Cancelled),
+            _ => None,
+        }
+    }
+
+    // Orders created before saga was introduced have no state at all,
+    // they are treated as already reserved
+    fn can_become(current: Option<SagaState>, next: SagaState) -> bool {
+        use SagaState::*;
+
+        matches!(
+            (current, next),
+            (None, Reserving)
+                | (Some(Reserving), Reserved)
+                | (Some(Reserving), Cancelled)
+                | (None, PaymentPending)
+                | (Some(Reserved), PaymentPending)
+                | (Some(PaymentPending), Paid)
+                | (Some(Reserving), Compensating)
+                | (Some(PaymentPending), Compensating)
+                | (Some(Compensating), Compensating)
+                | (Some(Compensating), Compensated)
+                | (None, Compensating)
+                | (Some(Reserved), Compensating)
+                | (None, Refunding)
+                | (Some(Paid), Refunding)
+                | (Some(Refunding), Refunding)
+                | (Some(Refunding), Compensating)
+        )
+    }
+}
+
+pub struct Transition {
+    state: SagaState,
+    timeout_ms: Option<u64>,
+}
+
+impl Transition {
+    pub fn new(state: SagaState, timeout_ms: Option<u64>) -> Self {
+        Transition { state, timeout_ms }
+    }
+
+    pub fn write(&self, changes: &mut Changes, user_id: &str, order_id: &str) {
+        changes.push(Change::SetSaga {
+            user_id: user_id.to_string(),
+            order_id: order_id.to_string(),
+            state: self.state,
+            deadline: self.timeout_ms.map(|timeout_ms| now_ms() + timeout_ms),
+        });
+    }
+}
+
+pub fn remove(changes: &mut Changes, user_id: &str, order_id: &str) {
+    changes.push(Change::RemoveSaga {
+        user_id: user_id.to_string(),
+        order_id: order_id.to_string(),
+    });
+}
+
+pub fn state(
+    user_id: &str,
+    order_id: &str,
+    storage: &dyn OrderRepository,
+) -> Result<Option<SagaState>, Box<dyn std::error::Error>> {
+    storage.saga_state(user_id, order_id)
+}
+
+fn check_transition(
+    user_id: &str,
+    order_id: &str,
+    current: Option<SagaState>,
+    next: SagaState,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if SagaState::can_become(current, next) {
+        Ok(())
+    } else {
+        Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!(
+                "line:{}: Order '{}' of user '{}' can't go from state {:?} to {:?}",
+                line!(),
+                order_id,
+                user_id,
+                current,
+                next
+            ),
+        )))
+    }
+}
+
+// Goods of the order can be changed only while nothing is in flight
+pub fn check_modifiable(
+    user_id: &str,
+    order_id: &str,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match state(user_id, order_id, storage)? {
+        Some(SagaState::Reserving)
+        | Some(SagaState::PaymentPending)
+        | Some(SagaState::Refunding)
+        | Some(SagaState::Compensating) => Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!(
+                "line:{}: Order '{}' can't be changed while it is being processed",
+                line!(),
+                order_id
+            ),
+        ))),
+        _ => Ok(()),
+    }
+}
+
+pub fn request_payment(
+    user_id: &str,
+    order_id: &str,
+    options: &SagaOptions,
+    outbox: &mut Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let current = state(user_id, order_id, storage)?;
+    check_transition(user_id, order_id, current, SagaState::PaymentPending)?;
+
+    match storage.order(user_id, order_id)? {
+        None => Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!(
+                "line:{}: Billing can't be made, there is no order with id: {}",
+                line!(),
+                order_id
+            ),
+        ))),
+        Some(mut order) => {
+            // Billing charges the amount order was priced with
+            let payment = serde_json::json!({
+                "amount": pricing::totals(&order).total,
+                "currency": order.currency,
+            });
+            let mut changes = Changes::default();
+            status::change(
+                &mut changes,
+                user_id,
+                order_id,
+                &mut order,
+                OrderStatus::AwaitingPayment,
+                outbox.origin(),
+            )?;
+            promotions::redeem(&mut changes, user_id, order_id, &order, storage)?;
+            changes.push(Change::PutOrder {
+                user_id: user_id.to_string(),
+                order_id: order_id.to_string(),
+                order,
+            });
+            Transition::new(SagaState::PaymentPending, Some(options.payment_timeout_ms)).write(
+                &mut changes,
+                user_id,
+                order_id,
+            );
+            outbox.push(
+                Destination::Billing,
+                &[
+                    ("user_id", user_id),
+                    ("order_id", order_id),
+                    ("operation", "make_billing"),
+                ],
+                payment.to_string(),
+            );
+            outbox.write(&mut changes)?;
+            storage.apply(changes)
+        }
+    }
+}
+
+// Compensating action: reserved goods are returned to warehouse,
+// warehouse knows what was reserved for the order, so it is safe to repeat
+fn compensate(
+    user_id: &str,
+    order_id: &str,
+    current: Option<SagaState>,
+    options: &SagaOptions,
+    outbox: &mut Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    check_transition(user_id, order_id, current, SagaState::Compensating)?;
+
+    let mut changes = Changes::default();
+
+    if current == Some(SagaState::Reserving) {
+        changes.push(Change::ClearPending {
+            user_id: user_id.to_string(),
+            order_id: order_id.to_string(),
+        });
+    }
+
+    Transition::new(
+        SagaState::Compensating,
+        Some(options.compensation_timeout_ms),
+    )
+    .write(&mut changes, user_id, order_id);
+    outbox.push(
+        Destination::Warehouse,
+        &[
+            ("user_id", user_id),
+            ("order_id", order_id),
+            ("operation", "release"),
+        ],
+        "".to_string(),
+    );
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
+
+// Order is refunded at most once, whether it was cancelled
+// or paid after it was compensated
+fn refund_id(user_id: &str, order_id: &str) -> String {
+    format!("refund:user_id:{}:order_id:{}", user_id, order_id)
+}
+
+fn push_refund(user_id: &str, order_id: &str, outbox: &mut Outbox) {
+    outbox.push(
+        Destination::Billing,
+        &[
+            ("user_id", user_id),
+            ("order_id", order_id),
+            ("operation", "refund"),
+            ("refund_id", &refund_id(user_id, order_id)),
+        ],
+        "".to_string(),
+    );
+}
+
+// Billing makes refund once per its id, so request is safe to repeat
+fn request_refund(
+    user_id: &str,
+    order_id: &str,
+    current: Option<SagaState>,
+    options: &SagaOptions,
+    outbox: &mut Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    check_transition(user_id, order_id, current, SagaState::Refunding)?;
+
+    let mut changes = Changes::default();
+    Transition::new(SagaState::Refunding, Some(options.refund_timeout_ms)).write(
+        &mut changes,
+        user_id,
+        order_id,
+    );
+    push_refund(user_id, order_id, outbox);
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
+
+// Cancelled order is kept, its goods are returned to warehouse,
+// paid order is refunded before that
+pub fn cancel(
+    user_id: &str,
+    order_id: &str,
+    options: &SagaOptions,
+    outbox: &mut Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    check_modifiable(user_id, order_id, storage)?;
+
+    if storage.pending(user_id, order_id)?.is_some() {
+        return Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!(
+                "line:{}: Order '{}' can't be cancelled while it is being changed",
+                line!(),
+                order_id
+            ),
+        )));
+    }
+
+    let order = match storage.order(user_id, order_id)? {
+        Some(order) => order,
+        None => {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Order '{}' of user '{}' does not exist",
+                    line!(),
+                    order_id,
+                    user_id
+                ),
+            )))
+        }
+    };
+
+    let current = state(user_id, order_id, storage)?;
+
+    match order.status {
+        OrderStatus::Paid => request_refund(user_id, order_id, current, options, outbox, storage),
+        OrderStatus::New | OrderStatus::Reserved | OrderStatus::AwaitingPayment => {
+            compensate(user_id, order_id, current, options, outbox, storage)
+        }
+        status => Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!(
+                "line:{}: Order '{}' can't be cancelled in status '{}'",
+                line!(),
+                order_id,
+                status.as_str()
+            ),
+        ))),
+    }
+}
+
+fn complete_compensation(
+    user_id: &str,
+    order_id: &str,
+    outbox: &Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut changes = Changes::default();
+
+    if let Some(mut order) = storage.order(user_id, order_id)? {
+        status::change(
+            &mut changes,
+            user_id,
+            order_id,
+            &mut order,
+            OrderStatus::Cancelled,
+            outbox.origin(),
+        )?;
+        promotions::release(&mut changes, user_id, order_id, &order);
+        changes.push(Change::PutOrder {
+            user_id: user_id.to_string(),
+            order_id: order_id.to_string(),
+            order,
+        });
+    }
+
+    Transition::new(SagaState::Compensated, None).write(&mut changes, user_id, order_id);
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
+
+// Answers of warehouse and billing move the saga forward,
+// warehouse prices goods it reserved
+pub fn on_reply(
+    transaction: &str,
+    status: &str,
+    quote: Option<Quote>,
+    user_id: &str,
+    order_id: &str,
+    ctx: Context,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Context {
+        outbox,
+        storage,
+        options,
+    } = ctx;
+    let current = state(user_id, order_id, storage)?;
+
+    match (transaction, status) {
+        ("create", "commit") => {
+            check_transition(user_id, order_id, current, SagaState::Reserved)?;
+            let transition = Transition::new(SagaState::Reserved, None);
+
+            if let Some(quote) = &quote {
+                schedules::notify_shortage(user_id, order_id, quote.backorders(), outbox, storage)?;
+            }
+
+            commit_tx(
+                user_id,
+                order_id,
+                Some(OrderStatus::Reserved),
+                quote.as_ref(),
+                Some(&transition),
+                outbox,
+                storage,
+            )
+        }
+        ("create", "rollout") => {
+            check_transition(user_id, order_id, current, SagaState::Cancelled)?;
+            let transition = Transition::new(SagaState::Cancelled, None);
+            rollout_tx(user_id, order_id, Some(&transition), outbox, storage)
+        }
+        ("delete", "commit") => commit_delete_tx(user_id, order_id, outbox, storage),
+        // Payment came after the order timed out and was compensated,
+        // the money is returned, the order stays cancelled
+        ("billing", "commit")
+            if current == Some(SagaState::Compensating)
+                || current == Some(SagaState::Compensated) =>
+        {
+            warn!(
+                "line:{}: Order '{}' of user '{}' was paid in state {:?}, refunding",
+                line!(),
+                order_id,
+                user_id,
+                current
+            );
+            push_refund(user_id, order_id, outbox);
+            outbox.commit(storage)
+        }
+        ("billing", "commit") => {
+            check_transition(user_id, order_id, current, SagaState::Paid)?;
+            let transition = Transition::new(SagaState::Paid, None);
+            make_billing(user_id, order_id, &transition, outbox, storage)
+        }
+        ("billing", "rollout") => compensate(user_id, order_id, current, options, outbox, storage),
+        ("release", "commit") => {
+            check_transition(user_id, order_id, current, SagaState::Compensated)?;
+            complete_compensation(user_id, order_id, outbox, storage)
+        }
+        // Warehouse only confirms that aborted transaction won't be applied
+        ("abort", _) => outbox.commit(storage),
+        ("ship", "commit") => outbox.commit(storage),
+        ("ship", _) => Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!(
+                "line:{}: Warehouse couldn't create shipment of order '{}'",
+                line!(),
+                order_id
+            ),
+        ))),
+        // Refund of late payment, goods are already being returned
+        ("refund", "commit")
+            if current == Some(SagaState::Compensating)
+                || current == Some(SagaState::Compensated) =>
+        {
+            outbox.commit(storage)
+        }
+        ("refund", "commit") => compensate(user_id, order_id, current, options, outbox, storage),
+        ("refund", "rollout") => Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!(
+                "line:{}: Billing couldn't refund order '{}', it will be retried",
+                line!(),
+                order_id
+            ),
+        ))),
+        ("release", "rollout") => Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!(
+                "line:{}: Warehouse couldn't release goods of order '{}', it will be retried",
+                line!(),
+                order_id
+            ),
+        ))),
+        (_, "commit") => commit_tx(
+            user_id,
+            order_id,
+            None,
+            quote.as_ref(),
+            None,
+            outbox,
+            storage,
+        ),
+        (_, _) => rollout_tx(user_id, order_id, None, outbox, storage),
+    }
+}
+
+fn sweep_saga(
+    saga: &SagaRecord,
+    options: &SagaOptions,
+    outbox: &mut Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (user_id, order_id) = (&saga.user_id[..], &saga.order_id[..]);
+
+    match saga.state {
+        SagaState::Reserving | SagaState::PaymentPending | SagaState::Compensating => {
+            warn!(
+                "line:{}: Order '{}' of user '{}' timed out in state {:?}, compensating",
+                line!(),
+                order_id,
+                user_id,
+                saga.state
+            );
+            compensate(
+                user_id,
+                order_id,
+                Some(saga.state),
+                options,
+                outbox,
+                storage,
+            )
+        }
+        SagaState::Refunding => {
+            warn!(
+                "line:{}: Refund of order '{}' of user '{}' timed out, requesting again",
+                line!(),
+                order_id,
+                user_id
+            );
+            request_refund(
+                user_id,
+                order_id,
+                Some(saga.state),
+                options,
+                outbox,
+                storage,
+            )
+        }
+        // Saga is already finished, only its deadline is left
+        state => {
+            let mut changes = Changes::default();
+            Transition::new(state, None).write(&mut changes, user_id, order_id);
+            storage.apply(changes)
+        }
+    }
+}
+
+// Saga which can't be swept is left for the next sweep,
+// so it doesn't hold back sagas expired after it
+fn sweep_expired(
+    options: &SagaOptions,
+    processed_ttl_secs: usize,
+    storage: &dyn OrderRepository,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let now = now_ms();
+    let sagas = storage.expired_sagas(now, options.sweep_batch_size)?;
+
+    for saga in &sagas {
+        let mut outbox = Outbox::new(
+            &format!(
+                "saga:user_id:{}:order_id:{}:{}",
+                saga.user_id, saga.order_id, now
+            ),
+            processed_ttl_secs,
+        );
+
+        if let Err(e) = sweep_saga(saga, options, &mut outbox, storage) {
+            error!(
+                "line:{}: Can't sweep saga of order '{}' of user '{}': {}",
+                line!(),
+                saga.order_id,
+                saga.user_id,
+                e
+            );
+        }
+    }
+
+    Ok(sagas.len())
+}
+
+pub fn sweep(
+    options: SagaOptions,
+    processing: KafkaProcessingOptions,
+    storage: Arc<dyn OrderRepository>,
+    running: Arc<AtomicBool>,
+) {
+    while running.load(Ordering::SeqCst) {
+        match sweep_expired(&options, processing.processed_ttl_secs, storage.as_ref()) {
+            Ok(0) => {}
+            Ok(count) => info!("line:{}: Swept {} expired sagas", line!(), count),
+            Err(e) => error!("line:{}: Can't sweep expired sagas: {}", line!(), e),
+        }
+
+        std::thread::sleep(Duration::from_millis(options.sweep_interval_ms));
+    }
+
+    info!(
+        "thread id {:?}: stopping saga sweeper thread",
+        std::thread::current().id(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backorders::FulfilmentPolicy;
+    use crate::db::CreateOrder;
+    use crate::storage::MemoryStorage;
+    use serde_json::Value;
+    use std::collections::{BTreeMap, HashMap};
+
+    const USER_ID: &str = "1";
+
+    fn options() -> SagaOptions {
+        SagaOptions {
+            reservation_timeout_ms: 60_000,
+            payment_timeout_ms: 60_000,
+            refund_timeout_ms: 60_000,
+            compensation_timeout_ms: 60_000,
+            sweep_interval_ms: 1_000,
+            sweep_batch_size: 10,
+        }
+    }
+
+    fn create(storage: &dyn OrderRepository, timeout_ms: u64) -> String {
+        let goods: BTreeMap<u64, u64> = vec![(100, 2)].into_iter().collect();
+        let mut outbox = Outbox::new("create", 60);
+        CreateOrder::from_lines(&goods, None, FulfilmentPolicy::AllOrNothing)
+            .create(
+                USER_ID,
+                &Transition::new(SagaState::Reserving, Some(timeout_ms)),
+                &mut outbox,
+                storage,
+            )
+            .unwrap()
+            .to_string()
+    }
+
+    fn reply(
+        transaction: &str,
+        status: &str,
+        payload: &str,
+        order_id: &str,
+        storage: &dyn OrderRepository,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let pricing = crate::PricingOptions { tax_rate_bp: 1_000 };
+        let mut outbox = Outbox::new(&format!("{}:{}", transaction, status), 60);
+        on_reply(
+            transaction,
+            status,
+            Quote::parse(payload, &pricing)?,
+            USER_ID,
+            order_id,
+            Context {
+                outbox: &mut outbox,
+                storage,
+                options: &options(),
+            },
+        )
+    }
+
+    // Headers and payload of messages with the operation, the oldest first
+    fn sent(
+        storage: &dyn OrderRepository,
+        operation: &str,
+    ) -> Vec<(HashMap<String, String>, String)> {
+        storage
+            .outbox(100)
+            .unwrap()
+            .into_iter()
+            .map(|entry| {
+                let message: Value = serde_json::from_str(&entry.entry).unwrap();
+                let headers: HashMap<String, String> =
+                    serde_json::from_value::<Vec<(String, String)>>(message["headers"].clone())
+                        .unwrap()
+                        .into_iter()
+                        .collect();
+                (headers, message["payload"].as_str().unwrap().to_string())
+            })
+            .filter(|(headers, _)| headers["operation"] == operation)
+            .collect()
+    }
+
+    fn reserved(storage: &dyn OrderRepository) -> String {
+        let order_id = create(storage, 60_000);
+        reply(
+            "create",
+            "commit",
+            r#"{"currency": "EUR", "prices": {"100": 5}}"#,
+            &order_id,
+            storage,
+        )
+        .unwrap();
+        order_id
+    }
+
+    #[test]
+    fn order_is_stored_when_warehouse_reserves_it() {
+        let storage = MemoryStorage::new();
+        let order_id = create(&storage, 60_000);
+
+        assert!(storage.order(USER_ID, &order_id).unwrap().is_none());
+        assert!(storage.pending(USER_ID, &order_id).unwrap().is_some());
+        assert_eq!(
+            state(USER_ID, &order_id, &storage).unwrap(),
+            Some(SagaState::Reserving)
+        );
+        assert_eq!(sent(&storage, "create")[0].0["order_id"], order_id);
+
+        reply(
+            "create",
+            "commit",
+            r#"{"currency": "EUR", "prices": {"100": 5}}"#,
+            &order_id,
+            &storage,
+        )
+        .unwrap();
+
+        let order = storage.order(USER_ID, &order_id).unwrap().unwrap();
+        assert_eq!(order.status, OrderStatus::Reserved);
+        assert_eq!(pricing::totals(&order).total, 11);
+        assert!(storage.pending(USER_ID, &order_id).unwrap().is_none());
+        assert_eq!(
+            state(USER_ID, &order_id, &storage).unwrap(),
+            Some(SagaState::Reserved)
+        );
+    }
+
+    #[test]
+    fn order_rejected_by_warehouse_is_cancelled() {
+        let storage = MemoryStorage::new();
+        let order_id = create(&storage, 60_000);
+        reply("create", "rollout", "", &order_id, &storage).unwrap();
+
+        assert!(storage.order(USER_ID, &order_id).unwrap().is_none());
+        assert!(storage.pending(USER_ID, &order_id).unwrap().is_none());
+        assert_eq!(
+            state(USER_ID, &order_id, &storage).unwrap(),
+            Some(SagaState::Cancelled)
+        );
+    }
+
+    #[test]
+    fn repeated_answer_of_finished_transaction_is_rejected() {
+        let storage = MemoryStorage::new();
+        let order_id = reserved(&storage);
+
+        assert!(reply("update", "commit", "", &order_id, &storage).is_err());
+    }
+
+    #[test]
+    fn paid_order_is_shipped() {
+        let storage = MemoryStorage::new();
+        let order_id = reserved(&storage);
+        let mut outbox = Outbox::new("make_billing", 60);
+        request_payment(USER_ID, &order_id, &options(), &mut outbox, &storage).unwrap();
+
+        let (_, payment) = &sent(&storage, "make_billing")[0];
+        assert_eq!(payment, r#"{"amount":11,"currency":"EUR"}"#);
+        assert_eq!(
+            state(USER_ID, &order_id, &storage).unwrap(),
+            Some(SagaState::PaymentPending)
+        );
+
+        reply("billing", "commit", "", &order_id, &storage).unwrap();
+
+        let order = storage.order(USER_ID, &order_id).unwrap().unwrap();
+        assert_eq!(order.status, OrderStatus::Paid);
+        assert_eq!(
+            state(USER_ID, &order_id, &storage).unwrap(),
+            Some(SagaState::Paid)
+        );
+        assert_eq!(sent(&storage, "ship").len(), 1);
+    }
+
+    #[test]
+    fn failed_payment_returns_goods_and_cancels_order() {
+        let storage = MemoryStorage::new();
+        let order_id = reserved(&storage);
+        let mut outbox = Outbox::new("make_billing", 60);
+        request_payment(USER_ID, &order_id, &options(), &mut outbox, &storage).unwrap();
+
+        reply("billing", "rollout", "", &order_id, &storage).unwrap();
+        assert_eq!(sent(&storage, "release").len(), 1);
+        assert_eq!(
+            state(USER_ID, &order_id, &storage).unwrap(),
+            Some(SagaState::Compensating)
+        );
+
+        reply("release", "commit", "", &order_id, &storage).unwrap();
+        let order = storage.order(USER_ID, &order_id).unwrap().unwrap();
+        assert_eq!(order.status, OrderStatus::Cancelled);
+        assert_eq!(
+            state(USER_ID, &order_id, &storage).unwrap(),
+            Some(SagaState::Compensated)
+        );
+    }
+
+    #[test]
+    fn payment_of_compensated_order_is_refunded_once_per_order() {
+        let storage = MemoryStorage::new();
+        let order_id = reserved(&storage);
+        let mut outbox = Outbox::new("make_billing", 60);
+        request_payment(USER_ID, &order_id, &options(), &mut outbox, &storage).unwrap();
+        reply("billing", "rollout", "", &order_id, &storage).unwrap();
+        reply("release", "commit", "", &order_id, &storage).unwrap();
+
+        // Payment was made after all and is answered twice
+        reply("billing", "commit", "", &order_id, &storage).unwrap();
+        reply("billing", "commit", "", &order_id, &storage).unwrap();
+
+        let refunds = sent(&storage, "refund");
+        assert_eq!(refunds.len(), 2);
+        assert!(refunds
+            .iter()
+            .all(|(headers, _)| headers["refund_id"] == refund_id(USER_ID, &order_id)));
+        assert_eq!(
+            storage.order(USER_ID, &order_id).unwrap().unwrap().status,
+            OrderStatus::Cancelled
+        );
+        assert_eq!(
+            state(USER_ID, &order_id, &storage).unwrap(),
+            Some(SagaState::Compensated)
+        );
+    }
+
+    #[test]
+    fn expired_sagas_are_compensated() {
+        let storage = MemoryStorage::new();
+        let expired = create(&storage, 0);
+        let waiting = create(&storage, 60_000);
+        std::thread::sleep(Duration::from_millis(2));
+
+        assert_eq!(sweep_expired(&options(), 60, &storage).unwrap(), 1);
+        assert_eq!(
+            state(USER_ID, &expired, &storage).unwrap(),
+            Some(SagaState::Compensating)
+        );
+        assert!(storage.pending(USER_ID, &expired).unwrap().is_none());
+        assert_eq!(
+            state(USER_ID, &waiting, &storage).unwrap(),
+            Some(SagaState::Reserving)
+        );
+        assert_eq!(sent(&storage, "release")[0].0["order_id"], expired);
+    }
+}
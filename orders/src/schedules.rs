@@ -0,0 +1,755 @@
+use crate::backorders::FulfilmentPolicy;
+use crate::db::CreateOrder;
+use crate::delivery::Delivery;
+use crate::outbox::{Destination, Outbox};
+use crate::saga::Transition;
+use crate::storage::{now_ms, Change, Changes, OrderRepository, ScheduleRecord};
+use crate::templates::without_window;
+use crate::{KafkaProcessingOptions, ScheduleOptions};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+const MINUTE_MS: u64 = 60_000;
+const DAY_MS: u64 = 86_400_000;
+// Only the latest runs are kept with the schedule
+const RUNS_KEPT: usize = 10;
+
+// What a run does if some goods are out of stock: with 'skip' warehouse
+// rejects the order and nothing is made until the next run, with 'backorder'
+// goods in stock are reserved, the rest is backordered and the user is
+// notified that the run is short of stock
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShortagePolicy {
+    #[default]
+    Skip,
+    Backorder,
+}
+
+impl ShortagePolicy {
+    fn fulfilment(self) -> FulfilmentPolicy {
+        match self {
+            ShortagePolicy::Skip => FulfilmentPolicy::AllOrNothing,
+            ShortagePolicy::Backorder => FulfilmentPolicy::Partial,
+        }
+    }
+}
+
+// Either '{"every_days": 7}' or '{"cron": "0 9 * * 1"}', cron is in UTC
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Recurrence {
+    EveryDays(u64),
+    Cron(String),
+}
+
+impl Recurrence {
+    // Runs missed while service was down are skipped, not made all at once
+    fn next_run(&self, last: u64, now: u64) -> Result<u64, Box<dyn std::error::Error>> {
+        match self {
+            Recurrence::EveryDays(days) => {
+                let period = days * DAY_MS;
+
+                if last > now {
+                    Ok(last)
+                } else {
+                    Ok(last + period * ((now - last) / period + 1))
+                }
+            }
+            Recurrence::Cron(spec) => Cron::parse(spec)?.first_from(last.max(now) + 1),
+        }
+    }
+
+    // Schedule with days runs first right away unless its start is set
+    fn first_run(
+        &self,
+        starts_at: Option<u64>,
+        now: u64,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let start = starts_at.unwrap_or(now);
+
+        match self {
+            Recurrence::EveryDays(_) if start >= now => Ok(start),
+            Recurrence::EveryDays(_) => self.next_run(start, now),
+            Recurrence::Cron(spec) => Cron::parse(spec)?.first_from(start.max(now)),
+        }
+    }
+}
+
+// Days since epoch to month and day of month, see
+// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn month_day(days: u64) -> (u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (month, day)
+}
+
+// Bits of values the field matches, field is '*', a number or a range,
+// with optional step, or a list of them
+fn cron_field(spec: &str, min: u64, max: u64) -> Option<u64> {
+    let mut bits = 0;
+
+    for part in spec.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(i) => (&part[..i], part[i + 1..].parse::<u64>().ok()?),
+            None => (part, 1),
+        };
+        let (from, to) = if range == "*" {
+            (min, max)
+        } else {
+            match range.find('-') {
+                Some(i) => (range[..i].parse().ok()?, range[i + 1..].parse().ok()?),
+                None if step > 1 => (range.parse().ok()?, max),
+                None => {
+                    let value = range.parse().ok()?;
+                    (value, value)
+                }
+            }
+        };
+
+        if step == 0 || from < min || to > max || from > to {
+            return None;
+        }
+
+        for value in (from..=to).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+
+    Some(bits)
+}
+
+// Minute, hour, day of month, month and day of week
+struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // When both days are restricted either of them matches, as in cron
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl Cron {
+    fn from_fields(fields: &[&str]) -> Option<Cron> {
+        match *fields {
+            [minutes, hours, days, months, weekdays] => {
+                let weekday_bits = cron_field(weekdays, 0, 7)?;
+
+                Some(Cron {
+                    minutes: cron_field(minutes, 0, 59)?,
+                    hours: cron_field(hours, 0, 23)?,
+                    days: cron_field(days, 1, 31)?,
+                    months: cron_field(months, 1, 12)?,
+                    // Both 0 and 7 are Sunday
+                    weekdays: weekday_bits | ((weekday_bits >> 7) & 1),
+                    any_day: days == "*",
+                    any_weekday: weekdays == "*",
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn parse(spec: &str) -> Result<Cron, Box<dyn std::error::Error>> {
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+
+        match Cron::from_fields(&fields) {
+            Some(cron) => Ok(cron),
+            None => Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!("line:{}: Invalid cron expression '{}'", line!(), spec),
+            ))),
+        }
+    }
+
+    fn matches_day(&self, days: u64) -> bool {
+        let (month, day) = month_day(days);
+        // Epoch was on Thursday
+        let weekday = (days + 4) % 7;
+        let day_matches = self.days & (1 << day) != 0;
+        let weekday_matches = self.weekdays & (1 << weekday) != 0;
+
+        self.months & (1 << month) != 0
+            && if self.any_day || self.any_weekday {
+                day_matches && weekday_matches
+            } else {
+                day_matches || weekday_matches
+            }
+    }
+
+    // First matching minute not earlier than the moment, every combination
+    // of fields, e.g. 29th of February on Monday, comes within 28 years
+    fn first_from(&self, at: u64) -> Result<u64, Box<dyn std::error::Error>> {
+        let start = at.div_ceil(MINUTE_MS);
+        let first_day = start / 1440;
+
+        for day in first_day..first_day + 28 * 366 {
+            if !self.matches_day(day) {
+                continue;
+            }
+
+            for hour in (0..24).filter(|hour| self.hours & (1 << hour) != 0) {
+                for minute in (0..60).filter(|minute| self.minutes & (1 << minute) != 0) {
+                    let minutes = day * 1440 + hour * 60 + minute;
+
+                    if minutes >= start {
+                        return Ok(minutes * MINUTE_MS);
+                    }
+                }
+            }
+        }
+
+        Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!("line:{}: Cron expression never matches", line!()),
+        )))
+    }
+}
+
+// Order made by a run, its outcome is looked up when the schedule is viewed
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScheduleRun {
+    pub run_at: u64,
+    pub order_id: u64,
+}
+
+// Goods a user receives regularly, schedules of a user are told apart by name
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Schedule {
+    pub name: String,
+    pub goods: BTreeMap<u64, u64>,
+    #[serde(default)]
+    pub delivery: Option<Delivery>,
+    #[serde(default)]
+    pub on_shortage: ShortagePolicy,
+    pub recurrence: Recurrence,
+    pub next_run_at: u64,
+    // The oldest run first
+    #[serde(default)]
+    pub runs: Vec<ScheduleRun>,
+    pub updated_at: u64,
+}
+
+// Nulls of optional fields aren't accepted by json schema of new orders
+fn without_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .filter(|(_, value)| !value.is_null())
+                .map(|(key, value)| (key, without_nulls(value)))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+impl Schedule {
+    // Same order as a user posts to gateway
+    fn order(&self) -> Result<String, serde_json::Error> {
+        let goods: Vec<Value> = self
+            .goods
+            .iter()
+            .map(|(id, count)| serde_json::json!({ "id": id, "count": count }))
+            .collect();
+        let mut order = serde_json::json!({
+            "goods": goods,
+            "fulfilment": self.on_shortage.fulfilment().as_str(),
+        });
+
+        if let Some(delivery) = &self.delivery {
+            order["delivery"] = without_nulls(serde_json::to_value(delivery)?);
+        }
+
+        Ok(order.to_string())
+    }
+}
+
+// Outcome of a run: order is made, it is short of stock, it is still being
+// reserved or it was skipped because warehouse rejected it
+pub fn outcome(
+    user_id: &str,
+    run: &ScheduleRun,
+    storage: &dyn OrderRepository,
+) -> Result<&'static str, Box<dyn std::error::Error>> {
+    let order_id = run.order_id.to_string();
+
+    match storage.order(user_id, &order_id)? {
+        Some(order) if !order.backorders.is_empty() => Ok("short_of_stock"),
+        Some(_) => Ok("created"),
+        None if storage.pending(user_id, &order_id)?.is_some() => Ok("pending"),
+        None => Ok("skipped"),
+    }
+}
+
+#[derive(Deserialize)]
+struct ScheduleGood {
+    id: u64,
+    count: u64,
+}
+
+#[derive(Deserialize)]
+pub struct PutSchedule {
+    name: String,
+    goods: Vec<ScheduleGood>,
+    #[serde(default)]
+    delivery: Option<Delivery>,
+    #[serde(default)]
+    on_shortage: ShortagePolicy,
+    recurrence: Recurrence,
+    #[serde(default)]
+    starts_at: Option<u64>,
+}
+
+impl PutSchedule {
+    // Schedule with the same name is replaced, its runs are kept
+    pub fn put(
+        self,
+        user_id: &str,
+        outbox: &Outbox,
+        storage: &dyn OrderRepository,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(delivery) = &self.delivery {
+            delivery.check()?;
+        }
+
+        let now = now_ms();
+        let next_run_at = self.recurrence.first_run(self.starts_at, now)?;
+        let mut goods = BTreeMap::new();
+
+        for good in &self.goods {
+            *goods.entry(good.id).or_default() += good.count;
+        }
+
+        let runs = storage
+            .schedule(user_id, &self.name)?
+            .map(|schedule| schedule.runs)
+            .unwrap_or_default();
+
+        let mut changes = Changes::default();
+        changes.push(Change::PutSchedule {
+            user_id: user_id.to_string(),
+            schedule: Schedule {
+                name: self.name,
+                goods,
+                delivery: without_window(self.delivery),
+                on_shortage: self.on_shortage,
+                recurrence: self.recurrence,
+                next_run_at,
+                runs,
+                updated_at: now,
+            },
+        });
+        outbox.write(&mut changes)?;
+        storage.apply(changes)
+    }
+}
+
+pub fn delete(
+    user_id: &str,
+    name: &str,
+    outbox: &Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut changes = Changes::default();
+    changes.push(Change::DeleteSchedule {
+        user_id: user_id.to_string(),
+        name: name.to_string(),
+    });
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
+
+// Order sent by scheduler is created as any other one and is remembered
+// as a run of the schedule in the same storage transaction
+pub fn start_run(
+    order: &CreateOrder,
+    user_id: &str,
+    name: &str,
+    run_at: u64,
+    transition: &Transition,
+    outbox: &mut Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut schedule = match storage.schedule(user_id, name)? {
+        Some(schedule) => schedule,
+        None => {
+            warn!(
+                "line:{}: Schedule '{}' of user '{}' was deleted, its run isn't made",
+                line!(),
+                name,
+                user_id
+            );
+            return outbox.commit(storage);
+        }
+    };
+
+    let mut changes = Changes::default();
+    let order_id = order.begin(&mut changes, user_id, transition, outbox, storage)?;
+    schedule.runs.push(ScheduleRun {
+        run_at,
+        order_id: order_id as u64,
+    });
+
+    if schedule.runs.len() > RUNS_KEPT {
+        let extra = schedule.runs.len() - RUNS_KEPT;
+        schedule.runs.drain(..extra);
+    }
+
+    changes.push(Change::PutSchedule {
+        user_id: user_id.to_string(),
+        schedule,
+    });
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
+
+// Run of a schedule with 'backorder' policy which warehouse couldn't fill
+// is reported to the user with goods which are backordered
+pub fn notify_shortage(
+    user_id: &str,
+    order_id: &str,
+    backorders: &BTreeMap<u64, u64>,
+    outbox: &mut Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if backorders.is_empty() {
+        return Ok(());
+    }
+
+    let id: u64 = order_id.parse()?;
+    let schedule = storage
+        .schedules(user_id)?
+        .into_iter()
+        .find(|schedule| schedule.runs.iter().any(|run| run.order_id == id));
+
+    if let Some(schedule) = schedule {
+        outbox.push(
+            Destination::Notifications,
+            &[
+                ("user_id", user_id),
+                ("order_id", order_id),
+                ("operation", "short_of_stock"),
+                ("schedule", &schedule.name),
+            ],
+            serde_json::to_string(backorders)?,
+        );
+    }
+
+    Ok(())
+}
+
+fn run_schedule(
+    record: &ScheduleRecord,
+    now: u64,
+    processed_ttl_secs: usize,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (user_id, mut schedule) = (&record.user_id[..], record.schedule.clone());
+    let run_at = schedule.next_run_at.to_string();
+
+    // Id is the same for every attempt of the run, so orders service
+    // makes one order even if the run is sent more than once
+    let mut outbox = Outbox::new(
+        &format!(
+            "schedule:user_id:{}:name:{}:{}",
+            user_id, schedule.name, run_at
+        ),
+        processed_ttl_secs,
+    );
+    outbox.push(
+        Destination::Orders,
+        &[
+            ("user_id", user_id),
+            ("operation", "create"),
+            ("actor", "scheduler"),
+            ("schedule", &schedule.name),
+            ("run_at", &run_at),
+        ],
+        schedule.order()?,
+    );
+
+    schedule.next_run_at = schedule.recurrence.next_run(schedule.next_run_at, now)?;
+    let mut changes = Changes::default();
+    changes.push(Change::PutSchedule {
+        user_id: user_id.to_string(),
+        schedule,
+    });
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
+
+// Run which failed is skipped, so the schedule isn't tried again on every
+// sweep, schedule whose recurrence can't be read is tried again a day later
+fn skip_run(
+    record: &ScheduleRecord,
+    now: u64,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut schedule = record.schedule.clone();
+    schedule.next_run_at = schedule
+        .recurrence
+        .next_run(schedule.next_run_at, now)
+        .unwrap_or(now + DAY_MS);
+
+    let mut changes = Changes::default();
+    changes.push(Change::PutSchedule {
+        user_id: record.user_id.clone(),
+        schedule,
+    });
+    storage.apply(changes)
+}
+
+// Schedule which can't be run is reported and the others are run anyway
+fn run_due(
+    options: &ScheduleOptions,
+    processed_ttl_secs: usize,
+    storage: &dyn OrderRepository,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let now = now_ms();
+    let due = storage.due_schedules(now, options.sweep_batch_size)?;
+
+    for record in &due {
+        if let Err(e) = run_schedule(record, now, processed_ttl_secs, storage) {
+            error!(
+                "line:{}: Can't run schedule '{}' of user '{}': {}",
+                line!(),
+                record.schedule.name,
+                record.user_id,
+                e
+            );
+
+            if let Err(e) = skip_run(record, now, storage) {
+                error!(
+                    "line:{}: Can't skip run of schedule '{}' of user '{}': {}",
+                    line!(),
+                    record.schedule.name,
+                    record.user_id,
+                    e
+                );
+            }
+        }
+    }
+
+    Ok(due.len())
+}
+
+pub fn sweep(
+    options: ScheduleOptions,
+    processing: KafkaProcessingOptions,
+    storage: Arc<dyn OrderRepository>,
+    running: Arc<AtomicBool>,
+) {
+    while running.load(Ordering::SeqCst) {
+        match run_due(&options, processing.processed_ttl_secs, storage.as_ref()) {
+            Ok(0) => {}
+            Ok(count) => info!("line:{}: Ran {} scheduled orders", line!(), count),
+            Err(e) => error!("line:{}: Can't run scheduled orders: {}", line!(), e),
+        }
+
+        std::thread::sleep(Duration::from_millis(options.sweep_interval_ms));
+    }
+
+    info!(
+        "thread id {:?}: stopping scheduler thread",
+        std::thread::current().id(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn bits(values: &[u64]) -> u64 {
+        values.iter().fold(0, |bits, value| bits | 1 << value)
+    }
+
+    fn next(spec: &str, at: u64) -> u64 {
+        Cron::parse(spec).unwrap().first_from(at).unwrap()
+    }
+
+    #[test]
+    fn cron_field_takes_values_ranges_steps_and_lists() {
+        assert_eq!(
+            cron_field("*", 1, 12),
+            Some(bits(&(1..=12).collect::<Vec<_>>()))
+        );
+        assert_eq!(cron_field("5", 0, 59), Some(bits(&[5])));
+        assert_eq!(cron_field("1-3", 0, 59), Some(bits(&[1, 2, 3])));
+        assert_eq!(cron_field("*/15", 0, 59), Some(bits(&[0, 15, 30, 45])));
+        assert_eq!(cron_field("10/20", 0, 59), Some(bits(&[10, 30, 50])));
+        assert_eq!(cron_field("1-10/4", 0, 59), Some(bits(&[1, 5, 9])));
+        assert_eq!(cron_field("1,3,20-21", 0, 59), Some(bits(&[1, 3, 20, 21])));
+    }
+
+    #[test]
+    fn cron_field_rejects_values_out_of_bounds() {
+        assert_eq!(cron_field("60", 0, 59), None);
+        assert_eq!(cron_field("0", 1, 31), None);
+        assert_eq!(cron_field("5-1", 0, 59), None);
+        assert_eq!(cron_field("*/0", 0, 59), None);
+        assert_eq!(cron_field("a", 0, 59), None);
+        assert_eq!(cron_field("1,", 0, 59), None);
+    }
+
+    #[test]
+    fn cron_needs_five_fields() {
+        assert!(Cron::parse("0 9 * *").is_err());
+        assert!(Cron::parse("0 9 * * 1 2").is_err());
+        assert!(Cron::parse("0 24 * * *").is_err());
+    }
+
+    #[test]
+    fn month_day_follows_calendar() {
+        assert_eq!(month_day(0), (1, 1));
+        assert_eq!(month_day(20_088), (12, 31));
+        // 2000 is a leap year, 2100 is not
+        assert_eq!(month_day(11_016), (2, 29));
+        assert_eq!(month_day(11_017), (3, 1));
+        assert_eq!(month_day(47_540), (2, 28));
+        assert_eq!(month_day(47_541), (3, 1));
+    }
+
+    #[test]
+    fn cron_runs_on_weekday() {
+        // 1970-01-01 was Thursday, the next Monday is 5th
+        assert_eq!(next("0 9 * * 1", 0), 378_000_000);
+        // Both 0 and 7 are Sunday, 2023-10-01 was Sunday
+        assert_eq!(next("0 0 * * 7", 1_696_118_400_000), 1_696_118_400_000);
+        assert_eq!(next("0 0 * * 0", 1_696_118_400_001), 1_696_723_200_000);
+    }
+
+    #[test]
+    fn cron_skips_months_without_the_day() {
+        // From 2021-02-01 to 2021-03-31
+        assert_eq!(next("0 0 31 * *", 1_612_137_600_000), 1_617_148_800_000);
+        // From 2021-03-01 to 2024-02-29
+        assert_eq!(next("0 0 29 2 *", 1_614_556_800_000), 1_709_164_800_000);
+    }
+
+    #[test]
+    fn cron_matches_either_restricted_day() {
+        // From Sunday 2023-10-01 Friday 6th comes before 13th
+        assert_eq!(next("0 0 13 * 5", 1_696_118_400_000), 1_696_550_400_000);
+        // With one of them unrestricted only the other one counts
+        assert_eq!(next("0 0 13 * *", 1_696_118_400_000), 1_697_155_200_000);
+        assert_eq!(next("0 0 * * 5", 1_696_118_400_000), 1_696_550_400_000);
+    }
+
+    #[test]
+    fn cron_runs_at_the_moment_or_the_next_minute() {
+        assert_eq!(next("*/15 * * * *", 15 * MINUTE_MS), 15 * MINUTE_MS);
+        assert_eq!(next("*/15 * * * *", 15 * MINUTE_MS + 1), 30 * MINUTE_MS);
+        assert_eq!(next("0 0 1 1 *", 1), 365 * DAY_MS);
+    }
+
+    #[test]
+    fn cron_never_matching_is_an_error() {
+        assert!(Cron::parse("0 0 31 2 *").unwrap().first_from(0).is_err());
+    }
+
+    #[test]
+    fn next_run_skips_missed_runs() {
+        let weekly = Recurrence::EveryDays(7);
+        assert_eq!(weekly.next_run(0, 10 * DAY_MS).unwrap(), 14 * DAY_MS);
+        assert_eq!(weekly.next_run(0, 14 * DAY_MS).unwrap(), 21 * DAY_MS);
+        assert_eq!(weekly.next_run(20 * DAY_MS, DAY_MS).unwrap(), 20 * DAY_MS);
+
+        let hourly = Recurrence::Cron("0 * * * *".to_string());
+        assert_eq!(hourly.next_run(0, 0).unwrap(), 60 * MINUTE_MS);
+        assert_eq!(
+            hourly.next_run(0, 5 * 60 * MINUTE_MS + 1).unwrap(),
+            6 * 60 * MINUTE_MS
+        );
+    }
+
+    #[test]
+    fn first_run_is_right_away_or_at_start() {
+        let weekly = Recurrence::EveryDays(7);
+        assert_eq!(weekly.first_run(None, DAY_MS).unwrap(), DAY_MS);
+        assert_eq!(
+            weekly.first_run(Some(3 * DAY_MS), DAY_MS).unwrap(),
+            3 * DAY_MS
+        );
+        assert_eq!(weekly.first_run(Some(0), DAY_MS).unwrap(), 7 * DAY_MS);
+    }
+
+    fn put(storage: &dyn OrderRepository, recurrence: Recurrence, runs: Vec<ScheduleRun>) {
+        let mut changes = Changes::default();
+        changes.push(Change::PutSchedule {
+            user_id: "1".to_string(),
+            schedule: Schedule {
+                name: "weekly".to_string(),
+                goods: vec![(1, 2)].into_iter().collect(),
+                delivery: None,
+                on_shortage: ShortagePolicy::Backorder,
+                recurrence,
+                next_run_at: 0,
+                runs,
+                updated_at: 0,
+            },
+        });
+        storage.apply(changes).unwrap();
+    }
+
+    #[test]
+    fn failed_run_is_skipped() {
+        let storage = MemoryStorage::new();
+        put(&storage, Recurrence::Cron("bad".to_string()), vec![]);
+        let options = ScheduleOptions {
+            sweep_interval_ms: 1_000,
+            sweep_batch_size: 10,
+        };
+        let now = now_ms();
+
+        assert_eq!(run_due(&options, 60, &storage).unwrap(), 1);
+        let schedule = storage.schedule("1", "weekly").unwrap().unwrap();
+        assert!(schedule.next_run_at >= now + DAY_MS);
+        assert!(storage.outbox(10).unwrap().is_empty());
+        assert_eq!(run_due(&options, 60, &storage).unwrap(), 0);
+    }
+
+    #[test]
+    fn shortage_of_scheduled_run_is_notified() {
+        let storage = MemoryStorage::new();
+        put(
+            &storage,
+            Recurrence::EveryDays(7),
+            vec![ScheduleRun {
+                run_at: 0,
+                order_id: 5,
+            }],
+        );
+        let backorders: BTreeMap<u64, u64> = vec![(1, 1)].into_iter().collect();
+
+        let mut outbox = Outbox::new("reply", 60);
+        notify_shortage("1", "5", &backorders, &mut outbox, &storage).unwrap();
+        notify_shortage("1", "5", &BTreeMap::new(), &mut outbox, &storage).unwrap();
+        notify_shortage("1", "6", &backorders, &mut outbox, &storage).unwrap();
+        outbox.commit(&storage).unwrap();
+
+        let sent = storage.outbox(10).unwrap();
+        assert_eq!(sent.len(), 1);
+        let message: Value = serde_json::from_str(&sent[0].entry).unwrap();
+        assert_eq!(message["destination"], "notifications");
+        assert_eq!(message["payload"], r#"{"1":1}"#);
+        assert!(message["headers"]
+            .as_array()
+            .unwrap()
+            .contains(&serde_json::json!(["schedule", "weekly"])));
+    }
+}
@@ -0,0 +1,133 @@
+use crate::events::{self, EventKind, Origin};
+use crate::storage::{Changes, Order};
+use std::io::{Error, ErrorKind};
+
+// Order goes through the following statuses:
+// new -> reserved -> awaiting_payment -> paid -> shipped -> delivered
+// new | reserved | awaiting_payment | paid -> cancelled, if goods or payment were
+// rejected or order was cancelled, paid order is refunded before that
+// paid | shipped | delivered -> refunded
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OrderStatus {
+    #[default]
+    New,
+    Reserved,
+    AwaitingPayment,
+    Paid,
+    Shipped,
+    Delivered,
+    Cancelled,
+    Refunded,
+}
+
+impl OrderStatus {
+    pub const ALL: [OrderStatus; 8] = [
+        OrderStatus::New,
+        OrderStatus::Reserved,
+        OrderStatus::AwaitingPayment,
+        OrderStatus::Paid,
+        OrderStatus::Shipped,
+        OrderStatus::Delivered,
+        OrderStatus::Cancelled,
+        OrderStatus::Refunded,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OrderStatus::New => "new",
+            OrderStatus::Reserved => "reserved",
+            OrderStatus::AwaitingPayment => "awaiting_payment",
+            OrderStatus::Paid => "paid",
+            OrderStatus::Shipped => "shipped",
+            OrderStatus::Delivered => "delivered",
+            OrderStatus::Cancelled => "cancelled",
+            OrderStatus::Refunded => "refunded",
+        }
+    }
+
+    // Orders paid before statuses were introduced are stored as 'payed'
+    pub fn parse(status: &str) -> Option<OrderStatus> {
+        match status {
+            "new" => Some(OrderStatus::New),
+            "reserved" => Some(OrderStatus::Reserved),
+            "awaiting_payment" => Some(OrderStatus::AwaitingPayment),
+            "paid" | "payed" => Some(OrderStatus::Paid),
+            "shipped" => Some(OrderStatus::Shipped),
+            "delivered" => Some(OrderStatus::Delivered),
+            "cancelled" => Some(OrderStatus::Cancelled),
+            "refunded" => Some(OrderStatus::Refunded),
+            _ => None,
+        }
+    }
+
+    // Orders reserved before statuses were introduced stay new,
+    // so they can be paid or cancelled as well
+    fn can_become(self, next: OrderStatus) -> bool {
+        use OrderStatus::*;
+
+        matches!(
+            (self, next),
+            (New, Reserved)
+                | (New, AwaitingPayment)
+                | (New, Cancelled)
+                | (Reserved, AwaitingPayment)
+                | (Reserved, Cancelled)
+                | (AwaitingPayment, Paid)
+                | (AwaitingPayment, Cancelled)
+                | (Paid, Cancelled)
+                | (Paid, Shipped)
+                | (Paid, Refunded)
+                | (Shipped, Delivered)
+                | (Shipped, Refunded)
+                | (Delivered, Refunded)
+        )
+    }
+
+    // Goods can be changed only until payment is requested
+    pub fn is_modifiable(self) -> bool {
+        matches!(self, OrderStatus::New | OrderStatus::Reserved)
+    }
+
+    // Statuses which are set by other services, the rest follow the saga
+    pub fn is_external(self) -> bool {
+        matches!(
+            self,
+            OrderStatus::Shipped | OrderStatus::Delivered | OrderStatus::Refunded
+        )
+    }
+}
+
+// The only way status of an order is changed, order itself is written by caller
+pub fn change(
+    changes: &mut Changes,
+    user_id: &str,
+    order_id: &str,
+    order: &mut Order,
+    next: OrderStatus,
+    origin: &Origin,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !order.status.can_become(next) {
+        return Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!(
+                "line:{}: Order '{}' of user '{}' can't go from status '{}' to '{}'",
+                line!(),
+                order_id,
+                user_id,
+                order.status.as_str(),
+                next.as_str()
+            ),
+        )));
+    }
+
+    order.status = next;
+    events::record(
+        changes,
+        user_id,
+        order_id,
+        EventKind::Status(next),
+        origin,
+        String::new(),
+    );
+    Ok(())
+}
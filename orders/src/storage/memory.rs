@@ -0,0 +1,739 @@
+use super::{
+    check_promotion_uses, check_version, now_ms, page, Change, Changes, Conflict, Order, OrderPage,
+    OrderQuery, OrderRepository, OrderScan, OrderSearch, OutboxEntry, SagaRecord, ScheduleRecord,
+    TransactionRecord,
+};
+use crate::events::OrderEvent;
+use crate::promotions::{Promotion, PromotionUse};
+use crate::returns::OrderReturn;
+use crate::saga::SagaState;
+use crate::schedules::Schedule;
+use crate::templates::OrderTemplate;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::io::{Error, ErrorKind};
+use std::sync::{Mutex, MutexGuard};
+
+type OrderKey = (String, String);
+
+#[derive(Default)]
+struct State {
+    order_id: i64,
+    orders: HashMap<OrderKey, Order>,
+    pending: HashMap<OrderKey, Order>,
+    events: HashMap<OrderKey, Vec<OrderEvent>>,
+    sagas: HashMap<OrderKey, (SagaState, Option<u64>)>,
+    transactions: HashMap<OrderKey, TransactionRecord>,
+    promotions: BTreeMap<String, Promotion>,
+    promotion_uses: HashMap<String, BTreeSet<OrderKey>>,
+    returns: HashMap<OrderKey, BTreeMap<u64, OrderReturn>>,
+    return_ids: HashMap<OrderKey, u64>,
+    templates: HashMap<String, BTreeMap<String, OrderTemplate>>,
+    schedules: HashMap<String, BTreeMap<String, Schedule>>,
+    processed: HashMap<String, u64>,
+    outbox: VecDeque<OutboxEntry>,
+    outbox_ids: u64,
+    outbox_lock: Option<(String, u64)>,
+}
+
+fn key(user_id: &str, order_id: &str) -> OrderKey {
+    (user_id.to_string(), order_id.to_string())
+}
+
+// Keeps everything in process memory, nothing survives restart,
+// it is meant for tests and local runs without databases
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: Mutex<State>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+
+    fn state(&self) -> Result<MutexGuard<'_, State>, Box<dyn std::error::Error>> {
+        self.state.lock().map_err(|e| {
+            Box::new(Error::new(
+                ErrorKind::Other,
+                format!("line:{}: Memory storage is poisoned: {}", line!(), e),
+            )) as Box<dyn std::error::Error>
+        })
+    }
+}
+
+impl OrderRepository for MemoryStorage {
+    fn next_order_id(&self) -> Result<i64, Box<dyn std::error::Error>> {
+        let mut state = self.state()?;
+        state.order_id += 1;
+        Ok(state.order_id)
+    }
+
+    fn next_return_id(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let mut state = self.state()?;
+        let return_id = state.return_ids.entry(key(user_id, order_id)).or_default();
+        *return_id += 1;
+        Ok(*return_id)
+    }
+
+    fn order(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Option<Order>, Box<dyn std::error::Error>> {
+        Ok(self.state()?.orders.get(&key(user_id, order_id)).cloned())
+    }
+
+    fn orders(
+        &self,
+        user_id: &str,
+        query: &OrderQuery,
+    ) -> Result<OrderPage, Box<dyn std::error::Error>> {
+        let state = self.state()?;
+        let mut orders = vec![];
+
+        for ((owner, order_id), order) in &state.orders {
+            if owner == user_id {
+                orders.push((order_id.parse()?, order.clone()));
+            }
+        }
+
+        Ok(page(orders, query))
+    }
+
+    fn search_orders(
+        &self,
+        search: &OrderSearch,
+        cursor: u64,
+    ) -> Result<OrderScan, Box<dyn std::error::Error>> {
+        let state = self.state()?;
+        let mut orders = vec![];
+
+        for ((user_id, order_id), order) in &state.orders {
+            let order_id: u64 = order_id.parse()?;
+
+            if order_id > cursor && search.matches(user_id, order_id, order) {
+                orders.push((user_id.clone(), order_id, order.clone()));
+            }
+        }
+
+        orders.sort_by_key(|(_, order_id, _)| *order_id);
+        let next = if orders.len() > search.query.limit {
+            orders.truncate(search.query.limit);
+            orders.last().map(|(_, order_id, _)| *order_id)
+        } else {
+            None
+        };
+
+        Ok(OrderScan { orders, next })
+    }
+
+    fn events(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Vec<OrderEvent>, Box<dyn std::error::Error>> {
+        Ok(self
+            .state()?
+            .events
+            .get(&key(user_id, order_id))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn pending(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Option<Order>, Box<dyn std::error::Error>> {
+        Ok(self.state()?.pending.get(&key(user_id, order_id)).cloned())
+    }
+
+    fn promotion(&self, code: &str) -> Result<Option<Promotion>, Box<dyn std::error::Error>> {
+        Ok(self.state()?.promotions.get(code).cloned())
+    }
+
+    fn promotions(&self) -> Result<Vec<Promotion>, Box<dyn std::error::Error>> {
+        Ok(self.state()?.promotions.values().cloned().collect())
+    }
+
+    fn promotion_uses(&self, code: &str) -> Result<Vec<PromotionUse>, Box<dyn std::error::Error>> {
+        Ok(self
+            .state()?
+            .promotion_uses
+            .get(code)
+            .map(|uses| {
+                uses.iter()
+                    .map(|(user_id, order_id)| PromotionUse {
+                        user_id: user_id.clone(),
+                        order_id: order_id.clone(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn returns(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Vec<OrderReturn>, Box<dyn std::error::Error>> {
+        Ok(self
+            .state()?
+            .returns
+            .get(&key(user_id, order_id))
+            .map(|returns| returns.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn template(
+        &self,
+        user_id: &str,
+        name: &str,
+    ) -> Result<Option<OrderTemplate>, Box<dyn std::error::Error>> {
+        Ok(self
+            .state()?
+            .templates
+            .get(user_id)
+            .and_then(|templates| templates.get(name))
+            .cloned())
+    }
+
+    fn templates(&self, user_id: &str) -> Result<Vec<OrderTemplate>, Box<dyn std::error::Error>> {
+        Ok(self
+            .state()?
+            .templates
+            .get(user_id)
+            .map(|templates| templates.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn schedule(
+        &self,
+        user_id: &str,
+        name: &str,
+    ) -> Result<Option<Schedule>, Box<dyn std::error::Error>> {
+        Ok(self
+            .state()?
+            .schedules
+            .get(user_id)
+            .and_then(|schedules| schedules.get(name))
+            .cloned())
+    }
+
+    fn schedules(&self, user_id: &str) -> Result<Vec<Schedule>, Box<dyn std::error::Error>> {
+        Ok(self
+            .state()?
+            .schedules
+            .get(user_id)
+            .map(|schedules| schedules.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn due_schedules(
+        &self,
+        now: u64,
+        limit: usize,
+    ) -> Result<Vec<ScheduleRecord>, Box<dyn std::error::Error>> {
+        let state = self.state()?;
+        let mut schedules: Vec<ScheduleRecord> = state
+            .schedules
+            .iter()
+            .flat_map(|(user_id, schedules)| {
+                schedules
+                    .values()
+                    .filter(|schedule| schedule.next_run_at <= now)
+                    .map(move |schedule| ScheduleRecord {
+                        user_id: user_id.clone(),
+                        schedule: schedule.clone(),
+                    })
+            })
+            .collect();
+
+        schedules.sort_by_key(|record| record.schedule.next_run_at);
+        schedules.truncate(limit);
+        Ok(schedules)
+    }
+
+    fn saga_state(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Option<SagaState>, Box<dyn std::error::Error>> {
+        Ok(self
+            .state()?
+            .sagas
+            .get(&key(user_id, order_id))
+            .map(|(state, _)| *state))
+    }
+
+    fn expired_sagas(
+        &self,
+        now: u64,
+        limit: usize,
+    ) -> Result<Vec<SagaRecord>, Box<dyn std::error::Error>> {
+        let state = self.state()?;
+        let mut sagas: Vec<(u64, SagaRecord)> = state
+            .sagas
+            .iter()
+            .filter_map(|((user_id, order_id), (state, deadline))| match deadline {
+                Some(deadline) if *deadline <= now => Some((
+                    *deadline,
+                    SagaRecord {
+                        user_id: user_id.clone(),
+                        order_id: order_id.clone(),
+                        state: *state,
+                    },
+                )),
+                _ => None,
+            })
+            .collect();
+
+        sagas.sort_by_key(|(deadline, _)| *deadline);
+        Ok(sagas
+            .into_iter()
+            .take(limit)
+            .map(|(_, saga)| saga)
+            .collect())
+    }
+
+    fn transactions(&self) -> Result<Vec<TransactionRecord>, Box<dyn std::error::Error>> {
+        let mut result: Vec<TransactionRecord> =
+            self.state()?.transactions.values().cloned().collect();
+        result.sort_by_key(|record| record.deadline);
+        Ok(result)
+    }
+
+    fn expired_transactions(
+        &self,
+        now: u64,
+        limit: usize,
+    ) -> Result<Vec<TransactionRecord>, Box<dyn std::error::Error>> {
+        Ok(self
+            .transactions()?
+            .into_iter()
+            .filter(|record| record.deadline <= now)
+            .take(limit)
+            .collect())
+    }
+
+    fn is_processed(&self, message_id: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self
+            .state()?
+            .processed
+            .get(message_id)
+            .is_some_and(|expires_at| *expires_at > now_ms()))
+    }
+
+    fn purge_processed(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let now = now_ms();
+        self.state()?
+            .processed
+            .retain(|_, expires_at| *expires_at > now);
+        Ok(())
+    }
+
+    // Everything is checked before the first change, so changes are
+    // applied either all or none
+    fn apply(&self, changes: Changes) -> Result<(), Box<dyn std::error::Error>> {
+        let mut state = self.state()?;
+
+        for change in &changes.0 {
+            match change {
+                Change::BeginPending {
+                    user_id,
+                    order_id,
+                    order,
+                } => {
+                    let key = key(user_id, order_id);
+
+                    if state.pending.contains_key(&key) {
+                        return Err(Box::new(Conflict(format!(
+                            "line:{}: Transaction of order '{}' of user '{}' already exists",
+                            line!(),
+                            order_id,
+                            user_id
+                        ))));
+                    }
+
+                    let stored = state.orders.get(&key).map(|order| order.version);
+                    check_version(user_id, order_id, stored, order)?;
+                }
+                Change::PutOrder {
+                    user_id,
+                    order_id,
+                    order,
+                } => {
+                    let stored = state
+                        .orders
+                        .get(&key(user_id, order_id))
+                        .map(|order| order.version);
+                    check_version(user_id, order_id, stored, order)?;
+                }
+                Change::AddPromotionUse {
+                    code,
+                    user_id,
+                    order_id,
+                    max_uses,
+                    max_uses_per_user,
+                } => {
+                    let others: Vec<&OrderKey> = state
+                        .promotion_uses
+                        .get(code)
+                        .into_iter()
+                        .flatten()
+                        .filter(|used| **used != key(user_id, order_id))
+                        .collect();
+                    let user_uses = others.iter().filter(|used| used.0 == *user_id).count();
+                    check_promotion_uses(
+                        code,
+                        others.len() as u64,
+                        user_uses as u64,
+                        *max_uses,
+                        *max_uses_per_user,
+                    )?;
+                }
+                _ => {}
+            }
+        }
+
+        for change in changes.0 {
+            match change {
+                Change::PutOrder {
+                    user_id,
+                    order_id,
+                    mut order,
+                } => {
+                    order.updated_at = now_ms();
+                    order.version += 1;
+                    state.orders.insert((user_id, order_id), order);
+                }
+                Change::DeleteOrder { user_id, order_id } => {
+                    let key = (user_id, order_id);
+                    state.orders.remove(&key);
+                    state.events.remove(&key);
+                    state.returns.remove(&key);
+                    state.return_ids.remove(&key);
+                }
+                Change::BeginPending {
+                    user_id,
+                    order_id,
+                    order,
+                } => {
+                    state.pending.insert((user_id, order_id), order);
+                }
+                Change::ClearPending { user_id, order_id } => {
+                    state.pending.remove(&(user_id, order_id));
+                }
+                Change::SetSaga {
+                    user_id,
+                    order_id,
+                    state: saga_state,
+                    deadline,
+                } => {
+                    state
+                        .sagas
+                        .insert((user_id, order_id), (saga_state, deadline));
+                }
+                Change::RemoveSaga { user_id, order_id } => {
+                    state.sagas.remove(&(user_id, order_id));
+                }
+                Change::BeginTransaction(record) => {
+                    state
+                        .transactions
+                        .insert(key(&record.user_id, &record.order_id), record);
+                }
+                Change::RetryTransaction {
+                    user_id,
+                    order_id,
+                    deadline,
+                } => {
+                    if let Some(record) = state.transactions.get_mut(&(user_id, order_id)) {
+                        record.attempts += 1;
+                        record.deadline = deadline;
+                    }
+                }
+                Change::FinishTransaction { user_id, order_id } => {
+                    state.transactions.remove(&(user_id, order_id));
+                }
+                Change::RecordEvent {
+                    user_id,
+                    order_id,
+                    event,
+                } => {
+                    state
+                        .events
+                        .entry((user_id, order_id))
+                        .or_default()
+                        .push(event);
+                }
+                Change::PutPromotion(promotion) => {
+                    state.promotions.insert(promotion.code.clone(), promotion);
+                }
+                Change::DeletePromotion { code } => {
+                    state.promotions.remove(&code);
+                }
+                Change::AddPromotionUse {
+                    code,
+                    user_id,
+                    order_id,
+                    ..
+                } => {
+                    state
+                        .promotion_uses
+                        .entry(code)
+                        .or_default()
+                        .insert((user_id, order_id));
+                }
+                Change::RemovePromotionUse {
+                    code,
+                    user_id,
+                    order_id,
+                } => {
+                    if let Some(uses) = state.promotion_uses.get_mut(&code) {
+                        uses.remove(&(user_id, order_id));
+                    }
+                }
+                Change::PutReturn {
+                    user_id,
+                    order_id,
+                    order_return,
+                } => {
+                    state
+                        .returns
+                        .entry((user_id, order_id))
+                        .or_default()
+                        .insert(order_return.return_id, order_return);
+                }
+                Change::PutTemplate { user_id, template } => {
+                    state
+                        .templates
+                        .entry(user_id)
+                        .or_default()
+                        .insert(template.name.clone(), template);
+                }
+                Change::DeleteTemplate { user_id, name } => {
+                    if let Some(templates) = state.templates.get_mut(&user_id) {
+                        templates.remove(&name);
+                    }
+                }
+                Change::PutSchedule { user_id, schedule } => {
+                    state
+                        .schedules
+                        .entry(user_id)
+                        .or_default()
+                        .insert(schedule.name.clone(), schedule);
+                }
+                Change::DeleteSchedule { user_id, name } => {
+                    if let Some(schedules) = state.schedules.get_mut(&user_id) {
+                        schedules.remove(&name);
+                    }
+                }
+                Change::PushOutbox(entry) => {
+                    state.outbox_ids += 1;
+                    let id = state.outbox_ids;
+                    state.outbox.push_back(OutboxEntry { id, entry });
+                }
+                Change::MarkProcessed {
+                    message_id,
+                    ttl_secs,
+                } => {
+                    state
+                        .processed
+                        .insert(message_id, now_ms() + ttl_secs as u64 * 1000);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn lock_outbox(&self, owner: &str, ttl_ms: u64) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut state = self.state()?;
+        let now = now_ms();
+
+        match &state.outbox_lock {
+            Some((current, expires_at)) if current != owner && *expires_at >= now => Ok(false),
+            _ => {
+                state.outbox_lock = Some((owner.to_string(), now + ttl_ms));
+                Ok(true)
+            }
+        }
+    }
+
+    fn unlock_outbox(&self, owner: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut state = self.state()?;
+
+        if let Some((current, _)) = &state.outbox_lock {
+            if current == owner {
+                state.outbox_lock = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn outbox(&self, limit: usize) -> Result<Vec<OutboxEntry>, Box<dyn std::error::Error>> {
+        Ok(self.state()?.outbox.iter().take(limit).cloned().collect())
+    }
+
+    fn ack_outbox(
+        &self,
+        owner: &str,
+        entries: &[OutboxEntry],
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut state = self.state()?;
+
+        match &state.outbox_lock {
+            Some((current, expires_at)) if current == owner && *expires_at >= now_ms() => {
+                let ids: BTreeSet<u64> = entries.iter().map(|entry| entry.id).collect();
+                state.outbox.retain(|entry| !ids.contains(&entry.id));
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(storage: &MemoryStorage, change: Change) -> Result<(), Box<dyn std::error::Error>> {
+        let mut changes = Changes::default();
+        changes.push(change);
+        storage.apply(changes)
+    }
+
+    fn put_order(order_id: &str, version: u64) -> Change {
+        Change::PutOrder {
+            user_id: "1".to_string(),
+            order_id: order_id.to_string(),
+            order: Order {
+                version,
+                ..Order::default()
+            },
+        }
+    }
+
+    fn use_promotion(user_id: &str, order_id: &str) -> Change {
+        Change::AddPromotionUse {
+            code: "SPRING".to_string(),
+            user_id: user_id.to_string(),
+            order_id: order_id.to_string(),
+            max_uses: Some(2),
+            max_uses_per_user: Some(1),
+        }
+    }
+
+    #[test]
+    fn order_read_before_it_was_written_is_conflict() {
+        let storage = MemoryStorage::new();
+        put(&storage, put_order("1", 0)).unwrap();
+        assert_eq!(storage.order("1", "1").unwrap().unwrap().version, 1);
+
+        let e = put(&storage, put_order("1", 0)).unwrap_err();
+        assert!(e.is::<Conflict>());
+        put(&storage, put_order("1", 1)).unwrap();
+        assert_eq!(storage.order("1", "1").unwrap().unwrap().version, 2);
+    }
+
+    #[test]
+    fn conflict_leaves_all_changes_unapplied() {
+        let storage = MemoryStorage::new();
+        put(&storage, put_order("1", 0)).unwrap();
+
+        let mut changes = Changes::default();
+        changes.push(put_order("2", 0));
+        changes.push(put_order("1", 0));
+        assert!(storage.apply(changes).unwrap_err().is::<Conflict>());
+        assert!(storage.order("1", "2").unwrap().is_none());
+    }
+
+    #[test]
+    fn order_has_one_pending_copy() {
+        let storage = MemoryStorage::new();
+        let pending = || Change::BeginPending {
+            user_id: "1".to_string(),
+            order_id: "1".to_string(),
+            order: Order::default(),
+        };
+        put(&storage, pending()).unwrap();
+
+        assert!(put(&storage, pending()).unwrap_err().is::<Conflict>());
+        put(
+            &storage,
+            Change::ClearPending {
+                user_id: "1".to_string(),
+                order_id: "1".to_string(),
+            },
+        )
+        .unwrap();
+        put(&storage, pending()).unwrap();
+    }
+
+    #[test]
+    fn promotion_isnt_used_over_its_limits() {
+        let storage = MemoryStorage::new();
+        put(&storage, use_promotion("1", "1")).unwrap();
+        // Same use is added again
+        put(&storage, use_promotion("1", "1")).unwrap();
+
+        assert!(put(&storage, use_promotion("1", "2"))
+            .unwrap_err()
+            .is::<Conflict>());
+        put(&storage, use_promotion("2", "3")).unwrap();
+        assert!(put(&storage, use_promotion("3", "4"))
+            .unwrap_err()
+            .is::<Conflict>());
+        assert_eq!(storage.promotion_uses("SPRING").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn return_ids_start_again_for_deleted_order() {
+        let storage = MemoryStorage::new();
+        assert_eq!(storage.next_return_id("1", "1").unwrap(), 1);
+        assert_eq!(storage.next_return_id("1", "1").unwrap(), 2);
+        assert_eq!(storage.next_return_id("1", "2").unwrap(), 1);
+
+        put(
+            &storage,
+            Change::DeleteOrder {
+                user_id: "1".to_string(),
+                order_id: "1".to_string(),
+            },
+        )
+        .unwrap();
+        assert_eq!(storage.next_return_id("1", "1").unwrap(), 1);
+    }
+
+    #[test]
+    fn outbox_is_acked_by_lock_owner_only() {
+        let storage = MemoryStorage::new();
+        put(&storage, Change::PushOutbox("first".to_string())).unwrap();
+        put(&storage, Change::PushOutbox("second".to_string())).unwrap();
+
+        assert!(storage.lock_outbox("relay-1", 60_000).unwrap());
+        assert!(!storage.lock_outbox("relay-2", 60_000).unwrap());
+        let published = storage.outbox(1).unwrap();
+        assert!(!storage.ack_outbox("relay-2", &published).unwrap());
+        assert_eq!(storage.outbox(10).unwrap().len(), 2);
+
+        // Entry pushed after the read isn't acked with the read ones
+        put(&storage, Change::PushOutbox("third".to_string())).unwrap();
+        assert!(storage.ack_outbox("relay-1", &published).unwrap());
+        let left: Vec<String> = storage
+            .outbox(10)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.entry)
+            .collect();
+        assert_eq!(left, vec!["second", "third"]);
+
+        storage.unlock_outbox("relay-1").unwrap();
+        assert!(storage.lock_outbox("relay-2", 60_000).unwrap());
+    }
+}
@@ -0,0 +1,497 @@
+use crate::backorders::FulfilmentPolicy;
+use crate::delivery::Delivery;
+use crate::events::OrderEvent;
+use crate::promotions::{Promotion, PromotionUse};
+use crate::returns::OrderReturn;
+use crate::saga::SagaState;
+use crate::schedules::Schedule;
+use crate::status::OrderStatus;
+use crate::templates::OrderTemplate;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod memory;
+mod postgres;
+mod redis;
+
+pub use self::memory::MemoryStorage;
+pub use self::postgres::PostgresStorage;
+pub use self::redis::RedisStorage;
+
+#[derive(Clone, Debug, Default)]
+pub struct Order {
+    pub status: OrderStatus,
+    pub goods: BTreeMap<u64, u64>,
+    // Milliseconds since epoch, orders created before it was stored have 0
+    pub created_at: u64,
+    // Set by storage every time the order is written
+    pub updated_at: u64,
+    // Incremented by storage every time the order is written, clients send it
+    // back to make sure they change the order they have seen
+    pub version: u64,
+    // Unit prices of goods in minor units, snapshotted when goods are reserved,
+    // orders created before prices were introduced have none
+    pub prices: BTreeMap<u64, u64>,
+    pub currency: String,
+    // Tax rate in basis points at the moment of the last snapshot
+    pub tax_rate_bp: u64,
+    // Copy of the promotion applied to the order
+    pub promotion: Option<Promotion>,
+    // Where and how goods are delivered, orders created before it was
+    // introduced have none
+    pub delivery: Option<Delivery>,
+    // Whether goods which are out of stock are backordered
+    pub fulfilment: FulfilmentPolicy,
+    // Counts of goods the order still waits for, set by warehouse
+    pub backorders: BTreeMap<u64, u64>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum SortField {
+    OrderId,
+    CreatedAt,
+}
+
+impl SortField {
+    pub fn key(self, order_id: u64, order: &Order) -> u64 {
+        match self {
+            SortField::OrderId => order_id,
+            SortField::CreatedAt => order.created_at,
+        }
+    }
+}
+
+// Position of the last order of a page, next page starts right after it
+#[derive(Clone, Copy)]
+pub struct Cursor {
+    pub key: u64,
+    pub order_id: u64,
+}
+
+// Both bounds of creation date are inclusive
+pub struct OrderQuery {
+    pub status: Option<OrderStatus>,
+    pub created_from: Option<u64>,
+    pub created_to: Option<u64>,
+    pub sort: SortField,
+    pub descending: bool,
+    pub after: Option<Cursor>,
+    pub limit: usize,
+}
+
+impl OrderQuery {
+    fn matches(&self, order: &Order) -> bool {
+        self.status.is_none_or(|status| status == order.status)
+            && self
+                .created_from
+                .is_none_or(|from| order.created_at >= from)
+            && self.created_to.is_none_or(|to| order.created_at <= to)
+    }
+
+    fn cursor(&self, order_id: u64, order: &Order) -> Cursor {
+        Cursor {
+            key: self.sort.key(order_id, order),
+            order_id,
+        }
+    }
+}
+
+pub struct OrderPage {
+    pub orders: Vec<(u64, Order)>,
+    // Number of orders matching filters on all pages
+    pub total: u64,
+    pub next: Option<Cursor>,
+}
+
+// Orders of all users, the ones with given id, owner, status or good are
+// found by indexes, status, creation date and limit are taken from the query,
+// its sort and cursor aren't used
+pub struct OrderSearch {
+    pub order_id: Option<u64>,
+    pub user_id: Option<String>,
+    pub good_id: Option<u64>,
+    pub query: OrderQuery,
+}
+
+impl OrderSearch {
+    fn matches(&self, user_id: &str, order_id: u64, order: &Order) -> bool {
+        self.order_id.is_none_or(|id| id == order_id)
+            && self.user_id.as_deref().is_none_or(|id| id == user_id)
+            && self
+                .good_id
+                .is_none_or(|good_id| order.goods.contains_key(&good_id))
+            && self.query.matches(order)
+    }
+}
+
+// Found orders go by id, the cursor is id of the last of them, search is
+// finished when there is no next one
+pub struct OrderScan {
+    pub orders: Vec<(String, u64, Order)>,
+    pub next: Option<u64>,
+}
+
+// Orders are ordered by sort key and then by id, so pages are stable even
+// if orders are created or removed between requests
+fn page(mut orders: Vec<(u64, Order)>, query: &OrderQuery) -> OrderPage {
+    orders.retain(|(_, order)| query.matches(order));
+    let total = orders.len() as u64;
+
+    orders.sort_by_key(|(order_id, order)| (query.sort.key(*order_id, order), *order_id));
+
+    if query.descending {
+        orders.reverse();
+    }
+
+    if let Some(after) = query.after {
+        orders.retain(|(order_id, order)| {
+            let position = (query.sort.key(*order_id, order), *order_id);
+
+            if query.descending {
+                position < (after.key, after.order_id)
+            } else {
+                position > (after.key, after.order_id)
+            }
+        });
+    }
+
+    let next = if orders.len() > query.limit {
+        orders.truncate(query.limit);
+        orders
+            .last()
+            .map(|(order_id, order)| query.cursor(*order_id, order))
+    } else {
+        None
+    };
+
+    OrderPage {
+        orders,
+        total,
+        next,
+    }
+}
+
+// Order was written by someone else after it was read, what was computed
+// from it is stale and the whole operation should be repeated
+#[derive(Debug)]
+pub struct Conflict(pub String);
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Conflict {}
+
+// New order has version 0 and is written only where there is none yet
+fn check_version(
+    user_id: &str,
+    order_id: &str,
+    stored: Option<u64>,
+    order: &Order,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if stored.unwrap_or(0) == order.version {
+        Ok(())
+    } else {
+        Err(Box::new(Conflict(format!(
+            "line:{}: Order '{}' of user '{}' was changed, its version is {:?}, not {}",
+            line!(),
+            order_id,
+            user_id,
+            stored,
+            order.version
+        ))))
+    }
+}
+
+// Uses are counted without the one being added, so it can be added again
+fn check_promotion_uses(
+    code: &str,
+    uses: u64,
+    user_uses: u64,
+    max_uses: Option<u64>,
+    max_uses_per_user: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if max_uses.is_some_and(|max| uses >= max)
+        || max_uses_per_user.is_some_and(|max| user_uses >= max)
+    {
+        Err(Box::new(Conflict(format!(
+            "line:{}: Promotion '{}' was used up meanwhile",
+            line!(),
+            code
+        ))))
+    } else {
+        Ok(())
+    }
+}
+
+pub struct SagaRecord {
+    pub user_id: String,
+    pub order_id: String,
+    pub state: SagaState,
+}
+
+pub struct ScheduleRecord {
+    pub user_id: String,
+    pub schedule: Schedule,
+}
+
+// Pending change of an order waiting for warehouse answer, message is kept
+// serialized, so it can be resent as is
+#[derive(Clone)]
+pub struct TransactionRecord {
+    pub user_id: String,
+    pub order_id: String,
+    pub operation: String,
+    pub message: String,
+    pub created_at: u64,
+    pub deadline: u64,
+    pub attempts: u64,
+}
+
+pub enum Change {
+    // Fails with conflict if the order was written after it was read,
+    // version of the order is the one it was read with
+    PutOrder {
+        user_id: String,
+        order_id: String,
+        order: Order,
+    },
+    DeleteOrder {
+        user_id: String,
+        order_id: String,
+    },
+    // Fails with conflict if order already has pending copy
+    // or it was written after it was read
+    BeginPending {
+        user_id: String,
+        order_id: String,
+        order: Order,
+    },
+    ClearPending {
+        user_id: String,
+        order_id: String,
+    },
+    SetSaga {
+        user_id: String,
+        order_id: String,
+        state: SagaState,
+        deadline: Option<u64>,
+    },
+    RemoveSaga {
+        user_id: String,
+        order_id: String,
+    },
+    BeginTransaction(TransactionRecord),
+    RetryTransaction {
+        user_id: String,
+        order_id: String,
+        deadline: u64,
+    },
+    FinishTransaction {
+        user_id: String,
+        order_id: String,
+    },
+    // Events are removed together with the order
+    RecordEvent {
+        user_id: String,
+        order_id: String,
+        event: OrderEvent,
+    },
+    PutPromotion(Promotion),
+    DeletePromotion {
+        code: String,
+    },
+    // Adding the same use again changes nothing, use over the limits
+    // the code was checked with isn't added
+    AddPromotionUse {
+        code: String,
+        user_id: String,
+        order_id: String,
+        max_uses: Option<u64>,
+        max_uses_per_user: Option<u64>,
+    },
+    RemovePromotionUse {
+        code: String,
+        user_id: String,
+        order_id: String,
+    },
+    // Returns are removed together with the order
+    PutReturn {
+        user_id: String,
+        order_id: String,
+        order_return: OrderReturn,
+    },
+    PutTemplate {
+        user_id: String,
+        template: OrderTemplate,
+    },
+    DeleteTemplate {
+        user_id: String,
+        name: String,
+    },
+    PutSchedule {
+        user_id: String,
+        schedule: Schedule,
+    },
+    DeleteSchedule {
+        user_id: String,
+        name: String,
+    },
+    PushOutbox(String),
+    MarkProcessed {
+        message_id: String,
+        ttl_secs: usize,
+    },
+}
+
+// Entry of outbox read by relay, exactly the published ones are acked
+#[derive(Clone, Debug)]
+pub struct OutboxEntry {
+    pub id: u64,
+    pub entry: String,
+}
+
+// Everything produced by processing one message is applied by storage at once:
+// order change, saga state, messages for other services and processed mark
+#[derive(Default)]
+pub struct Changes(Vec<Change>);
+
+impl Changes {
+    pub fn push(&mut self, change: Change) {
+        self.0.push(change);
+    }
+}
+
+// Business logic and api handlers work with orders only through this trait,
+// so storage can be replaced without touching them
+pub trait OrderRepository: Send + Sync {
+    fn next_order_id(&self) -> Result<i64, Box<dyn std::error::Error>>;
+
+    // Returns are numbered within the order
+    fn next_return_id(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<u64, Box<dyn std::error::Error>>;
+
+    fn order(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Option<Order>, Box<dyn std::error::Error>>;
+
+    fn orders(
+        &self,
+        user_id: &str,
+        query: &OrderQuery,
+    ) -> Result<OrderPage, Box<dyn std::error::Error>>;
+
+    // First batch is read with cursor 0
+    fn search_orders(
+        &self,
+        search: &OrderSearch,
+        cursor: u64,
+    ) -> Result<OrderScan, Box<dyn std::error::Error>>;
+
+    // Events of the order from the oldest one
+    fn events(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Vec<OrderEvent>, Box<dyn std::error::Error>>;
+
+    fn pending(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Option<Order>, Box<dyn std::error::Error>>;
+
+    fn promotion(&self, code: &str) -> Result<Option<Promotion>, Box<dyn std::error::Error>>;
+
+    // All promotions ordered by code
+    fn promotions(&self) -> Result<Vec<Promotion>, Box<dyn std::error::Error>>;
+
+    fn promotion_uses(&self, code: &str) -> Result<Vec<PromotionUse>, Box<dyn std::error::Error>>;
+
+    // Returns of the order ordered by id
+    fn returns(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Vec<OrderReturn>, Box<dyn std::error::Error>>;
+
+    fn template(
+        &self,
+        user_id: &str,
+        name: &str,
+    ) -> Result<Option<OrderTemplate>, Box<dyn std::error::Error>>;
+
+    // Templates of the user ordered by name
+    fn templates(&self, user_id: &str) -> Result<Vec<OrderTemplate>, Box<dyn std::error::Error>>;
+
+    fn schedule(
+        &self,
+        user_id: &str,
+        name: &str,
+    ) -> Result<Option<Schedule>, Box<dyn std::error::Error>>;
+
+    // Schedules of the user ordered by name
+    fn schedules(&self, user_id: &str) -> Result<Vec<Schedule>, Box<dyn std::error::Error>>;
+
+    // Schedules which should have run by now, the most overdue first
+    fn due_schedules(
+        &self,
+        now: u64,
+        limit: usize,
+    ) -> Result<Vec<ScheduleRecord>, Box<dyn std::error::Error>>;
+
+    fn saga_state(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Option<SagaState>, Box<dyn std::error::Error>>;
+
+    fn expired_sagas(
+        &self,
+        now: u64,
+        limit: usize,
+    ) -> Result<Vec<SagaRecord>, Box<dyn std::error::Error>>;
+
+    fn transactions(&self) -> Result<Vec<TransactionRecord>, Box<dyn std::error::Error>>;
+
+    fn expired_transactions(
+        &self,
+        now: u64,
+        limit: usize,
+    ) -> Result<Vec<TransactionRecord>, Box<dyn std::error::Error>>;
+
+    fn is_processed(&self, message_id: &str) -> Result<bool, Box<dyn std::error::Error>>;
+
+    fn purge_processed(&self) -> Result<(), Box<dyn std::error::Error>>;
+
+    fn apply(&self, changes: Changes) -> Result<(), Box<dyn std::error::Error>>;
+
+    fn lock_outbox(&self, owner: &str, ttl_ms: u64) -> Result<bool, Box<dyn std::error::Error>>;
+
+    fn unlock_outbox(&self, owner: &str) -> Result<(), Box<dyn std::error::Error>>;
+
+    fn outbox(&self, limit: usize) -> Result<Vec<OutboxEntry>, Box<dyn std::error::Error>>;
+
+    // Removes published entries if the lock is still held by the owner,
+    // returns false when it was taken over and entries are left in outbox
+    fn ack_outbox(
+        &self,
+        owner: &str,
+        entries: &[OutboxEntry],
+    ) -> Result<bool, Box<dyn std::error::Error>>;
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,1180 @@
+use super::{
+    check_promotion_uses, check_version, now_ms, Change, Changes, Conflict, Order, OrderPage,
+    OrderQuery, OrderRepository, OrderScan, OrderSearch, OutboxEntry, SagaRecord, ScheduleRecord,
+    SortField, TransactionRecord,
+};
+use crate::backorders::FulfilmentPolicy;
+use crate::events::{EventKind, OrderEvent};
+use crate::promotions::{Promotion, PromotionUse};
+use crate::returns::OrderReturn;
+use crate::saga::SagaState;
+use crate::schedules::Schedule;
+use crate::status::OrderStatus;
+use crate::templates::OrderTemplate;
+use postgres::types::ToSql;
+use postgres::{NoTls, Row, Transaction};
+use r2d2_postgres::{r2d2, PostgresConnectionManager};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+
+const OUTBOX_LOCK_NAME: &str = "orders";
+
+// Applied in order on start, every migration is applied only once
+const MIGRATIONS: &[(i32, &str)] = &[
+    (1, include_str!("../../migrations/1_initial.sql")),
+    (2, include_str!("../../migrations/2_order_created_at.sql")),
+    (
+        3,
+        include_str!("../../migrations/3_order_status_history.sql"),
+    ),
+    (4, include_str!("../../migrations/4_order_events.sql")),
+    (5, include_str!("../../migrations/5_order_prices.sql")),
+    (6, include_str!("../../migrations/6_promotions.sql")),
+    (7, include_str!("../../migrations/7_order_version.sql")),
+    (8, include_str!("../../migrations/8_order_delivery.sql")),
+    (9, include_str!("../../migrations/9_order_backorders.sql")),
+    (10, include_str!("../../migrations/10_order_returns.sql")),
+    (11, include_str!("../../migrations/11_order_templates.sql")),
+    (12, include_str!("../../migrations/12_order_schedules.sql")),
+    (13, include_str!("../../migrations/13_order_export.sql")),
+    (14, include_str!("../../migrations/14_order_search.sql")),
+    (15, include_str!("../../migrations/15_order_return_ids.sql")),
+];
+
+// Goods of an order, their unit prices and backordered counts
+type Lines = (BTreeMap<u64, u64>, BTreeMap<u64, u64>, BTreeMap<u64, u64>);
+
+fn parse_state(state: &str) -> Result<SagaState, Box<dyn std::error::Error>> {
+    match SagaState::parse(state) {
+        Some(state) => Ok(state),
+        None => Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!("line:{}: Unknown saga state: {}", line!(), state),
+        ))),
+    }
+}
+
+fn parse_status(status: &str) -> Result<OrderStatus, Box<dyn std::error::Error>> {
+    match OrderStatus::parse(status) {
+        Some(status) => Ok(status),
+        None => Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!("line:{}: Unknown order status: {}", line!(), status),
+        ))),
+    }
+}
+
+fn parse_fulfilment(policy: &str) -> Result<FulfilmentPolicy, Box<dyn std::error::Error>> {
+    match FulfilmentPolicy::parse(policy) {
+        Some(policy) => Ok(policy),
+        None => Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!("line:{}: Unknown fulfilment policy: {}", line!(), policy),
+        ))),
+    }
+}
+
+// Promotion and delivery details of orders are kept in json
+fn json_to_sql<T: Serialize>(value: &Option<T>) -> Result<Option<String>, serde_json::Error> {
+    value.as_ref().map(serde_json::to_string).transpose()
+}
+
+fn json_from_sql<T: DeserializeOwned>(
+    value: Option<String>,
+) -> Result<Option<T>, Box<dyn std::error::Error>> {
+    match value {
+        Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+        None => Ok(None),
+    }
+}
+
+fn transaction_record(row: &Row) -> TransactionRecord {
+    TransactionRecord {
+        user_id: row.get(0),
+        order_id: row.get::<_, i64>(1).to_string(),
+        operation: row.get(2),
+        message: row.get(3),
+        created_at: row.get::<_, i64>(4) as u64,
+        deadline: row.get::<_, i64>(5) as u64,
+        attempts: row.get::<_, i64>(6) as u64,
+    }
+}
+
+fn put_lines(
+    tx: &mut Transaction,
+    user_id: &str,
+    order_id: i64,
+    order: &Order,
+) -> Result<(), postgres::Error> {
+    tx.execute(
+        "DELETE FROM order_lines WHERE user_id = $1 AND order_id = $2",
+        &[&user_id, &order_id],
+    )?;
+
+    for (good_id, count) in &order.goods {
+        let price = order.prices.get(good_id).map(|price| *price as i64);
+        let backordered = order.backorders.get(good_id).cloned().unwrap_or(0);
+        tx.execute(
+            "INSERT INTO order_lines (user_id, order_id, good_id, count, unit_price, backordered)
+             VALUES ($1, $2, $3, $4, $5, $6)",
+            &[
+                &user_id,
+                &order_id,
+                &(*good_id as i64),
+                &(*count as i64),
+                &price,
+                &(backordered as i64),
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+// Row of the order is locked till the end of transaction, so version
+// can't change between it is checked and the order is written
+fn locked_version(
+    tx: &mut Transaction,
+    user_id: &str,
+    order_id: &str,
+) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+    let rows = tx.query(
+        "SELECT version FROM orders WHERE user_id = $1 AND order_id = $2 FOR UPDATE",
+        &[&user_id, &order_id.parse::<i64>()?],
+    )?;
+    Ok(rows.first().map(|row| row.get::<_, i64>(0) as u64))
+}
+
+fn apply_change(tx: &mut Transaction, change: &Change) -> Result<(), Box<dyn std::error::Error>> {
+    match change {
+        Change::PutOrder {
+            user_id,
+            order_id,
+            order,
+        } => {
+            check_version(
+                user_id,
+                order_id,
+                locked_version(tx, user_id, order_id)?,
+                order,
+            )?;
+            let order_id: i64 = order_id.parse()?;
+            let written = tx.execute(
+                "INSERT INTO orders (user_id, order_id, status, created_at, updated_at,
+                 currency, tax_rate_bp, promotion, version, delivery, fulfilment)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+                 ON CONFLICT (user_id, order_id) DO UPDATE SET status = EXCLUDED.status,
+                 created_at = EXCLUDED.created_at, updated_at = EXCLUDED.updated_at,
+                 currency = EXCLUDED.currency, tax_rate_bp = EXCLUDED.tax_rate_bp,
+                 promotion = EXCLUDED.promotion, version = EXCLUDED.version,
+                 delivery = EXCLUDED.delivery, fulfilment = EXCLUDED.fulfilment
+                 WHERE orders.version = $12",
+                &[
+                    user_id,
+                    &order_id,
+                    &order.status.as_str(),
+                    &(order.created_at as i64),
+                    &(now_ms() as i64),
+                    &order.currency,
+                    &(order.tax_rate_bp as i64),
+                    &json_to_sql(&order.promotion)?,
+                    &(order.version as i64 + 1),
+                    &json_to_sql(&order.delivery)?,
+                    &order.fulfilment.as_str(),
+                    &(order.version as i64),
+                ],
+            )?;
+
+            // New order was inserted by another transaction in between
+            if written == 0 {
+                return Err(Box::new(Conflict(format!(
+                    "line:{}: Order '{}' of user '{}' was written concurrently",
+                    line!(),
+                    order_id,
+                    user_id
+                ))));
+            }
+
+            put_lines(tx, user_id, order_id, order)?;
+        }
+        Change::DeleteOrder { user_id, order_id } => {
+            let order_id: i64 = order_id.parse()?;
+            tx.execute(
+                "DELETE FROM orders WHERE user_id = $1 AND order_id = $2",
+                &[user_id, &order_id],
+            )?;
+            tx.execute(
+                "DELETE FROM order_events WHERE user_id = $1 AND order_id = $2",
+                &[user_id, &order_id],
+            )?;
+            tx.execute(
+                "DELETE FROM order_returns WHERE user_id = $1 AND order_id = $2",
+                &[user_id, &order_id],
+            )?;
+            tx.execute(
+                "DELETE FROM order_return_ids WHERE user_id = $1 AND order_id = $2",
+                &[user_id, &order_id],
+            )?;
+        }
+        Change::BeginPending {
+            user_id,
+            order_id,
+            order,
+        } => {
+            check_version(
+                user_id,
+                order_id,
+                locked_version(tx, user_id, order_id)?,
+                order,
+            )?;
+            let inserted = tx.execute(
+                "INSERT INTO pending_orders (user_id, order_id, status, goods, created_at,
+                 prices, currency, tax_rate_bp, promotion, version, delivery, fulfilment,
+                 backorders)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+                 ON CONFLICT DO NOTHING",
+                &[
+                    user_id,
+                    &order_id.parse::<i64>()?,
+                    &order.status.as_str(),
+                    &serde_json::to_string(&order.goods)?,
+                    &(order.created_at as i64),
+                    &serde_json::to_string(&order.prices)?,
+                    &order.currency,
+                    &(order.tax_rate_bp as i64),
+                    &json_to_sql(&order.promotion)?,
+                    &(order.version as i64),
+                    &json_to_sql(&order.delivery)?,
+                    &order.fulfilment.as_str(),
+                    &serde_json::to_string(&order.backorders)?,
+                ],
+            )?;
+
+            if inserted == 0 {
+                return Err(Box::new(Conflict(format!(
+                    "line:{}: Transaction of order '{}' of user '{}' already exists",
+                    line!(),
+                    order_id,
+                    user_id
+                ))));
+            }
+        }
+        Change::ClearPending { user_id, order_id } => {
+            tx.execute(
+                "DELETE FROM pending_orders WHERE user_id = $1 AND order_id = $2",
+                &[user_id, &order_id.parse::<i64>()?],
+            )?;
+        }
+        Change::SetSaga {
+            user_id,
+            order_id,
+            state,
+            deadline,
+        } => {
+            tx.execute(
+                "INSERT INTO sagas (user_id, order_id, state, deadline, updated_at)
+                 VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT (user_id, order_id) DO UPDATE SET state = EXCLUDED.state,
+                 deadline = EXCLUDED.deadline, updated_at = EXCLUDED.updated_at",
+                &[
+                    user_id,
+                    &order_id.parse::<i64>()?,
+                    &state.as_str(),
+                    &deadline.map(|deadline| deadline as i64),
+                    &(now_ms() as i64),
+                ],
+            )?;
+        }
+        Change::RemoveSaga { user_id, order_id } => {
+            tx.execute(
+                "DELETE FROM sagas WHERE user_id = $1 AND order_id = $2",
+                &[user_id, &order_id.parse::<i64>()?],
+            )?;
+        }
+        Change::BeginTransaction(record) => {
+            tx.execute(
+                "INSERT INTO order_transactions
+                 (user_id, order_id, operation, message, created_at, deadline, attempts)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)
+                 ON CONFLICT (user_id, order_id) DO UPDATE SET operation = EXCLUDED.operation,
+                 message = EXCLUDED.message, created_at = EXCLUDED.created_at,
+                 deadline = EXCLUDED.deadline, attempts = EXCLUDED.attempts",
+                &[
+                    &record.user_id,
+                    &record.order_id.parse::<i64>()?,
+                    &record.operation,
+                    &record.message,
+                    &(record.created_at as i64),
+                    &(record.deadline as i64),
+                    &(record.attempts as i64),
+                ],
+            )?;
+        }
+        Change::RetryTransaction {
+            user_id,
+            order_id,
+            deadline,
+        } => {
+            tx.execute(
+                "UPDATE order_transactions SET attempts = attempts + 1, deadline = $3
+                 WHERE user_id = $1 AND order_id = $2",
+                &[user_id, &order_id.parse::<i64>()?, &(*deadline as i64)],
+            )?;
+        }
+        Change::FinishTransaction { user_id, order_id } => {
+            tx.execute(
+                "DELETE FROM order_transactions WHERE user_id = $1 AND order_id = $2",
+                &[user_id, &order_id.parse::<i64>()?],
+            )?;
+        }
+        Change::RecordEvent {
+            user_id,
+            order_id,
+            event,
+        } => {
+            tx.execute(
+                "INSERT INTO order_events
+                 (user_id, order_id, kind, at, actor, correlation_id, details)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)",
+                &[
+                    user_id,
+                    &order_id.parse::<i64>()?,
+                    &event.kind.as_str(),
+                    &(event.at as i64),
+                    &event.actor,
+                    &event.correlation_id,
+                    &event.details,
+                ],
+            )?;
+        }
+        Change::PutPromotion(promotion) => {
+            tx.execute(
+                "INSERT INTO promotions (code, definition) VALUES ($1, $2)
+                 ON CONFLICT (code) DO UPDATE SET definition = EXCLUDED.definition",
+                &[&promotion.code, &serde_json::to_string(promotion)?],
+            )?;
+        }
+        Change::PutReturn {
+            user_id,
+            order_id,
+            order_return,
+        } => {
+            tx.execute(
+                "INSERT INTO order_returns (user_id, order_id, return_id, definition)
+                 VALUES ($1, $2, $3, $4) ON CONFLICT (user_id, order_id, return_id)
+                 DO UPDATE SET definition = EXCLUDED.definition",
+                &[
+                    user_id,
+                    &order_id.parse::<i64>()?,
+                    &(order_return.return_id as i64),
+                    &serde_json::to_string(order_return)?,
+                ],
+            )?;
+        }
+        Change::PutTemplate { user_id, template } => {
+            tx.execute(
+                "INSERT INTO order_templates (user_id, name, definition) VALUES ($1, $2, $3)
+                 ON CONFLICT (user_id, name) DO UPDATE SET definition = EXCLUDED.definition",
+                &[user_id, &template.name, &serde_json::to_string(template)?],
+            )?;
+        }
+        Change::DeleteTemplate { user_id, name } => {
+            tx.execute(
+                "DELETE FROM order_templates WHERE user_id = $1 AND name = $2",
+                &[user_id, name],
+            )?;
+        }
+        Change::PutSchedule { user_id, schedule } => {
+            tx.execute(
+                "INSERT INTO order_schedules (user_id, name, next_run_at, definition)
+                 VALUES ($1, $2, $3, $4) ON CONFLICT (user_id, name)
+                 DO UPDATE SET next_run_at = EXCLUDED.next_run_at,
+                 definition = EXCLUDED.definition",
+                &[
+                    user_id,
+                    &schedule.name,
+                    &(schedule.next_run_at as i64),
+                    &serde_json::to_string(schedule)?,
+                ],
+            )?;
+        }
+        Change::DeleteSchedule { user_id, name } => {
+            tx.execute(
+                "DELETE FROM order_schedules WHERE user_id = $1 AND name = $2",
+                &[user_id, name],
+            )?;
+        }
+        Change::DeletePromotion { code } => {
+            tx.execute("DELETE FROM promotions WHERE code = $1", &[code])?;
+        }
+        // Uses of the code are counted under a lock, so its limits
+        // can't be exceeded by concurrent payments
+        Change::AddPromotionUse {
+            code,
+            user_id,
+            order_id,
+            max_uses,
+            max_uses_per_user,
+        } => {
+            let order_id: i64 = order_id.parse()?;
+            tx.execute("SELECT pg_advisory_xact_lock(hashtext($1))", &[code])?;
+            let row = tx.query_one(
+                "SELECT COUNT(*), COUNT(*) FILTER (WHERE user_id = $2) FROM promotion_uses
+                 WHERE code = $1 AND NOT (user_id = $2 AND order_id = $3)",
+                &[code, user_id, &order_id],
+            )?;
+            let (uses, user_uses): (i64, i64) = (row.get(0), row.get(1));
+            check_promotion_uses(
+                code,
+                uses as u64,
+                user_uses as u64,
+                *max_uses,
+                *max_uses_per_user,
+            )?;
+            tx.execute(
+                "INSERT INTO promotion_uses (code, user_id, order_id) VALUES ($1, $2, $3)
+                 ON CONFLICT DO NOTHING",
+                &[code, user_id, &order_id],
+            )?;
+        }
+        Change::RemovePromotionUse {
+            code,
+            user_id,
+            order_id,
+        } => {
+            tx.execute(
+                "DELETE FROM promotion_uses WHERE code = $1 AND user_id = $2 AND order_id = $3",
+                &[code, user_id, &order_id.parse::<i64>()?],
+            )?;
+        }
+        Change::PushOutbox(entry) => {
+            tx.execute("INSERT INTO outbox (entry) VALUES ($1)", &[entry])?;
+        }
+        Change::MarkProcessed {
+            message_id,
+            ttl_secs,
+        } => {
+            tx.execute(
+                "INSERT INTO processed_messages (message_id, expires_at) VALUES ($1, $2)
+                 ON CONFLICT (message_id) DO UPDATE SET expires_at = EXCLUDED.expires_at",
+                &[message_id, &((now_ms() + *ttl_secs as u64 * 1000) as i64)],
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+pub struct PostgresStorage {
+    pool: r2d2::Pool<PostgresConnectionManager<NoTls>>,
+}
+
+impl PostgresStorage {
+    pub fn new(connection_string: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let manager = PostgresConnectionManager::new(connection_string.parse()?, NoTls);
+        let pool = r2d2::Pool::builder().build(manager)?;
+        let storage = PostgresStorage { pool };
+        storage.migrate()?;
+        Ok(storage)
+    }
+
+    // Table is locked, so several instances started at once don't apply
+    // the same migration twice
+    fn migrate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.pool.get()?;
+        conn.batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (version INTEGER PRIMARY KEY)",
+        )?;
+
+        let mut tx = conn.transaction()?;
+        tx.batch_execute("LOCK TABLE schema_migrations IN EXCLUSIVE MODE")?;
+        let applied: Vec<i32> = tx
+            .query("SELECT version FROM schema_migrations", &[])?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+
+        for (version, migration) in MIGRATIONS {
+            if !applied.contains(version) {
+                info!("line:{}: Applying migration {}", line!(), version);
+                tx.batch_execute(migration)?;
+                tx.execute(
+                    "INSERT INTO schema_migrations (version) VALUES ($1)",
+                    &[version],
+                )?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn lines(
+        &self,
+        user_id: &str,
+        order_ids: &[i64],
+    ) -> Result<BTreeMap<i64, Lines>, Box<dyn std::error::Error>> {
+        let rows = self.pool.get()?.query(
+            "SELECT order_id, good_id, count, unit_price, backordered FROM order_lines
+             WHERE user_id = $1 AND order_id = ANY($2)",
+            &[&user_id, &order_ids],
+        )?;
+        Ok(collect_lines(rows))
+    }
+}
+
+// Rows are '(order_id, good_id, count, unit_price, backordered)'
+fn collect_lines(rows: Vec<Row>) -> BTreeMap<i64, Lines> {
+    let mut lines: BTreeMap<i64, Lines> = BTreeMap::new();
+
+    for row in rows {
+        let good_id = row.get::<_, i64>(1) as u64;
+        let (goods, prices, backorders) = lines.entry(row.get(0)).or_default();
+        goods.insert(good_id, row.get::<_, i64>(2) as u64);
+
+        if let Some(price) = row.get::<_, Option<i64>>(3) {
+            prices.insert(good_id, price as u64);
+        }
+
+        let backordered = row.get::<_, i64>(4);
+
+        if backordered > 0 {
+            backorders.insert(good_id, backordered as u64);
+        }
+    }
+
+    lines
+}
+
+// Columns are the ones selected by orders and search_orders
+fn row_to_order(row: &Row, lines: Lines) -> Result<Order, Box<dyn std::error::Error>> {
+    let (goods, prices, backorders) = lines;
+
+    Ok(Order {
+        status: parse_status(row.get(1))?,
+        goods,
+        created_at: row.get::<_, i64>(2) as u64,
+        updated_at: row.get::<_, i64>(3) as u64,
+        version: row.get::<_, i64>(7) as u64,
+        prices,
+        currency: row.get(4),
+        tax_rate_bp: row.get::<_, i64>(5) as u64,
+        promotion: json_from_sql(row.get(6))?,
+        delivery: json_from_sql(row.get(8))?,
+        fulfilment: parse_fulfilment(row.get(9))?,
+        backorders,
+    })
+}
+
+impl OrderRepository for PostgresStorage {
+    fn next_order_id(&self) -> Result<i64, Box<dyn std::error::Error>> {
+        let row = self
+            .pool
+            .get()?
+            .query_one("SELECT nextval('order_id_seq')", &[])?;
+        Ok(row.get(0))
+    }
+
+    fn next_return_id(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let order_id: i64 = order_id.parse()?;
+        let row = self.pool.get()?.query_one(
+            "INSERT INTO order_return_ids (user_id, order_id, return_id) VALUES ($1, $2, 1)
+             ON CONFLICT (user_id, order_id)
+             DO UPDATE SET return_id = order_return_ids.return_id + 1
+             RETURNING return_id",
+            &[&user_id, &order_id],
+        )?;
+        let return_id: i64 = row.get(0);
+        Ok(return_id as u64)
+    }
+
+    fn order(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Option<Order>, Box<dyn std::error::Error>> {
+        let order_id: i64 = order_id.parse()?;
+        let row = self.pool.get()?.query_opt(
+            "SELECT status, created_at, updated_at, currency, tax_rate_bp, promotion, version,
+             delivery, fulfilment FROM orders WHERE user_id = $1 AND order_id = $2",
+            &[&user_id, &order_id],
+        )?;
+
+        match row {
+            None => Ok(None),
+            Some(row) => {
+                let (goods, prices, backorders) = self
+                    .lines(user_id, &[order_id])?
+                    .remove(&order_id)
+                    .unwrap_or_default();
+
+                Ok(Some(Order {
+                    status: parse_status(row.get(0))?,
+                    goods,
+                    created_at: row.get::<_, i64>(1) as u64,
+                    updated_at: row.get::<_, i64>(2) as u64,
+                    version: row.get::<_, i64>(6) as u64,
+                    prices,
+                    currency: row.get(3),
+                    tax_rate_bp: row.get::<_, i64>(4) as u64,
+                    promotion: json_from_sql(row.get(5))?,
+                    delivery: json_from_sql(row.get(7))?,
+                    fulfilment: parse_fulfilment(row.get(8))?,
+                    backorders,
+                }))
+            }
+        }
+    }
+
+    // Next page is looked up by position of the last order, so it is
+    // served by index however far it is
+    fn orders(
+        &self,
+        user_id: &str,
+        query: &OrderQuery,
+    ) -> Result<OrderPage, Box<dyn std::error::Error>> {
+        let mut conn = self.pool.get()?;
+        let mut params: Vec<Box<dyn ToSql + Sync>> = vec![Box::new(user_id.to_string())];
+        let mut filter = "user_id = $1".to_string();
+
+        if let Some(status) = query.status {
+            params.push(Box::new(status.as_str()));
+            filter.push_str(&format!(" AND status = ${}", params.len()));
+        }
+
+        if let Some(from) = query.created_from {
+            params.push(Box::new(from as i64));
+            filter.push_str(&format!(" AND created_at >= ${}", params.len()));
+        }
+
+        if let Some(to) = query.created_to {
+            params.push(Box::new(to as i64));
+            filter.push_str(&format!(" AND created_at <= ${}", params.len()));
+        }
+
+        let refs: Vec<&(dyn ToSql + Sync)> = params.iter().map(|param| param.as_ref()).collect();
+        let total: i64 = conn
+            .query_one(
+                &format!("SELECT COUNT(*) FROM orders WHERE {}", filter)[..],
+                &refs,
+            )?
+            .get(0);
+
+        let column = match query.sort {
+            SortField::OrderId => "order_id",
+            SortField::CreatedAt => "created_at",
+        };
+        let (direction, comparison) = if query.descending {
+            ("DESC", "<")
+        } else {
+            ("ASC", ">")
+        };
+
+        if let Some(after) = query.after {
+            params.push(Box::new(after.key as i64));
+            params.push(Box::new(after.order_id as i64));
+            filter.push_str(&format!(
+                " AND ({}, order_id) {} (${}, ${})",
+                column,
+                comparison,
+                params.len() - 1,
+                params.len()
+            ));
+        }
+
+        // One more order is read to know if there is next page
+        params.push(Box::new(query.limit as i64 + 1));
+        let sql = format!(
+            "SELECT order_id, status, created_at, updated_at, currency, tax_rate_bp, promotion,
+             version, delivery, fulfilment FROM orders WHERE {}
+             ORDER BY {} {}, order_id {} LIMIT ${}",
+            filter,
+            column,
+            direction,
+            direction,
+            params.len()
+        );
+        let refs: Vec<&(dyn ToSql + Sync)> = params.iter().map(|param| param.as_ref()).collect();
+        let rows = conn.query(&sql[..], &refs)?;
+        drop(conn);
+
+        let order_ids: Vec<i64> = rows.iter().map(|row| row.get(0)).collect();
+        let mut lines = self.lines(user_id, &order_ids)?;
+        let mut orders: Vec<(u64, Order)> = vec![];
+
+        for row in &rows {
+            let order_id: i64 = row.get(0);
+            let order = row_to_order(row, lines.remove(&order_id).unwrap_or_default())?;
+            orders.push((order_id as u64, order));
+        }
+
+        let next = if orders.len() > query.limit {
+            orders.truncate(query.limit);
+            orders
+                .last()
+                .map(|(order_id, order)| query.cursor(*order_id, order))
+        } else {
+            None
+        };
+
+        Ok(OrderPage {
+            orders,
+            total: total as u64,
+            next,
+        })
+    }
+
+    // Ids of orders come from one sequence, so they don't repeat between
+    // users, order with a good is found by index of lines
+    fn search_orders(
+        &self,
+        search: &OrderSearch,
+        cursor: u64,
+    ) -> Result<OrderScan, Box<dyn std::error::Error>> {
+        let query = &search.query;
+        let mut conn = self.pool.get()?;
+        let mut params: Vec<Box<dyn ToSql + Sync>> = vec![Box::new(cursor as i64)];
+        let mut filter = "order_id > $1".to_string();
+
+        if let Some(order_id) = search.order_id {
+            params.push(Box::new(order_id as i64));
+            filter.push_str(&format!(" AND order_id = ${}", params.len()));
+        }
+
+        if let Some(user_id) = &search.user_id {
+            params.push(Box::new(user_id.clone()));
+            filter.push_str(&format!(" AND user_id = ${}", params.len()));
+        }
+
+        if let Some(good_id) = search.good_id {
+            params.push(Box::new(good_id as i64));
+            filter.push_str(&format!(
+                " AND EXISTS (SELECT 1 FROM order_lines WHERE order_lines.user_id = orders.user_id
+                 AND order_lines.order_id = orders.order_id AND good_id = ${})",
+                params.len()
+            ));
+        }
+
+        if let Some(status) = query.status {
+            params.push(Box::new(status.as_str()));
+            filter.push_str(&format!(" AND status = ${}", params.len()));
+        }
+
+        if let Some(from) = query.created_from {
+            params.push(Box::new(from as i64));
+            filter.push_str(&format!(" AND created_at >= ${}", params.len()));
+        }
+
+        if let Some(to) = query.created_to {
+            params.push(Box::new(to as i64));
+            filter.push_str(&format!(" AND created_at <= ${}", params.len()));
+        }
+
+        params.push(Box::new(query.limit as i64));
+        let sql = format!(
+            "SELECT order_id, status, created_at, updated_at, currency, tax_rate_bp, promotion,
+             version, delivery, fulfilment, user_id FROM orders WHERE {}
+             ORDER BY order_id LIMIT ${}",
+            filter,
+            params.len()
+        );
+        let refs: Vec<&(dyn ToSql + Sync)> = params.iter().map(|param| param.as_ref()).collect();
+        let rows = conn.query(&sql[..], &refs)?;
+
+        let order_ids: Vec<i64> = rows.iter().map(|row| row.get(0)).collect();
+        let mut lines = collect_lines(conn.query(
+            "SELECT order_id, good_id, count, unit_price, backordered FROM order_lines
+             WHERE order_id = ANY($1)",
+            &[&order_ids],
+        )?);
+        drop(conn);
+
+        let mut orders = vec![];
+
+        for row in &rows {
+            let order_id: i64 = row.get(0);
+            let order = row_to_order(row, lines.remove(&order_id).unwrap_or_default())?;
+            orders.push((row.get(10), order_id as u64, order));
+        }
+
+        let next = if rows.len() == query.limit {
+            order_ids.last().map(|order_id| *order_id as u64)
+        } else {
+            None
+        };
+
+        Ok(OrderScan { orders, next })
+    }
+
+    fn events(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Vec<OrderEvent>, Box<dyn std::error::Error>> {
+        let rows = self.pool.get()?.query(
+            "SELECT kind, at, actor, correlation_id, details FROM order_events
+             WHERE user_id = $1 AND order_id = $2 ORDER BY id",
+            &[&user_id, &order_id.parse::<i64>()?],
+        )?;
+        let mut events = vec![];
+
+        for row in rows {
+            let kind: &str = row.get(0);
+            let kind = match EventKind::parse(kind) {
+                Some(kind) => kind,
+                None => {
+                    return Err(Box::new(Error::new(
+                        ErrorKind::Other,
+                        format!("line:{}: Unknown order event: {}", line!(), kind),
+                    )))
+                }
+            };
+
+            events.push(OrderEvent {
+                kind,
+                at: row.get::<_, i64>(1) as u64,
+                actor: row.get(2),
+                correlation_id: row.get(3),
+                details: row.get(4),
+            });
+        }
+
+        Ok(events)
+    }
+
+    fn pending(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Option<Order>, Box<dyn std::error::Error>> {
+        let row = self.pool.get()?.query_opt(
+            "SELECT status, goods, created_at, prices, currency, tax_rate_bp, promotion, version,
+             delivery, fulfilment, backorders FROM pending_orders
+             WHERE user_id = $1 AND order_id = $2",
+            &[&user_id, &order_id.parse::<i64>()?],
+        )?;
+
+        match row {
+            None => Ok(None),
+            Some(row) => Ok(Some(Order {
+                status: parse_status(row.get(0))?,
+                goods: serde_json::from_str(row.get(1))?,
+                created_at: row.get::<_, i64>(2) as u64,
+                updated_at: row.get::<_, i64>(2) as u64,
+                version: row.get::<_, i64>(7) as u64,
+                prices: serde_json::from_str(row.get(3))?,
+                currency: row.get(4),
+                tax_rate_bp: row.get::<_, i64>(5) as u64,
+                promotion: json_from_sql(row.get(6))?,
+                delivery: json_from_sql(row.get(8))?,
+                fulfilment: parse_fulfilment(row.get(9))?,
+                backorders: serde_json::from_str(row.get(10))?,
+            })),
+        }
+    }
+
+    fn promotion(&self, code: &str) -> Result<Option<Promotion>, Box<dyn std::error::Error>> {
+        let row = self.pool.get()?.query_opt(
+            "SELECT definition FROM promotions WHERE code = $1",
+            &[&code],
+        )?;
+
+        match row {
+            None => Ok(None),
+            Some(row) => Ok(Some(serde_json::from_str(row.get(0))?)),
+        }
+    }
+
+    fn promotions(&self) -> Result<Vec<Promotion>, Box<dyn std::error::Error>> {
+        let rows = self
+            .pool
+            .get()?
+            .query("SELECT definition FROM promotions ORDER BY code", &[])?;
+        let mut promotions = vec![];
+
+        for row in rows {
+            promotions.push(serde_json::from_str(row.get(0))?);
+        }
+
+        Ok(promotions)
+    }
+
+    fn returns(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Vec<OrderReturn>, Box<dyn std::error::Error>> {
+        let rows = self.pool.get()?.query(
+            "SELECT definition FROM order_returns
+             WHERE user_id = $1 AND order_id = $2 ORDER BY return_id",
+            &[&user_id, &order_id.parse::<i64>()?],
+        )?;
+        let mut returns = vec![];
+
+        for row in rows {
+            returns.push(serde_json::from_str(row.get(0))?);
+        }
+
+        Ok(returns)
+    }
+
+    fn promotion_uses(&self, code: &str) -> Result<Vec<PromotionUse>, Box<dyn std::error::Error>> {
+        let rows = self.pool.get()?.query(
+            "SELECT user_id, order_id FROM promotion_uses WHERE code = $1",
+            &[&code],
+        )?;
+        Ok(rows
+            .iter()
+            .map(|row| PromotionUse {
+                user_id: row.get(0),
+                order_id: row.get::<_, i64>(1).to_string(),
+            })
+            .collect())
+    }
+
+    fn template(
+        &self,
+        user_id: &str,
+        name: &str,
+    ) -> Result<Option<OrderTemplate>, Box<dyn std::error::Error>> {
+        let row = self.pool.get()?.query_opt(
+            "SELECT definition FROM order_templates WHERE user_id = $1 AND name = $2",
+            &[&user_id, &name],
+        )?;
+
+        match row {
+            None => Ok(None),
+            Some(row) => Ok(Some(serde_json::from_str(row.get(0))?)),
+        }
+    }
+
+    fn templates(&self, user_id: &str) -> Result<Vec<OrderTemplate>, Box<dyn std::error::Error>> {
+        let rows = self.pool.get()?.query(
+            "SELECT definition FROM order_templates WHERE user_id = $1 ORDER BY name",
+            &[&user_id],
+        )?;
+        let mut templates = vec![];
+
+        for row in rows {
+            templates.push(serde_json::from_str(row.get(0))?);
+        }
+
+        Ok(templates)
+    }
+
+    fn schedule(
+        &self,
+        user_id: &str,
+        name: &str,
+    ) -> Result<Option<Schedule>, Box<dyn std::error::Error>> {
+        let row = self.pool.get()?.query_opt(
+            "SELECT definition FROM order_schedules WHERE user_id = $1 AND name = $2",
+            &[&user_id, &name],
+        )?;
+
+        match row {
+            None => Ok(None),
+            Some(row) => Ok(Some(serde_json::from_str(row.get(0))?)),
+        }
+    }
+
+    fn schedules(&self, user_id: &str) -> Result<Vec<Schedule>, Box<dyn std::error::Error>> {
+        let rows = self.pool.get()?.query(
+            "SELECT definition FROM order_schedules WHERE user_id = $1 ORDER BY name",
+            &[&user_id],
+        )?;
+        let mut schedules = vec![];
+
+        for row in rows {
+            schedules.push(serde_json::from_str(row.get(0))?);
+        }
+
+        Ok(schedules)
+    }
+
+    fn due_schedules(
+        &self,
+        now: u64,
+        limit: usize,
+    ) -> Result<Vec<ScheduleRecord>, Box<dyn std::error::Error>> {
+        let rows = self.pool.get()?.query(
+            "SELECT user_id, definition FROM order_schedules
+             WHERE next_run_at <= $1 ORDER BY next_run_at LIMIT $2",
+            &[&(now as i64), &(limit as i64)],
+        )?;
+        let mut result = vec![];
+
+        for row in rows {
+            result.push(ScheduleRecord {
+                user_id: row.get(0),
+                schedule: serde_json::from_str(row.get(1))?,
+            });
+        }
+
+        Ok(result)
+    }
+
+    fn saga_state(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Option<SagaState>, Box<dyn std::error::Error>> {
+        let row = self.pool.get()?.query_opt(
+            "SELECT state FROM sagas WHERE user_id = $1 AND order_id = $2",
+            &[&user_id, &order_id.parse::<i64>()?],
+        )?;
+
+        match row {
+            None => Ok(None),
+            Some(row) => Ok(Some(parse_state(row.get(0))?)),
+        }
+    }
+
+    fn expired_sagas(
+        &self,
+        now: u64,
+        limit: usize,
+    ) -> Result<Vec<SagaRecord>, Box<dyn std::error::Error>> {
+        let rows = self.pool.get()?.query(
+            "SELECT user_id, order_id, state FROM sagas
+             WHERE deadline <= $1 ORDER BY deadline LIMIT $2",
+            &[&(now as i64), &(limit as i64)],
+        )?;
+        let mut result = vec![];
+
+        for row in rows {
+            result.push(SagaRecord {
+                user_id: row.get(0),
+                order_id: row.get::<_, i64>(1).to_string(),
+                state: parse_state(row.get(2))?,
+            });
+        }
+
+        Ok(result)
+    }
+
+    fn transactions(&self) -> Result<Vec<TransactionRecord>, Box<dyn std::error::Error>> {
+        let rows = self.pool.get()?.query(
+            "SELECT user_id, order_id, operation, message, created_at, deadline, attempts
+             FROM order_transactions ORDER BY deadline",
+            &[],
+        )?;
+        Ok(rows.iter().map(transaction_record).collect())
+    }
+
+    fn expired_transactions(
+        &self,
+        now: u64,
+        limit: usize,
+    ) -> Result<Vec<TransactionRecord>, Box<dyn std::error::Error>> {
+        let rows = self.pool.get()?.query(
+            "SELECT user_id, order_id, operation, message, created_at, deadline, attempts
+             FROM order_transactions WHERE deadline <= $1 ORDER BY deadline LIMIT $2",
+            &[&(now as i64), &(limit as i64)],
+        )?;
+        Ok(rows.iter().map(transaction_record).collect())
+    }
+
+    fn is_processed(&self, message_id: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let row = self.pool.get()?.query_opt(
+            "SELECT 1 FROM processed_messages WHERE message_id = $1 AND expires_at > $2",
+            &[&message_id, &(now_ms() as i64)],
+        )?;
+        Ok(row.is_some())
+    }
+
+    fn purge_processed(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.pool.get()?.execute(
+            "DELETE FROM processed_messages WHERE expires_at <= $1",
+            &[&(now_ms() as i64)],
+        )?;
+        Ok(())
+    }
+
+    // Writers of outbox are serialized, so ids of its entries follow
+    // the order in which transactions were committed
+    fn apply(&self, changes: Changes) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.pool.get()?;
+        let mut tx = conn.transaction()?;
+
+        if changes
+            .0
+            .iter()
+            .any(|change| matches!(change, Change::PushOutbox(_)))
+        {
+            tx.batch_execute("LOCK TABLE outbox IN SHARE ROW EXCLUSIVE MODE")?;
+        }
+
+        for change in &changes.0 {
+            apply_change(&mut tx, change)?;
+        }
+
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn lock_outbox(&self, owner: &str, ttl_ms: u64) -> Result<bool, Box<dyn std::error::Error>> {
+        let now = now_ms() as i64;
+        let locked = self.pool.get()?.execute(
+            "INSERT INTO outbox_locks (name, owner, expires_at) VALUES ($1, $2, $3)
+             ON CONFLICT (name) DO UPDATE SET owner = EXCLUDED.owner,
+             expires_at = EXCLUDED.expires_at
+             WHERE outbox_locks.owner = EXCLUDED.owner OR outbox_locks.expires_at < $4",
+            &[&OUTBOX_LOCK_NAME, &owner, &(now + ttl_ms as i64), &now],
+        )?;
+        Ok(locked == 1)
+    }
+
+    fn unlock_outbox(&self, owner: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.pool.get()?.execute(
+            "DELETE FROM outbox_locks WHERE name = $1 AND owner = $2",
+            &[&OUTBOX_LOCK_NAME, &owner],
+        )?;
+        Ok(())
+    }
+
+    fn outbox(&self, limit: usize) -> Result<Vec<OutboxEntry>, Box<dyn std::error::Error>> {
+        let rows = self.pool.get()?.query(
+            "SELECT id, entry FROM outbox ORDER BY id LIMIT $1",
+            &[&(limit as i64)],
+        )?;
+        Ok(rows
+            .iter()
+            .map(|row| OutboxEntry {
+                id: row.get::<_, i64>(0) as u64,
+                entry: row.get(1),
+            })
+            .collect())
+    }
+
+    // Lock row is held until entries are deleted, so the lock can't be
+    // taken over by another relay in between
+    fn ack_outbox(
+        &self,
+        owner: &str,
+        entries: &[OutboxEntry],
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut conn = self.pool.get()?;
+        let mut tx = conn.transaction()?;
+        let locked = tx.query(
+            "SELECT 1 FROM outbox_locks WHERE name = $1 AND owner = $2 AND expires_at >= $3
+             FOR UPDATE",
+            &[&OUTBOX_LOCK_NAME, &owner, &(now_ms() as i64)],
+        )?;
+
+        if locked.is_empty() {
+            return Ok(false);
+        }
+
+        let ids: Vec<i64> = entries.iter().map(|entry| entry.id as i64).collect();
+        tx.execute("DELETE FROM outbox WHERE id = ANY($1)", &[&ids])?;
+        tx.commit()?;
+        Ok(true)
+    }
+}
@@ -0,0 +1,1384 @@
+use super::{
+    check_promotion_uses, check_version, now_ms, page, Change, Changes, Conflict, Order, OrderPage,
+    OrderQuery, OrderRepository, OrderScan, OrderSearch, OutboxEntry, SagaRecord, ScheduleRecord,
+    TransactionRecord,
+};
+use crate::backorders::FulfilmentPolicy;
+use crate::events::{EventKind, OrderEvent};
+use crate::promotions::{Promotion, PromotionUse};
+use crate::returns::OrderReturn;
+use crate::saga::SagaState;
+use crate::schedules::Schedule;
+use crate::status::OrderStatus;
+use crate::templates::OrderTemplate;
+use r2d2_redis::{r2d2, redis, RedisConnectionManager};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::ops::DerefMut;
+
+const ORDER_ID_KEY: &str = "order_id";
+const SAGA_DEADLINES_KEY: &str = "saga:deadlines";
+const TX_DEADLINES_KEY: &str = "tx_info:deadlines";
+// Sorted set of '<user_id>:<name>' of schedules, scored by time of the next run
+const SCHEDULES_DUE_KEY: &str = "schedules:due";
+const PROCESSED_KEY_PREFIX: &str = "processed:orders";
+const OUTBOX_KEY: &str = "outbox:orders";
+const PROMOTIONS_KEY: &str = "promotions";
+const OUTBOX_LOCK_KEY: &str = "outbox:orders:lock";
+// Set once all orders stored before indexes were introduced are indexed,
+// it is renamed when a new index is added, so old orders get into it too
+const INDEXED_KEY: &str = "orders:indexed:3";
+// Sorted set of keys of orders of all users scored by order id, sets of
+// orders with a status or a good are kept the same way
+const ALL_ORDERS_KEY: &str = "orders:all";
+
+// Lock is prolonged by its owner, so only one relay publishes messages
+// and their order is kept the same as order of state changes
+const ACQUIRE_LOCK: &str = r#"
+    if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+        return 1
+    end
+    if redis.call('GET', KEYS[1]) == ARGV[1] then
+        redis.call('PEXPIRE', KEYS[1], ARGV[2])
+        return 1
+    end
+    return 0"#;
+
+// Entries have no ids in list, published ones are removed by content,
+// and only while the lock is still held by the relay which read them
+const ACK_OUTBOX: &str = r#"
+    if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+        return 0
+    end
+    for i = 2, #ARGV do
+        redis.call('LREM', KEYS[2], 1, ARGV[i])
+    end
+    return 1"#;
+
+// Returns made before the counter existed were numbered by their count
+const NEXT_RETURN_ID: &str = r#"
+    if redis.call('EXISTS', KEYS[1]) == 0 then
+        redis.call('SET', KEYS[1], redis.call('HLEN', KEYS[2]))
+    end
+    return redis.call('INCR', KEYS[1])"#;
+
+const RELEASE_LOCK: &str = r#"
+    if redis.call('GET', KEYS[1]) == ARGV[1] then
+        return redis.call('DEL', KEYS[1])
+    end
+    return 0"#;
+
+fn order_key(user_id: &str, order_id: &str) -> String {
+    format!("user_id:{}:order_id:{}", user_id, order_id)
+}
+
+// Sorted set of ids of all orders of a user, scored by id
+fn user_orders_key(user_id: &str) -> String {
+    format!("orders:user_id:{}", user_id)
+}
+
+fn status_orders_key(status: OrderStatus) -> String {
+    format!("orders:status:{}", status.as_str())
+}
+
+fn good_orders_key(good_id: u64) -> String {
+    format!("orders:good_id:{}", good_id)
+}
+
+// Order is moved out of sets of other statuses, but previous goods of the
+// order aren't known here, so sets of goods are cleaned up by search
+fn index_order(pipe: &mut redis::Pipeline, key: &str, order_id: u64, order: &Order) {
+    pipe.cmd("ZADD")
+        .arg(ALL_ORDERS_KEY)
+        .arg(order_id)
+        .arg(key)
+        .ignore();
+
+    for status in OrderStatus::ALL
+        .iter()
+        .filter(|status| **status != order.status)
+    {
+        pipe.cmd("ZREM")
+            .arg(&[&status_orders_key(*status), key])
+            .ignore();
+    }
+
+    pipe.cmd("ZADD")
+        .arg(status_orders_key(order.status))
+        .arg(order_id)
+        .arg(key)
+        .ignore();
+
+    for good_id in order.goods.keys() {
+        pipe.cmd("ZADD")
+            .arg(good_orders_key(*good_id))
+            .arg(order_id)
+            .arg(key)
+            .ignore();
+    }
+}
+
+// List of events of the order in json, the oldest first
+fn events_key(user_id: &str, order_id: &str) -> String {
+    format!("events:user_id:{}:order_id:{}", user_id, order_id)
+}
+
+// Hash of returns of the order in json by return id
+fn returns_key(user_id: &str, order_id: &str) -> String {
+    format!("returns:user_id:{}:order_id:{}", user_id, order_id)
+}
+
+// Last return id given within the order
+fn return_id_key(user_id: &str, order_id: &str) -> String {
+    format!("return_id:user_id:{}:order_id:{}", user_id, order_id)
+}
+
+// Hash of order templates of the user in json by name
+fn templates_key(user_id: &str) -> String {
+    format!("templates:user_id:{}", user_id)
+}
+
+// Hash of schedules of the user in json by name
+fn schedules_key(user_id: &str) -> String {
+    format!("schedules:user_id:{}", user_id)
+}
+
+fn schedule_member(user_id: &str, name: &str) -> String {
+    format!("user_id:{}:name:{}", user_id, name)
+}
+
+// Members look like 'user_id:<user_id>:name:<name>'
+fn split_schedule_member(member: &str) -> Option<(String, String)> {
+    let splits: Vec<&str> = member.split(':').collect();
+
+    if splits.len() == 4 {
+        Some((splits[1].to_string(), splits[3].to_string()))
+    } else {
+        None
+    }
+}
+
+// List of '<at>:<status>' entries written before events were introduced
+fn history_key(user_id: &str, order_id: &str) -> String {
+    format!("status_history:user_id:{}:order_id:{}", user_id, order_id)
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredEvent {
+    kind: String,
+    at: u64,
+    actor: String,
+    correlation_id: String,
+    details: String,
+}
+
+fn parse_kind(kind: &str) -> Result<EventKind, Box<dyn std::error::Error>> {
+    match EventKind::parse(kind) {
+        Some(kind) => Ok(kind),
+        None => Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!("line:{}: Unknown order event: {}", line!(), kind),
+        ))),
+    }
+}
+
+fn parse_status(status: &str) -> Result<OrderStatus, Box<dyn std::error::Error>> {
+    match OrderStatus::parse(status) {
+        Some(status) => Ok(status),
+        None => Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!("line:{}: Unknown order status: {}", line!(), status),
+        ))),
+    }
+}
+
+fn parse_fulfilment(policy: &str) -> Result<FulfilmentPolicy, Box<dyn std::error::Error>> {
+    match FulfilmentPolicy::parse(policy) {
+        Some(policy) => Ok(policy),
+        None => Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!("line:{}: Unknown fulfilment policy: {}", line!(), policy),
+        ))),
+    }
+}
+
+// Promotion is kept in json, codes of all promotions are in a set
+fn promotion_key(code: &str) -> String {
+    format!("promotion:{}", code)
+}
+
+// Set of '<user_id>:<order_id>' of orders paid with the code
+fn promotion_uses_key(code: &str) -> String {
+    format!("promotion_uses:{}", code)
+}
+
+fn tx_key(user_id: &str, order_id: &str) -> String {
+    format!("tx:user_id:{}:order_id:{}", user_id, order_id)
+}
+
+fn saga_key(user_id: &str, order_id: &str) -> String {
+    format!("saga:user_id:{}:order_id:{}", user_id, order_id)
+}
+
+fn info_key(user_id: &str, order_id: &str) -> String {
+    format!("tx_info:user_id:{}:order_id:{}", user_id, order_id)
+}
+
+// Keys look like '<prefix>:user_id:<user_id>:order_id:<order_id>'
+fn split_key(key: &str) -> Option<(String, String)> {
+    let splits: Vec<&str> = key.split(':').collect();
+
+    if splits.len() == 5 {
+        Some((splits[2].to_string(), splits[4].to_string()))
+    } else {
+        None
+    }
+}
+
+fn parse_order(hash: HashMap<String, String>) -> Result<Order, Box<dyn std::error::Error>> {
+    let mut order = Order::default();
+
+    for (key, value) in hash {
+        if key == "status" {
+            order.status = parse_status(&value)?;
+        } else if key == "created_at" {
+            order.created_at = value.parse()?;
+        } else if key == "updated_at" {
+            order.updated_at = value.parse()?;
+        } else if key == "version" {
+            order.version = value.parse()?;
+        } else if key == "currency" {
+            order.currency = value;
+        } else if key == "tax_rate_bp" {
+            order.tax_rate_bp = value.parse()?;
+        } else if key == "promotion" {
+            order.promotion = Some(serde_json::from_str(&value)?);
+        } else if key == "delivery" {
+            order.delivery = Some(serde_json::from_str(&value)?);
+        } else if key == "fulfilment" {
+            order.fulfilment = parse_fulfilment(&value)?;
+        } else if let Some(good_id) = key.strip_prefix("backorder:") {
+            order.backorders.insert(good_id.parse()?, value.parse()?);
+        } else if let Some(good_id) = key.strip_prefix("price:") {
+            order.prices.insert(good_id.parse()?, value.parse()?);
+        } else if let Some(good_id) = key.strip_prefix("good_id:") {
+            order.goods.insert(good_id.parse()?, value.parse()?);
+        }
+    }
+
+    Ok(order)
+}
+
+fn write_order(
+    pipe: &mut redis::Pipeline,
+    key: &str,
+    order: &Order,
+) -> Result<(), Box<dyn std::error::Error>> {
+    pipe.cmd("DEL")
+        .arg(key)
+        .cmd("HSET")
+        .arg(&[key, "status", order.status.as_str()])
+        .cmd("HSET")
+        .arg(&[key, "created_at"])
+        .arg(order.created_at)
+        .cmd("HSET")
+        .arg(&[key, "updated_at"])
+        .arg(now_ms())
+        .cmd("HSET")
+        .arg(&[key, "version"])
+        .arg(order.version)
+        .cmd("HSET")
+        .arg(&[key, "currency", &order.currency])
+        .cmd("HSET")
+        .arg(&[key, "tax_rate_bp"])
+        .arg(order.tax_rate_bp)
+        .cmd("HSET")
+        .arg(&[key, "fulfilment", order.fulfilment.as_str()]);
+
+    for (good_id, count) in &order.goods {
+        pipe.cmd("HSET")
+            .arg(&[key, &format!("good_id:{}", good_id)])
+            .arg(*count);
+    }
+
+    for (good_id, price) in &order.prices {
+        pipe.cmd("HSET")
+            .arg(&[key, &format!("price:{}", good_id)])
+            .arg(*price);
+    }
+
+    for (good_id, count) in &order.backorders {
+        pipe.cmd("HSET")
+            .arg(&[key, &format!("backorder:{}", good_id)])
+            .arg(*count);
+    }
+
+    if let Some(promotion) = &order.promotion {
+        pipe.cmd("HSET")
+            .arg(&[key, "promotion", &serde_json::to_string(promotion)?]);
+    }
+
+    if let Some(delivery) = &order.delivery {
+        pipe.cmd("HSET")
+            .arg(&[key, "delivery", &serde_json::to_string(delivery)?]);
+    }
+
+    Ok(())
+}
+
+pub struct RedisStorage {
+    pool: r2d2::Pool<RedisConnectionManager>,
+}
+
+impl RedisStorage {
+    pub fn new(connection_string: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let manager = RedisConnectionManager::new(connection_string)?;
+        let pool = r2d2::Pool::builder().build(manager)?;
+        let storage = RedisStorage { pool };
+        storage.index_orders()?;
+        Ok(storage)
+    }
+
+    // Whole keyspace is scanned only once, afterwards indexes are kept
+    // up to date by changes of orders
+    fn index_orders(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.pool.get()?;
+        let indexed: i32 = redis::cmd("EXISTS")
+            .arg(INDEXED_KEY)
+            .query(conn.deref_mut())?;
+
+        if indexed == 1 {
+            return Ok(());
+        }
+
+        info!("line:{}: Indexing orders of users", line!());
+        let mut cursor = 0;
+
+        loop {
+            let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                .cursor_arg(cursor)
+                .arg(&["MATCH", "user_id:*:order_id:*", "COUNT", "1000"])
+                .query(conn.deref_mut())?;
+            let mut pipe = redis::pipe();
+
+            for key in &keys {
+                pipe.cmd("HGETALL").arg(key);
+            }
+
+            let hashes: Vec<HashMap<String, String>> = pipe.query(conn.deref_mut())?;
+            let mut pipe = redis::pipe();
+
+            for (key, hash) in keys.iter().zip(hashes) {
+                let splits: Vec<&str> = key.split(':').collect();
+
+                if splits.len() == 4 && !hash.is_empty() {
+                    if let Ok(order_id) = splits[3].parse::<u64>() {
+                        pipe.cmd("ZADD")
+                            .arg(user_orders_key(splits[1]))
+                            .arg(order_id)
+                            .arg(order_id)
+                            .ignore();
+                        index_order(&mut pipe, key, order_id, &parse_order(hash)?);
+                    }
+                }
+            }
+
+            pipe.query::<()>(conn.deref_mut())?;
+
+            if next == 0 {
+                break;
+            }
+
+            cursor = next;
+        }
+
+        redis::cmd("SET")
+            .arg(&[INDEXED_KEY, "1"])
+            .query::<()>(conn.deref_mut())?;
+        Ok(())
+    }
+
+    fn hash(
+        &self,
+        key: &str,
+    ) -> Result<Option<HashMap<String, String>>, Box<dyn std::error::Error>> {
+        let hash: HashMap<String, String> = redis::cmd("HGETALL")
+            .arg(key)
+            .query(self.pool.get()?.deref_mut())?;
+
+        if hash.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(hash))
+        }
+    }
+
+    fn transaction(
+        &self,
+        key: &str,
+    ) -> Result<Option<TransactionRecord>, Box<dyn std::error::Error>> {
+        let ids = split_key(key);
+        let info = self.hash(key)?;
+
+        let ((user_id, order_id), info) = match (ids, info) {
+            (Some(ids), Some(info)) if info.contains_key("message") => (ids, info),
+            _ => return Ok(None),
+        };
+
+        let field = |name: &str| -> u64 {
+            info.get(name)
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(0)
+        };
+
+        Ok(Some(TransactionRecord {
+            user_id,
+            order_id,
+            operation: info.get("operation").cloned().unwrap_or_default(),
+            message: info["message"].clone(),
+            created_at: field("created_at"),
+            deadline: field("deadline"),
+            attempts: field("attempts"),
+        }))
+    }
+
+    fn check_watched(
+        &self,
+        conn: &mut r2d2::PooledConnection<RedisConnectionManager>,
+        pending_keys: &[String],
+        versions: &[(&String, &String, &Order)],
+        promotion_uses: &[&Change],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if !pending_keys.is_empty() {
+            let exists: i32 = redis::cmd("EXISTS")
+                .arg(pending_keys)
+                .query(conn.deref_mut())?;
+
+            if exists != 0 {
+                return Err(Box::new(Conflict(format!(
+                    "line:{}: Transaction {} already exists",
+                    line!(),
+                    pending_keys.join(", ")
+                ))));
+            }
+        }
+
+        for (user_id, order_id, order) in versions {
+            let stored: Option<u64> = redis::cmd("HGET")
+                .arg(&[&order_key(user_id, order_id), "version"])
+                .query(conn.deref_mut())?;
+            check_version(user_id, order_id, stored, order)?;
+        }
+
+        for change in promotion_uses {
+            if let Change::AddPromotionUse {
+                code,
+                user_id,
+                order_id,
+                max_uses,
+                max_uses_per_user,
+            } = change
+            {
+                let members: Vec<String> = redis::cmd("SMEMBERS")
+                    .arg(promotion_uses_key(code))
+                    .query(conn.deref_mut())?;
+                let member = format!("{}:{}", user_id, order_id);
+                let others: Vec<&String> = members.iter().filter(|used| **used != member).collect();
+                let user_uses = others
+                    .iter()
+                    .filter(|used| used.starts_with(&format!("{}:", user_id)))
+                    .count();
+                check_promotion_uses(
+                    code,
+                    others.len() as u64,
+                    user_uses as u64,
+                    *max_uses,
+                    *max_uses_per_user,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_change(
+        &self,
+        pipe: &mut redis::Pipeline,
+        change: &Change,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match change {
+            Change::PutOrder {
+                user_id,
+                order_id,
+                order,
+            } => {
+                let key = order_key(user_id, order_id);
+                write_order(pipe, &key, order)?;
+                pipe.cmd("HINCRBY")
+                    .arg(&[&key, "version"])
+                    .arg(1)
+                    .cmd("ZADD")
+                    .arg(user_orders_key(user_id))
+                    .arg(order_id)
+                    .arg(order_id);
+                index_order(pipe, &key, order_id.parse()?, order);
+            }
+            Change::DeleteOrder { user_id, order_id } => {
+                pipe.cmd("DEL")
+                    .arg(order_key(user_id, order_id))
+                    .cmd("DEL")
+                    .arg(events_key(user_id, order_id))
+                    .cmd("DEL")
+                    .arg(history_key(user_id, order_id))
+                    .cmd("DEL")
+                    .arg(returns_key(user_id, order_id))
+                    .cmd("DEL")
+                    .arg(return_id_key(user_id, order_id))
+                    .cmd("ZREM")
+                    .arg(&[user_orders_key(user_id), order_id.to_string()])
+                    .cmd("ZREM")
+                    .arg(&[ALL_ORDERS_KEY, &order_key(user_id, order_id)]);
+
+                for status in OrderStatus::ALL.iter() {
+                    pipe.cmd("ZREM")
+                        .arg(&[status_orders_key(*status), order_key(user_id, order_id)]);
+                }
+            }
+            Change::BeginPending {
+                user_id,
+                order_id,
+                order,
+            } => write_order(pipe, &tx_key(user_id, order_id), order)?,
+            Change::ClearPending { user_id, order_id } => {
+                pipe.cmd("DEL").arg(tx_key(user_id, order_id));
+            }
+            Change::SetSaga {
+                user_id,
+                order_id,
+                state,
+                deadline,
+            } => {
+                let saga_key = &saga_key(user_id, order_id);
+
+                pipe.cmd("HSET")
+                    .arg(&[saga_key, "state", state.as_str()])
+                    .cmd("HSET")
+                    .arg(&[saga_key, "updated_at"])
+                    .arg(now_ms());
+
+                match deadline {
+                    Some(deadline) => {
+                        pipe.cmd("HSET")
+                            .arg(&[saga_key, "deadline"])
+                            .arg(*deadline)
+                            .cmd("ZADD")
+                            .arg(SAGA_DEADLINES_KEY)
+                            .arg(*deadline)
+                            .arg(saga_key);
+                    }
+                    None => {
+                        pipe.cmd("HDEL")
+                            .arg(&[saga_key, "deadline"])
+                            .cmd("ZREM")
+                            .arg(&[SAGA_DEADLINES_KEY, saga_key]);
+                    }
+                }
+            }
+            Change::RemoveSaga { user_id, order_id } => {
+                let saga_key = &saga_key(user_id, order_id);
+                pipe.cmd("DEL")
+                    .arg(saga_key)
+                    .cmd("ZREM")
+                    .arg(&[SAGA_DEADLINES_KEY, saga_key]);
+            }
+            Change::BeginTransaction(record) => {
+                let info_key = &info_key(&record.user_id, &record.order_id);
+                pipe.cmd("DEL")
+                    .arg(info_key)
+                    .cmd("HSET")
+                    .arg(&[info_key, "operation", &record.operation])
+                    .cmd("HSET")
+                    .arg(&[info_key, "message", &record.message])
+                    .cmd("HSET")
+                    .arg(&[info_key, "created_at"])
+                    .arg(record.created_at)
+                    .cmd("HSET")
+                    .arg(&[info_key, "deadline"])
+                    .arg(record.deadline)
+                    .cmd("HSET")
+                    .arg(&[info_key, "attempts"])
+                    .arg(record.attempts)
+                    .cmd("ZADD")
+                    .arg(TX_DEADLINES_KEY)
+                    .arg(record.deadline)
+                    .arg(info_key);
+            }
+            Change::RetryTransaction {
+                user_id,
+                order_id,
+                deadline,
+            } => {
+                let info_key = &info_key(user_id, order_id);
+                pipe.cmd("HINCRBY")
+                    .arg(&[info_key, "attempts", "1"])
+                    .cmd("HSET")
+                    .arg(&[info_key, "deadline"])
+                    .arg(*deadline)
+                    .cmd("ZADD")
+                    .arg(TX_DEADLINES_KEY)
+                    .arg(*deadline)
+                    .arg(info_key);
+            }
+            Change::FinishTransaction { user_id, order_id } => {
+                let info_key = &info_key(user_id, order_id);
+                pipe.cmd("DEL")
+                    .arg(info_key)
+                    .cmd("ZREM")
+                    .arg(&[TX_DEADLINES_KEY, info_key]);
+            }
+            Change::RecordEvent {
+                user_id,
+                order_id,
+                event,
+            } => {
+                let event = StoredEvent {
+                    kind: event.kind.as_str().to_string(),
+                    at: event.at,
+                    actor: event.actor.clone(),
+                    correlation_id: event.correlation_id.clone(),
+                    details: event.details.clone(),
+                };
+                pipe.cmd("RPUSH")
+                    .arg(events_key(user_id, order_id))
+                    .arg(serde_json::to_string(&event)?);
+            }
+            Change::PutPromotion(promotion) => {
+                pipe.cmd("SET")
+                    .arg(promotion_key(&promotion.code))
+                    .arg(serde_json::to_string(promotion)?)
+                    .cmd("SADD")
+                    .arg(&[PROMOTIONS_KEY, &promotion.code]);
+            }
+            Change::PutReturn {
+                user_id,
+                order_id,
+                order_return,
+            } => {
+                pipe.cmd("HSET")
+                    .arg(returns_key(user_id, order_id))
+                    .arg(order_return.return_id)
+                    .arg(serde_json::to_string(order_return)?);
+            }
+            Change::PutTemplate { user_id, template } => {
+                pipe.cmd("HSET")
+                    .arg(templates_key(user_id))
+                    .arg(&template.name)
+                    .arg(serde_json::to_string(template)?);
+            }
+            Change::DeleteTemplate { user_id, name } => {
+                pipe.cmd("HDEL").arg(templates_key(user_id)).arg(name);
+            }
+            Change::PutSchedule { user_id, schedule } => {
+                pipe.cmd("HSET")
+                    .arg(schedules_key(user_id))
+                    .arg(&schedule.name)
+                    .arg(serde_json::to_string(schedule)?)
+                    .cmd("ZADD")
+                    .arg(SCHEDULES_DUE_KEY)
+                    .arg(schedule.next_run_at)
+                    .arg(schedule_member(user_id, &schedule.name));
+            }
+            Change::DeleteSchedule { user_id, name } => {
+                pipe.cmd("HDEL")
+                    .arg(schedules_key(user_id))
+                    .arg(name)
+                    .cmd("ZREM")
+                    .arg(SCHEDULES_DUE_KEY)
+                    .arg(schedule_member(user_id, name));
+            }
+            Change::DeletePromotion { code } => {
+                pipe.cmd("DEL")
+                    .arg(promotion_key(code))
+                    .cmd("SREM")
+                    .arg(&[PROMOTIONS_KEY, code]);
+            }
+            Change::AddPromotionUse {
+                code,
+                user_id,
+                order_id,
+                ..
+            } => {
+                pipe.cmd("SADD")
+                    .arg(promotion_uses_key(code))
+                    .arg(format!("{}:{}", user_id, order_id));
+            }
+            Change::RemovePromotionUse {
+                code,
+                user_id,
+                order_id,
+            } => {
+                pipe.cmd("SREM")
+                    .arg(promotion_uses_key(code))
+                    .arg(format!("{}:{}", user_id, order_id));
+            }
+            Change::PushOutbox(entry) => {
+                pipe.cmd("RPUSH").arg(OUTBOX_KEY).arg(entry);
+            }
+            Change::MarkProcessed {
+                message_id,
+                ttl_secs,
+            } => {
+                pipe.cmd("SET")
+                    .arg(format!("{}:{}", PROCESSED_KEY_PREFIX, message_id))
+                    .arg(1)
+                    .arg("EX")
+                    .arg(*ttl_secs);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl OrderRepository for RedisStorage {
+    fn next_order_id(&self) -> Result<i64, Box<dyn std::error::Error>> {
+        let order_id: i64 = redis::cmd("INCR")
+            .arg(ORDER_ID_KEY)
+            .query(self.pool.get()?.deref_mut())?;
+        Ok(order_id)
+    }
+
+    fn next_return_id(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let return_id: u64 = redis::cmd("EVAL")
+            .arg(&[
+                NEXT_RETURN_ID,
+                "2",
+                &return_id_key(user_id, order_id),
+                &returns_key(user_id, order_id),
+            ])
+            .query(self.pool.get()?.deref_mut())?;
+        Ok(return_id)
+    }
+
+    fn order(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Option<Order>, Box<dyn std::error::Error>> {
+        match self.hash(&order_key(user_id, order_id))? {
+            Some(hash) => Ok(Some(parse_order(hash)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Filters and sorting are applied to all orders of the user, their
+    // number is expected to stay small
+    fn orders(
+        &self,
+        user_id: &str,
+        query: &OrderQuery,
+    ) -> Result<OrderPage, Box<dyn std::error::Error>> {
+        let mut conn = self.pool.get()?;
+        let order_ids: Vec<String> = redis::cmd("ZRANGE")
+            .arg(user_orders_key(user_id))
+            .arg(0)
+            .arg(-1)
+            .query(conn.deref_mut())?;
+
+        let mut pipe = redis::pipe();
+
+        for order_id in &order_ids {
+            pipe.cmd("HGETALL").arg(order_key(user_id, order_id));
+        }
+
+        let hashes: Vec<HashMap<String, String>> = pipe.query(conn.deref_mut())?;
+        let mut orders = vec![];
+
+        for (order_id, hash) in order_ids.iter().zip(hashes) {
+            if !hash.is_empty() {
+                orders.push((order_id.parse()?, parse_order(hash)?));
+            }
+        }
+
+        Ok(page(orders, query))
+    }
+
+    // Orders are read from the smallest index which fits the search, the
+    // rest of filters is checked on orders themselves, members of indexes
+    // which no longer belong to them are removed along the way
+    fn search_orders(
+        &self,
+        search: &OrderSearch,
+        cursor: u64,
+    ) -> Result<OrderScan, Box<dyn std::error::Error>> {
+        let limit = search.query.limit;
+        let (index, owner) = match (&search.user_id, search.good_id, search.query.status) {
+            (Some(user_id), _, _) => (user_orders_key(user_id), Some(user_id)),
+            (None, Some(good_id), _) => (good_orders_key(good_id), None),
+            (None, None, Some(status)) => (status_orders_key(status), None),
+            (None, None, None) => (ALL_ORDERS_KEY.to_string(), None),
+        };
+        // Order ids are unique, so search by id reads the one entry with it
+        let (mut min, max) = match search.order_id {
+            Some(order_id) if order_id <= cursor => {
+                return Ok(OrderScan {
+                    orders: vec![],
+                    next: None,
+                })
+            }
+            Some(order_id) => (format!("[{}", order_id), order_id.to_string()),
+            None => (format!("({}", cursor), "+inf".to_string()),
+        };
+
+        let mut conn = self.pool.get()?;
+        let mut orders = vec![];
+
+        loop {
+            let entries: Vec<(String, u64)> = redis::cmd("ZRANGEBYSCORE")
+                .arg(&index)
+                .arg(&min)
+                .arg(&max)
+                .arg("WITHSCORES")
+                .arg("LIMIT")
+                .arg(0)
+                .arg(limit)
+                .query(conn.deref_mut())?;
+            let keys: Vec<String> = entries
+                .iter()
+                .map(|(member, _)| match owner {
+                    Some(user_id) => order_key(user_id, member),
+                    None => member.clone(),
+                })
+                .collect();
+
+            let mut pipe = redis::pipe();
+
+            for key in &keys {
+                pipe.cmd("HGETALL").arg(key);
+            }
+
+            let hashes: Vec<HashMap<String, String>> = pipe.query(conn.deref_mut())?;
+            let mut stale = redis::pipe();
+
+            for (((member, order_id), key), hash) in entries.iter().zip(&keys).zip(hashes) {
+                let user_id = match key.split(':').nth(1) {
+                    Some(user_id) => user_id,
+                    None => continue,
+                };
+
+                if hash.is_empty() {
+                    stale.cmd("ZREM").arg(&[&index, member]).ignore();
+                    continue;
+                }
+
+                let order = parse_order(hash)?;
+                let belongs = match (owner, search.good_id, search.query.status) {
+                    (None, Some(good_id), _) => order.goods.contains_key(&good_id),
+                    (None, None, Some(status)) => order.status == status,
+                    _ => true,
+                };
+
+                if !belongs {
+                    stale.cmd("ZREM").arg(&[&index, member]).ignore();
+                } else if search.matches(user_id, *order_id, &order) {
+                    orders.push((user_id.to_string(), *order_id, order));
+                }
+            }
+
+            stale.query::<()>(conn.deref_mut())?;
+
+            let exhausted = entries.len() < limit || search.order_id.is_some();
+
+            if let Some((_, order_id)) = entries.last() {
+                min = format!("({}", order_id);
+            }
+
+            if exhausted || orders.len() >= limit {
+                let next = if orders.len() > limit || (orders.len() == limit && !exhausted) {
+                    orders.truncate(limit);
+                    orders.last().map(|(_, order_id, _)| *order_id)
+                } else {
+                    None
+                };
+
+                return Ok(OrderScan { orders, next });
+            }
+        }
+    }
+
+    fn events(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Vec<OrderEvent>, Box<dyn std::error::Error>> {
+        let mut conn = self.pool.get()?;
+        let entries: Vec<String> = redis::cmd("LRANGE")
+            .arg(events_key(user_id, order_id))
+            .arg(0)
+            .arg(-1)
+            .query(conn.deref_mut())?;
+        let mut events = vec![];
+
+        for entry in entries {
+            let event: StoredEvent = serde_json::from_str(&entry)?;
+            events.push(OrderEvent {
+                kind: parse_kind(&event.kind)?,
+                at: event.at,
+                actor: event.actor,
+                correlation_id: event.correlation_id,
+                details: event.details,
+            });
+        }
+
+        if !events.is_empty() {
+            return Ok(events);
+        }
+
+        let entries: Vec<String> = redis::cmd("LRANGE")
+            .arg(history_key(user_id, order_id))
+            .arg(0)
+            .arg(-1)
+            .query(conn.deref_mut())?;
+
+        for entry in entries {
+            let mut splits = entry.splitn(2, ':');
+
+            if let (Some(at), Some(status)) = (splits.next(), splits.next()) {
+                let kind = match parse_status(status)? {
+                    OrderStatus::New => EventKind::Created,
+                    status => EventKind::Status(status),
+                };
+                events.push(OrderEvent {
+                    kind,
+                    at: at.parse()?,
+                    actor: "system".to_string(),
+                    correlation_id: String::new(),
+                    details: String::new(),
+                });
+            }
+        }
+
+        Ok(events)
+    }
+
+    fn pending(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Option<Order>, Box<dyn std::error::Error>> {
+        match self.hash(&tx_key(user_id, order_id))? {
+            Some(hash) => Ok(Some(parse_order(hash)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn promotion(&self, code: &str) -> Result<Option<Promotion>, Box<dyn std::error::Error>> {
+        let promotion: Option<String> = redis::cmd("GET")
+            .arg(promotion_key(code))
+            .query(self.pool.get()?.deref_mut())?;
+
+        match promotion {
+            Some(promotion) => Ok(Some(serde_json::from_str(&promotion)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn promotions(&self) -> Result<Vec<Promotion>, Box<dyn std::error::Error>> {
+        let mut conn = self.pool.get()?;
+        let mut codes: Vec<String> = redis::cmd("SMEMBERS")
+            .arg(PROMOTIONS_KEY)
+            .query(conn.deref_mut())?;
+        codes.sort();
+
+        let mut pipe = redis::pipe();
+
+        for code in &codes {
+            pipe.cmd("GET").arg(promotion_key(code));
+        }
+
+        let entries: Vec<Option<String>> = pipe.query(conn.deref_mut())?;
+        let mut promotions = vec![];
+
+        for entry in entries.into_iter().flatten() {
+            promotions.push(serde_json::from_str(&entry)?);
+        }
+
+        Ok(promotions)
+    }
+
+    // Order id never contains ':', so user id is everything before the last one
+    fn promotion_uses(&self, code: &str) -> Result<Vec<PromotionUse>, Box<dyn std::error::Error>> {
+        let members: Vec<String> = redis::cmd("SMEMBERS")
+            .arg(promotion_uses_key(code))
+            .query(self.pool.get()?.deref_mut())?;
+
+        Ok(members
+            .into_iter()
+            .filter_map(|member| {
+                let mut splits = member.rsplitn(2, ':');
+                let order_id = splits.next()?.to_string();
+                let user_id = splits.next()?.to_string();
+                Some(PromotionUse { user_id, order_id })
+            })
+            .collect())
+    }
+
+    fn returns(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Vec<OrderReturn>, Box<dyn std::error::Error>> {
+        let values: Vec<String> = redis::cmd("HVALS")
+            .arg(returns_key(user_id, order_id))
+            .query(self.pool.get()?.deref_mut())?;
+        let mut returns = vec![];
+
+        for value in values {
+            let order_return: OrderReturn = serde_json::from_str(&value)?;
+            returns.push(order_return);
+        }
+
+        returns.sort_by_key(|order_return| order_return.return_id);
+        Ok(returns)
+    }
+
+    fn template(
+        &self,
+        user_id: &str,
+        name: &str,
+    ) -> Result<Option<OrderTemplate>, Box<dyn std::error::Error>> {
+        let value: Option<String> = redis::cmd("HGET")
+            .arg(templates_key(user_id))
+            .arg(name)
+            .query(self.pool.get()?.deref_mut())?;
+
+        match value {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn templates(&self, user_id: &str) -> Result<Vec<OrderTemplate>, Box<dyn std::error::Error>> {
+        let values: Vec<String> = redis::cmd("HVALS")
+            .arg(templates_key(user_id))
+            .query(self.pool.get()?.deref_mut())?;
+        let mut templates = vec![];
+
+        for value in values {
+            let template: OrderTemplate = serde_json::from_str(&value)?;
+            templates.push(template);
+        }
+
+        templates.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(templates)
+    }
+
+    fn schedule(
+        &self,
+        user_id: &str,
+        name: &str,
+    ) -> Result<Option<Schedule>, Box<dyn std::error::Error>> {
+        let value: Option<String> = redis::cmd("HGET")
+            .arg(schedules_key(user_id))
+            .arg(name)
+            .query(self.pool.get()?.deref_mut())?;
+
+        match value {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn schedules(&self, user_id: &str) -> Result<Vec<Schedule>, Box<dyn std::error::Error>> {
+        let values: Vec<String> = redis::cmd("HVALS")
+            .arg(schedules_key(user_id))
+            .query(self.pool.get()?.deref_mut())?;
+        let mut schedules = vec![];
+
+        for value in values {
+            let schedule: Schedule = serde_json::from_str(&value)?;
+            schedules.push(schedule);
+        }
+
+        schedules.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(schedules)
+    }
+
+    fn due_schedules(
+        &self,
+        now: u64,
+        limit: usize,
+    ) -> Result<Vec<ScheduleRecord>, Box<dyn std::error::Error>> {
+        let members: Vec<String> = redis::cmd("ZRANGEBYSCORE")
+            .arg(&[SCHEDULES_DUE_KEY, "-inf"])
+            .arg(now)
+            .arg(&["LIMIT", "0"])
+            .arg(limit)
+            .query(self.pool.get()?.deref_mut())?;
+        let mut result = vec![];
+
+        for member in &members {
+            let schedule = match split_schedule_member(member) {
+                Some((user_id, name)) => self
+                    .schedule(&user_id, &name)?
+                    .map(|schedule| (user_id, schedule)),
+                None => None,
+            };
+
+            match schedule {
+                Some((user_id, schedule)) => result.push(ScheduleRecord { user_id, schedule }),
+                None => {
+                    error!(
+                        "line:{}: Invalid schedule in due schedules: {}",
+                        line!(),
+                        member
+                    );
+                    redis::cmd("ZREM")
+                        .arg(&[SCHEDULES_DUE_KEY, member])
+                        .query::<()>(self.pool.get()?.deref_mut())?;
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn saga_state(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Option<SagaState>, Box<dyn std::error::Error>> {
+        let state: Option<String> = redis::cmd("HGET")
+            .arg(&[&saga_key(user_id, order_id), "state"])
+            .query(self.pool.get()?.deref_mut())?;
+
+        match state {
+            None => Ok(None),
+            Some(state) => match SagaState::parse(&state) {
+                Some(state) => Ok(Some(state)),
+                None => Err(Box::new(Error::new(
+                    ErrorKind::Other,
+                    format!("line:{}: Unknown saga state: {}", line!(), state),
+                ))),
+            },
+        }
+    }
+
+    fn expired_sagas(
+        &self,
+        now: u64,
+        limit: usize,
+    ) -> Result<Vec<SagaRecord>, Box<dyn std::error::Error>> {
+        let keys: Vec<String> = redis::cmd("ZRANGEBYSCORE")
+            .arg(&[SAGA_DEADLINES_KEY, "-inf"])
+            .arg(now)
+            .arg(&["LIMIT", "0"])
+            .arg(limit)
+            .query(self.pool.get()?.deref_mut())?;
+        let mut result = vec![];
+
+        for key in &keys {
+            let state = match split_key(key) {
+                Some((user_id, order_id)) => self
+                    .saga_state(&user_id, &order_id)?
+                    .map(|state| (user_id, order_id, state)),
+                None => None,
+            };
+
+            match state {
+                Some((user_id, order_id, state)) => result.push(SagaRecord {
+                    user_id,
+                    order_id,
+                    state,
+                }),
+                None => {
+                    error!("line:{}: Invalid saga key in deadlines: {}", line!(), key);
+                    redis::cmd("ZREM")
+                        .arg(&[SAGA_DEADLINES_KEY, key])
+                        .query::<()>(self.pool.get()?.deref_mut())?;
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn transactions(&self) -> Result<Vec<TransactionRecord>, Box<dyn std::error::Error>> {
+        let keys: Vec<String> = redis::cmd("ZRANGE")
+            .arg(&[TX_DEADLINES_KEY, "0", "-1"])
+            .query(self.pool.get()?.deref_mut())?;
+        let mut result = vec![];
+
+        for key in &keys {
+            if let Some(record) = self.transaction(key)? {
+                result.push(record);
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn expired_transactions(
+        &self,
+        now: u64,
+        limit: usize,
+    ) -> Result<Vec<TransactionRecord>, Box<dyn std::error::Error>> {
+        let keys: Vec<String> = redis::cmd("ZRANGEBYSCORE")
+            .arg(&[TX_DEADLINES_KEY, "-inf"])
+            .arg(now)
+            .arg(&["LIMIT", "0"])
+            .arg(limit)
+            .query(self.pool.get()?.deref_mut())?;
+        let mut result = vec![];
+
+        for key in &keys {
+            match self.transaction(key)? {
+                Some(record) => result.push(record),
+                None => {
+                    error!("line:{}: Invalid transaction record: {}", line!(), key);
+                    redis::cmd("ZREM")
+                        .arg(&[TX_DEADLINES_KEY, key])
+                        .query::<()>(self.pool.get()?.deref_mut())?;
+                }
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn is_processed(&self, message_id: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let exists: i32 = redis::cmd("EXISTS")
+            .arg(format!("{}:{}", PROCESSED_KEY_PREFIX, message_id))
+            .query(self.pool.get()?.deref_mut())?;
+        Ok(exists == 1)
+    }
+
+    // Processed marks expire by themselves
+    fn purge_processed(&self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    // Orders and their pending copies are watched, so an order can't be
+    // written over a version it wasn't read with and two transactions
+    // can't be started for the same order at once, uses of promotions
+    // are watched, so they aren't counted over the limits
+    fn apply(&self, changes: Changes) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.pool.get()?;
+        let mut pending_keys = vec![];
+        let mut versions = vec![];
+        let mut promotion_keys = vec![];
+        let mut promotion_uses = vec![];
+
+        for change in &changes.0 {
+            match change {
+                Change::BeginPending {
+                    user_id,
+                    order_id,
+                    order,
+                } => {
+                    pending_keys.push(tx_key(user_id, order_id));
+                    versions.push((user_id, order_id, order));
+                }
+                Change::PutOrder {
+                    user_id,
+                    order_id,
+                    order,
+                } => versions.push((user_id, order_id, order)),
+                Change::AddPromotionUse { code, .. } => {
+                    promotion_keys.push(promotion_uses_key(code));
+                    promotion_uses.push(change);
+                }
+                _ => {}
+            }
+        }
+
+        let watched: Vec<String> = versions
+            .iter()
+            .map(|(user_id, order_id, _)| order_key(user_id, order_id))
+            .chain(pending_keys.iter().cloned())
+            .chain(promotion_keys)
+            .collect();
+
+        if !watched.is_empty() {
+            redis::cmd("WATCH")
+                .arg(&watched[..])
+                .query::<()>(conn.deref_mut())?;
+        }
+
+        let checked = self.check_watched(&mut conn, &pending_keys, &versions, &promotion_uses);
+
+        if let Err(e) = checked {
+            redis::cmd("UNWATCH").query::<()>(conn.deref_mut())?;
+            return Err(e);
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        for change in &changes.0 {
+            self.write_change(&mut pipe, change)?;
+        }
+
+        match pipe.query::<Option<()>>(conn.deref_mut())? {
+            Some(_) => Ok(()),
+            None => Err(Box::new(Conflict(format!(
+                "line:{}: Orders {} were written concurrently",
+                line!(),
+                watched.join(", ")
+            )))),
+        }
+    }
+
+    fn lock_outbox(&self, owner: &str, ttl_ms: u64) -> Result<bool, Box<dyn std::error::Error>> {
+        let locked: i32 = redis::cmd("EVAL")
+            .arg(&[ACQUIRE_LOCK, "1", OUTBOX_LOCK_KEY, owner])
+            .arg(ttl_ms)
+            .query(self.pool.get()?.deref_mut())?;
+        Ok(locked == 1)
+    }
+
+    fn unlock_outbox(&self, owner: &str) -> Result<(), Box<dyn std::error::Error>> {
+        redis::cmd("EVAL")
+            .arg(&[RELEASE_LOCK, "1", OUTBOX_LOCK_KEY, owner])
+            .query::<i32>(self.pool.get()?.deref_mut())?;
+        Ok(())
+    }
+
+    fn outbox(&self, limit: usize) -> Result<Vec<OutboxEntry>, Box<dyn std::error::Error>> {
+        let entries: Vec<String> = redis::cmd("LRANGE")
+            .arg(OUTBOX_KEY)
+            .arg(0)
+            .arg(limit as isize - 1)
+            .query(self.pool.get()?.deref_mut())?;
+        Ok(entries
+            .into_iter()
+            .enumerate()
+            .map(|(id, entry)| OutboxEntry {
+                id: id as u64,
+                entry,
+            })
+            .collect())
+    }
+
+    fn ack_outbox(
+        &self,
+        owner: &str,
+        entries: &[OutboxEntry],
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let acked: i32 = redis::cmd("EVAL")
+            .arg(&[ACK_OUTBOX, "2", OUTBOX_LOCK_KEY, OUTBOX_KEY, owner])
+            .arg(
+                entries
+                    .iter()
+                    .map(|entry| &entry.entry[..])
+                    .collect::<Vec<&str>>(),
+            )
+            .query(self.pool.get()?.deref_mut())?;
+        Ok(acked == 1)
+    }
+}
@@ -0,0 +1,166 @@
+use crate::backorders::FulfilmentPolicy;
+use crate::db::CreateOrder;
+use crate::delivery::Delivery;
+use crate::outbox::Outbox;
+use crate::saga::Transition;
+use crate::storage::{now_ms, Change, Changes, OrderRepository};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+
+// Goods a user orders again and again, templates of a user are told apart by name
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OrderTemplate {
+    pub name: String,
+    pub goods: BTreeMap<u64, u64>,
+    #[serde(default)]
+    pub delivery: Option<Delivery>,
+    #[serde(default)]
+    pub fulfilment: FulfilmentPolicy,
+    pub updated_at: u64,
+}
+
+#[derive(Deserialize)]
+struct TemplateGood {
+    id: u64,
+    count: u64,
+}
+
+#[derive(Deserialize)]
+pub struct PutTemplate {
+    name: String,
+    goods: Vec<TemplateGood>,
+    #[serde(default)]
+    delivery: Option<Delivery>,
+    #[serde(default)]
+    fulfilment: FulfilmentPolicy,
+}
+
+impl PutTemplate {
+    // Template with the same name is replaced
+    pub fn put(
+        self,
+        user_id: &str,
+        outbox: &Outbox,
+        storage: &dyn OrderRepository,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(delivery) = &self.delivery {
+            delivery.check()?;
+        }
+
+        let mut goods = BTreeMap::new();
+
+        for good in &self.goods {
+            *goods.entry(good.id).or_default() += good.count;
+        }
+
+        let mut changes = Changes::default();
+        changes.push(Change::PutTemplate {
+            user_id: user_id.to_string(),
+            template: OrderTemplate {
+                name: self.name,
+                goods,
+                delivery: without_window(self.delivery),
+                fulfilment: self.fulfilment,
+                updated_at: now_ms(),
+            },
+        });
+        outbox.write(&mut changes)?;
+        storage.apply(changes)
+    }
+}
+
+pub fn delete(
+    user_id: &str,
+    name: &str,
+    outbox: &Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut changes = Changes::default();
+    changes.push(Change::DeleteTemplate {
+        user_id: user_id.to_string(),
+        name: name.to_string(),
+    });
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
+
+// Delivery window is chosen for every order, one of an older order
+// has most likely passed
+pub fn without_window(delivery: Option<Delivery>) -> Option<Delivery> {
+    delivery.map(|delivery| Delivery {
+        window: None,
+        ..delivery
+    })
+}
+
+// New order gets the lines of the order as they are now, warehouse reserves
+// them from current stock under the same fulfilment policy
+pub fn reorder(
+    user_id: &str,
+    order_id: &str,
+    transition: &Transition,
+    outbox: &mut Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let order = match storage.order(user_id, order_id)? {
+        Some(order) => order,
+        None => {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Order '{}' of user '{}' does not exist",
+                    line!(),
+                    order_id,
+                    user_id
+                ),
+            )))
+        }
+    };
+
+    if order.goods.is_empty() {
+        return Err(Box::new(Error::new(
+            ErrorKind::Other,
+            format!(
+                "line:{}: Order '{}' has no goods to reorder",
+                line!(),
+                order_id
+            ),
+        )));
+    }
+
+    CreateOrder::from_lines(
+        &order.goods,
+        without_window(order.delivery),
+        order.fulfilment,
+    )
+    .create(user_id, transition, outbox, storage)?;
+    Ok(())
+}
+
+pub fn instantiate(
+    user_id: &str,
+    name: &str,
+    transition: &Transition,
+    outbox: &mut Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let template = match storage.template(user_id, name)? {
+        Some(template) => template,
+        None => {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Template '{}' of user '{}' does not exist",
+                    line!(),
+                    name,
+                    user_id
+                ),
+            )))
+        }
+    };
+
+    CreateOrder::from_lines(&template.goods, template.delivery, template.fulfilment)
+        .create(user_id, transition, outbox, storage)?;
+    Ok(())
+}
@@ -0,0 +1,349 @@
+use crate::outbox::{Destination, Outbox, OutboxMessage};
+use crate::storage::{now_ms, Change, Changes, OrderRepository, TransactionRecord};
+use crate::{KafkaProcessingOptions, TransactionOptions};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Every pending copy of an order waits for warehouse answer, the record keeps
+// message sent to warehouse, so it can be resent or aborted after deadline
+#[derive(Serialize)]
+pub struct TransactionInfo {
+    user_id: String,
+    order_id: String,
+    operation: String,
+    created_at: u64,
+    deadline: u64,
+    attempts: u64,
+    expired: bool,
+}
+
+pub fn begin(
+    changes: &mut Changes,
+    user_id: &str,
+    order_id: &str,
+    operation: &str,
+    message: &OutboxMessage,
+    options: &TransactionOptions,
+) -> Result<(), serde_json::Error> {
+    let now = now_ms();
+
+    changes.push(Change::BeginTransaction(TransactionRecord {
+        user_id: user_id.to_string(),
+        order_id: order_id.to_string(),
+        operation: operation.to_string(),
+        message: serde_json::to_string(message)?,
+        created_at: now,
+        deadline: now + options.timeout_ms,
+        attempts: 0,
+    }));
+
+    Ok(())
+}
+
+pub fn finish(changes: &mut Changes, user_id: &str, order_id: &str) {
+    changes.push(Change::FinishTransaction {
+        user_id: user_id.to_string(),
+        order_id: order_id.to_string(),
+    });
+}
+
+pub fn list(
+    storage: &dyn OrderRepository,
+) -> Result<Vec<TransactionInfo>, Box<dyn std::error::Error>> {
+    let now = now_ms();
+
+    Ok(storage
+        .transactions()?
+        .into_iter()
+        .map(|record| TransactionInfo {
+            expired: record.deadline <= now,
+            user_id: record.user_id,
+            order_id: record.order_id,
+            operation: record.operation,
+            created_at: record.created_at,
+            deadline: record.deadline,
+            attempts: record.attempts,
+        })
+        .collect())
+}
+
+fn retry(
+    record: &TransactionRecord,
+    message: OutboxMessage,
+    options: &TransactionOptions,
+    outbox: &mut Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut changes = Changes::default();
+    changes.push(Change::RetryTransaction {
+        user_id: record.user_id.clone(),
+        order_id: record.order_id.clone(),
+        deadline: now_ms() + options.timeout_ms,
+    });
+    outbox.push_message(message);
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
+
+// Order is left as it was before transaction, warehouse puts its reservation
+// in line with the order and never applies the aborted message
+fn abort(
+    user_id: &str,
+    order_id: &str,
+    message: &OutboxMessage,
+    outbox: &mut Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let goods: HashMap<String, i64> = storage
+        .order(user_id, order_id)?
+        .map(|order| order.goods)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(good_id, count)| (format!("good_id:{}", good_id), count as i64))
+        .collect();
+
+    let aborted_message_id = match message.message_id() {
+        Some(id) => id,
+        None => {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Transaction message of order '{}' has no id",
+                    line!(),
+                    order_id
+                ),
+            )))
+        }
+    };
+
+    let mut changes = Changes::default();
+    changes.push(Change::ClearPending {
+        user_id: user_id.to_string(),
+        order_id: order_id.to_string(),
+    });
+    finish(&mut changes, user_id, order_id);
+    outbox.push(
+        Destination::Warehouse,
+        &[
+            ("user_id", user_id),
+            ("order_id", order_id),
+            ("operation", "abort"),
+            ("aborted_message_id", aborted_message_id),
+        ],
+        serde_json::to_string(&goods)?,
+    );
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
+
+fn sweep_record(
+    record: &TransactionRecord,
+    options: &TransactionOptions,
+    outbox: &mut Outbox,
+    storage: &dyn OrderRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (user_id, order_id) = (&record.user_id[..], &record.order_id[..]);
+
+    let message = match serde_json::from_str::<OutboxMessage>(&record.message) {
+        Ok(message) => message,
+        Err(e) => {
+            error!(
+                "line:{}: Invalid transaction record of order '{}' of user '{}': {}",
+                line!(),
+                order_id,
+                user_id,
+                e
+            );
+            let mut changes = Changes::default();
+            finish(&mut changes, user_id, order_id);
+            return storage.apply(changes);
+        }
+    };
+
+    if record.attempts < options.max_retries {
+        warn!(
+            "line:{}: Transaction of order '{}' of user '{}' timed out, retry {}",
+            line!(),
+            order_id,
+            user_id,
+            record.attempts + 1
+        );
+        retry(record, message, options, outbox, storage)
+    } else {
+        error!(
+            "line:{}: Transaction of order '{}' of user '{}' timed out, aborting",
+            line!(),
+            order_id,
+            user_id
+        );
+        abort(user_id, order_id, &message, outbox, storage)
+    }
+}
+
+// Record which can't be swept is left for the next sweep,
+// so it doesn't hold back records expired after it
+fn sweep_expired(
+    options: &TransactionOptions,
+    processed_ttl_secs: usize,
+    storage: &dyn OrderRepository,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let now = now_ms();
+    let records = storage.expired_transactions(now, options.sweep_batch_size)?;
+
+    for record in &records {
+        let mut outbox = Outbox::new(
+            &format!(
+                "tx_info:user_id:{}:order_id:{}:{}",
+                record.user_id, record.order_id, now
+            ),
+            processed_ttl_secs,
+        );
+
+        if let Err(e) = sweep_record(record, options, &mut outbox, storage) {
+            error!(
+                "line:{}: Can't sweep transaction of order '{}' of user '{}': {}",
+                line!(),
+                record.order_id,
+                record.user_id,
+                e
+            );
+        }
+    }
+
+    Ok(records.len())
+}
+
+pub fn sweep(
+    options: TransactionOptions,
+    processing: KafkaProcessingOptions,
+    storage: Arc<dyn OrderRepository>,
+    running: Arc<AtomicBool>,
+) {
+    while running.load(Ordering::SeqCst) {
+        match sweep_expired(&options, processing.processed_ttl_secs, storage.as_ref()) {
+            Ok(0) => {}
+            Ok(count) => info!("line:{}: Swept {} expired transactions", line!(), count),
+            Err(e) => error!("line:{}: Can't sweep expired transactions: {}", line!(), e),
+        }
+
+        if let Err(e) = storage.purge_processed() {
+            error!("line:{}: Can't purge processed marks: {}", line!(), e);
+        }
+
+        std::thread::sleep(Duration::from_millis(options.sweep_interval_ms));
+    }
+
+    info!(
+        "thread id {:?}: stopping transactions sweeper thread",
+        std::thread::current().id(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn options(max_retries: u64) -> TransactionOptions {
+        TransactionOptions {
+            timeout_ms: 60_000,
+            max_retries,
+            sweep_interval_ms: 1_000,
+            sweep_batch_size: 10,
+            empty_order: Default::default(),
+        }
+    }
+
+    fn expired(order_id: &str, message: &str) -> Change {
+        Change::BeginTransaction(TransactionRecord {
+            user_id: "1".to_string(),
+            order_id: order_id.to_string(),
+            operation: "update".to_string(),
+            message: message.to_string(),
+            created_at: 0,
+            deadline: 0,
+            attempts: 0,
+        })
+    }
+
+    fn operations(storage: &dyn OrderRepository) -> Vec<String> {
+        storage
+            .outbox(100)
+            .unwrap()
+            .into_iter()
+            .map(|entry| {
+                let message: serde_json::Value = serde_json::from_str(&entry.entry).unwrap();
+                serde_json::from_value::<Vec<(String, String)>>(message["headers"].clone())
+                    .unwrap()
+                    .into_iter()
+                    .find(|(key, _)| key == "operation")
+                    .map(|(_, value)| value)
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn expired_transaction_is_retried_and_then_aborted() {
+        let storage = MemoryStorage::new();
+        let message = r#"{"destination":"warehouse","headers":[["message_id","1"],["operation","update"]],"payload":""}"#;
+        let mut changes = Changes::default();
+        changes.push(expired("1", message));
+        storage.apply(changes).unwrap();
+
+        assert_eq!(sweep_expired(&options(1), 60, &storage).unwrap(), 1);
+        let records = storage.transactions().unwrap();
+        assert_eq!(records[0].attempts, 1);
+        assert!(records[0].deadline > now_ms());
+        assert_eq!(operations(&storage), vec!["update"]);
+
+        // Deadline of the retry is moved back to sweep it again
+        let mut changes = Changes::default();
+        changes.push(Change::RetryTransaction {
+            user_id: "1".to_string(),
+            order_id: "1".to_string(),
+            deadline: 0,
+        });
+        storage.apply(changes).unwrap();
+
+        assert_eq!(sweep_expired(&options(1), 60, &storage).unwrap(), 1);
+        assert!(storage.transactions().unwrap().is_empty());
+        assert_eq!(operations(&storage), vec!["update", "abort"]);
+    }
+
+    #[test]
+    fn record_which_cant_be_swept_doesnt_hold_back_others() {
+        let storage = MemoryStorage::new();
+        let mut changes = Changes::default();
+        changes.push(expired(
+            "1",
+            r#"{"destination":"warehouse","headers":[],"payload":""}"#,
+        ));
+        changes.push(expired(
+            "2",
+            r#"{"destination":"warehouse","headers":[["message_id","2"],["operation","update"]],"payload":""}"#,
+        ));
+        storage.apply(changes).unwrap();
+
+        assert_eq!(sweep_expired(&options(0), 60, &storage).unwrap(), 2);
+        let records = storage.transactions().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].order_id, "1");
+        assert_eq!(operations(&storage), vec!["abort"]);
+    }
+
+    #[test]
+    fn invalid_record_is_finished() {
+        let storage = MemoryStorage::new();
+        let mut changes = Changes::default();
+        changes.push(expired("1", "{"));
+        storage.apply(changes).unwrap();
+
+        assert_eq!(sweep_expired(&options(0), 60, &storage).unwrap(), 1);
+        assert!(storage.transactions().unwrap().is_empty());
+    }
+}
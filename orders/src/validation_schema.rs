@@ -1,4 +1,4 @@
-use serde_json::{from_str, Value};
+use serde_json::{from_str, json, Value};
 
 // TODO: move validation schemas to file for flexible management
 lazy_static! {
@@ -25,6 +25,48 @@ lazy_static! {
                         "required": ["id", "count"],
                         "additionalProperties": false
                     }
+                },
+                "delivery": {
+                    "type": "object",
+                    "properties": {
+                        "method": {
+                            "type": "string",
+                            "enum": ["standard", "express", "pickup"]
+                        },
+                        "address": {
+                            "type": "object",
+                            "properties": {
+                                "recipient": { "type": "string", "minLength": 1, "maxLength": 200 },
+                                "line1": { "type": "string", "minLength": 1, "maxLength": 200 },
+                                "line2": { "type": "string", "maxLength": 200 },
+                                "city": { "type": "string", "minLength": 1, "maxLength": 100 },
+                                "region": { "type": "string", "maxLength": 100 },
+                                "postal_code": { "type": "string", "pattern": "^[A-Za-z0-9 -]{2,12}$" },
+                                "country": { "type": "string", "pattern": "^[A-Z]{2}$" }
+                            },
+                            "required": ["recipient", "line1", "city", "postal_code", "country"],
+                            "additionalProperties": false
+                        },
+                        "phone": {
+                            "type": "string",
+                            "pattern": "^\\+?[0-9][0-9 ()-]{4,19}$"
+                        },
+                        "window": {
+                            "type": "object",
+                            "properties": {
+                                "from": { "type": "integer", "minimum": 0 },
+                                "to": { "type": "integer", "minimum": 0 }
+                            },
+                            "required": ["from", "to"],
+                            "additionalProperties": false
+                        }
+                    },
+                    "required": ["method", "phone"],
+                    "additionalProperties": false
+                },
+                "fulfilment": {
+                    "type": "string",
+                    "enum": ["all_or_nothing", "partial"]
                 }
             },
             "required": ["goods"],
@@ -72,9 +114,191 @@ lazy_static! {
                                     "operation": { "const": "update" }
                                 },
                                 "required": ["id", "count", "operation"]
+                            },
+                            {
+                                "not": { "required": ["operation"] },
+                                "required": ["id", "count"]
                             }
                         ]
                     }
+                },
+                "mode": {
+                    "type": "string",
+                    "enum": ["patch", "replace"]
+                },
+                "delivery": {
+                    "type": "object",
+                    "properties": {
+                        "method": {
+                            "type": "string",
+                            "enum": ["standard", "express", "pickup"]
+                        },
+                        "address": {
+                            "type": "object",
+                            "properties": {
+                                "recipient": { "type": "string", "minLength": 1, "maxLength": 200 },
+                                "line1": { "type": "string", "minLength": 1, "maxLength": 200 },
+                                "line2": { "type": "string", "maxLength": 200 },
+                                "city": { "type": "string", "minLength": 1, "maxLength": 100 },
+                                "region": { "type": "string", "maxLength": 100 },
+                                "postal_code": { "type": "string", "pattern": "^[A-Za-z0-9 -]{2,12}$" },
+                                "country": { "type": "string", "pattern": "^[A-Z]{2}$" }
+                            },
+                            "required": ["recipient", "line1", "city", "postal_code", "country"],
+                            "additionalProperties": false
+                        },
+                        "phone": {
+                            "type": "string",
+                            "pattern": "^\\+?[0-9][0-9 ()-]{4,19}$"
+                        },
+                        "window": {
+                            "type": "object",
+                            "properties": {
+                                "from": { "type": "integer", "minimum": 0 },
+                                "to": { "type": "integer", "minimum": 0 }
+                            },
+                            "required": ["from", "to"],
+                            "additionalProperties": false
+                        }
+                    },
+                    "required": ["method", "phone"],
+                    "additionalProperties": false
+                }
+            },
+            "allOf": [
+                {
+                    "anyOf": [
+                        { "required": ["goods"] },
+                        { "required": ["delivery"] }
+                    ]
+                },
+                {
+                    "anyOf": [
+                        {
+                            "properties": {
+                                "mode": { "const": "replace" },
+                                "goods": {
+                                    "items": {
+                                        "properties": {
+                                            "operation": { "const": "update" }
+                                        }
+                                    }
+                                }
+                            },
+                            "required": ["mode"]
+                        },
+                        {
+                            "properties": {
+                                "mode": { "const": "patch" },
+                                "goods": {
+                                    "items": { "required": ["operation"] }
+                                }
+                            }
+                        }
+                    ]
+                }
+            ],
+            "additionalProperties": false
+        }"#,
+    )
+    .unwrap();
+}
+
+lazy_static! {
+    pub static ref VALIDATION_SCHEMA_PROMOTION: Value = from_str(
+        r#"
+        {
+            "type": "object",
+            "properties": {
+                "code": {
+                    "type": "string",
+                    "minLength": 1,
+                    "pattern": "^[A-Za-z0-9_-]+$"
+                },
+                "kind": {
+                    "type": "string",
+                    "enum": ["percent", "fixed"]
+                },
+                "value": {
+                    "type": "integer",
+                    "minimum": 1
+                },
+                "currency": {
+                    "type": "string"
+                },
+                "good_id": {
+                    "type": "integer"
+                },
+                "valid_from": {
+                    "type": "integer",
+                    "minimum": 0
+                },
+                "valid_to": {
+                    "type": "integer",
+                    "minimum": 0
+                },
+                "max_uses": {
+                    "type": "integer",
+                    "minimum": 1
+                },
+                "max_uses_per_user": {
+                    "type": "integer",
+                    "minimum": 1
+                }
+            },
+            "required": ["code", "kind", "value"],
+            "additionalProperties": false
+        }"#,
+    )
+    .unwrap();
+}
+
+lazy_static! {
+    pub static ref VALIDATION_SCHEMA_APPLY_PROMOTION: Value = from_str(
+        r#"
+        {
+            "type": "object",
+            "properties": {
+                "code": {
+                    "type": "string",
+                    "minLength": 1
+                }
+            },
+            "required": ["code"],
+            "additionalProperties": false
+        }"#,
+    )
+    .unwrap();
+}
+
+lazy_static! {
+    pub static ref VALIDATION_SCHEMA_REQUEST_RETURN: Value = from_str(
+        r#"
+        {
+            "type": "object",
+            "properties": {
+                "goods": {
+                    "type": "array",
+                    "uniqueItems": true,
+                    "minItems": 1,
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "id": {
+                                "type": "integer"
+                            },
+                            "count": {
+                                "type": "integer",
+                                "minimum": 1
+                            }
+                        },
+                        "required": ["id", "count"],
+                        "additionalProperties": false
+                    }
+                },
+                "reason": {
+                    "type": "string",
+                    "maxLength": 1000
                 }
             },
             "required": ["goods"],
@@ -83,3 +307,113 @@ lazy_static! {
     )
     .unwrap();
 }
+
+lazy_static! {
+    pub static ref VALIDATION_SCHEMA_REVIEW_RETURN: Value = from_str(
+        r#"
+        {
+            "type": "object",
+            "properties": {
+                "decision": {
+                    "type": "string",
+                    "enum": ["approve", "reject"]
+                }
+            },
+            "required": ["decision"],
+            "additionalProperties": false
+        }"#,
+    )
+    .unwrap();
+}
+
+lazy_static! {
+    // Template is an order with a name, which is created later
+    pub static ref VALIDATION_SCHEMA_TEMPLATE: Value = {
+        let mut schema = VALIDATION_SCHEMA_CREATE.clone();
+        schema["properties"]["name"] = json!({
+            "type": "string",
+            "pattern": "^[A-Za-z0-9_-]{1,64}$"
+        });
+        schema["required"] = json!(["name", "goods"]);
+        schema
+    };
+}
+
+lazy_static! {
+    // Schedule is an order with a name, which is created again and again,
+    // fulfilment follows from what is done on shortage of goods
+    pub static ref VALIDATION_SCHEMA_SCHEDULE: Value = {
+        let mut schema = VALIDATION_SCHEMA_TEMPLATE.clone();
+        if let Some(properties) = schema["properties"].as_object_mut() {
+            properties.remove("fulfilment");
+        }
+        schema["properties"]["on_shortage"] = json!({
+            "type": "string",
+            "enum": ["skip", "backorder"]
+        });
+        schema["properties"]["recurrence"] = json!({
+            "oneOf": [
+                {
+                    "type": "object",
+                    "properties": {
+                        "every_days": { "type": "integer", "minimum": 1, "maximum": 366 }
+                    },
+                    "required": ["every_days"],
+                    "additionalProperties": false
+                },
+                {
+                    "type": "object",
+                    "properties": {
+                        "cron": { "type": "string", "minLength": 9, "maxLength": 100 }
+                    },
+                    "required": ["cron"],
+                    "additionalProperties": false
+                }
+            ]
+        });
+        schema["properties"]["starts_at"] = json!({ "type": "integer", "minimum": 0 });
+        schema["required"] = json!(["name", "goods", "recurrence"]);
+        schema
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use valico::json_schema::Scope;
+
+    fn valid_update(update: &str) -> bool {
+        let mut scope = Scope::new();
+        let validator = scope
+            .compile_and_return(VALIDATION_SCHEMA_UPDATE.clone(), true)
+            .unwrap();
+        validator.validate(&from_str(update).unwrap()).is_valid()
+    }
+
+    #[test]
+    fn goods_without_operation_replace_lines() {
+        assert!(valid_update(
+            r#"{"mode": "replace", "goods": [{"id": 1, "count": 2}]}"#
+        ));
+        assert!(valid_update(
+            r#"{"mode": "replace", "goods": [{"id": 1, "count": 2, "operation": "update"}]}"#
+        ));
+        assert!(!valid_update(
+            r#"{"mode": "replace", "goods": [{"id": 1, "operation": "delete"}]}"#
+        ));
+    }
+
+    #[test]
+    fn patched_goods_need_operation() {
+        assert!(valid_update(
+            r#"{"goods": [{"id": 1, "count": 2, "operation": "update"}, {"id": 2, "operation": "delete"}]}"#
+        ));
+        assert!(!valid_update(r#"{"goods": [{"id": 1, "count": 2}]}"#));
+        assert!(!valid_update(
+            r#"{"mode": "patch", "goods": [{"id": 1, "count": 2}]}"#
+        ));
+        assert!(valid_update(
+            r#"{"delivery": {"method": "pickup", "phone": "+4912345"}}"#
+        ));
+    }
+}
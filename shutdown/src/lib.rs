@@ -0,0 +1,79 @@
+#[macro_use]
+extern crate log;
+
+use serde::Deserialize;
+use signal_hook::{iterator::Signals, SIGINT, SIGQUIT, SIGTERM};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+#[derive(Clone, Deserialize)]
+pub struct ShutdownOptions {
+    pub readiness_delay_ms: u64,
+    pub timeout_secs: u64,
+}
+
+// Readiness probe fails as soon as shutdown starts, so balancer stops
+// routing new requests while in-flight ones are still served
+pub struct Readiness(AtomicBool);
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Readiness(AtomicBool::new(true))
+    }
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn set_not_ready(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(feature = "actix-web")]
+pub fn readiness(readiness: actix_web::web::Data<Readiness>) -> actix_web::HttpResponse {
+    if readiness.is_ready() {
+        actix_web::HttpResponse::Ok().finish()
+    } else {
+        actix_web::HttpResponse::ServiceUnavailable().finish()
+    }
+}
+
+fn wait_for_signal() {
+    let signals = Signals::new([SIGINT, SIGTERM, SIGQUIT]).unwrap();
+    let _ = signals.forever().next();
+}
+
+// Process exits anyway if draining takes longer than the deadline
+fn start_watchdog(timeout_secs: u64) {
+    std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_secs(timeout_secs));
+        error!(
+            "line:{}: Graceful shutdown didn't finish in {} seconds, exiting",
+            line!(),
+            timeout_secs
+        );
+        std::process::exit(1);
+    });
+}
+
+// Blocks until the service is asked to stop, then stops its parts in order:
+// readiness probe fails, server finishes in-flight requests, and after
+// that the rest of the work is drained
+pub fn on_signal(
+    options: &ShutdownOptions,
+    readiness: &Readiness,
+    stop_server: impl FnOnce(),
+    drain: impl FnOnce(),
+) {
+    wait_for_signal();
+    info!("Shutting down, waiting for in-flight work to finish");
+    start_watchdog(options.timeout_secs);
+
+    readiness.set_not_ready();
+    std::thread::sleep(Duration::from_millis(options.readiness_delay_ms));
+    stop_server();
+    drain();
+}
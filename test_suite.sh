@@ -10,14 +10,17 @@ FAILED="${RED}FAILED${NC}:"
 PASSED="${GREEN}PASSED${NC}:"
 
 USER_ID=lieroz
+ADMIN_TOKEN=admin
 
 function create_order {
-    redis-cli -p 6380 HSET good_id:1 count 5
+    redis-cli -p 6380 HSET good_id:1 count 5 price 100 currency USD
 
     token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
 
+    payload=${1:-'{"goods": [{"id": 1, "count": 1}]}'}
+
     status_code=$(curl -s -o /dev/null -w "%{http_code}" \
-        localhost:8080/user/$USER_ID/order -d '{"goods": [{"id": 1, "count": 1}]}' \
+        localhost:8080/user/$USER_ID/order -d "$payload" \
         -H "Local-Authorization: $(echo $token | xargs)")
 
     if [[ $status_code -ne 201 ]] ; then
@@ -57,10 +60,65 @@ function update_order_op_delete {
     fi
 }
 
+function update_order {
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    status_code=$(curl -s -o /dev/null -w "%{http_code}" \
+        -X PUT localhost:8080/user/$USER_ID/order/1 \
+        -d "$1" \
+        -H "Local-Authorization: $(echo $token | xargs)")
+
+    if [[ $status_code -ne 200 ]] ; then
+        echo -e "$FAILED expected 200 was $status_code"
+    else
+        echo -e "$PASSED /user/1/order/1 PUT $1"
+    fi
+}
+
+function get_order_etag {
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    etag=$(curl -s -D - -o /dev/null localhost:8080/user/$USER_ID/order/1 \
+        -H "Local-Authorization: $(echo $token | xargs)" | grep -i '^etag:' | cut -d' ' -f2 | tr -d '\r')
+}
+
+function update_order_delivery {
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    status_code=$(curl -s -o /dev/null -w "%{http_code}" \
+        -X PUT localhost:8080/user/$USER_ID/order/1 \
+        -d "{\"delivery\": $1}" \
+        -H "Local-Authorization: $(echo $token | xargs)")
+
+    if [[ $status_code -ne 200 ]] ; then
+        echo -e "$FAILED expected 200 was $status_code"
+    else
+        echo -e "$PASSED /user/1/order/1 PUT delivery"
+    fi
+}
+
+function update_order_if_match {
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    status_code=$(curl -s -o /dev/null -w "%{http_code}" \
+        -X PUT localhost:8080/user/$USER_ID/order/1 \
+        -d '{"goods": [{"id": 1, "count": 2, "operation": "update"}]}' \
+        -H "If-Match: $1" \
+        -H "Local-Authorization: $(echo $token | xargs)")
+
+    if [[ $status_code -ne $2 ]] ; then
+        echo -e "$FAILED expected $2 was $status_code"
+    else
+        echo -e "$PASSED /user/1/order/1 PUT If-Match: $1"
+    fi
+}
+
 function get_order {
     token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
 
-    response=($(curl -s -w "\n%{http_code}" localhost:8080/user/$USER_ID/order/1 \
+    order_id=${2:-1}
+
+    response=($(curl -s -w "\n%{http_code}" localhost:8080/user/$USER_ID/order/$order_id \
         -H "Local-Authorization: $(echo $token | xargs)"| {
         read body
         read code
@@ -72,27 +130,114 @@ function get_order {
         echo -e "$FAILED expected 200 was ${response[0]}"
         echo -e "$FAILED expected $1 was ${response[1]}"
     else
-        echo -e "$PASSED /user/1/order/1 GET"
+        echo -e "$PASSED /user/1/order/$order_id GET"
     fi
 }
 
-function delete_order {
+function get_order_history {
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    events=$(curl -s localhost:8080/user/$USER_ID/order/1/history \
+        -H "Local-Authorization: $(echo $token | xargs)" \
+        | grep -o '"event":"[a-z_]*"' | cut -d '"' -f 4 | xargs)
+
+    if [[ "$events" != "$1" ]] ; then
+        echo -e "$FAILED expected $1 was $events"
+    else
+        echo -e "$PASSED /user/1/order/1/history GET"
+    fi
+}
+
+function put_promotion {
+    status_code=$(curl -s -o /dev/null -w "%{http_code}" \
+        localhost:8080/admin/promotions -d "$1" \
+        -H "Admin-Authorization: $ADMIN_TOKEN")
+
+    if [[ $status_code -ne 201 ]] ; then
+        echo -e "$FAILED expected 201 was $status_code"
+    else
+        echo -e "$PASSED /admin/promotions POST"
+    fi
+}
+
+function delete_promotion {
+    status_code=$(curl -s -o /dev/null -w "%{http_code}" \
+        -X DELETE localhost:8080/admin/promotions/$1 \
+        -H "Admin-Authorization: $ADMIN_TOKEN")
+
+    if [[ $status_code -ne 200 ]] ; then
+        echo -e "$FAILED expected 200 was $status_code"
+    else
+        echo -e "$PASSED /admin/promotions/$1 DELETE"
+    fi
+}
+
+function export_orders {
+    # Timestamps and status history change from run to run, they are cut off
+    row=$(curl -s "localhost:8080/admin/orders/export?$1" \
+        -H "Admin-Authorization: $ADMIN_TOKEN" | sed -n 2p | cut -d, -f1-3,6-15)
+
+    if [[ "$row" != "$2" ]] ; then
+        echo -e "$FAILED expected $2 was $row"
+    else
+        echo -e "$PASSED /admin/orders/export?$1 GET"
+    fi
+}
+
+function search_orders {
+    found=$(curl -s "localhost:8080/admin/orders/search?$1" \
+        -H "Admin-Authorization: $ADMIN_TOKEN" | grep -o '"order_id":[0-9]*' | xargs)
+
+    if [[ "$found" != "$2" ]] ; then
+        echo -e "$FAILED expected $2 was $found"
+    else
+        echo -e "$PASSED /admin/orders/search?$1 GET"
+    fi
+}
+
+function apply_promotion {
     token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
 
     status_code=$(curl -s -o /dev/null -w "%{http_code}" \
-        -X DELETE localhost:8080/user/$USER_ID/order/1 \
+        localhost:8080/user/$USER_ID/order/1/promotion -d "{\"code\": \"$1\"}" \
         -H "Local-Authorization: $(echo $token | xargs)")
 
     if [[ $status_code -ne 200 ]] ; then
         echo -e "$FAILED expected 200 was $status_code"
     else
-        echo -e "$PASSED /user/1/order/1 DELETE"
+        echo -e "$PASSED /user/1/order/1/promotion POST"
+    fi
+}
+
+function delete_order {
+    status_code=$(curl -s -o /dev/null -w "%{http_code}" \
+        -X DELETE localhost:8080/admin/user/$USER_ID/order/1 \
+        -H "Admin-Authorization: $ADMIN_TOKEN")
+
+    if [[ $status_code -ne 200 ]] ; then
+        echo -e "$FAILED expected 200 was $status_code"
+    else
+        echo -e "$PASSED /admin/user/1/order/1 DELETE"
     fi
 
     redis-cli -p 6379 flushdb
     redis-cli -p 6380 flushdb
 }
 
+function cancel_order {
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    status_code=$(curl -s -o /dev/null -w "%{http_code}" \
+        -X POST localhost:8080/user/$USER_ID/order/1/cancel \
+        -H "Local-Authorization: $(echo $token | xargs)")
+
+    if [[ $status_code -ne 200 ]] ; then
+        echo -e "$FAILED expected 200 was $status_code"
+    else
+        echo -e "$PASSED /user/1/order/1/cancel POST"
+    fi
+}
+
 function create_billing {
     token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
 
@@ -107,10 +252,240 @@ function create_billing {
     fi
 }
 
+function advance_shipment {
+    status_code=$(curl -s -o /dev/null -w "%{http_code}" \
+        localhost:8080/admin/user/$USER_ID/order/1/shipment -d "$1" \
+        -H "Admin-Authorization: $ADMIN_TOKEN")
+
+    if [[ $status_code -ne 200 ]] ; then
+        echo -e "$FAILED expected 200 was $status_code"
+    else
+        echo -e "$PASSED /admin/user/1/order/1/shipment POST"
+    fi
+}
+
+function get_shipment {
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    shipment=$(curl -s localhost:8080/user/$USER_ID/order/1/shipment \
+        -H "Local-Authorization: $(echo $token | xargs)" | grep -o '"state":"[a-z]*","goods"\|"tracking_number":"[A-Za-z0-9-]*"' | tr '\n' ' ')
+
+    if [[ "$shipment" != "$1 " ]] ; then
+        echo -e "$FAILED expected $1 was $shipment"
+    else
+        echo -e "$PASSED /user/1/order/1/shipment GET"
+    fi
+}
+
+function restock_good {
+    status_code=$(curl -s -o /dev/null -w "%{http_code}" \
+        localhost:8080/admin/goods/1/stock -d "$1" \
+        -H "Admin-Authorization: $ADMIN_TOKEN")
+
+    if [[ $status_code -ne 200 ]] ; then
+        echo -e "$FAILED expected 200 was $status_code"
+    else
+        echo -e "$PASSED /admin/goods/1/stock POST"
+    fi
+}
+
+function request_return {
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    status_code=$(curl -s -o /dev/null -w "%{http_code}" \
+        localhost:8080/user/$USER_ID/order/1/returns -d "$1" \
+        -H "Local-Authorization: $(echo $token | xargs)")
+
+    if [[ $status_code -ne 200 ]] ; then
+        echo -e "$FAILED expected 200 was $status_code"
+    else
+        echo -e "$PASSED /user/1/order/1/returns POST"
+    fi
+}
+
+function review_return {
+    status_code=$(curl -s -o /dev/null -w "%{http_code}" \
+        localhost:8080/admin/user/$USER_ID/order/1/returns/1 -d "$1" \
+        -H "Admin-Authorization: $ADMIN_TOKEN")
+
+    if [[ $status_code -ne 200 ]] ; then
+        echo -e "$FAILED expected 200 was $status_code"
+    else
+        echo -e "$PASSED /admin/user/1/order/1/returns/1 POST"
+    fi
+}
+
+function get_returns {
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    returns=$(curl -s localhost:8080/user/$USER_ID/order/1/returns \
+        -H "Local-Authorization: $(echo $token | xargs)" | grep -o '"status":"[a-z]*"\|"refund":[0-9]*' | tr '\n' ' ')
+
+    if [[ "$returns" != "$1 " ]] ; then
+        echo -e "$FAILED expected $1 was $returns"
+    else
+        echo -e "$PASSED /user/1/order/1/returns GET"
+    fi
+}
+
+function reorder {
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    status_code=$(curl -s -o /dev/null -w "%{http_code}" -X POST \
+        localhost:8080/user/$USER_ID/order/1/reorder \
+        -H "Local-Authorization: $(echo $token | xargs)")
+
+    if [[ $status_code -ne 201 ]] ; then
+        echo -e "$FAILED expected 201 was $status_code"
+    else
+        echo -e "$PASSED /user/1/order/1/reorder POST"
+    fi
+}
+
+function put_template {
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    status_code=$(curl -s -o /dev/null -w "%{http_code}" \
+        localhost:8080/user/$USER_ID/templates -d "$1" \
+        -H "Local-Authorization: $(echo $token | xargs)")
+
+    if [[ $status_code -ne 200 ]] ; then
+        echo -e "$FAILED expected 200 was $status_code"
+    else
+        echo -e "$PASSED /user/1/templates POST"
+    fi
+}
+
+function get_templates {
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    templates=$(curl -s localhost:8080/user/$USER_ID/templates \
+        -H "Local-Authorization: $(echo $token | xargs)" | grep -o '"name":"[A-Za-z0-9_-]*"' | tr '\n' ' ')
+
+    if [[ "$templates" != "$1 " ]] ; then
+        echo -e "$FAILED expected $1 was $templates"
+    else
+        echo -e "$PASSED /user/1/templates GET"
+    fi
+}
+
+function instantiate_template {
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    status_code=$(curl -s -o /dev/null -w "%{http_code}" -X POST \
+        localhost:8080/user/$USER_ID/templates/$1/order \
+        -H "Local-Authorization: $(echo $token | xargs)")
+
+    if [[ $status_code -ne 201 ]] ; then
+        echo -e "$FAILED expected 201 was $status_code"
+    else
+        echo -e "$PASSED /user/1/templates/$1/order POST"
+    fi
+}
+
+function put_schedule {
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    status_code=$(curl -s -o /dev/null -w "%{http_code}" \
+        localhost:8080/user/$USER_ID/schedules -d "$1" \
+        -H "Local-Authorization: $(echo $token | xargs)")
+
+    if [[ $status_code -ne 200 ]] ; then
+        echo -e "$FAILED expected 200 was $status_code"
+    else
+        echo -e "$PASSED /user/1/schedules POST"
+    fi
+}
+
+function get_schedules {
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    outcomes=$(curl -s localhost:8080/user/$USER_ID/schedules \
+        -H "Local-Authorization: $(echo $token | xargs)" | grep -o '"outcome":"[a-z_]*"' | tr '\n' ' ')
+
+    if [[ "$outcomes" != "$1 " ]] ; then
+        echo -e "$FAILED expected $1 was $outcomes"
+    else
+        echo -e "$PASSED /user/1/schedules GET"
+    fi
+}
+
+function delete_schedule {
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    status_code=$(curl -s -o /dev/null -w "%{http_code}" -X DELETE \
+        localhost:8080/user/$USER_ID/schedules/$1 \
+        -H "Local-Authorization: $(echo $token | xargs)")
+
+    if [[ $status_code -ne 200 ]] ; then
+        echo -e "$FAILED expected 200 was $status_code"
+    else
+        echo -e "$PASSED /user/1/schedules/$1 DELETE"
+    fi
+}
+
+function add_to_cart {
+    redis-cli -p 6380 HSET good_id:1 count 5 price 100 currency USD
+
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    status_code=$(curl -s -o /dev/null -w "%{http_code}" \
+        localhost:8080/user/$USER_ID/cart/goods -d "$1" \
+        -H "Local-Authorization: $(echo $token | xargs)")
+
+    if [[ $status_code -ne 200 ]] ; then
+        echo -e "$FAILED expected 200 was $status_code"
+    else
+        echo -e "$PASSED /user/1/cart/goods POST"
+    fi
+}
+
+function update_cart_good {
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    status_code=$(curl -s -o /dev/null -w "%{http_code}" -X PUT \
+        localhost:8080/user/$USER_ID/cart/goods/$1 -d "$2" \
+        -H "Local-Authorization: $(echo $token | xargs)")
+
+    if [[ $status_code -ne 200 ]] ; then
+        echo -e "$FAILED expected 200 was $status_code"
+    else
+        echo -e "$PASSED /user/1/cart/goods/$1 PUT"
+    fi
+}
+
+function get_cart {
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    # Expiry ticks down, so only goods are compared
+    goods=$(curl -s localhost:8080/user/$USER_ID/cart \
+        -H "Local-Authorization: $(echo $token | xargs)" | grep -o '"goods":\[[^]]*\]')
+
+    if [[ "$goods" != "$1" ]] ; then
+        echo -e "$FAILED expected $1 was $goods"
+    else
+        echo -e "$PASSED /user/1/cart GET"
+    fi
+}
+
+function checkout_cart {
+    token=$(curl -s localhost:3000/auth -d "{\"login\": \"$USER_ID\", \"password\": \"qwerty\"}" -H 'Content-Type: application/json')
+
+    status_code=$(curl -s -o /dev/null -w "%{http_code}" -X POST \
+        localhost:8080/user/$USER_ID/cart/checkout -d "$1" \
+        -H "Local-Authorization: $(echo $token | xargs)")
+
+    if [[ $status_code -ne ${2:-201} ]] ; then
+        echo -e "$FAILED expected ${2:-201} was $status_code"
+    else
+        echo -e "$PASSED /user/1/cart/checkout POST"
+    fi
+}
+
 function test_create_get_delete_order {
     create_order
     sleep 0.1
-    get_order '{"status":"new","goods":[{"id":1,"count":1,"naming":""}]}'
+    get_order '{"status":"reserved","goods":[{"id":1,"count":1,"naming":"","price":100,"total":100,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":100,"promotion_code":null,"discount":0,"tax":0,"total":100,"delivery":null,"fulfilment":"all_or_nothing"}'
     sleep 0.1
     delete_order
     sleep 0.1
@@ -121,7 +496,7 @@ function test_create_update_get_delete_order {
     sleep 0.1
     update_order_op_update
     sleep 0.1
-    get_order '{"status":"new","goods":[{"id":1,"count":3,"naming":""}]}'
+    get_order '{"status":"reserved","goods":[{"id":1,"count":3,"naming":"","price":100,"total":300,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":300,"promotion_code":null,"discount":0,"tax":0,"total":300,"delivery":null,"fulfilment":"all_or_nothing"}'
     sleep 0.1
     delete_order
     sleep 0.1
@@ -130,7 +505,47 @@ function test_create_update_get_delete_order {
     sleep 0.1
     update_order_op_delete
     sleep 0.1
-    get_order '{"status":"new","goods":[]}'
+    # Order can't be left without goods
+    get_order '{"status":"reserved","goods":[{"id":1,"count":1,"naming":"","price":100,"total":100,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":100,"promotion_code":null,"discount":0,"tax":0,"total":100,"delivery":null,"fulfilment":"all_or_nothing"}'
+    sleep 0.1
+    delete_order
+    sleep 0.1
+}
+
+function test_update_lines {
+    create_order
+    sleep 0.1
+    # Repeated lines of one good are merged
+    update_order '{"goods": [{"id": 1, "count": 1, "operation": "update"}, {"id": 1, "count": 2, "operation": "update"}]}'
+    sleep 0.1
+    get_order '{"status":"reserved","goods":[{"id":1,"count":3,"naming":"","price":100,"total":300,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":300,"promotion_code":null,"discount":0,"tax":0,"total":300,"delivery":null,"fulfilment":"all_or_nothing"}'
+    sleep 0.1
+    update_order '{"mode": "replace", "goods": [{"id": 1, "count": 2}]}'
+    sleep 0.1
+    get_order '{"status":"reserved","goods":[{"id":1,"count":2,"naming":"","price":100,"total":200,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":200,"promotion_code":null,"discount":0,"tax":0,"total":200,"delivery":null,"fulfilment":"all_or_nothing"}'
+    sleep 0.1
+    delete_order
+    sleep 0.1
+}
+
+function test_export {
+    create_order
+    sleep 0.1
+    export_orders 'format=csv&status=reserved' "$USER_ID,1,reserved,USD,100,0,0,100,1,1,100,100,0"
+    export_orders 'format=csv&status=paid' ''
+    sleep 0.1
+    delete_order
+    sleep 0.1
+}
+
+function test_search {
+    create_order
+    sleep 0.1
+    search_orders 'order_id=1' 'order_id:1'
+    search_orders "user_id=$USER_ID&status=reserved" 'order_id:1'
+    search_orders 'good_id=1&status=reserved' 'order_id:1'
+    search_orders 'good_id=2' ''
+    search_orders 'status=paid' ''
     sleep 0.1
     delete_order
     sleep 0.1
@@ -139,11 +554,11 @@ function test_create_update_get_delete_order {
 function test_billing {
     create_order
     sleep 0.1
-    get_order '{"status":"new","goods":[{"id":1,"count":1,"naming":""}]}'
+    get_order '{"status":"reserved","goods":[{"id":1,"count":1,"naming":"","price":100,"total":100,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":100,"promotion_code":null,"discount":0,"tax":0,"total":100,"delivery":null,"fulfilment":"all_or_nothing"}'
     sleep 0.1
     create_billing
     sleep 0.1
-    get_order '{"status":"payed","goods":[{"id":1,"count":1,"naming":""}]}'
+    get_order '{"status":"paid","goods":[{"id":1,"count":1,"naming":"","price":100,"total":100,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":100,"promotion_code":null,"discount":0,"tax":0,"total":100,"delivery":null,"fulfilment":"all_or_nothing"}'
     sleep 0.1
     delete_order
     sleep 0.1
@@ -152,16 +567,174 @@ function test_billing {
 function test_update_after_billing {
     create_order
     sleep 0.1
-    get_order '{"status":"new","goods":[{"id":1,"count":1,"naming":""}]}'
+    get_order '{"status":"reserved","goods":[{"id":1,"count":1,"naming":"","price":100,"total":100,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":100,"promotion_code":null,"discount":0,"tax":0,"total":100,"delivery":null,"fulfilment":"all_or_nothing"}'
     sleep 0.1
     create_billing
     sleep 0.1
-    get_order '{"status":"payed","goods":[{"id":1,"count":1,"naming":""}]}'
+    get_order '{"status":"paid","goods":[{"id":1,"count":1,"naming":"","price":100,"total":100,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":100,"promotion_code":null,"discount":0,"tax":0,"total":100,"delivery":null,"fulfilment":"all_or_nothing"}'
     sleep 0.1
     update_order_op_update
     sleep 0.1
-    get_order '{"status":"payed","goods":[{"id":1,"count":1,"naming":""}]}'
+    get_order '{"status":"paid","goods":[{"id":1,"count":1,"naming":"","price":100,"total":100,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":100,"promotion_code":null,"discount":0,"tax":0,"total":100,"delivery":null,"fulfilment":"all_or_nothing"}'
+    sleep 0.1
+    delete_order
+    sleep 0.1
+}
+
+function test_cancel_order {
+    create_order
+    sleep 0.1
+    cancel_order
+    sleep 0.1
+    get_order '{"status":"cancelled","goods":[{"id":1,"count":1,"naming":"","price":100,"total":100,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":100,"promotion_code":null,"discount":0,"tax":0,"total":100,"delivery":null,"fulfilment":"all_or_nothing"}'
+    get_order_history "created reserved cancelled"
+    sleep 0.1
+    delete_order
+    sleep 0.1
+
+    create_order
+    sleep 0.1
+    create_billing
+    sleep 0.1
+    cancel_order
+    sleep 0.1
+    get_order '{"status":"cancelled","goods":[{"id":1,"count":1,"naming":"","price":100,"total":100,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":100,"promotion_code":null,"discount":0,"tax":0,"total":100,"delivery":null,"fulfilment":"all_or_nothing"}'
+    sleep 0.1
+    delete_order
+    sleep 0.1
+}
+
+function test_promotion {
+    put_promotion '{"code": "SPRING", "kind": "percent", "value": 1000}'
+    create_order
+    sleep 0.1
+    apply_promotion SPRING
+    sleep 0.1
+    get_order '{"status":"reserved","goods":[{"id":1,"count":1,"naming":"","price":100,"total":100,"discount":10,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":100,"promotion_code":"SPRING","discount":10,"tax":0,"total":90,"delivery":null,"fulfilment":"all_or_nothing"}'
+    sleep 0.1
+    delete_order
+    delete_promotion SPRING
+    sleep 0.1
+}
+
+function test_stale_update {
+    create_order
+    sleep 0.1
+    get_order_etag
+    stale=$etag
+    update_order_if_match "$stale" 200
+    sleep 0.1
+    update_order_if_match "$stale" 412
+    get_order '{"status":"reserved","goods":[{"id":1,"count":2,"naming":"","price":100,"total":200,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":200,"promotion_code":null,"discount":0,"tax":0,"total":200,"delivery":null,"fulfilment":"all_or_nothing"}'
+    sleep 0.1
+    delete_order
+    sleep 0.1
+}
+
+function test_delivery {
+    create_order '{"goods": [{"id": 1, "count": 1}], "delivery": {"method": "pickup", "phone": "+15550100"}}'
+    sleep 0.1
+    get_order '{"status":"reserved","goods":[{"id":1,"count":1,"naming":"","price":100,"total":100,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":100,"promotion_code":null,"discount":0,"tax":0,"total":100,"delivery":{"address":null,"method":"pickup","phone":"+15550100","window":null},"fulfilment":"all_or_nothing"}'
+    update_order_delivery '{"method": "standard", "phone": "+15550100", "address": {"recipient": "Lieroz", "line1": "1-Main-St", "city": "Springfield", "postal_code": "12345", "country": "US"}}'
+    sleep 0.1
+    get_order '{"status":"reserved","goods":[{"id":1,"count":1,"naming":"","price":100,"total":100,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":100,"promotion_code":null,"discount":0,"tax":0,"total":100,"delivery":{"address":{"city":"Springfield","country":"US","line1":"1-Main-St","line2":"","postal_code":"12345","recipient":"Lieroz","region":""},"method":"standard","phone":"+15550100","window":null},"fulfilment":"all_or_nothing"}'
+    sleep 0.1
+    delete_order
+    sleep 0.1
+}
+
+function test_shipment {
+    create_order
+    sleep 0.1
+    create_billing
+    sleep 0.1
+    get_shipment '"state":"picking","goods"'
+    advance_shipment '{"state": "packed"}'
+    sleep 0.1
+    advance_shipment '{"state": "shipped", "carrier": "UPS", "tracking_number": "1Z999AA1"}'
+    sleep 0.1
+    get_shipment '"state":"shipped","goods" "tracking_number":"1Z999AA1"'
+    get_order '{"status":"shipped","goods":[{"id":1,"count":1,"naming":"","price":100,"total":100,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":100,"promotion_code":null,"discount":0,"tax":0,"total":100,"delivery":null,"fulfilment":"all_or_nothing"}'
+    sleep 0.1
+    delete_order
+    sleep 0.1
+}
+
+function test_backorders {
+    create_order '{"goods": [{"id": 1, "count": 7}], "fulfilment": "partial"}'
+    sleep 0.1
+    get_order '{"status":"reserved","goods":[{"id":1,"count":7,"naming":"","price":100,"total":700,"discount":0,"backordered":2,"state":"partially_reserved"}],"currency":"USD","subtotal":700,"promotion_code":null,"discount":0,"tax":0,"total":700,"delivery":null,"fulfilment":"partial"}'
+    restock_good '{"count": 3}'
+    sleep 0.1
+    get_order '{"status":"reserved","goods":[{"id":1,"count":7,"naming":"","price":100,"total":700,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":700,"promotion_code":null,"discount":0,"tax":0,"total":700,"delivery":null,"fulfilment":"partial"}'
+    sleep 0.1
+    delete_order
+    sleep 0.1
+}
+
+function test_returns {
+    create_order
+    sleep 0.1
+    create_billing
+    sleep 0.1
+    advance_shipment '{"state": "packed"}'
+    sleep 0.1
+    advance_shipment '{"state": "shipped", "tracking_number": "1Z999AA1"}'
+    sleep 0.1
+    advance_shipment '{"state": "delivered"}'
+    sleep 0.1
+    request_return '{"goods": [{"id": 1, "count": 1}], "reason": "damaged"}'
+    sleep 0.1
+    get_returns '"status":"requested" "refund":100'
+    review_return '{"decision": "approve"}'
+    sleep 0.3
+    get_returns '"status":"refunded" "refund":100'
+    get_order '{"status":"refunded","goods":[{"id":1,"count":1,"naming":"","price":100,"total":100,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":100,"promotion_code":null,"discount":0,"tax":0,"total":100,"delivery":null,"fulfilment":"all_or_nothing"}'
+    sleep 0.1
+    delete_order
+    sleep 0.1
+}
+
+function test_reorder {
+    create_order
+    sleep 0.1
+    reorder
+    sleep 0.1
+    get_order '{"status":"reserved","goods":[{"id":1,"count":1,"naming":"","price":100,"total":100,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":100,"promotion_code":null,"discount":0,"tax":0,"total":100,"delivery":null,"fulfilment":"all_or_nothing"}' 2
+    put_template '{"name": "weekly", "goods": [{"id": 1, "count": 2}]}'
+    sleep 0.1
+    get_templates '"name":"weekly"'
+    instantiate_template weekly
+    sleep 0.1
+    get_order '{"status":"reserved","goods":[{"id":1,"count":2,"naming":"","price":100,"total":200,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":200,"promotion_code":null,"discount":0,"tax":0,"total":200,"delivery":null,"fulfilment":"all_or_nothing"}' 3
+    delete_order
+    sleep 0.1
+}
+
+function test_schedules {
+    redis-cli -p 6380 HSET good_id:1 count 5 price 100 currency USD
+    put_schedule '{"name": "weekly", "goods": [{"id": 1, "count": 2}], "recurrence": {"every_days": 7}}'
+    # Scheduler looks for due schedules once a second
+    sleep 1.5
+    get_order '{"status":"reserved","goods":[{"id":1,"count":2,"naming":"","price":100,"total":200,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":200,"promotion_code":null,"discount":0,"tax":0,"total":200,"delivery":null,"fulfilment":"all_or_nothing"}'
+    get_schedules '"outcome":"created"'
+    delete_schedule weekly
+    sleep 0.1
+    delete_order
+    sleep 0.1
+}
+
+function test_cart {
+    add_to_cart '{"id": 1, "count": 1}'
+    add_to_cart '{"id": 1, "count": 2}'
+    get_cart '"goods":[{"id":1,"count":3,"naming":"","price":100,"currency":"USD","total":300,"in_stock":5}]'
+    update_cart_good 1 '{"count": 2}'
+    get_cart '"goods":[{"id":1,"count":2,"naming":"","price":100,"currency":"USD","total":200,"in_stock":5}]'
+    checkout_cart '{"fulfilment": "partial"}'
     sleep 0.1
+    get_order '{"status":"reserved","goods":[{"id":1,"count":2,"naming":"","price":100,"total":200,"discount":0,"backordered":0,"state":"reserved"}],"currency":"USD","subtotal":200,"promotion_code":null,"discount":0,"tax":0,"total":200,"delivery":null,"fulfilment":"partial"}'
+    get_cart '"goods":[]'
+    checkout_cart '' 400
     delete_order
     sleep 0.1
 }
@@ -170,7 +743,33 @@ echo -e "${ORANGE}TEST: test_create_get_delete_order$NC"
 test_create_get_delete_order
 echo -e "${ORANGE}TEST: test_create_update_get_delete_order$NC"
 test_create_update_get_delete_order
+echo -e "${ORANGE}TEST: test_update_lines$NC"
+test_update_lines
 echo -e "${ORANGE}TEST: test_billing$NC"
 test_billing
+echo -e "${ORANGE}TEST: test_export$NC"
+test_export
+echo -e "${ORANGE}TEST: test_search$NC"
+test_search
 echo -e "${ORANGE}TEST: test_update_after_billing$NC"
 test_update_after_billing
+echo -e "${ORANGE}TEST: test_cancel_order$NC"
+test_cancel_order
+echo -e "${ORANGE}TEST: test_promotion$NC"
+test_promotion
+echo -e "${ORANGE}TEST: test_stale_update$NC"
+test_stale_update
+echo -e "${ORANGE}TEST: test_delivery$NC"
+test_delivery
+echo -e "${ORANGE}TEST: test_shipment$NC"
+test_shipment
+echo -e "${ORANGE}TEST: test_backorders$NC"
+test_backorders
+echo -e "${ORANGE}TEST: test_returns$NC"
+test_returns
+echo -e "${ORANGE}TEST: test_reorder$NC"
+test_reorder
+echo -e "${ORANGE}TEST: test_cart$NC"
+test_cart
+echo -e "${ORANGE}TEST: test_schedules$NC"
+test_schedules
@@ -1,6 +1,7 @@
 use actix_web::{web, HttpResponse};
 
 use crate::api::*;
+use shutdown::readiness;
 
 pub fn config_app(cfg: &mut web::ServiceConfig) {
     cfg.service(
@@ -8,15 +9,17 @@ pub fn config_app(cfg: &mut web::ServiceConfig) {
             .service(
                 web::scope("/probe")
                     .service(web::resource("/liveness").route(web::get().to(|| HttpResponse::Ok())))
-                    .service(
-                        web::resource("/readiness").route(web::get().to(|| HttpResponse::Ok())),
-                    )
+                    .service(web::resource("/readiness").route(web::get().to(readiness)))
                     .service(web::resource("/startup").route(web::get().to(|| HttpResponse::Ok()))),
             )
             .service(
                 web::scope("/goods")
                     .service(web::resource("").route(web::get().to(get_goods)))
                     .service(web::resource("/{good_id}").route(web::get().to(get_good))),
+            )
+            .service(
+                web::resource("/user/{user_id}/order/{order_id}/shipment")
+                    .route(web::get().to(get_shipment)),
             ),
     );
 }
@@ -0,0 +1,209 @@
+use crate::outbox::{Destination, Outbox};
+use crate::storage::{Change, Changes, InventoryRepository};
+use serde::Deserialize;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Error, ErrorKind};
+
+// With partial fulfilment goods which are in stock are reserved and
+// the rest waits until stock is replenished, otherwise order is rejected
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FulfilmentPolicy {
+    #[default]
+    AllOrNothing,
+    Partial,
+}
+
+impl FulfilmentPolicy {
+    pub fn parse(policy: &str) -> Option<FulfilmentPolicy> {
+        match policy {
+            "all_or_nothing" => Some(FulfilmentPolicy::AllOrNothing),
+            "partial" => Some(FulfilmentPolicy::Partial),
+            _ => None,
+        }
+    }
+}
+
+// Decides how many of 'wanted' goods are taken out of 'available' stock,
+// missing ones are added to backorder
+pub fn reserve(
+    good_id: u64,
+    wanted: i64,
+    available: i64,
+    policy: FulfilmentPolicy,
+    backorder: &mut BTreeMap<u64, i64>,
+) -> Result<i64, Box<dyn std::error::Error>> {
+    let taken = match policy {
+        FulfilmentPolicy::AllOrNothing if available < wanted => {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Not enough good in warehouse with id: {}",
+                    line!(),
+                    good_id
+                ),
+            )));
+        }
+        FulfilmentPolicy::AllOrNothing => wanted,
+        FulfilmentPolicy::Partial => std::cmp::max(std::cmp::min(wanted, available), 0),
+    };
+
+    if wanted > taken {
+        *backorder.entry(good_id).or_default() += wanted - taken;
+    }
+
+    Ok(taken)
+}
+
+// Moves goods from stock to reservation of the order
+pub fn take(changes: &mut Changes, user_id: &str, order_id: &str, good_id: u64, count: i64) {
+    if count != 0 {
+        changes.push(Change::AdjustStock {
+            good_id,
+            delta: -count,
+        });
+        changes.push(Change::AdjustReservation {
+            user_id: user_id.to_string(),
+            order_id: order_id.to_string(),
+            good_id,
+            delta: count,
+        });
+    }
+}
+
+// Count of the good which is no longer wanted is taken from backorder first,
+// returns how many of them were reserved
+pub fn reduce(backorder: &mut BTreeMap<u64, i64>, good_id: u64, count: i64) -> i64 {
+    let missing = backorder.get(&good_id).cloned().unwrap_or(0);
+    let unreserved = std::cmp::min(missing, count);
+
+    if missing > unreserved {
+        backorder.insert(good_id, missing - unreserved);
+    } else {
+        backorder.remove(&good_id);
+    }
+
+    count - unreserved
+}
+
+#[derive(Deserialize)]
+pub struct Restock {
+    count: i64,
+}
+
+// Goods customers sent back, they are sold again
+#[derive(Deserialize)]
+pub struct ReturnedGoods {
+    goods: BTreeMap<u64, i64>,
+}
+
+impl Restock {
+    pub fn restock(
+        &self,
+        good_id: u64,
+        correlation_id: &str,
+        outbox: &mut Outbox,
+        storage: &dyn InventoryRepository,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let goods = std::iter::once((good_id, self.count)).collect();
+        replenish(&goods, correlation_id, outbox, storage)
+    }
+}
+
+impl ReturnedGoods {
+    pub fn restock(
+        &self,
+        correlation_id: &str,
+        outbox: &mut Outbox,
+        storage: &dyn InventoryRepository,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        replenish(&self.goods, correlation_id, outbox, storage)
+    }
+}
+
+// New goods go to orders which wait for them longer first, every order
+// which got goods is told what it still waits for
+fn replenish(
+    goods: &BTreeMap<u64, i64>,
+    correlation_id: &str,
+    outbox: &mut Outbox,
+    storage: &dyn InventoryRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let waiting = storage.backordered()?;
+    // Backorder of an order is read once, so all goods are taken from it
+    let mut backorders: HashMap<(String, String), BTreeMap<u64, i64>> = HashMap::new();
+    let mut filled: Vec<(String, String)> = vec![];
+    let mut changes = Changes::default();
+
+    for (good_id, count) in goods {
+        let mut available = match storage.stock(*good_id)? {
+            Some(stock) => {
+                changes.push(Change::CheckStock {
+                    good_id: *good_id,
+                    count: stock,
+                });
+                stock + count
+            }
+            None => {
+                return Err(Box::new(Error::new(
+                    ErrorKind::Other,
+                    format!("line:{}: There is no good with id: {}", line!(), good_id),
+                )))
+            }
+        };
+        changes.push(Change::AdjustStock {
+            good_id: *good_id,
+            delta: *count,
+        });
+
+        for (user_id, order_id) in &waiting {
+            if available <= 0 {
+                break;
+            }
+
+            let key = (user_id.clone(), order_id.clone());
+            let backorder = match backorders.entry(key.clone()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(storage.backorder(user_id, order_id)?),
+            };
+            let missing = match backorder.get(good_id) {
+                Some(missing) => *missing,
+                None => continue,
+            };
+            let taken = std::cmp::min(missing, available);
+            reduce(backorder, *good_id, taken);
+            available -= taken;
+
+            take(&mut changes, user_id, order_id, *good_id, taken);
+
+            if !filled.contains(&key) {
+                filled.push(key);
+            }
+        }
+    }
+
+    for key in filled {
+        let backorder = backorders.remove(&key).unwrap_or_default();
+        let (user_id, order_id) = key;
+        outbox.push(
+            Destination::Orders,
+            &[
+                ("user_id", &user_id),
+                ("order_id", &order_id),
+                ("operation", "backorder_filled"),
+                ("actor", "warehouse"),
+                ("correlation_id", correlation_id),
+            ],
+            serde_json::json!({ "backorders": backorder }).to_string(),
+        );
+        changes.push(Change::SetBackorder {
+            user_id,
+            order_id,
+            goods: backorder,
+        });
+    }
+
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
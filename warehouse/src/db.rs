@@ -1,8 +1,110 @@
-use r2d2_redis::{r2d2, redis, RedisConnectionManager};
-use serde::Deserialize;
-use std::collections::HashMap;
+use crate::backorders::{self, FulfilmentPolicy};
+use crate::fulfilment;
+use crate::outbox::Outbox;
+use crate::storage::{Change, Changes, InventoryRepository};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::io::{Error, ErrorKind};
-use std::ops::DerefMut;
+
+// Goods taken by every order are remembered, so they are returned to stock
+// exactly once, even if release is repeated or comes before reservation
+const RELEASED_TTL_SECS: usize = 604_800;
+
+// Orders service names goods in payloads by their keys 'good_id:<id>'
+fn parse_goods<T: Copy>(
+    goods: &HashMap<String, T>,
+) -> Result<BTreeMap<u64, T>, Box<dyn std::error::Error>> {
+    let mut result = BTreeMap::new();
+
+    for (key, count) in goods {
+        match key.strip_prefix("good_id:") {
+            Some(good_id) => {
+                result.insert(good_id.parse()?, *count);
+            }
+            None => {
+                return Err(Box::new(Error::new(
+                    ErrorKind::Other,
+                    format!("line:{}: Invalid good key: {}", line!(), key),
+                )))
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+fn return_to_stock(changes: &mut Changes, reserved: BTreeMap<u64, i64>) {
+    for (good_id, count) in reserved {
+        changes.push(Change::AdjustStock {
+            good_id,
+            delta: count,
+        });
+    }
+}
+
+// Prices of reserved goods in minor units, orders service keeps them in the order,
+// so later changes of prices don't affect it, backorders are all goods the order
+// still waits for
+#[derive(Default, Serialize)]
+struct Quote {
+    currency: String,
+    prices: BTreeMap<u64, u64>,
+    backorders: BTreeMap<u64, i64>,
+}
+
+// All goods of one order are sold in the same currency
+fn quote(
+    good_ids: impl Iterator<Item = u64>,
+    currency: Option<&str>,
+    storage: &dyn InventoryRepository,
+) -> Result<Quote, Box<dyn std::error::Error>> {
+    let mut quote = Quote {
+        currency: currency.unwrap_or_default().to_string(),
+        prices: BTreeMap::new(),
+        backorders: BTreeMap::new(),
+    };
+
+    for good_id in good_ids {
+        let good = match storage.good(good_id)? {
+            Some(good) => good,
+            None => {
+                return Err(Box::new(Error::new(
+                    ErrorKind::Other,
+                    format!("line:{}: There is no good with id: {}", line!(), good_id),
+                )))
+            }
+        };
+
+        let (price, currency) = match (good.get("price"), good.get("currency")) {
+            (Some(price), Some(currency)) => (price.parse()?, currency),
+            _ => {
+                return Err(Box::new(Error::new(
+                    ErrorKind::Other,
+                    format!("line:{}: Good with id: {} has no price", line!(), good_id),
+                )))
+            }
+        };
+
+        if quote.currency.is_empty() {
+            quote.currency = currency.clone();
+        } else if &quote.currency != currency {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Good with id: {} is sold in '{}', order is in '{}'",
+                    line!(),
+                    good_id,
+                    currency,
+                    quote.currency
+                ),
+            )));
+        }
+
+        quote.prices.insert(good_id, price);
+    }
+
+    Ok(quote)
+}
 
 #[derive(Deserialize)]
 struct CreateGood {
@@ -13,80 +115,75 @@ struct CreateGood {
 #[derive(Deserialize)]
 pub struct CreateOrder {
     goods: Vec<CreateGood>,
+    #[serde(default)]
+    fulfilment: FulfilmentPolicy,
 }
 
 impl CreateOrder {
     pub fn create(
         &self,
-        conn: &mut r2d2::PooledConnection<RedisConnectionManager>,
+        user_id: &str,
+        order_id: &str,
+        outbox: &mut Outbox,
+        storage: &dyn InventoryRepository,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut pipe = redis::pipe();
-
-        for good in &self.goods {
-            pipe.cmd("HGET")
-                .arg(&[&format!("good_id:{}", good.id), "count"]);
+        if storage.is_released(user_id, order_id)? {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Order '{}' was already released, goods can't be reserved",
+                    line!(),
+                    order_id
+                ),
+            )));
         }
 
-        let goods: Vec<redis::Value> = pipe.query(conn.deref_mut())?;
-
-        if goods.is_empty() {
+        if self.goods.is_empty() {
             return Err(Box::new(Error::new(
                 ErrorKind::Other,
                 format!("line:{}: There are no goods specified in order!", line!()),
             )));
-        } else {
-            pipe = redis::pipe();
-
-            for (i, data) in goods.iter().enumerate() {
-                match data {
-                    redis::Value::Data(data) => {
-                        let count: i64 = std::str::from_utf8(&data)?.parse()?;
-                        let good = &self.goods[i];
-
-                        if count >= good.count {
-                            pipe.cmd("HSET").arg(&[
-                                &format!("good_id:{}", good.id),
-                                "count",
-                                &(count - good.count).to_string(),
-                            ]);
-                        } else {
-                            return Err(Box::new(Error::new(
-                                ErrorKind::Other,
-                                format!(
-                                    "line:{}: Not enough good in warehouse with id: {}",
-                                    line!(),
-                                    self.goods[i].id
-                                ),
-                            )));
-                        }
-                    }
-                    redis::Value::Nil => {
-                        return Err(Box::new(Error::new(
-                            ErrorKind::Other,
-                            format!(
-                                "line:{}: There is no good with id: {}",
-                                line!(),
-                                self.goods[i].id
-                            ),
-                        )));
-                    }
-                    value => {
-                        return Err(Box::new(Error::new(
-                            ErrorKind::Other,
-                            format!(
-                                "line:{}: Redis returned invalid value: {:?}",
-                                line!(),
-                                value
-                            ),
-                        )));
-                    }
+        }
+
+        let mut quote = quote(self.goods.iter().map(|good| good.id), None, storage)?;
+        let mut changes = Changes::default();
+
+        for good in &self.goods {
+            match storage.stock(good.id)? {
+                Some(count) => {
+                    changes.push(Change::CheckStock {
+                        good_id: good.id,
+                        count,
+                    });
+                    let taken = backorders::reserve(
+                        good.id,
+                        good.count,
+                        count,
+                        self.fulfilment,
+                        &mut quote.backorders,
+                    )?;
+                    backorders::take(&mut changes, user_id, order_id, good.id, taken);
+                }
+                None => {
+                    return Err(Box::new(Error::new(
+                        ErrorKind::Other,
+                        format!("line:{}: There is no good with id: {}", line!(), good.id),
+                    )));
                 }
             }
+        }
 
-            let _ = pipe.query(conn.deref_mut())?;
+        if !quote.backorders.is_empty() {
+            changes.push(Change::SetBackorder {
+                user_id: user_id.to_string(),
+                order_id: order_id.to_string(),
+                goods: quote.backorders.clone(),
+            });
         }
 
-        Ok(())
+        outbox.set_payload(serde_json::to_string(&quote)?);
+        outbox.write(&mut changes)?;
+        storage.apply(changes)
     }
 }
 
@@ -104,99 +201,220 @@ pub struct UpdateOrder {
 }
 
 impl UpdateOrder {
+    // Only goods with changed count are priced again, count is the
+    // difference between old and new count of the good
     pub fn update(
         &self,
-        conn: &mut r2d2::PooledConnection<RedisConnectionManager>,
+        user_id: &str,
+        order_id: &str,
+        currency: Option<&str>,
+        policy: FulfilmentPolicy,
+        outbox: &mut Outbox,
+        storage: &dyn InventoryRepository,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut pipe = redis::pipe();
-
-        for good in &self.goods {
-            pipe.cmd("HGET")
-                .arg(&[&format!("good_id:{}", good.id), "count"]);
+        // Orders service sends one line per good, repeated one would
+        // adjust the stock twice
+        let mut good_ids = BTreeSet::new();
+        if let Some(good) = self.goods.iter().find(|good| !good_ids.insert(good.id)) {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Good {} is repeated in the update",
+                    line!(),
+                    good.id
+                ),
+            )));
         }
 
-        let goods: Vec<redis::Value> = pipe.query(conn.deref_mut())?;
-
-        for (i, data) in goods.iter().enumerate() {
-            match data {
-                redis::Value::Data(data) => {
-                    let good = &self.goods[i];
-                    let total: i64 = std::str::from_utf8(&data)?.parse()?;
-
-                    match &good.operation[..] {
-                        "update" => {
-                            if total + good.count >= 0 {
-                                pipe.cmd("HINCRBY").arg(&[
-                                    &format!("good_id:{}", good.id),
-                                    "count",
-                                    &good.count.to_string(),
-                                ]);
-                            } else {
-                                return Err(Box::new(Error::new(
-                                    ErrorKind::Other,
-                                    format!(
-                                        "line:{}: Not enough good in warehouse with id: {}",
-                                        line!(),
-                                        self.goods[i].id
-                                    ),
-                                )));
-                            }
-                        }
-                        "delete" => {
-                            pipe.cmd("HINCRBY").arg(&[
-                                &format!("good_id:{}", good.id),
-                                "count",
-                                &good.count.to_string(),
-                            ]);
-                        }
-                        _ => {
-                            return Err(Box::new(Error::new(
-                                ErrorKind::Other,
-                                format!("line:{}: Unknown operation: {}", line!(), good.operation),
-                            )));
-                        }
-                    }
+        let updated = self
+            .goods
+            .iter()
+            .filter(|good| good.operation == "update")
+            .map(|good| good.id);
+        let mut quote = quote(updated, currency, storage)?;
+        let backorder = storage.backorder(user_id, order_id)?;
+        quote.backorders = backorder.clone();
+
+        let mut changes = Changes::default();
+
+        for good in &self.goods {
+            let total = match storage.stock(good.id)? {
+                Some(total) => {
+                    changes.push(Change::CheckStock {
+                        good_id: good.id,
+                        count: total,
+                    });
+                    total
                 }
-                redis::Value::Nil => {
+                None => {
                     return Err(Box::new(Error::new(
                         ErrorKind::Other,
-                        format!(
-                            "line:{}: There is no good with id: {}",
-                            line!(),
-                            self.goods[i].id
-                        ),
+                        format!("line:{}: There is no good with id: {}", line!(), good.id),
                     )));
                 }
-                value => {
+            };
+
+            match &good.operation[..] {
+                "update" if good.count >= 0 => {
+                    let returned = backorders::reduce(&mut quote.backorders, good.id, good.count);
+                    backorders::take(&mut changes, user_id, order_id, good.id, -returned);
+                }
+                "update" => {
+                    let taken = backorders::reserve(
+                        good.id,
+                        -good.count,
+                        total,
+                        policy,
+                        &mut quote.backorders,
+                    )?;
+                    backorders::take(&mut changes, user_id, order_id, good.id, taken);
+                }
+                "delete" => {
+                    let returned = backorders::reduce(&mut quote.backorders, good.id, good.count);
+                    changes.push(Change::AdjustStock {
+                        good_id: good.id,
+                        delta: returned,
+                    });
+                    changes.push(Change::RemoveReservedGood {
+                        user_id: user_id.to_string(),
+                        order_id: order_id.to_string(),
+                        good_id: good.id,
+                    });
+                }
+                _ => {
                     return Err(Box::new(Error::new(
                         ErrorKind::Other,
-                        format!(
-                            "line:{}: Redis returned invalid value: {:?}",
-                            line!(),
-                            value
-                        ),
+                        format!("line:{}: Unknown operation: {}", line!(), good.operation),
                     )));
                 }
             }
         }
 
-        let _ = pipe.query(conn.deref_mut())?;
+        if quote.backorders != backorder {
+            changes.push(Change::SetBackorder {
+                user_id: user_id.to_string(),
+                order_id: order_id.to_string(),
+                goods: quote.backorders.clone(),
+            });
+        }
 
-        Ok(())
+        outbox.set_payload(serde_json::to_string(&quote)?);
+        outbox.write(&mut changes)?;
+        storage.apply(changes)
     }
 }
 
+// Orders reserved before reservations were tracked are returned by payload
 pub fn delete_order(
     goods: HashMap<String, u64>,
-    conn: &mut r2d2::PooledConnection<RedisConnectionManager>,
+    user_id: &str,
+    order_id: &str,
+    outbox: &Outbox,
+    storage: &dyn InventoryRepository,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let mut pipe = redis::pipe();
+    let mut reserved = storage.reservation(user_id, order_id)?;
+    let shipped = storage
+        .shipment(user_id, order_id)?
+        .is_some_and(|shipment| shipment.has_left());
 
-    for (k, v) in goods {
-        pipe.cmd("HINCRBY").arg(&[&k, "count", &v.to_string()]);
+    if reserved.is_empty() && !shipped && !storage.is_released(user_id, order_id)? {
+        reserved = parse_goods(&goods)?
+            .into_iter()
+            .map(|(good_id, count)| (good_id, count as i64))
+            .collect();
     }
 
-    let _ = pipe.query(conn.deref_mut())?;
+    let mut changes = Changes::default();
+    return_to_stock(&mut changes, reserved);
+    fulfilment::cancel_shipment(&mut changes, user_id, order_id, storage)?;
+    changes.push(Change::ClearReservation {
+        user_id: user_id.to_string(),
+        order_id: order_id.to_string(),
+    });
+    changes.push(Change::SetBackorder {
+        user_id: user_id.to_string(),
+        order_id: order_id.to_string(),
+        goods: BTreeMap::new(),
+    });
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
+
+// Compensating action of order saga, repeated release returns nothing
+pub fn release_order(
+    user_id: &str,
+    order_id: &str,
+    outbox: &Outbox,
+    storage: &dyn InventoryRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut changes = Changes::default();
+    return_to_stock(&mut changes, storage.reservation(user_id, order_id)?);
+    fulfilment::cancel_shipment(&mut changes, user_id, order_id, storage)?;
+    changes.push(Change::ClearReservation {
+        user_id: user_id.to_string(),
+        order_id: order_id.to_string(),
+    });
+    changes.push(Change::SetBackorder {
+        user_id: user_id.to_string(),
+        order_id: order_id.to_string(),
+        goods: BTreeMap::new(),
+    });
+    changes.push(Change::MarkReleased {
+        user_id: user_id.to_string(),
+        order_id: order_id.to_string(),
+        ttl_secs: RELEASED_TTL_SECS,
+    });
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
+}
+
+// Transaction was given up by orders service, if it was already applied,
+// reservation is put back in line with the order, otherwise it is never applied
+pub fn abort_transaction(
+    goods: HashMap<String, i64>,
+    user_id: &str,
+    order_id: &str,
+    aborted_message_id: &str,
+    outbox: &Outbox,
+    storage: &dyn InventoryRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut changes = Changes::default();
+
+    if storage.is_processed(aborted_message_id)? {
+        // Goods the order waits for aren't reserved
+        let backorder = storage.backorder(user_id, order_id)?;
+        let goods: BTreeMap<u64, i64> = parse_goods(&goods)?
+            .into_iter()
+            .map(|(good_id, count)| {
+                let missing = backorder.get(&good_id).cloned().unwrap_or(0);
+                (good_id, std::cmp::max(count - missing, 0))
+            })
+            .collect();
+        let reserved = storage.reservation(user_id, order_id)?;
+
+        for (good_id, count) in &goods {
+            changes.push(Change::AdjustStock {
+                good_id: *good_id,
+                delta: reserved.get(good_id).unwrap_or(&0) - count,
+            });
+        }
+
+        for (good_id, count) in &reserved {
+            if !goods.contains_key(good_id) {
+                changes.push(Change::AdjustStock {
+                    good_id: *good_id,
+                    delta: *count,
+                });
+            }
+        }
+
+        changes.push(Change::SetReservation {
+            user_id: user_id.to_string(),
+            order_id: order_id.to_string(),
+            goods,
+        });
+    }
 
-    Ok(())
+    outbox.write(&mut changes)?;
+    storage.apply(changes)
 }
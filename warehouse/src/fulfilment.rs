@@ -0,0 +1,277 @@
+use crate::outbox::{Destination, Outbox};
+use crate::storage::{now_ms, Change, Changes, InventoryRepository};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShipmentState {
+    Picking,
+    Packed,
+    Shipped,
+    Delivered,
+}
+
+impl ShipmentState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ShipmentState::Picking => "picking",
+            ShipmentState::Packed => "packed",
+            ShipmentState::Shipped => "shipped",
+            ShipmentState::Delivered => "delivered",
+        }
+    }
+
+    // Shipment only moves forward, one state at a time
+    fn next(self) -> Option<ShipmentState> {
+        match self {
+            ShipmentState::Picking => Some(ShipmentState::Packed),
+            ShipmentState::Packed => Some(ShipmentState::Shipped),
+            ShipmentState::Shipped => Some(ShipmentState::Delivered),
+            ShipmentState::Delivered => None,
+        }
+    }
+
+    // Status orders service is told about when shipment reaches the state
+    fn order_status(self) -> Option<&'static str> {
+        match self {
+            ShipmentState::Shipped => Some("shipped"),
+            ShipmentState::Delivered => Some("delivered"),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ShipmentEvent {
+    pub state: ShipmentState,
+    pub at: u64,
+}
+
+// Goods are the ones reserved for the order when it was paid,
+// delivery details are copied from the order as they are
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Shipment {
+    pub state: ShipmentState,
+    pub goods: BTreeMap<u64, i64>,
+    #[serde(default)]
+    pub delivery: Option<serde_json::Value>,
+    #[serde(default)]
+    pub carrier: Option<String>,
+    #[serde(default)]
+    pub tracking_number: Option<String>,
+    pub history: Vec<ShipmentEvent>,
+}
+
+impl Shipment {
+    // Reserved goods are taken out of the warehouse when it is shipped
+    pub fn has_left(&self) -> bool {
+        matches!(
+            self.state,
+            ShipmentState::Shipped | ShipmentState::Delivered
+        )
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CreateShipment {
+    #[serde(default)]
+    delivery: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+pub struct AdvanceShipment {
+    state: ShipmentState,
+    #[serde(default)]
+    carrier: Option<String>,
+    #[serde(default)]
+    tracking_number: Option<String>,
+}
+
+impl CreateShipment {
+    pub fn create(
+        self,
+        user_id: &str,
+        order_id: &str,
+        outbox: &Outbox,
+        storage: &dyn InventoryRepository,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if storage.shipment(user_id, order_id)?.is_some() {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Shipment of order '{}' of user '{}' already exists",
+                    line!(),
+                    order_id,
+                    user_id
+                ),
+            )));
+        }
+
+        let goods = storage.reservation(user_id, order_id)?;
+
+        if goods.is_empty() && storage.backorder(user_id, order_id)?.is_empty() {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Nothing is reserved for order '{}' of user '{}'",
+                    line!(),
+                    order_id,
+                    user_id
+                ),
+            )));
+        }
+
+        let mut changes = Changes::default();
+        changes.push(Change::PutShipment {
+            user_id: user_id.to_string(),
+            order_id: order_id.to_string(),
+            shipment: Shipment {
+                state: ShipmentState::Picking,
+                goods,
+                delivery: self.delivery,
+                carrier: None,
+                tracking_number: None,
+                history: vec![ShipmentEvent {
+                    state: ShipmentState::Picking,
+                    at: now_ms(),
+                }],
+            },
+        });
+        outbox.write(&mut changes)?;
+        storage.apply(changes)
+    }
+}
+
+impl AdvanceShipment {
+    // Reserved goods leave the warehouse when shipment is shipped,
+    // orders service is told about it and about delivery
+    pub fn advance(
+        self,
+        user_id: &str,
+        order_id: &str,
+        correlation_id: &str,
+        outbox: &mut Outbox,
+        storage: &dyn InventoryRepository,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut shipment = match storage.shipment(user_id, order_id)? {
+            Some(shipment) => shipment,
+            None => {
+                return Err(Box::new(Error::new(
+                    ErrorKind::Other,
+                    format!(
+                        "line:{}: There is no shipment of order '{}' of user '{}'",
+                        line!(),
+                        order_id,
+                        user_id
+                    ),
+                )))
+            }
+        };
+
+        if shipment.state.next() != Some(self.state) {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Shipment of order '{}' can't become '{}' from '{}'",
+                    line!(),
+                    order_id,
+                    self.state.as_str(),
+                    shipment.state.as_str()
+                ),
+            )));
+        }
+
+        if self.carrier.is_some() {
+            shipment.carrier = self.carrier;
+        }
+
+        if self.tracking_number.is_some() {
+            shipment.tracking_number = self.tracking_number;
+        }
+
+        let mut changes = Changes::default();
+
+        // Shipment is packed only when all goods of the order are reserved
+        if self.state == ShipmentState::Packed {
+            if !storage.backorder(user_id, order_id)?.is_empty() {
+                return Err(Box::new(Error::new(
+                    ErrorKind::Other,
+                    format!(
+                        "line:{}: Shipment of order '{}' waits for backordered goods",
+                        line!(),
+                        order_id
+                    ),
+                )));
+            }
+
+            shipment.goods = storage.reservation(user_id, order_id)?;
+        }
+
+        if self.state == ShipmentState::Shipped {
+            if shipment.tracking_number.is_none() {
+                return Err(Box::new(Error::new(
+                    ErrorKind::Other,
+                    format!(
+                        "line:{}: Shipment of order '{}' can't be shipped without tracking number",
+                        line!(),
+                        order_id
+                    ),
+                )));
+            }
+
+            changes.push(Change::ClearReservation {
+                user_id: user_id.to_string(),
+                order_id: order_id.to_string(),
+            });
+        }
+
+        if let Some(status) = self.state.order_status() {
+            outbox.push(
+                Destination::Orders,
+                &[
+                    ("user_id", user_id),
+                    ("order_id", order_id),
+                    ("operation", "set_status"),
+                    ("status", status),
+                    ("actor", "warehouse"),
+                    ("correlation_id", correlation_id),
+                ],
+                "".to_string(),
+            );
+        }
+
+        shipment.state = self.state;
+        shipment.history.push(ShipmentEvent {
+            state: self.state,
+            at: now_ms(),
+        });
+        changes.push(Change::PutShipment {
+            user_id: user_id.to_string(),
+            order_id: order_id.to_string(),
+            shipment,
+        });
+        outbox.write(&mut changes)?;
+        storage.apply(changes)
+    }
+}
+
+// Shipment which hasn't left the warehouse is dropped when order is cancelled
+pub fn cancel_shipment(
+    changes: &mut Changes,
+    user_id: &str,
+    order_id: &str,
+    storage: &dyn InventoryRepository,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(shipment) = storage.shipment(user_id, order_id)? {
+        if !shipment.has_left() {
+            changes.push(Change::DeleteShipment {
+                user_id: user_id.to_string(),
+                order_id: order_id.to_string(),
+            });
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,12 @@
+use rdkafka::message::{BorrowedMessage, Message};
+use std::collections::HashMap;
+
+// Producers of this project put 'message_id' header into every message,
+// gateway doesn't do it, so message position in topic is used instead,
+// it is stable between redeliveries of the same message
+pub fn message_id(msg: &BorrowedMessage, metadata: &HashMap<&str, &str>) -> String {
+    match metadata.get("message_id") {
+        Some(id) => id.to_string(),
+        None => format!("{}:{}:{}", msg.topic(), msg.partition(), msg.offset()),
+    }
+}
@@ -1,17 +1,25 @@
-use crate::db::{delete_order, CreateOrder, UpdateOrder};
-use crate::validation_schema::{VALIDATION_SCHEMA_CREATE, VALIDATION_SCHEMA_UPDATE};
-use crate::KafkaTopics;
+use crate::backorders::{FulfilmentPolicy, Restock, ReturnedGoods};
+use crate::db::{abort_transaction, delete_order, release_order, CreateOrder, UpdateOrder};
+use crate::fulfilment::{AdvanceShipment, CreateShipment};
+use crate::idempotency::message_id;
+use crate::outbox::{Destination, Outbox};
+use crate::storage::{Conflict, InventoryRepository};
+use crate::validation_schema::{
+    VALIDATION_SCHEMA_ADVANCE_SHIPMENT, VALIDATION_SCHEMA_CREATE, VALIDATION_SCHEMA_RESTOCK,
+    VALIDATION_SCHEMA_UPDATE,
+};
+use crate::{KafkaProcessingOptions, KafkaTopics};
 use futures::stream::Stream;
-use r2d2_redis::{r2d2, RedisConnectionManager};
+use r2d2_redis::{r2d2, redis};
 use rdkafka::client::ClientContext;
 use rdkafka::consumer::stream_consumer::StreamConsumer;
 use rdkafka::consumer::{CommitMode, Consumer, ConsumerContext, Rebalance};
 use rdkafka::error::KafkaResult;
-use rdkafka::message::{BorrowedHeaders, Headers, Message, OwnedHeaders};
-use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::message::{BorrowedHeaders, BorrowedMessage, Headers, Message};
 use std::collections::HashMap;
 use std::io::{Error, ErrorKind};
 use std::sync::Arc;
+use std::time::Duration;
 use valico::json_schema::{schema, Scope};
 
 pub struct WarehouseContext;
@@ -65,19 +73,90 @@ fn get_kafka_message_metadata<'a>(
     Ok(metadata)
 }
 
+// Errors of infrastructure (redis is down) and stock changed by another
+// message are worth retrying, all other errors mean that message itself
+// is wrong and will never be processed
+fn is_transient(e: &(dyn std::error::Error + 'static)) -> bool {
+    if let Some(e) = e.downcast_ref::<redis::RedisError>() {
+        e.is_io_error()
+    } else {
+        e.is::<r2d2::Error>() || e.is::<Conflict>()
+    }
+}
+
+fn with_retries<T>(
+    options: &KafkaProcessingOptions,
+    mut f: impl FnMut() -> Result<T, Box<dyn std::error::Error>>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let mut backoff = options.retry_backoff_ms;
+
+    loop {
+        match f() {
+            Err(ref e) if is_transient(e.as_ref()) => {
+                warn!(
+                    "line:{}: Transient error: {}, retrying in {} ms",
+                    line!(),
+                    e,
+                    backoff
+                );
+                std::thread::sleep(Duration::from_millis(backoff));
+                backoff = std::cmp::min(backoff * 2, options.max_retry_backoff_ms);
+            }
+            result => return result,
+        }
+    }
+}
+
 fn process_operation(
     validators: &HashMap<&str, schema::ScopedSchema>,
     op: &str,
+    metadata: &HashMap<&str, &str>,
     payload: &str,
-    pool: &r2d2::Pool<RedisConnectionManager>,
+    outbox: &mut Outbox,
+    storage: &dyn InventoryRepository,
 ) -> Result<Option<serde_json::Value>, Box<dyn std::error::Error>> {
-    println!("op: {}", op);
     match validators.get(op) {
         // TODO: this can be called via hashmap and command pattern
-        None => match &op[..] {
+        None => match op {
             "delete" => {
                 let value: HashMap<String, u64> = serde_json::from_str(payload)?;
-                let _ = delete_order(value, &mut pool.get().unwrap())?;
+                delete_order(
+                    value,
+                    metadata["user_id"],
+                    metadata["order_id"],
+                    outbox,
+                    storage,
+                )?;
+                Ok(None)
+            }
+            "abort" => {
+                let value: HashMap<String, i64> = serde_json::from_str(payload)?;
+                abort_transaction(
+                    value,
+                    metadata["user_id"],
+                    metadata["order_id"],
+                    metadata["aborted_message_id"],
+                    outbox,
+                    storage,
+                )?;
+                Ok(None)
+            }
+            "release" => {
+                release_order(metadata["user_id"], metadata["order_id"], outbox, storage)?;
+                Ok(None)
+            }
+            "ship" => {
+                let shipment: CreateShipment = serde_json::from_str(payload)?;
+                shipment.create(metadata["user_id"], metadata["order_id"], outbox, storage)?;
+                Ok(None)
+            }
+            "return" => {
+                let goods: ReturnedGoods = serde_json::from_str(payload)?;
+                goods.restock(
+                    metadata.get("correlation_id").cloned().unwrap_or(""),
+                    outbox,
+                    storage,
+                )?;
                 Ok(None)
             }
             _ => Err(Box::new(Error::new(
@@ -88,18 +167,54 @@ fn process_operation(
         Some(validator) => match serde_json::from_str(payload) {
             Ok(value) => {
                 if validator.validate(&value).is_valid() {
-                    match &op[..] {
+                    match op {
                         "create" => {
                             let order: CreateOrder = serde_json::value::from_value(value.clone())?;
-                            order.create(&mut pool.get().unwrap())?;
+                            order.create(
+                                metadata["user_id"],
+                                metadata["order_id"],
+                                outbox,
+                                storage,
+                            )?;
                             Ok(Some(value))
                         }
                         "update" => {
-                            let order: UpdateOrder =
-                                serde_json::value::from_value(value.clone()).unwrap();
-                            let _ = order.update(&mut pool.get().unwrap())?;
+                            let order: UpdateOrder = serde_json::value::from_value(value.clone())?;
+                            let policy = metadata
+                                .get("fulfilment")
+                                .and_then(|policy| FulfilmentPolicy::parse(policy))
+                                .unwrap_or_default();
+                            order.update(
+                                metadata["user_id"],
+                                metadata["order_id"],
+                                metadata.get("currency").cloned(),
+                                policy,
+                                outbox,
+                                storage,
+                            )?;
                             Ok(Some(value))
                         }
+                        "advance_shipment" => {
+                            let shipment: AdvanceShipment = serde_json::value::from_value(value)?;
+                            shipment.advance(
+                                metadata["user_id"],
+                                metadata["order_id"],
+                                metadata.get("correlation_id").cloned().unwrap_or(""),
+                                outbox,
+                                storage,
+                            )?;
+                            Ok(None)
+                        }
+                        "restock" => {
+                            let restock: Restock = serde_json::value::from_value(value)?;
+                            restock.restock(
+                                metadata["good_id"].parse()?,
+                                metadata.get("correlation_id").cloned().unwrap_or(""),
+                                outbox,
+                                storage,
+                            )?;
+                            Ok(None)
+                        }
                         _ => Err(Box::new(Error::new(
                             ErrorKind::Other,
                             format!("line:{}: Unknown operation: {}", line!(), op),
@@ -117,11 +232,130 @@ fn process_operation(
     }
 }
 
+const UNANSWERED_OPERATIONS: &[&str] = &["advance_shipment", "restock"];
+
+fn transaction_reply(
+    message_id: &str,
+    options: &KafkaProcessingOptions,
+    metadata: &HashMap<&str, &str>,
+    op: &str,
+    status: &str,
+) -> Outbox {
+    let mut outbox = Outbox::new(message_id, options.processed_ttl_secs);
+    let mut headers = vec![
+        ("user_id", metadata["user_id"]),
+        ("order_id", metadata["order_id"]),
+        ("transaction", op),
+        ("operation", status),
+        ("actor", "warehouse"),
+        (
+            "correlation_id",
+            metadata
+                .get("correlation_id")
+                .cloned()
+                .unwrap_or(message_id),
+        ),
+    ];
+
+    // Returns of an order are told apart by their id
+    if let Some(return_id) = metadata.get("return_id") {
+        headers.push(("return_id", return_id));
+    }
+
+    outbox.push(Destination::Transactions, &headers, "".to_string());
+    outbox
+}
+
+// Stock change and 'commit' answer are stored in one storage transaction,
+// business errors are answered with 'rollout', infrastructure errors are retried
+fn handle_message(
+    validators: &HashMap<&str, schema::ScopedSchema>,
+    options: &KafkaProcessingOptions,
+    storage: &dyn InventoryRepository,
+    msg: &BorrowedMessage,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let payload = match msg.payload_view::<str>() {
+        None => "",
+        Some(Ok(payload)) => payload,
+        Some(Err(e)) => {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "line:{}: Can't deserialize message payload: {:?}",
+                    line!(),
+                    e
+                ),
+            )))
+        }
+    };
+
+    debug!(
+        "payload: '{}', topic: {}, partition: {}, offset: {}, timestamp: {:?}",
+        payload,
+        msg.topic(),
+        msg.partition(),
+        msg.offset(),
+        msg.timestamp()
+    );
+
+    let headers = msg.headers();
+    let metadata = get_kafka_message_metadata(&headers)?;
+    let message_id = message_id(msg, &metadata);
+
+    if with_retries(options, || storage.is_processed(&message_id))? {
+        info!(
+            "line:{}: Message '{}' was already processed, skipping",
+            line!(),
+            message_id
+        );
+        return Ok(());
+    }
+
+    let op = match metadata.get("operation") {
+        Some(op) => op,
+        None => {
+            return Err(Box::new(Error::new(
+                ErrorKind::Other,
+                format!("line:{}: Operation type wasn't passed in message", line!()),
+            )))
+        }
+    };
+
+    // Shipments and stock are managed by warehouse staff, nobody waits for an answer
+    let replies = !UNANSWERED_OPERATIONS.contains(op);
+
+    // Every attempt starts with an empty outbox, so nothing is sent twice
+    if let Err(e) = with_retries(options, || {
+        let mut outbox = if replies {
+            transaction_reply(&message_id, options, &metadata, op, "commit")
+        } else {
+            Outbox::new(&message_id, options.processed_ttl_secs)
+        };
+
+        if let Some(aborted_message_id) = metadata.get("aborted_message_id") {
+            outbox.skip(aborted_message_id);
+        }
+
+        process_operation(validators, op, &metadata, payload, &mut outbox, storage)
+    }) {
+        error!("line:{}: Error: {}", line!(), e);
+
+        let outbox = if replies {
+            transaction_reply(&message_id, options, &metadata, op, "rollout")
+        } else {
+            Outbox::new(&message_id, options.processed_ttl_secs)
+        };
+        with_retries(options, || outbox.commit(storage))?;
+    }
+
+    Ok(())
+}
+
 pub fn consume_and_process(
     topics: KafkaTopics,
-    producer: FutureProducer,
+    options: KafkaProcessingOptions,
     consumer: Arc<StreamConsumer<WarehouseContext>>,
-    pool: r2d2::Pool<RedisConnectionManager>,
+    storage: Arc<dyn InventoryRepository>,
 ) {
     consumer
         .subscribe(&[&topics.warehouse_service_topic])
@@ -140,69 +374,30 @@ pub fn consume_and_process(
         .unwrap();
     validators.insert("update", update_validator);
 
+    let mut advance_shipment_scope = Scope::new();
+    let advance_shipment_validator = advance_shipment_scope
+        .compile_and_return(VALIDATION_SCHEMA_ADVANCE_SHIPMENT.clone(), true)
+        .unwrap();
+    validators.insert("advance_shipment", advance_shipment_validator);
+
+    let mut restock_scope = Scope::new();
+    let restock_validator = restock_scope
+        .compile_and_return(VALIDATION_SCHEMA_RESTOCK.clone(), true)
+        .unwrap();
+    validators.insert("restock", restock_validator);
+
     for message in consumer.start().wait() {
         match message {
             Err(e) => error!("line:{}: Can't read from kafka stream: {:?}", line!(), e),
             Ok(Err(e)) => error!("line:{}: Error: kafka error: {}", line!(), e),
             Ok(Ok(msg)) => {
-                match msg.payload_view::<str>() {
-                    None => {
-                        error!("line:{}: Empty payload came from kafka", line!());
-                    }
-                    Some(Ok(payload)) => {
-                        debug!(
-                            "payload: '{}', topic: {}, partition: {}, offset: {}, timestamp: {:?}",
-                            payload,
-                            msg.topic(),
-                            msg.partition(),
-                            msg.offset(),
-                            msg.timestamp()
-                        );
-
-                        match get_kafka_message_metadata(&msg.headers()) {
-                            Ok(metadata) => match metadata.get("operation") {
-                                Some(op) => {
-                                    let mut headers = OwnedHeaders::new()
-                                        .add("user_id", metadata["user_id"])
-                                        .add("order_id", metadata["order_id"])
-                                        .add("transaction", *op);
-
-                                    match process_operation(&validators, op, payload, &pool) {
-                                        Ok(_) => {
-                                            headers = headers.add("operation", "commit");
-                                        }
-                                        Err(e) => {
-                                            headers = headers.add("operation", "rollout");
-                                            error!("line:{}: Error: {}", line!(), e);
-                                        }
-                                    }
-
-                                    let payload = "".to_string();
-                                    let record: FutureRecord<String, String> =
-                                        FutureRecord::to(&topics.transactions_topic)
-                                            .headers(headers)
-                                            .payload(&payload);
-
-                                    let _ = producer.send(record, 0);
-                                }
-                                None => error!(
-                                    "line:{}: Operation type wasn't passed in message",
-                                    line!()
-                                ),
-                            },
-                            Err(e) => {
-                                error!("line:{}: Can't parse kafka message headers: {}", line!(), e)
-                            }
-                        }
-                    }
-                    Some(Err(e)) => error!(
-                        "line:{}: Can't deserialize message payload: {:?}",
-                        line!(),
-                        e
-                    ),
+                if let Err(e) = handle_message(&validators, &options, storage.as_ref(), &msg) {
+                    error!("line:{}: Error: {}, message is skipped", line!(), e);
                 }
 
-                consumer.commit_message(&msg, CommitMode::Async).unwrap();
+                if let Err(e) = consumer.commit_message(&msg, CommitMode::Sync) {
+                    error!("line:{}: Can't commit offset: {}", line!(), e);
+                }
             }
         }
     }
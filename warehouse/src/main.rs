@@ -3,19 +3,26 @@ extern crate lazy_static;
 #[macro_use]
 extern crate log;
 
-use actix_web::{middleware::Logger, App, HttpServer};
-use r2d2_redis::{r2d2, RedisConnectionManager};
+use actix_web::{middleware::Logger, web, App, HttpServer};
+use futures::Future;
 use rdkafka::config::{ClientConfig, RDKafkaLogLevel};
 use rdkafka::consumer::stream_consumer::StreamConsumer;
 use rdkafka::producer::FutureProducer;
 use serde::Deserialize;
-use signal_hook::{iterator::Signals, SIGINT, SIGQUIT, SIGTERM};
+use shutdown::{Readiness, ShutdownOptions};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use storage::{InventoryRepository, MemoryStorage, RedisStorage};
 
 mod api;
 mod appconfig;
+mod backorders;
 mod db;
+mod fulfilment;
+mod idempotency;
 mod kafka_processor;
+mod outbox;
+mod storage;
 mod validation_schema;
 
 #[derive(Deserialize)]
@@ -46,6 +53,26 @@ pub struct KafkaConsumerOptions {
 pub struct KafkaTopics {
     warehouse_service_topic: String,
     transactions_topic: String,
+    orders_service_topic: String,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct KafkaProcessingOptions {
+    retry_backoff_ms: u64,
+    max_retry_backoff_ms: u64,
+    processed_ttl_secs: usize,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct OutboxOptions {
+    poll_interval_ms: u64,
+    batch_size: usize,
+    lock_ttl_ms: u64,
+}
+
+#[derive(Deserialize)]
+struct StorageOptions {
+    backend: String,
 }
 
 #[derive(Deserialize)]
@@ -54,6 +81,10 @@ struct Config {
     kafka_producer: KafkaProducerOptions,
     kafka_consumer: KafkaConsumerOptions,
     kafka_topics: KafkaTopics,
+    kafka_processing: KafkaProcessingOptions,
+    outbox: OutboxOptions,
+    shutdown: ShutdownOptions,
+    storage: StorageOptions,
 }
 
 fn read_config(config_file_path: &str) -> Result<String, std::io::Error> {
@@ -90,11 +121,16 @@ fn main() {
             std::env::set_var("RUST_LOG", &config.server.log_level);
             env_logger::init();
 
-            let manager =
-                RedisConnectionManager::new(&config.server.redis_connection_string[..]).unwrap();
-            let pool = r2d2::Pool::builder().build(manager).unwrap();
+            let storage: Arc<dyn InventoryRepository> = match &config.storage.backend[..] {
+                "redis" => Arc::new(
+                    RedisStorage::new(&config.server.redis_connection_string)
+                        .expect("Redis storage creation failed"),
+                ),
+                "memory" => Arc::new(MemoryStorage::new()),
+                backend => panic!("Unknown storage backend: {}", backend),
+            };
 
-            let mut handlers = vec![];
+            let mut consumer_handlers = vec![];
             let mut consumers: Vec<Arc<StreamConsumer<kafka_processor::WarehouseContext>>> = vec![];
 
             for _ in 0..config.server.kafka_workers {
@@ -123,52 +159,66 @@ fn main() {
                 );
                 consumers.push(Arc::clone(&consumer));
 
-                let producer: FutureProducer = ClientConfig::new()
-                    .set(
-                        "bootstrap.servers",
-                        &config.kafka_producer.bootstrap_servers,
-                    )
-                    .set(
-                        "message.timeout.ms",
-                        &config.kafka_producer.message_timeout_ms,
-                    )
-                    .create()
-                    .expect("Producer creation error");
                 let kafka_topics = config.kafka_topics.clone();
-                let pool = pool.clone();
+                let kafka_processing = config.kafka_processing.clone();
+                let storage = Arc::clone(&storage);
 
-                handlers.push(std::thread::spawn(move || {
+                consumer_handlers.push(std::thread::spawn(move || {
                     kafka_processor::consume_and_process(
                         kafka_topics,
-                        producer,
+                        kafka_processing,
                         Arc::clone(&consumer),
-                        pool,
+                        storage,
                     )
                 }));
             }
 
-            let signals = Signals::new(&[SIGINT, SIGTERM, SIGQUIT]).unwrap();
-            let signal_handler = std::thread::spawn(move || {
-                for _ in signals.forever() {
-                    for consumer in consumers.iter() {
-                        consumer.stop();
-                    }
+            let producer: FutureProducer = ClientConfig::new()
+                .set(
+                    "bootstrap.servers",
+                    &config.kafka_producer.bootstrap_servers,
+                )
+                .set(
+                    "message.timeout.ms",
+                    &config.kafka_producer.message_timeout_ms,
+                )
+                .create()
+                .expect("Producer creation error");
+            let running = Arc::new(AtomicBool::new(true));
+            let mut handlers = vec![];
 
-                    break;
-                }
-            });
+            {
+                let kafka_topics = config.kafka_topics.clone();
+                let outbox = config.outbox.clone();
+                let storage = Arc::clone(&storage);
+                let running = Arc::clone(&running);
 
+                handlers.push(std::thread::spawn(move || {
+                    outbox::relay(kafka_topics, outbox, producer, storage, running)
+                }));
+            }
+
+            let readiness = web::Data::new(Readiness::default());
             let sys = actix_rt::System::new("warehouse");
 
             let mut listen_fd = listenfd::ListenFd::from_env();
-            let mut server = HttpServer::new(move || {
-                App::new()
-                    .configure(appconfig::config_app)
-                    .data(pool.clone())
-                    .wrap(Logger::new(
-                    "ip: %a, date: %t, response code: %s, response size: %b (bytes), duration: %D (ms)",
-                ))
-            });
+            let mut server = {
+                let readiness = readiness.clone();
+                HttpServer::new(move || {
+                    App::new()
+                        .configure(appconfig::config_app)
+                        .register_data(readiness.clone())
+                        .data(storage.clone())
+                        .wrap(Logger::new(
+                        "ip: %a, date: %t, response code: %s, response size: %b (bytes), duration: %D (ms)",
+                    ))
+                })
+            };
+
+            // Signals are handled below, so that all parts of service stop in order
+            server = server
+                .disable_signals()
+                .shutdown_timeout(config.shutdown.timeout_secs);
 
             server = if let Some(l) = listen_fd.take_tcp_listener(0).unwrap() {
                 server.listen(l).unwrap()
@@ -179,14 +229,38 @@ fn main() {
                     .unwrap()
             };
 
-            server.start();
-            let _ = sys.run();
+            let server = server.start();
+            let system = actix_rt::System::current();
+            let shutdown_options = config.shutdown;
 
-            signal_handler.join().unwrap();
+            let signal_handler = std::thread::spawn(move || {
+                shutdown::on_signal(
+                    &shutdown_options,
+                    &readiness,
+                    || {
+                        let _ = server.stop(true).wait();
+                    },
+                    || {
+                        for consumer in consumers.iter() {
+                            consumer.stop();
+                        }
 
-            for handler in handlers {
-                handler.join().unwrap();
-            }
+                        for handler in consumer_handlers {
+                            handler.join().unwrap();
+                        }
+
+                        running.store(false, Ordering::SeqCst);
+
+                        for handler in handlers {
+                            handler.join().unwrap();
+                        }
+                    },
+                );
+                system.stop();
+            });
+
+            let _ = sys.run();
+            signal_handler.join().unwrap();
         }
     }
 }
@@ -0,0 +1,383 @@
+use super::{check_stock, now_ms, Change, Changes, Good, InventoryRepository};
+use crate::fulfilment::Shipment;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{Error, ErrorKind};
+use std::sync::{Mutex, MutexGuard};
+
+type OrderKey = (String, String);
+
+#[derive(Default)]
+struct State {
+    goods: BTreeMap<u64, Good>,
+    reservations: HashMap<OrderKey, BTreeMap<u64, i64>>,
+    released: HashMap<OrderKey, u64>,
+    shipments: HashMap<OrderKey, Shipment>,
+    // Order waits for goods since the number, it keeps backorders in order
+    backorders: HashMap<OrderKey, (u64, BTreeMap<u64, i64>)>,
+    backorder_seq: u64,
+    processed: HashMap<String, u64>,
+    outbox: VecDeque<String>,
+    outbox_lock: Option<(String, u64)>,
+}
+
+fn key(user_id: &str, order_id: &str) -> OrderKey {
+    (user_id.to_string(), order_id.to_string())
+}
+
+fn expires_at(ttl_secs: usize) -> u64 {
+    now_ms() + ttl_secs as u64 * 1000
+}
+
+// Keeps everything in process memory, nothing survives restart,
+// it is meant for tests and local runs without databases
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: Mutex<State>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+
+    fn state(&self) -> Result<MutexGuard<'_, State>, Box<dyn std::error::Error>> {
+        self.state.lock().map_err(|e| {
+            Box::new(Error::new(
+                ErrorKind::Other,
+                format!("line:{}: Memory storage is poisoned: {}", line!(), e),
+            )) as Box<dyn std::error::Error>
+        })
+    }
+}
+
+impl InventoryRepository for MemoryStorage {
+    fn goods(&self, limit: usize) -> Result<Vec<(u64, Good)>, Box<dyn std::error::Error>> {
+        Ok(self
+            .state()?
+            .goods
+            .iter()
+            .take(limit)
+            .map(|(good_id, good)| (*good_id, good.clone()))
+            .collect())
+    }
+
+    fn good(&self, good_id: u64) -> Result<Option<Good>, Box<dyn std::error::Error>> {
+        Ok(self.state()?.goods.get(&good_id).cloned())
+    }
+
+    fn stock(&self, good_id: u64) -> Result<Option<i64>, Box<dyn std::error::Error>> {
+        match self
+            .state()?
+            .goods
+            .get(&good_id)
+            .and_then(|good| good.get("count"))
+        {
+            Some(count) => Ok(Some(count.parse()?)),
+            None => Ok(None),
+        }
+    }
+
+    fn reservation(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<BTreeMap<u64, i64>, Box<dyn std::error::Error>> {
+        Ok(self
+            .state()?
+            .reservations
+            .get(&key(user_id, order_id))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn is_released(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self
+            .state()?
+            .released
+            .get(&key(user_id, order_id))
+            .is_some_and(|expires_at| *expires_at > now_ms()))
+    }
+
+    fn backorder(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<BTreeMap<u64, i64>, Box<dyn std::error::Error>> {
+        Ok(self
+            .state()?
+            .backorders
+            .get(&key(user_id, order_id))
+            .map(|(_, goods)| goods.clone())
+            .unwrap_or_default())
+    }
+
+    fn backordered(&self) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+        let state = self.state()?;
+        let mut orders: Vec<(&u64, &OrderKey)> = state
+            .backorders
+            .iter()
+            .map(|(key, (since, _))| (since, key))
+            .collect();
+        orders.sort();
+        Ok(orders.into_iter().map(|(_, key)| key.clone()).collect())
+    }
+
+    fn shipment(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Option<Shipment>, Box<dyn std::error::Error>> {
+        Ok(self
+            .state()?
+            .shipments
+            .get(&key(user_id, order_id))
+            .cloned())
+    }
+
+    fn is_processed(&self, message_id: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self
+            .state()?
+            .processed
+            .get(message_id)
+            .is_some_and(|expires_at| *expires_at > now_ms()))
+    }
+
+    // Stock counts are checked before the first change, so changes are
+    // applied either all or none
+    fn apply(&self, changes: Changes) -> Result<(), Box<dyn std::error::Error>> {
+        let mut state = self.state()?;
+
+        for change in &changes.0 {
+            match change {
+                Change::CheckStock { good_id, count } => {
+                    let stored = match state.goods.get(good_id).and_then(|good| good.get("count")) {
+                        Some(stored) => Some(stored.parse::<i64>()?),
+                        None => None,
+                    };
+                    check_stock(*good_id, stored, *count)?;
+                }
+                Change::AdjustStock { good_id, .. } => {
+                    if let Some(count) = state.goods.get(good_id).and_then(|good| good.get("count"))
+                    {
+                        count.parse::<i64>()?;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for change in changes.0 {
+            match change {
+                Change::CheckStock { .. } => {}
+                Change::AdjustStock { good_id, delta } => {
+                    let good = state.goods.entry(good_id).or_default();
+                    let count = good
+                        .get("count")
+                        .map_or(Ok(0), |count| count.parse::<i64>())?;
+                    good.insert("count".to_string(), (count + delta).to_string());
+                }
+                Change::AdjustReservation {
+                    user_id,
+                    order_id,
+                    good_id,
+                    delta,
+                } => {
+                    *state
+                        .reservations
+                        .entry((user_id, order_id))
+                        .or_default()
+                        .entry(good_id)
+                        .or_default() += delta;
+                }
+                Change::RemoveReservedGood {
+                    user_id,
+                    order_id,
+                    good_id,
+                } => {
+                    if let Some(reservation) = state.reservations.get_mut(&(user_id, order_id)) {
+                        reservation.remove(&good_id);
+                    }
+                }
+                Change::SetReservation {
+                    user_id,
+                    order_id,
+                    goods,
+                } => {
+                    state.reservations.insert((user_id, order_id), goods);
+                }
+                Change::ClearReservation { user_id, order_id } => {
+                    state.reservations.remove(&(user_id, order_id));
+                }
+                Change::MarkReleased {
+                    user_id,
+                    order_id,
+                    ttl_secs,
+                } => {
+                    state
+                        .released
+                        .insert((user_id, order_id), expires_at(ttl_secs));
+                }
+                Change::SetBackorder {
+                    user_id,
+                    order_id,
+                    goods,
+                } => {
+                    let key = (user_id, order_id);
+
+                    if goods.is_empty() {
+                        state.backorders.remove(&key);
+                    } else if let Some((_, current)) = state.backorders.get_mut(&key) {
+                        *current = goods;
+                    } else {
+                        state.backorder_seq += 1;
+                        let since = state.backorder_seq;
+                        state.backorders.insert(key, (since, goods));
+                    }
+                }
+                Change::PutShipment {
+                    user_id,
+                    order_id,
+                    shipment,
+                } => {
+                    state.shipments.insert((user_id, order_id), shipment);
+                }
+                Change::DeleteShipment { user_id, order_id } => {
+                    state.shipments.remove(&(user_id, order_id));
+                }
+                Change::PushOutbox(entry) => state.outbox.push_back(entry),
+                Change::MarkProcessed {
+                    message_id,
+                    ttl_secs,
+                } => {
+                    state.processed.insert(message_id, expires_at(ttl_secs));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn lock_outbox(&self, owner: &str, ttl_ms: u64) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut state = self.state()?;
+        let now = now_ms();
+
+        match &state.outbox_lock {
+            Some((current, expires_at)) if current != owner && *expires_at >= now => Ok(false),
+            _ => {
+                state.outbox_lock = Some((owner.to_string(), now + ttl_ms));
+                Ok(true)
+            }
+        }
+    }
+
+    fn unlock_outbox(&self, owner: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut state = self.state()?;
+
+        if let Some((current, _)) = &state.outbox_lock {
+            if current == owner {
+                state.outbox_lock = None;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn outbox(&self, limit: usize) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        Ok(self.state()?.outbox.iter().take(limit).cloned().collect())
+    }
+
+    fn ack_outbox(
+        &self,
+        owner: &str,
+        entries: &[String],
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut state = self.state()?;
+
+        match &state.outbox_lock {
+            Some((current, expires_at)) if current == owner && *expires_at >= now_ms() => {
+                for entry in entries {
+                    if let Some(index) = state.outbox.iter().position(|e| e == entry) {
+                        state.outbox.remove(index);
+                    }
+                }
+
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Conflict;
+
+    fn apply(
+        storage: &MemoryStorage,
+        changes: Vec<Change>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut batch = Changes::default();
+        for change in changes {
+            batch.push(change);
+        }
+        storage.apply(batch)
+    }
+
+    #[test]
+    fn stock_changed_after_it_was_read_is_conflict() {
+        let storage = MemoryStorage::new();
+        apply(
+            &storage,
+            vec![Change::AdjustStock {
+                good_id: 1,
+                delta: 5,
+            }],
+        )
+        .unwrap();
+
+        let reserve = |count| {
+            vec![
+                Change::CheckStock { good_id: 1, count },
+                Change::AdjustStock {
+                    good_id: 1,
+                    delta: -2,
+                },
+                Change::AdjustReservation {
+                    user_id: "1".to_string(),
+                    order_id: "1".to_string(),
+                    good_id: 1,
+                    delta: 2,
+                },
+            ]
+        };
+        apply(&storage, reserve(5)).unwrap();
+
+        // Another reservation read the stock before the first one was applied
+        let e = apply(&storage, reserve(5)).unwrap_err();
+        assert!(e.is::<Conflict>());
+        assert_eq!(storage.stock(1).unwrap(), Some(3));
+        assert_eq!(
+            storage.reservation("1", "1").unwrap(),
+            vec![(1, 2)].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn stock_of_unknown_good_is_conflict() {
+        let storage = MemoryStorage::new();
+        let e = apply(
+            &storage,
+            vec![Change::CheckStock {
+                good_id: 1,
+                count: 0,
+            }],
+        )
+        .unwrap_err();
+        assert!(e.is::<Conflict>());
+    }
+}
@@ -0,0 +1,176 @@
+use crate::fulfilment::Shipment;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod memory;
+mod redis;
+
+pub use self::memory::MemoryStorage;
+pub use self::redis::RedisStorage;
+
+// All stored fields of a good, 'count' is the amount left in stock
+pub type Good = HashMap<String, String>;
+
+pub enum Change {
+    // Stock the changes were computed from, they aren't applied
+    // if it was changed meanwhile
+    CheckStock {
+        good_id: u64,
+        count: i64,
+    },
+    AdjustStock {
+        good_id: u64,
+        delta: i64,
+    },
+    AdjustReservation {
+        user_id: String,
+        order_id: String,
+        good_id: u64,
+        delta: i64,
+    },
+    RemoveReservedGood {
+        user_id: String,
+        order_id: String,
+        good_id: u64,
+    },
+    SetReservation {
+        user_id: String,
+        order_id: String,
+        goods: BTreeMap<u64, i64>,
+    },
+    ClearReservation {
+        user_id: String,
+        order_id: String,
+    },
+    MarkReleased {
+        user_id: String,
+        order_id: String,
+        ttl_secs: usize,
+    },
+    // Empty goods remove the order from backorders
+    SetBackorder {
+        user_id: String,
+        order_id: String,
+        goods: BTreeMap<u64, i64>,
+    },
+    PutShipment {
+        user_id: String,
+        order_id: String,
+        shipment: Shipment,
+    },
+    DeleteShipment {
+        user_id: String,
+        order_id: String,
+    },
+    PushOutbox(String),
+    MarkProcessed {
+        message_id: String,
+        ttl_secs: usize,
+    },
+}
+
+// Everything produced by processing one message is applied by storage at once:
+// stock and reservation changes, answer for orders service and processed mark
+#[derive(Default)]
+pub struct Changes(Vec<Change>);
+
+impl Changes {
+    pub fn push(&mut self, change: Change) {
+        self.0.push(change);
+    }
+}
+
+// Stock was changed by someone else after it was read, what was computed
+// from it is stale and the whole operation should be repeated
+#[derive(Debug)]
+pub struct Conflict(pub String);
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Conflict {}
+
+fn check_stock(
+    good_id: u64,
+    stored: Option<i64>,
+    count: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if stored == Some(count) {
+        Ok(())
+    } else {
+        Err(Box::new(Conflict(format!(
+            "line:{}: Stock of good {} was changed, it is {:?}, not {}",
+            line!(),
+            good_id,
+            stored,
+            count
+        ))))
+    }
+}
+
+// Business logic and api handlers work with stock only through this trait,
+// so storage can be replaced without touching them
+pub trait InventoryRepository: Send + Sync {
+    fn goods(&self, limit: usize) -> Result<Vec<(u64, Good)>, Box<dyn std::error::Error>>;
+
+    fn good(&self, good_id: u64) -> Result<Option<Good>, Box<dyn std::error::Error>>;
+
+    fn stock(&self, good_id: u64) -> Result<Option<i64>, Box<dyn std::error::Error>>;
+
+    fn reservation(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<BTreeMap<u64, i64>, Box<dyn std::error::Error>>;
+
+    fn is_released(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>>;
+
+    // Goods which are missing for the order, they are reserved when stock is replenished
+    fn backorder(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<BTreeMap<u64, i64>, Box<dyn std::error::Error>>;
+
+    // Orders waiting for goods, the ones which wait longer go first
+    fn backordered(&self) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>>;
+
+    fn shipment(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Option<Shipment>, Box<dyn std::error::Error>>;
+
+    fn is_processed(&self, message_id: &str) -> Result<bool, Box<dyn std::error::Error>>;
+
+    fn apply(&self, changes: Changes) -> Result<(), Box<dyn std::error::Error>>;
+
+    fn lock_outbox(&self, owner: &str, ttl_ms: u64) -> Result<bool, Box<dyn std::error::Error>>;
+
+    fn unlock_outbox(&self, owner: &str) -> Result<(), Box<dyn std::error::Error>>;
+
+    fn outbox(&self, limit: usize) -> Result<Vec<String>, Box<dyn std::error::Error>>;
+
+    // Removes published entries if the lock is still held by the owner,
+    // returns false when it was taken over and entries are left in outbox
+    fn ack_outbox(
+        &self,
+        owner: &str,
+        entries: &[String],
+    ) -> Result<bool, Box<dyn std::error::Error>>;
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,415 @@
+use super::{check_stock, now_ms, Change, Changes, Conflict, Good, InventoryRepository};
+use crate::fulfilment::Shipment;
+use r2d2_redis::{r2d2, redis, RedisConnectionManager};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::DerefMut;
+
+const PROCESSED_KEY_PREFIX: &str = "processed:warehouse";
+const OUTBOX_KEY: &str = "outbox:warehouse";
+const OUTBOX_LOCK_KEY: &str = "outbox:warehouse:lock";
+
+// Lock is prolonged by its owner, so only one relay publishes messages
+// and their order is kept the same as order of state changes
+const ACQUIRE_LOCK: &str = r#"
+    if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+        return 1
+    end
+    if redis.call('GET', KEYS[1]) == ARGV[1] then
+        redis.call('PEXPIRE', KEYS[1], ARGV[2])
+        return 1
+    end
+    return 0"#;
+
+// Entries have no ids in list, published ones are removed by content,
+// and only while the lock is still held by the relay which read them
+const ACK_OUTBOX: &str = r#"
+    if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+        return 0
+    end
+    for i = 2, #ARGV do
+        redis.call('LREM', KEYS[2], 1, ARGV[i])
+    end
+    return 1"#;
+
+const RELEASE_LOCK: &str = r#"
+    if redis.call('GET', KEYS[1]) == ARGV[1] then
+        return redis.call('DEL', KEYS[1])
+    end
+    return 0"#;
+
+fn good_key(good_id: u64) -> String {
+    format!("good_id:{}", good_id)
+}
+
+fn reservation_key(user_id: &str, order_id: &str) -> String {
+    format!("reservation:user_id:{}:order_id:{}", user_id, order_id)
+}
+
+fn released_key(user_id: &str, order_id: &str) -> String {
+    format!("released:user_id:{}:order_id:{}", user_id, order_id)
+}
+
+fn backorder_key(user_id: &str, order_id: &str) -> String {
+    format!("backorder:user_id:{}:order_id:{}", user_id, order_id)
+}
+
+// Orders waiting for goods are scored by the time they started to wait,
+// members are '<order id>:<user id>' as order id has no colons
+const BACKORDERS_KEY: &str = "backorders";
+
+fn backorders_member(user_id: &str, order_id: &str) -> String {
+    format!("{}:{}", order_id, user_id)
+}
+
+// Shipment is kept in json
+fn shipment_key(user_id: &str, order_id: &str) -> String {
+    format!("shipment:user_id:{}:order_id:{}", user_id, order_id)
+}
+
+pub struct RedisStorage {
+    pool: r2d2::Pool<RedisConnectionManager>,
+}
+
+impl RedisStorage {
+    pub fn new(connection_string: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let manager = RedisConnectionManager::new(connection_string)?;
+        let pool = r2d2::Pool::builder().build(manager)?;
+        Ok(RedisStorage { pool })
+    }
+
+    fn write_change(
+        &self,
+        pipe: &mut redis::Pipeline,
+        change: &Change,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match change {
+            Change::CheckStock { .. } => {}
+            Change::AdjustStock { good_id, delta } => {
+                pipe.cmd("HINCRBY")
+                    .arg(&[&good_key(*good_id), "count"])
+                    .arg(*delta);
+            }
+            Change::AdjustReservation {
+                user_id,
+                order_id,
+                good_id,
+                delta,
+            } => {
+                pipe.cmd("HINCRBY")
+                    .arg(&[reservation_key(user_id, order_id), good_key(*good_id)])
+                    .arg(*delta);
+            }
+            Change::RemoveReservedGood {
+                user_id,
+                order_id,
+                good_id,
+            } => {
+                pipe.cmd("HDEL")
+                    .arg(&[reservation_key(user_id, order_id), good_key(*good_id)]);
+            }
+            Change::SetReservation {
+                user_id,
+                order_id,
+                goods,
+            } => {
+                let reservation_key = &reservation_key(user_id, order_id);
+                pipe.cmd("DEL").arg(reservation_key);
+
+                for (good_id, count) in goods {
+                    pipe.cmd("HSET")
+                        .arg(&[reservation_key, &good_key(*good_id)])
+                        .arg(*count);
+                }
+            }
+            Change::ClearReservation { user_id, order_id } => {
+                pipe.cmd("DEL").arg(reservation_key(user_id, order_id));
+            }
+            Change::MarkReleased {
+                user_id,
+                order_id,
+                ttl_secs,
+            } => {
+                pipe.cmd("SET")
+                    .arg(released_key(user_id, order_id))
+                    .arg(1)
+                    .arg("EX")
+                    .arg(*ttl_secs);
+            }
+            Change::SetBackorder {
+                user_id,
+                order_id,
+                goods,
+            } => {
+                let key = &backorder_key(user_id, order_id);
+                let member = backorders_member(user_id, order_id);
+                pipe.cmd("DEL").arg(key);
+
+                if goods.is_empty() {
+                    pipe.cmd("ZREM").arg(BACKORDERS_KEY).arg(member);
+                } else {
+                    // Order keeps its place in line while it waits
+                    pipe.cmd("ZADD")
+                        .arg(&[BACKORDERS_KEY, "NX"])
+                        .arg(now_ms())
+                        .arg(member);
+
+                    for (good_id, count) in goods {
+                        pipe.cmd("HSET")
+                            .arg(&[key, &good_key(*good_id)])
+                            .arg(*count);
+                    }
+                }
+            }
+            Change::PutShipment {
+                user_id,
+                order_id,
+                shipment,
+            } => {
+                pipe.cmd("SET")
+                    .arg(shipment_key(user_id, order_id))
+                    .arg(serde_json::to_string(shipment)?);
+            }
+            Change::DeleteShipment { user_id, order_id } => {
+                pipe.cmd("DEL").arg(shipment_key(user_id, order_id));
+            }
+            Change::PushOutbox(entry) => {
+                pipe.cmd("RPUSH").arg(OUTBOX_KEY).arg(entry);
+            }
+            Change::MarkProcessed {
+                message_id,
+                ttl_secs,
+            } => {
+                pipe.cmd("SET")
+                    .arg(format!("{}:{}", PROCESSED_KEY_PREFIX, message_id))
+                    .arg(1)
+                    .arg("EX")
+                    .arg(*ttl_secs);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl InventoryRepository for RedisStorage {
+    fn goods(&self, limit: usize) -> Result<Vec<(u64, Good)>, Box<dyn std::error::Error>> {
+        let mut conn = self.pool.get()?;
+        let (_, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .cursor_arg(0)
+            .arg(&["MATCH", "good_id:*", "COUNT"])
+            .arg(limit)
+            .query(conn.deref_mut())?;
+
+        let mut pipe = redis::pipe();
+
+        for key in &keys {
+            pipe.cmd("HGETALL").arg(key);
+        }
+
+        let hashes: Vec<HashMap<String, String>> = pipe.query(conn.deref_mut())?;
+        let mut result = vec![];
+
+        for (key, hash) in keys.iter().zip(hashes) {
+            if let Some(good_id) = key.strip_prefix("good_id:") {
+                result.push((good_id.parse()?, hash));
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn good(&self, good_id: u64) -> Result<Option<Good>, Box<dyn std::error::Error>> {
+        let hash: HashMap<String, String> = redis::cmd("HGETALL")
+            .arg(good_key(good_id))
+            .query(self.pool.get()?.deref_mut())?;
+
+        if hash.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(hash))
+        }
+    }
+
+    fn stock(&self, good_id: u64) -> Result<Option<i64>, Box<dyn std::error::Error>> {
+        let count: Option<i64> = redis::cmd("HGET")
+            .arg(&[&good_key(good_id), "count"])
+            .query(self.pool.get()?.deref_mut())?;
+        Ok(count)
+    }
+
+    fn reservation(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<BTreeMap<u64, i64>, Box<dyn std::error::Error>> {
+        let reserved: HashMap<String, i64> = redis::cmd("HGETALL")
+            .arg(reservation_key(user_id, order_id))
+            .query(self.pool.get()?.deref_mut())?;
+        let mut result = BTreeMap::new();
+
+        for (key, count) in reserved {
+            if let Some(good_id) = key.strip_prefix("good_id:") {
+                result.insert(good_id.parse()?, count);
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn is_released(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let exists: i32 = redis::cmd("EXISTS")
+            .arg(released_key(user_id, order_id))
+            .query(self.pool.get()?.deref_mut())?;
+        Ok(exists == 1)
+    }
+
+    fn backorder(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<BTreeMap<u64, i64>, Box<dyn std::error::Error>> {
+        let missing: HashMap<String, i64> = redis::cmd("HGETALL")
+            .arg(backorder_key(user_id, order_id))
+            .query(self.pool.get()?.deref_mut())?;
+        let mut result = BTreeMap::new();
+
+        for (key, count) in missing {
+            if let Some(good_id) = key.strip_prefix("good_id:") {
+                result.insert(good_id.parse()?, count);
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn backordered(&self) -> Result<Vec<(String, String)>, Box<dyn std::error::Error>> {
+        let members: Vec<String> = redis::cmd("ZRANGE")
+            .arg(BACKORDERS_KEY)
+            .arg(0)
+            .arg(-1)
+            .query(self.pool.get()?.deref_mut())?;
+
+        Ok(members
+            .into_iter()
+            .filter_map(|member| {
+                let mut splits = member.splitn(2, ':');
+                let order_id = splits.next()?.to_string();
+                let user_id = splits.next()?.to_string();
+                Some((user_id, order_id))
+            })
+            .collect())
+    }
+
+    fn shipment(
+        &self,
+        user_id: &str,
+        order_id: &str,
+    ) -> Result<Option<Shipment>, Box<dyn std::error::Error>> {
+        let shipment: Option<String> = redis::cmd("GET")
+            .arg(shipment_key(user_id, order_id))
+            .query(self.pool.get()?.deref_mut())?;
+
+        match shipment {
+            Some(shipment) => Ok(Some(serde_json::from_str(&shipment)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn is_processed(&self, message_id: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let exists: i32 = redis::cmd("EXISTS")
+            .arg(format!("{}:{}", PROCESSED_KEY_PREFIX, message_id))
+            .query(self.pool.get()?.deref_mut())?;
+        Ok(exists == 1)
+    }
+
+    // Checked stock is watched, so changes computed from it are applied
+    // only if nobody changed it in between
+    fn apply(&self, changes: Changes) -> Result<(), Box<dyn std::error::Error>> {
+        let mut conn = self.pool.get()?;
+        let checked: Vec<(u64, i64)> = changes
+            .0
+            .iter()
+            .filter_map(|change| match change {
+                Change::CheckStock { good_id, count } => Some((*good_id, *count)),
+                _ => None,
+            })
+            .collect();
+        let watched: Vec<String> = checked
+            .iter()
+            .map(|(good_id, _)| good_key(*good_id))
+            .collect();
+
+        if !watched.is_empty() {
+            redis::cmd("WATCH")
+                .arg(&watched[..])
+                .query::<()>(conn.deref_mut())?;
+
+            for (good_id, count) in &checked {
+                let stored: Option<i64> = redis::cmd("HGET")
+                    .arg(&[&good_key(*good_id), "count"])
+                    .query(conn.deref_mut())?;
+
+                if let Err(e) = check_stock(*good_id, stored, *count) {
+                    redis::cmd("UNWATCH").query::<()>(conn.deref_mut())?;
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        for change in &changes.0 {
+            self.write_change(&mut pipe, change)?;
+        }
+
+        match pipe.query::<Option<()>>(conn.deref_mut())? {
+            Some(_) => Ok(()),
+            None => Err(Box::new(Conflict(format!(
+                "line:{}: Stock of goods {} was changed concurrently",
+                line!(),
+                watched.join(", ")
+            )))),
+        }
+    }
+
+    fn lock_outbox(&self, owner: &str, ttl_ms: u64) -> Result<bool, Box<dyn std::error::Error>> {
+        let locked: i32 = redis::cmd("EVAL")
+            .arg(&[ACQUIRE_LOCK, "1", OUTBOX_LOCK_KEY, owner])
+            .arg(ttl_ms)
+            .query(self.pool.get()?.deref_mut())?;
+        Ok(locked == 1)
+    }
+
+    fn unlock_outbox(&self, owner: &str) -> Result<(), Box<dyn std::error::Error>> {
+        redis::cmd("EVAL")
+            .arg(&[RELEASE_LOCK, "1", OUTBOX_LOCK_KEY, owner])
+            .query::<i32>(self.pool.get()?.deref_mut())?;
+        Ok(())
+    }
+
+    fn outbox(&self, limit: usize) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let entries: Vec<String> = redis::cmd("LRANGE")
+            .arg(OUTBOX_KEY)
+            .arg(0)
+            .arg(limit as isize - 1)
+            .query(self.pool.get()?.deref_mut())?;
+        Ok(entries)
+    }
+
+    fn ack_outbox(
+        &self,
+        owner: &str,
+        entries: &[String],
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let acked: i32 = redis::cmd("EVAL")
+            .arg(&[ACK_OUTBOX, "2", OUTBOX_LOCK_KEY, OUTBOX_KEY, owner])
+            .arg(entries)
+            .query(self.pool.get()?.deref_mut())?;
+        Ok(acked == 1)
+    }
+}
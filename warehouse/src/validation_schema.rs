@@ -25,6 +25,10 @@ lazy_static! {
                         "required": ["id", "count"],
                         "additionalProperties": false
                     }
+                },
+                "fulfilment": {
+                    "type": "string",
+                    "enum": ["all_or_nothing", "partial"]
                 }
             },
             "required": ["goods"],
@@ -43,7 +47,6 @@ lazy_static! {
                 "goods": {
                     "type": "array",
                     "uniqueItems": true,
-                    "minItems": 1,
                     "items": {
                         "type": "object",
                         "properties": {
@@ -82,3 +85,48 @@ lazy_static! {
     )
     .unwrap();
 }
+
+lazy_static! {
+    pub static ref VALIDATION_SCHEMA_ADVANCE_SHIPMENT: Value = from_str(
+        r#"
+        {
+            "type": "object",
+            "properties": {
+                "state": {
+                    "type": "string",
+                    "enum": ["packed", "shipped", "delivered"]
+                },
+                "carrier": {
+                    "type": "string",
+                    "minLength": 1,
+                    "maxLength": 100
+                },
+                "tracking_number": {
+                    "type": "string",
+                    "pattern": "^[A-Za-z0-9-]{4,40}$"
+                }
+            },
+            "required": ["state"],
+            "additionalProperties": false
+        }"#,
+    )
+    .unwrap();
+}
+
+lazy_static! {
+    pub static ref VALIDATION_SCHEMA_RESTOCK: Value = from_str(
+        r#"
+        {
+            "type": "object",
+            "properties": {
+                "count": {
+                    "type": "integer",
+                    "minimum": 1
+                }
+            },
+            "required": ["count"],
+            "additionalProperties": false
+        }"#,
+    )
+    .unwrap();
+}